@@ -124,6 +124,22 @@ def test_ahocorasick_rs_standard_indexes(benchmark, test_data):
     print(benchmark(run))
 
 
+@parameterize_datasets
+def test_ahocorasick_rs_standard_into(benchmark, test_data):
+    """ahocorasick_rs standard matching algorithm, appending to one list."""
+    patterns, haystacks = test_data
+    ac = ahocorasick_rs.AhoCorasick(patterns)
+
+    def run():
+        out = []
+        for haystack in haystacks:
+            ac.find_matches_into(haystack, out)
+            out.clear()
+        return out
+
+    print(benchmark(run))
+
+
 @parameterize_datasets
 def test_ahocorasick_rs_overlapping(benchmark, test_data):
     """ahocorasick_rs overlapping matches."""
@@ -164,3 +180,49 @@ def test_overhead(benchmark, test_data):
             _ = haystack
 
     print(benchmark(run))
+
+
+# A few single byte patterns are searched for with memchr; repeating one of
+# them disables that, so the general automaton gets used instead.
+@pytest.mark.parametrize(
+    "patterns", [["?", "!", ","], ["?", "!", ",", "?"]], ids=["memchr", "automaton"]
+)
+def test_ahocorasick_rs_single_bytes(benchmark, patterns):
+    """ahocorasick_rs with single byte patterns."""
+    ac = ahocorasick_rs.AhoCorasick(patterns)
+
+    def run():
+        for haystack in HAYSTACKS_LONG:
+            x = ac.find_matches_as_indexes(haystack)
+        return x
+
+    print(benchmark(run))
+
+
+# 1MB haystacks: ASCII ones need no byte to code point lookup table, a
+# single non-ASCII character at the end means building one.
+HAYSTACK_ASCII_1MB = ("hello world, arbitrarymonkey says hi to the fish " * 21000)[
+    :1_000_000
+]
+HAYSTACK_NON_ASCII_1MB = HAYSTACK_ASCII_1MB[:-1] + "é"
+
+
+@pytest.mark.parametrize(
+    "haystack,force_byte_offsets",
+    [
+        (HAYSTACK_ASCII_1MB, False),
+        (HAYSTACK_ASCII_1MB, True),
+        (HAYSTACK_NON_ASCII_1MB, False),
+    ],
+    ids=["ascii", "forced", "table"],
+)
+def test_ahocorasick_rs_1mb(benchmark, haystack, force_byte_offsets):
+    """ahocorasick_rs on a 1MB haystack, with and without a lookup table."""
+    ac = ahocorasick_rs.AhoCorasick(
+        PATTERNS_SHORT, force_byte_offsets_on_ascii=force_byte_offsets
+    )
+
+    def run():
+        return ac.find_matches_as_indexes(haystack)
+
+    print(len(benchmark(run)))
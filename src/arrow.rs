@@ -0,0 +1,292 @@
+//! Reading Arrow string arrays via the Arrow PyCapsule interface, see
+//! https://arrow.apache.org/docs/format/CDataInterface/PyCapsuleInterface.html
+
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+};
+
+use pyo3::{exceptions::PyValueError, ffi, prelude::*, AsPyPointer};
+
+#[repr(C)]
+struct ArrowSchema {
+    format: *const c_char,
+    name: *const c_char,
+    metadata: *const c_char,
+    flags: i64,
+    n_children: i64,
+    children: *mut *mut ArrowSchema,
+    dictionary: *mut ArrowSchema,
+    release: Option<unsafe extern "C" fn(*mut ArrowSchema)>,
+    private_data: *mut c_void,
+}
+
+#[repr(C)]
+struct ArrowArray {
+    length: i64,
+    null_count: i64,
+    offset: i64,
+    n_buffers: i64,
+    n_children: i64,
+    buffers: *mut *const c_void,
+    children: *mut *mut ArrowArray,
+    dictionary: *mut ArrowArray,
+    release: Option<unsafe extern "C" fn(*mut ArrowArray)>,
+    private_data: *mut c_void,
+}
+
+#[repr(C)]
+struct ArrowArrayStream {
+    get_schema: Option<unsafe extern "C" fn(*mut ArrowArrayStream, *mut ArrowSchema) -> c_int>,
+    get_next: Option<unsafe extern "C" fn(*mut ArrowArrayStream, *mut ArrowArray) -> c_int>,
+    get_last_error: Option<unsafe extern "C" fn(*mut ArrowArrayStream) -> *const c_char>,
+    release: Option<unsafe extern "C" fn(*mut ArrowArrayStream)>,
+    private_data: *mut c_void,
+}
+
+impl ArrowSchema {
+    fn empty() -> Self {
+        Self {
+            format: std::ptr::null(),
+            name: std::ptr::null(),
+            metadata: std::ptr::null(),
+            flags: 0,
+            n_children: 0,
+            children: std::ptr::null_mut(),
+            dictionary: std::ptr::null_mut(),
+            release: None,
+            private_data: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl ArrowArray {
+    fn empty() -> Self {
+        Self {
+            length: 0,
+            null_count: 0,
+            offset: 0,
+            n_buffers: 0,
+            n_children: 0,
+            buffers: std::ptr::null_mut(),
+            children: std::ptr::null_mut(),
+            dictionary: std::ptr::null_mut(),
+            release: None,
+            private_data: std::ptr::null_mut(),
+        }
+    }
+}
+
+/// Strings read from Arrow arrays, concatenated into a single buffer with the
+/// end offset of each, like StoredPatterns::Compact. Arrays from a stream are
+/// released as soon as they've been read, so their buffers can't be borrowed
+/// for longer; this copies the text once, without an allocation per string.
+#[derive(Default)]
+pub struct ArrowStrings {
+    pub text: String,
+    pub ends: Vec<usize>,
+}
+
+impl ArrowStrings {
+    fn push(&mut self, value: &str) {
+        self.text.push_str(value);
+        self.ends.push(self.text.len());
+    }
+
+    /// The strings, as slices of the buffer.
+    pub fn iter(&self) -> impl Iterator<Item = &str> + Send + '_ {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(self.ends.iter().copied())
+            .map(move |(start, end)| &self.text[start..end])
+    }
+}
+
+/// Which of the Arrow string layouts we're reading.
+#[derive(Clone, Copy)]
+enum Layout {
+    /// "string", with 32-bit offsets.
+    String,
+    /// "large_string", with 64-bit offsets.
+    LargeString,
+}
+
+/// Get the pointer stored in a PyCapsule with the given name.
+fn capsule_pointer<T>(capsule: &PyAny, name: &'static [u8]) -> PyResult<*mut T> {
+    let name = CStr::from_bytes_with_nul(name).unwrap();
+    let pointer = unsafe { ffi::PyCapsule_GetPointer(capsule.as_ptr(), name.as_ptr()) };
+    if pointer.is_null() {
+        return Err(PyErr::fetch(capsule.py()));
+    }
+    Ok(pointer as *mut T)
+}
+
+/// Figure out the layout from a schema.
+fn get_layout(schema: &ArrowSchema) -> PyResult<Layout> {
+    let format = unsafe { CStr::from_ptr(schema.format) }.to_bytes();
+    match format {
+        b"u" => Ok(Layout::String),
+        b"U" => Ok(Layout::LargeString),
+        _ => Err(PyValueError::new_err(format!(
+            "Expected an Arrow string or large_string array, got Arrow format {:?}.",
+            String::from_utf8_lossy(format)
+        ))),
+    }
+}
+
+/// Append the values of an Arrow array to the given ArrowStrings. `index` is
+/// the index of the first item of the array within the overall input, for
+/// error messages.
+fn read_array(
+    array: &ArrowArray,
+    layout: Layout,
+    skip_nulls: bool,
+    index: usize,
+    result: &mut ArrowStrings,
+) -> PyResult<()> {
+    if array.n_buffers != 3 {
+        return Err(PyValueError::new_err(
+            "Arrow string arrays should have 3 buffers.",
+        ));
+    }
+    let length = array.length as usize;
+    let offset = array.offset as usize;
+    let buffers = unsafe { std::slice::from_raw_parts(array.buffers, 3) };
+    let validity = buffers[0] as *const u8;
+    let data = buffers[2] as *const u8;
+    for i in 0..length {
+        let position = offset + i;
+        let is_valid = array.null_count == 0
+            || validity.is_null()
+            || unsafe { *validity.add(position / 8) } & (1 << (position % 8)) != 0;
+        if !is_valid {
+            if skip_nulls {
+                continue;
+            }
+            return Err(PyValueError::new_err(format!(
+                "Pattern at index {} is null.",
+                index + i
+            )));
+        }
+        let (start, end) = match layout {
+            Layout::String => {
+                let offsets = buffers[1] as *const i32;
+                unsafe {
+                    (
+                        *offsets.add(position) as usize,
+                        *offsets.add(position + 1) as usize,
+                    )
+                }
+            }
+            Layout::LargeString => {
+                let offsets = buffers[1] as *const i64;
+                unsafe {
+                    (
+                        *offsets.add(position) as usize,
+                        *offsets.add(position + 1) as usize,
+                    )
+                }
+            }
+        };
+        if end < start {
+            return Err(PyValueError::new_err(format!(
+                "Pattern at index {} has invalid offsets.",
+                index + i
+            )));
+        }
+        // The data buffer may be null if every value is empty.
+        let value = if data.is_null() {
+            if end != start {
+                return Err(PyValueError::new_err(
+                    "Arrow string array has non-empty values but no data buffer.",
+                ));
+            }
+            &[][..]
+        } else {
+            unsafe { std::slice::from_raw_parts(data.add(start), end - start) }
+        };
+        let value = std::str::from_utf8(value).map_err(|e| {
+            PyValueError::new_err(format!(
+                "Pattern at index {} isn't valid UTF-8: {}",
+                index + i,
+                e
+            ))
+        })?;
+        result.push(value);
+    }
+    Ok(())
+}
+
+/// Read the values of an object exposing an Arrow string or large_string
+/// array (or a stream of them) via the Arrow PyCapsule interface. Nulls
+/// either cause a ValueError or are skipped.
+pub fn read_arrow_strings(obj: &PyAny, skip_nulls: bool) -> PyResult<ArrowStrings> {
+    let mut result = ArrowStrings::default();
+    if obj.hasattr("__arrow_c_stream__")? {
+        let capsule = obj.call_method0("__arrow_c_stream__")?;
+        let stream = capsule_pointer::<ArrowArrayStream>(capsule, b"arrow_array_stream\0")?;
+        // The stream itself is owned by the capsule, which releases it when
+        // it's garbage collected; schemas and arrays we get from the stream
+        // are ours to release.
+        let stream_error = |code: c_int| {
+            let message = unsafe {
+                match (*stream).get_last_error {
+                    Some(get_last_error) => {
+                        let error = get_last_error(stream);
+                        if error.is_null() {
+                            None
+                        } else {
+                            Some(CStr::from_ptr(error).to_string_lossy().into_owned())
+                        }
+                    }
+                    None => None,
+                }
+            };
+            PyValueError::new_err(format!(
+                "Error reading Arrow stream: {}",
+                message.unwrap_or_else(|| format!("error code {}", code))
+            ))
+        };
+        let mut schema = ArrowSchema::empty();
+        let code = unsafe { ((*stream).get_schema.unwrap())(stream, &mut schema) };
+        if code != 0 {
+            return Err(stream_error(code));
+        }
+        let layout = get_layout(&schema);
+        if let Some(release) = schema.release {
+            unsafe { release(&mut schema) };
+        }
+        let layout = layout?;
+        let mut index = 0;
+        loop {
+            let mut array = ArrowArray::empty();
+            let code = unsafe { ((*stream).get_next.unwrap())(stream, &mut array) };
+            if code != 0 {
+                return Err(stream_error(code));
+            }
+            let release = match array.release {
+                Some(release) => release,
+                // A released array marks the end of the stream.
+                None => break,
+            };
+            let read = read_array(&array, layout, skip_nulls, index, &mut result);
+            index += array.length as usize;
+            unsafe { release(&mut array) };
+            read?;
+        }
+    } else if obj.hasattr("__arrow_c_array__")? {
+        // The schema and array are owned by the capsules, which release them
+        // when they're garbage collected.
+        let (schema_capsule, array_capsule): (&PyAny, &PyAny) =
+            obj.call_method0("__arrow_c_array__")?.extract()?;
+        let schema = capsule_pointer::<ArrowSchema>(schema_capsule, b"arrow_schema\0")?;
+        let array = capsule_pointer::<ArrowArray>(array_capsule, b"arrow_array\0")?;
+        let layout = get_layout(unsafe { &*schema })?;
+        read_array(unsafe { &*array }, layout, skip_nulls, 0, &mut result)?;
+    } else {
+        return Err(PyValueError::new_err(
+            "Expected an object supporting the Arrow PyCapsule interface (__arrow_c_stream__ or __arrow_c_array__).",
+        ));
+    }
+    Ok(result)
+}
@@ -1,126 +1,6542 @@
+mod arrow;
+mod saved;
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, Read, Write},
+    ops::Deref,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, Match, MatchKind};
-use pyo3::{exceptions::PyValueError, prelude::*, types::PyUnicode};
+use lru::LruCache;
+use memchr::{memchr2_iter, memchr3_iter, memchr_iter};
+use pyo3::{
+    basic::CompareOp,
+    buffer::PyBuffer,
+    create_exception,
+    exceptions::{
+        PyIOError, PyImportError, PyIndexError, PyRuntimeError, PyStopIteration, PyTypeError,
+        PyValueError,
+    },
+    once_cell::GILOnceCell,
+    prelude::*,
+    pyclass::PyClass,
+    pyclass_init::PyClassInitializer,
+    types::{PyBytes, PyDict, PyIterator, PyList, PyTuple, PyType, PyUnicode},
+};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Convert the Python-level matchkind constant into a MatchKind.
+fn parse_matchkind(matchkind: &str) -> PyResult<MatchKind> {
+    match matchkind {
+        "MATCHKIND_STANDARD" => Ok(MatchKind::Standard),
+        "MATCHKIND_LEFTMOST_FIRST" => Ok(MatchKind::LeftmostFirst),
+        "MATCHKIND_LEFTMOST_LONGEST" => Ok(MatchKind::LeftmostLongest),
+        _ => Err(PyValueError::new_err(
+            "matchkind must be one of the ahocorasick_rs.MATCHKIND_* constants.",
+        )),
+    }
+}
+
+/// A match of a pattern in a haystack. Unlike aho_corasick::Match, we can
+/// construct these ourselves.
+#[derive(Clone, Copy)]
+struct PatternMatch {
+    pattern: usize,
+    start: usize,
+    end: usize,
+}
+
+impl PatternMatch {
+    fn pattern(&self) -> usize {
+        self.pattern
+    }
+
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.end
+    }
+}
+
+impl From<Match> for PatternMatch {
+    fn from(m: Match) -> Self {
+        Self {
+            pattern: m.pattern(),
+            start: m.start(),
+            end: m.end(),
+        }
+    }
+}
+
+/// Which of aho-corasick's automatons to build.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Implementation {
+    NonContiguous,
+    Dfa,
+}
+
+impl Implementation {
+    /// The name used for the implementation argument and property.
+    fn name(self) -> &'static str {
+        match self {
+            Implementation::NonContiguous => "noncontiguous",
+            Implementation::Dfa => "dfa",
+        }
+    }
+}
+
+/// Parse the implementation argument; None means the default, a DFA.
+fn parse_implementation(implementation: Option<&str>) -> PyResult<Implementation> {
+    match implementation {
+        None | Some("dfa") => Ok(Implementation::Dfa),
+        Some("noncontiguous") => Ok(Implementation::NonContiguous),
+        Some("contiguous") => Err(PyValueError::new_err(
+            "implementation=\"contiguous\" isn't available: the aho-corasick 0.7 crate this \
+             is built on has no contiguous NFA, so use \"dfa\" or \"noncontiguous\".",
+        )),
+        Some(other) => Err(PyValueError::new_err(format!(
+            "implementation must be \"dfa\" or \"noncontiguous\", not {:?}.",
+            other
+        ))),
+    }
+}
+
+/// The underlying AhoCorasick automaton, plus a faster search for the common
+/// case of a few single byte patterns. Everything other than find_iter() and
+/// find_overlapping_iter() is delegated to the AhoCorasick.
+struct Automaton {
+    ac_impl: AhoCorasick,
+    // Which automaton ac_impl is.
+    kind: Implementation,
+    // If there are 1 to 3 patterns, all distinct single bytes, they're
+    // searched for with memchr instead of the automaton. Matches can't
+    // overlap and the match kind doesn't matter, so the results are the same.
+    single_bytes: Option<Vec<u8>>,
+    // The length of the shortest pattern, in bytes; unlike the longest, the
+    // AhoCorasick doesn't track it.
+    min_pattern_len: usize,
+    // The total length of all the patterns, in bytes.
+    total_pattern_len: usize,
+    // The greatest common divisor of the patterns' lengths, so it's cheap to
+    // check whether they're all multiples of some word size.
+    pattern_len_gcd: usize,
+    // How long building took, including releasing and reacquiring the GIL.
+    build_time: Duration,
+}
+
+impl Deref for Automaton {
+    type Target = AhoCorasick;
+
+    fn deref(&self) -> &AhoCorasick {
+        &self.ac_impl
+    }
+}
+
+impl Automaton {
+    /// The heap memory used by the automaton, in bytes.
+    fn memory_usage(&self) -> usize {
+        self.ac_impl.heap_bytes() + self.single_bytes.as_ref().map_or(0, Vec::capacity)
+    }
+
+    /// The name of the implementation used for non-overlapping searches.
+    fn implementation(&self) -> &'static str {
+        if self.single_bytes.is_some() {
+            "memchr"
+        } else {
+            self.kind.name()
+        }
+    }
+
+    fn find_iter<'a>(
+        &'a self,
+        haystack: &'a [u8],
+    ) -> Box<dyn Iterator<Item = PatternMatch> + Send + 'a> {
+        if self.too_short(haystack) {
+            return Box::new(std::iter::empty());
+        }
+        match &self.single_bytes {
+            Some(bytes) => {
+                let positions: Box<dyn Iterator<Item = usize> + Send> = match bytes[..] {
+                    [a] => Box::new(memchr_iter(a, haystack)),
+                    [a, b] => Box::new(memchr2_iter(a, b, haystack)),
+                    [a, b, c] => Box::new(memchr3_iter(a, b, c, haystack)),
+                    _ => unreachable!(),
+                };
+                Box::new(positions.map(move |start| PatternMatch {
+                    pattern: bytes.iter().position(|&b| b == haystack[start]).unwrap(),
+                    start,
+                    end: start + 1,
+                }))
+            }
+            None => Box::new(self.ac_impl.find_iter(haystack).map(PatternMatch::from)),
+        }
+    }
+
+    /// Whether the haystack is shorter than every pattern, so searching it
+    /// can be skipped, which adds up with lots of tiny haystacks.
+    fn too_short(&self, haystack: &[u8]) -> bool {
+        haystack.len() < self.min_pattern_len
+    }
+
+    /// Whether any pattern matches the haystack, stopping at the first
+    /// match.
+    fn is_match(&self, haystack: &[u8]) -> bool {
+        if self.too_short(haystack) {
+            return false;
+        }
+        match &self.single_bytes {
+            Some(_) => self.find_iter(haystack).next().is_some(),
+            None => self.ac_impl.is_match(haystack),
+        }
+    }
+
+    fn find_overlapping_iter<'a>(
+        &'a self,
+        haystack: &'a [u8],
+    ) -> Box<dyn Iterator<Item = PatternMatch> + Send + 'a> {
+        if self.single_bytes.is_some() || self.too_short(haystack) {
+            return self.find_iter(haystack);
+        }
+        Box::new(
+            self.ac_impl
+                .find_overlapping_iter(haystack)
+                .map(PatternMatch::from),
+        )
+    }
+}
+
+/// A summary of how an automaton was built, returned by the build_info
+/// property of AhoCorasick and BytesAhoCorasick.
+#[pyclass(name = "BuildInfo", module = "ahocorasick_rs")]
+struct PyBuildInfo {
+    /// The implementation used for non-overlapping searches.
+    #[pyo3(get)]
+    implementation: &'static str,
+    /// The match kind, as one of the MATCHKIND_* constants.
+    #[pyo3(get)]
+    matchkind: &'static str,
+    /// The heap memory used by the automaton, in bytes, not including
+    /// stored patterns.
+    #[pyo3(get)]
+    automaton_bytes: usize,
+    #[pyo3(get)]
+    patterns_len: usize,
+    /// The total length of the patterns, in UTF-8 bytes.
+    #[pyo3(get)]
+    total_pattern_bytes: usize,
+    #[pyo3(get)]
+    min_pattern_length: usize,
+    #[pyo3(get)]
+    max_pattern_length: usize,
+    /// The automaton's store_patterns property.
+    #[pyo3(get)]
+    store_patterns: PyObject,
+    #[pyo3(get)]
+    build_time_us: u64,
+}
+
+impl PyBuildInfo {
+    fn new(automaton: &Automaton, store_patterns: PyObject) -> Self {
+        Self {
+            implementation: automaton.implementation(),
+            matchkind: matchkind_name(*automaton.match_kind()),
+            automaton_bytes: automaton.memory_usage(),
+            patterns_len: automaton.pattern_count(),
+            total_pattern_bytes: automaton.total_pattern_len,
+            min_pattern_length: automaton.min_pattern_len,
+            max_pattern_length: automaton.max_pattern_len(),
+            store_patterns,
+            build_time_us: automaton.build_time.as_micros() as u64,
+        }
+    }
+}
+
+/// Methods for PyBuildInfo.
+#[pymethods]
+impl PyBuildInfo {
+    fn __repr__(&self, py: Python) -> PyResult<String> {
+        Ok(format!(
+            "BuildInfo(implementation='{}', matchkind='{}', automaton_bytes={}, patterns_len={}, total_pattern_bytes={}, min_pattern_length={}, max_pattern_length={}, store_patterns={}, build_time_us={})",
+            self.implementation,
+            self.matchkind,
+            self.automaton_bytes,
+            self.patterns_len,
+            self.total_pattern_bytes,
+            self.min_pattern_length,
+            self.max_pattern_length,
+            self.store_patterns.as_ref(py).repr()?,
+            self.build_time_us,
+        ))
+    }
+}
+
+/// Return the patterns if there are 1 to 3 of them and they're all distinct
+/// single bytes.
+fn get_single_bytes<P: AsRef<[u8]>>(patterns: &[P]) -> Option<Vec<u8>> {
+    if patterns.is_empty() || patterns.len() > 3 {
+        return None;
+    }
+    let mut bytes = vec![];
+    for pattern in patterns {
+        match pattern.as_ref() {
+            [b] if !bytes.contains(b) => bytes.push(*b),
+            _ => return None,
+        }
+    }
+    Some(bytes)
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Find the matches for which keep() returns true, in order. Matches it
+/// rejects are skipped without hiding kept matches they overlap: with
+/// MATCHKIND_STANDARD, non-overlapping matches are taken from the kept
+/// overlapping matches greedily by earliest end, like a standard search;
+/// otherwise the search restarts at next_start(start) after a rejected
+/// match, which must be a position no kept match starts before.
+fn find_matches_where(
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    keep: impl Fn(&PatternMatch) -> bool,
+    next_start: impl Fn(usize) -> usize,
+) -> Vec<PatternMatch> {
+    if overlapping {
+        return ac_impl
+            .find_overlapping_iter(haystack)
+            .filter(|m| keep(m))
+            .collect();
+    }
+    let mut matches = vec![];
+    if *ac_impl.match_kind() == MatchKind::Standard {
+        let mut end = 0;
+        for m in ac_impl.find_overlapping_iter(haystack).filter(|m| keep(m)) {
+            if m.start() >= end {
+                end = m.end();
+                matches.push(m);
+            }
+        }
+        return matches;
+    }
+    let mut position = 0;
+    while position <= haystack.len() {
+        let m = match ac_impl.find_iter(&haystack[position..]).next() {
+            Some(m) => m,
+            None => break,
+        };
+        let m = PatternMatch {
+            pattern: m.pattern(),
+            start: position + m.start(),
+            end: position + m.end(),
+        };
+        if keep(&m) {
+            // An empty match would otherwise be found again:
+            position = if m.end() == m.start() {
+                next_start(m.start())
+            } else {
+                m.end()
+            };
+            matches.push(m);
+        } else {
+            position = next_start(m.start());
+        }
+    }
+    matches
+}
+
+/// Find the matches that start on a word_size boundary, in order; see
+/// find_matches_where().
+fn find_aligned_matches(
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    word_size: usize,
+    overlapping: bool,
+) -> Vec<PatternMatch> {
+    find_matches_where(
+        ac_impl,
+        haystack,
+        overlapping,
+        |m| m.start().is_multiple_of(word_size),
+        |start| (start / word_size + 1) * word_size,
+    )
+}
+
+/// The bytes that count as part of a word for whole_word searches: ASCII
+/// letters, digits and underscore unless the caller provides others.
+fn parse_word_bytes(
+    whole_word: bool,
+    word_bytes: Option<PyBuffer<u8>>,
+) -> PyResult<Option<[bool; 256]>> {
+    if !whole_word {
+        if word_bytes.is_some() {
+            return Err(PyValueError::new_err(
+                "word_bytes can only be used with whole_word=True.",
+            ));
+        }
+        return Ok(None);
+    }
+    let mut result = [false; 256];
+    match word_bytes {
+        Some(word_bytes) => {
+            for &byte in buffer_as_bytes(&word_bytes)? {
+                result[byte as usize] = true;
+            }
+        }
+        None => {
+            for byte in 0..=255u8 {
+                result[byte as usize] = byte.is_ascii_alphanumeric() || byte == b'_';
+            }
+        }
+    }
+    Ok(Some(result))
+}
+
+/// Like get_matches(), but only returning matches that are whole words: the
+/// bytes just before and after them, if any, aren't word bytes. Only
+/// haystack[..end] is searched, but the byte after a match ending at end is
+/// still checked, so a word cut short by the end isn't matched.
+fn get_whole_word_matches(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    end: usize,
+    overlapping: bool,
+    sort: bool,
+    word_bytes: &[bool; 256],
+) -> Vec<PatternMatch> {
+    let is_word = |index: usize| word_bytes[haystack[index] as usize];
+    let whole = |m: &PatternMatch| {
+        (m.start() == 0 || !is_word(m.start() - 1))
+            && (m.end() == haystack.len() || !is_word(m.end()))
+    };
+    py.allow_threads(|| {
+        let mut matches =
+            find_matches_where(ac_impl, &haystack[..end], overlapping, whole, |start| {
+                start + 1
+            });
+        if sort {
+            matches.sort_unstable_by_key(|m| (m.start(), m.end(), m.pattern()));
+        }
+        matches
+    })
+}
+
+/// Sorted matches for the BytesAhoCorasick methods that take whole_word,
+/// given the word bytes parse_word_bytes() returned.
+fn get_bytes_matches(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    word_bytes: Option<&[bool; 256]>,
+) -> Vec<PatternMatch> {
+    match word_bytes {
+        Some(word_bytes) => get_whole_word_matches(
+            py,
+            ac_impl,
+            haystack,
+            haystack.len(),
+            overlapping,
+            true,
+            word_bytes,
+        ),
+        None => get_matches(py, ac_impl, haystack, overlapping, true),
+    }
+}
+
+/// Below this many bytes in total, searching haystacks in parallel costs more
+/// in starting threads than it saves.
+const MIN_PARALLEL_BYTES: usize = 64 * 1024;
+
+/// The first overlapping match found is the one that ends first, which isn't
+/// what find_matches_in_many()'s first_only means, so they can't be combined.
+fn check_first_only(first_only: bool, overlapping: bool) -> PyResult<()> {
+    if first_only && overlapping {
+        return Err(PyValueError::new_err(
+            "first_only can't be combined with overlapping=True.",
+        ));
+    }
+    Ok(())
+}
+
+/// Apply f to each haystack, splitting them into contiguous runs searched by
+/// up to threads threads (by default one per logical CPU). Results are in
+/// the same order as the haystacks.
+fn map_haystacks_in_parallel<R: Send>(
+    haystacks: &[&[u8]],
+    threads: Option<usize>,
+    f: impl Fn(&[u8]) -> R + Sync,
+) -> Vec<R> {
+    let threads = threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .min(haystacks.len());
+    let total_len: usize = haystacks.iter().map(|haystack| haystack.len()).sum();
+    if threads <= 1 || total_len < MIN_PARALLEL_BYTES {
+        return haystacks.iter().map(|haystack| f(haystack)).collect();
+    }
+    let run_len = haystacks.len().div_ceil(threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = haystacks
+            .chunks(run_len)
+            .map(|run| scope.spawn(move || run.iter().map(|haystack| f(haystack)).collect()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| -> Vec<R> { handle.join().unwrap() })
+            .collect()
+    })
+}
+
+/// Build the underlying automaton, releasing the GIL while doing so.
+fn build_automaton<I, P>(
+    py: Python<'_>,
+    patterns: I,
+    matchkind: MatchKind,
+    kind: Implementation,
+) -> Automaton
+where
+    I: IntoIterator<Item = P> + Send,
+    P: AsRef<[u8]>,
+{
+    let start = Instant::now();
+    let mut automaton = py.allow_threads(|| {
+        let patterns: Vec<P> = patterns.into_iter().collect();
+        let ac_impl = AhoCorasickBuilder::new()
+            // A DFA results in faster matches, but uses more memory and
+            // takes longer to build:
+            .dfa(kind == Implementation::Dfa)
+            .match_kind(matchkind)
+            .build(&patterns);
+        Automaton {
+            ac_impl,
+            kind,
+            single_bytes: get_single_bytes(&patterns),
+            min_pattern_len: patterns.iter().map(|p| p.as_ref().len()).min().unwrap_or(0),
+            total_pattern_len: patterns.iter().map(|p| p.as_ref().len()).sum(),
+            pattern_len_gcd: patterns.iter().fold(0, |a, p| gcd(a, p.as_ref().len())),
+            build_time: Duration::ZERO,
+        }
+    });
+    automaton.build_time = start.elapsed();
+    automaton
+}
+
+/// Convert a MatchKind back into the name of the Python-level constant.
+fn matchkind_name(matchkind: MatchKind) -> &'static str {
+    match matchkind {
+        MatchKind::Standard => "MATCHKIND_STANDARD",
+        MatchKind::LeftmostFirst => "MATCHKIND_LEFTMOST_FIRST",
+        _ => "MATCHKIND_LEFTMOST_LONGEST",
+    }
+}
+
+/// Escape a string for use as a literal in a regular expression. This
+/// covers the metacharacters of both Python's re module and Rust's regex
+/// crate, including ones like "-" and "]" that only matter inside character
+/// classes, so the result is safe anywhere in a pattern.
+fn escape_regex(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Convert a regular expression that's really just a literal string, e.g.
+/// "^foo\\.bar$", into that string: anchors at the start and end are
+/// dropped, and escaped punctuation and \\n, \\r and \\t are unescaped. Returns
+/// an error message if there's anything else with a special meaning.
+fn regex_to_literal(regex: &str) -> Result<String, String> {
+    let regex = regex.strip_prefix('^').unwrap_or(regex);
+    // A trailing "$" is an anchor unless it's escaped, i.e. preceded by an
+    // odd number of backslashes:
+    let regex = match regex.strip_suffix('$') {
+        Some(stripped) if (stripped.len() - stripped.trim_end_matches('\\').len()) % 2 == 0 => {
+            stripped
+        }
+        _ => regex,
+    };
+    let mut result = String::with_capacity(regex.len());
+    let mut chars = regex.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('t') => result.push('\t'),
+                Some(escaped) if escaped.is_ascii_punctuation() => result.push(escaped),
+                Some(escaped) => {
+                    return Err(format!("\\{} isn't a literal character", escaped));
+                }
+                None => return Err("it ends with an unescaped backslash".to_owned()),
+            },
+            '.' | '*' | '+' | '?' | '|' | '(' | ')' | '[' | ']' | '{' | '}' | '^' | '$' => {
+                return Err(format!("{:?} is a regular expression metacharacter", c));
+            }
+            _ => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+// Raised for misuse of an automaton; a ValueError subclass, since that's what
+// was raised before it existed.
+create_exception!(ahocorasick_rs, AhoCorasickError, PyValueError);
+
+/// Make sure overlapping search is only requested from automatons that
+/// support it, i.e. those built with MATCHKIND_STANDARD; the leftmost match
+/// kinds can't report overlapping matches.
+fn check_overlapping(ac_impl: &AhoCorasick, overlapping: bool) -> PyResult<()> {
+    if overlapping && !ac_impl.supports_overlapping() {
+        return Err(AhoCorasickError::new_err(format!(
+            "Overlapping matches are only supported by automatons built with matchkind=MATCHKIND_STANDARD (the default), but this one uses {}; either pass overlapping=False or build with MATCHKIND_STANDARD.",
+            matchkind_name(*ac_impl.match_kind())
+        )));
+    }
+    Ok(())
+}
+
+/// Return matches for a given haystack. If sort is true, matches are sorted
+/// by (start, end, pattern index); otherwise they're in the order the
+/// automaton found them.
+fn get_matches(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    sort: bool,
+) -> Vec<PatternMatch> {
+    get_matches_with_capacity(py, ac_impl, haystack, overlapping, sort, 0)
+}
+
+/// Like get_matches(), preallocating space for the expected number of
+/// matches. The capacity is capped at the haystack's length plus one, so an
+/// overly large guess can't allocate much more than that.
+fn get_matches_with_capacity(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    sort: bool,
+    capacity: usize,
+) -> Vec<PatternMatch> {
+    py.allow_threads(|| find_matches(ac_impl, haystack, overlapping, sort, capacity))
+}
+
+/// The body of get_matches_with_capacity(), for callers that have already
+/// released the GIL.
+fn find_matches(
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    sort: bool,
+    capacity: usize,
+) -> Vec<PatternMatch> {
+    let mut matches = Vec::with_capacity(capacity.min(haystack.len() + 1));
+    if overlapping {
+        matches.extend(ac_impl.find_overlapping_iter(haystack));
+    } else {
+        matches.extend(ac_impl.find_iter(haystack));
+    }
+    if sort {
+        matches.sort_unstable_by_key(|m| (m.start(), m.end(), m.pattern()));
+    }
+    matches
+}
+
+/// The order to return matches in, if not the default.
+#[derive(Clone, Copy)]
+enum MatchOrder {
+    // By start, then end, so shorter (inner) matches come first.
+    Start,
+    // By end, then start, so longer (outer) matches come first.
+    End,
+    // Longest first, then by start.
+    Length,
+}
+
+/// Parse the order argument; None keeps the default order. Since sort=False
+/// asks for no sorting at all, the two can't be combined.
+fn parse_match_order(order: Option<&str>, sort: bool) -> PyResult<Option<MatchOrder>> {
+    let order = match order {
+        None => return Ok(None),
+        Some(order) => order,
+    };
+    if !sort {
+        return Err(PyValueError::new_err(
+            "order can't be combined with sort=False.",
+        ));
+    }
+    match order {
+        "start" => Ok(Some(MatchOrder::Start)),
+        "end" => Ok(Some(MatchOrder::End)),
+        "length" => Ok(Some(MatchOrder::Length)),
+        _ => Err(PyValueError::new_err(format!(
+            "order must be \"start\", \"end\" or \"length\", not {:?}.",
+            order
+        ))),
+    }
+}
+
+/// Sort matches in the given order, measuring their lengths with length().
+fn sort_matches(
+    matches: &mut [PatternMatch],
+    order: MatchOrder,
+    length: impl Fn(&PatternMatch) -> usize,
+) {
+    match order {
+        MatchOrder::Start => matches.sort_unstable_by_key(|m| (m.start(), m.end(), m.pattern())),
+        MatchOrder::End => matches.sort_unstable_by_key(|m| (m.end(), m.start(), m.pattern())),
+        MatchOrder::Length => {
+            matches.sort_unstable_by_key(|m| (Reverse(length(m)), m.start(), m.pattern()))
+        }
+    }
+}
+
+/// The number of code points in some UTF-8.
+fn code_point_len(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .filter(|&&byte| !is_continuation_byte(byte))
+        .count()
+}
+
+/// A Unicode normalization form, for normalize_haystack.
+#[derive(Clone, Copy)]
+enum NormalizationForm {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+fn parse_normalization_form(form: &str) -> PyResult<NormalizationForm> {
+    match form {
+        "NFC" => Ok(NormalizationForm::Nfc),
+        "NFD" => Ok(NormalizationForm::Nfd),
+        "NFKC" => Ok(NormalizationForm::Nfkc),
+        "NFKD" => Ok(NormalizationForm::Nfkd),
+        _ => Err(PyValueError::new_err(format!(
+            "normalize_haystack must be \"NFC\", \"NFD\", \"NFKC\" or \"NFKD\", not {:?}.",
+            form
+        ))),
+    }
+}
+
+/// Normalize text, appending the result to output.
+fn normalize_into(text: &str, form: NormalizationForm, output: &mut String) {
+    match form {
+        NormalizationForm::Nfc => output.extend(text.nfc()),
+        NormalizationForm::Nfd => output.extend(text.nfd()),
+        NormalizationForm::Nfkc => output.extend(text.nfkc()),
+        NormalizationForm::Nfkd => output.extend(text.nfkd()),
+    }
+}
+
+/// Normalize text, and if offset_map is true, also return the code point
+/// offset in text that each code point of the result came from, plus one
+/// more entry for the end. Normalization doesn't combine or reorder
+/// characters across grapheme clusters, so each cluster is normalized on
+/// its own, and its code points map to the cluster's start.
+fn normalize(
+    text: &str,
+    form: NormalizationForm,
+    offset_map: bool,
+) -> (String, Option<Vec<usize>>) {
+    let mut normalized = String::with_capacity(text.len());
+    if !offset_map {
+        normalize_into(text, form, &mut normalized);
+        return (normalized, None);
+    }
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    let mut offset = 0;
+    for cluster in text.graphemes(true) {
+        let start = normalized.len();
+        normalize_into(cluster, form, &mut normalized);
+        offsets.extend(std::iter::repeat_n(
+            offset,
+            normalized[start..].chars().count(),
+        ));
+        offset += cluster.chars().count();
+    }
+    offsets.push(offset);
+    (normalized, Some(offsets))
+}
+
+/// Keep only the matches whose length, as measured by length(), is at least
+/// min_length and, if max_length is given, at most max_length.
+fn filter_match_lengths(
+    matches: Vec<PatternMatch>,
+    min_length: usize,
+    max_length: Option<usize>,
+    length: impl Fn(&PatternMatch) -> usize,
+) -> Vec<PatternMatch> {
+    if min_length == 0 && max_length.is_none() {
+        return matches;
+    }
+    matches
+        .into_iter()
+        .filter(|m| {
+            let length = length(m);
+            length >= min_length && !matches!(max_length, Some(max) if length > max)
+        })
+        .collect()
+}
+
+/// Keep only one match per (start, end) span, the one with the lowest
+/// pattern index, otherwise preserving the order of matches. Only overlapping
+/// searches can find the same span more than once.
+fn deduplicate_matches(matches: Vec<PatternMatch>) -> Vec<PatternMatch> {
+    let mut lowest: HashMap<(usize, usize), usize> = HashMap::with_capacity(matches.len());
+    for m in matches.iter() {
+        let pattern = lowest.entry((m.start(), m.end())).or_insert(m.pattern());
+        *pattern = (*pattern).min(m.pattern());
+    }
+    matches
+        .into_iter()
+        .filter(|m| lowest[&(m.start(), m.end())] == m.pattern())
+        .collect()
+}
+
+/// Return the distinct indexes of patterns that match the haystack.
+fn get_matched_pattern_ids(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+) -> HashSet<usize> {
+    py.allow_threads(|| {
+        if overlapping {
+            ac_impl
+                .find_overlapping_iter(haystack)
+                .map(|m| m.pattern())
+                .collect()
+        } else {
+            ac_impl.find_iter(haystack).map(|m| m.pattern()).collect()
+        }
+    })
+}
+
+/// Return the first match of each pattern that matches the haystack, in the
+/// order they were found, stopping early once every pattern has matched.
+fn get_first_matches(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+) -> Vec<PatternMatch> {
+    py.allow_threads(|| {
+        let mut seen = vec![false; ac_impl.pattern_count()];
+        let mut result = vec![];
+        let matches = if overlapping {
+            ac_impl.find_overlapping_iter(haystack)
+        } else {
+            ac_impl.find_iter(haystack)
+        };
+        for m in matches {
+            if !seen[m.pattern()] {
+                seen[m.pattern()] = true;
+                result.push(m);
+                if result.len() == seen.len() {
+                    break;
+                }
+            }
+        }
+        result
+    })
+}
+
+/// Return which patterns match the haystack, as a mask with one bool per
+/// pattern, either as a list or, if packed is true, bit-packed into bytes
+/// with the first pattern in the most significant bit of the first byte
+/// (like numpy.packbits()).
+fn get_matched_pattern_mask(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    packed: bool,
+) -> PyObject {
+    let mask = py.allow_threads(|| {
+        let mut mask = vec![false; ac_impl.pattern_count()];
+        if overlapping {
+            for m in ac_impl.find_overlapping_iter(haystack) {
+                mask[m.pattern()] = true;
+            }
+        } else {
+            for m in ac_impl.find_iter(haystack) {
+                mask[m.pattern()] = true;
+            }
+        }
+        mask
+    });
+    if !packed {
+        return mask.into_py(py);
+    }
+    let mut bits = vec![0u8; mask.len().div_ceil(8)];
+    for (index, _) in mask.iter().enumerate().filter(|(_, matched)| **matched) {
+        bits[index / 8] |= 0x80 >> (index % 8);
+    }
+    PyBytes::new(py, &bits).into()
+}
+
+/// Map UTF-8 byte index to Unicode code point index; the latter is what
+/// Python users expect.
+enum ByteToCodePoint<'a> {
+    /// Byte offsets are code point offsets, because the haystack is ASCII
+    /// (or the user asked for byte offsets), so no lookup table is needed.
+    Identity,
+    /// Lookup table from byte offset to code point offset.
+    Table(Cow<'a, [usize]>),
+}
+
+impl ByteToCodePoint<'_> {
+    fn get(&self, byte_offset: usize) -> usize {
+        match self {
+            ByteToCodePoint::Identity => byte_offset,
+            ByteToCodePoint::Table(table) => table[byte_offset],
+        }
+    }
+
+    fn borrowed(&self) -> ByteToCodePoint<'_> {
+        match self {
+            ByteToCodePoint::Identity => ByteToCodePoint::Identity,
+            ByteToCodePoint::Table(table) => ByteToCodePoint::Table(Cow::Borrowed(table)),
+        }
+    }
+}
+
+/// Whether a byte of UTF-8 is a continuation byte, rather than the first byte
+/// of a code point.
+fn is_continuation_byte(byte: u8) -> bool {
+    byte & 0b1100_0000 == 0b1000_0000
+}
+
+/// Figure out the byte to code point map for a haystack, skipping the
+/// lookup table for ASCII haystacks. The haystack is UTF-8, possibly with
+/// encoded surrogates (see HaystackArg), so a code point starts at every
+/// byte that isn't a continuation byte.
+fn get_byte_to_code_point(haystack: &[u8]) -> ByteToCodePoint<'static> {
+    if haystack.is_ascii() {
+        return ByteToCodePoint::Identity;
+    }
+    let mut byte_to_code_point = vec![usize::MAX; haystack.len() + 1];
+    let mut codepoint_off = 0;
+    for (byte_off, byte) in haystack.iter().enumerate() {
+        if !is_continuation_byte(*byte) {
+            byte_to_code_point[byte_off] = codepoint_off;
+            codepoint_off += 1;
+        }
+    }
+    // End index is exclusive (e.g. 0:3 is first 3 characters), so handle
+    // the case where pattern is at end of string.
+    byte_to_code_point[haystack.len()] = codepoint_off;
+    ByteToCodePoint::Table(Cow::Owned(byte_to_code_point))
+}
+
+/// Return the byte offset of the given code point offset, or the haystack's
+/// length if it has fewer code points than that.
+fn code_point_to_byte(haystack: &[u8], code_point: usize) -> usize {
+    if haystack.get(..code_point).is_some_and(<[u8]>::is_ascii) {
+        return code_point;
+    }
+    haystack
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| !is_continuation_byte(**byte))
+        .nth(code_point)
+        .map_or(haystack.len(), |(byte_offset, _)| byte_offset)
+}
+
+/// Map UTF-8 byte index to UTF-16 code unit index, as used by JavaScript and
+/// the JVM; characters outside the Basic Multilingual Plane count as 2.
+fn get_byte_to_utf16(haystack: &str) -> Vec<usize> {
+    let mut byte_to_utf16 = vec![usize::MAX; haystack.len() + 1];
+    let mut utf16_off = 0;
+    for (byte_off, ch) in haystack.char_indices() {
+        byte_to_utf16[byte_off] = utf16_off;
+        utf16_off += ch.len_utf16();
+    }
+    byte_to_utf16[haystack.len()] = utf16_off;
+    byte_to_utf16
+}
+
+/// Return the byte spans of the words in the haystack, as defined by Unicode
+/// word segmentation (UAX #29). Whitespace and punctuation aren't words.
+fn get_word_spans(haystack: &str) -> Vec<(usize, usize)> {
+    haystack
+        .unicode_word_indices()
+        .map(|(start, word)| (start, start + word.len()))
+        .collect()
+}
+
+/// Return the byte spans of the extended grapheme clusters in the haystack,
+/// i.e. what a user would consider a single character. Unlike words, they
+/// cover the whole haystack.
+fn get_grapheme_spans(haystack: &str) -> Vec<(usize, usize)> {
+    haystack
+        .grapheme_indices(true)
+        .map(|(start, cluster)| (start, start + cluster.len()))
+        .collect()
+}
+
+/// Convert a match's byte span to indexes into sorted, disjoint spans (words
+/// or grapheme clusters), such that spans[start:end] are the spans that
+/// overlap the match. A match that starts or ends in the middle of a span is
+/// thus widened to the span boundaries, and one that doesn't overlap any
+/// span gives an empty span.
+fn get_span_offsets(spans: &[(usize, usize)], start: usize, end: usize) -> (usize, usize) {
+    let start_span = spans.partition_point(|&(_, span_end)| span_end <= start);
+    let end_span = spans.partition_point(|&(span_start, _)| span_start < end);
+    (start_span, end_span.max(start_span))
+}
+
+/// Group match spans by key, with keys in order of their first match.
+type GroupedSpans<K> = Vec<(K, Vec<(usize, usize)>)>;
+
+/// Group (key, start, end) matches by key, see GroupedSpans.
+fn group_spans<K: Hash + Eq + Copy>(
+    matches: impl Iterator<Item = (K, usize, usize)>,
+) -> GroupedSpans<K> {
+    let mut groups: GroupedSpans<K> = vec![];
+    let mut key_to_group: HashMap<K, usize> = HashMap::new();
+    for (key, start, end) in matches {
+        let group = *key_to_group.entry(key).or_insert_with(|| {
+            groups.push((key, vec![]));
+            groups.len() - 1
+        });
+        groups[group].1.push((start, end));
+    }
+    groups
+}
+
+/// Return the sorted, disjoint spans of the haystack that are covered by at
+/// least one match.
+fn get_covered_spans(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = get_matches(py, ac_impl, haystack, overlapping, false)
+        .into_iter()
+        .map(|m| (m.start(), m.end()))
+        .collect();
+    spans.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ if start == end => {}
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Return the spans of 0..length that aren't covered by the given sorted,
+/// disjoint spans.
+fn get_gaps(covered: &[(usize, usize)], length: usize) -> Vec<(usize, usize)> {
+    let mut gaps = vec![];
+    let mut pos = 0;
+    for &(start, end) in covered {
+        if start > pos {
+            gaps.push((pos, start));
+        }
+        pos = end;
+    }
+    if pos < length {
+        gaps.push((pos, length));
+    }
+    gaps
+}
+
+/// Get the contents of a buffer-protocol object as a byte slice.
+fn buffer_as_bytes(buffer: &PyBuffer<u8>) -> PyResult<&[u8]> {
+    if !buffer.is_c_contiguous() {
+        return Err(PyValueError::new_err("Buffer must be C-contiguous."));
+    }
+    // The buffer is contiguous and holds u8 items, so it's safe to view it as
+    // a byte slice for as long as the PyBuffer (which keeps the underlying
+    // object alive) is around.
+    Ok(unsafe { std::slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes()) })
+}
+
+/// If the object is a 1-dimensional array of Python objects, e.g. a NumPy
+/// array with dtype object, return its items as str patterns, reading the
+/// object pointers directly from its buffer rather than iterating. Returns
+/// None for other objects.
+#[cfg(feature = "numpy")]
+fn get_object_array_patterns(obj: &PyAny) -> PyResult<Option<Vec<Py<PyUnicode>>>> {
+    use pyo3::{ffi, AsPyPointer};
+
+    let py = obj.py();
+    if unsafe { ffi::PyObject_CheckBuffer(obj.as_ptr()) } == 0 {
+        return Ok(None);
+    }
+    let mut view = std::mem::MaybeUninit::<ffi::Py_buffer>::uninit();
+    if unsafe { ffi::PyObject_GetBuffer(obj.as_ptr(), view.as_mut_ptr(), ffi::PyBUF_RECORDS_RO) }
+        == -1
+    {
+        return Err(PyErr::fetch(py));
+    }
+    let mut view = unsafe { view.assume_init() };
+    let is_object_array = view.ndim == 1
+        && !view.format.is_null()
+        && matches!(
+            unsafe { std::ffi::CStr::from_ptr(view.format) }.to_bytes(),
+            b"O" | b"@O" | b"=O" | b"<O" | b">O" | b"!O"
+        )
+        && view.itemsize as usize == std::mem::size_of::<*mut ffi::PyObject>();
+    let result = if is_object_array {
+        // Exporters may leave out the shape and strides of contiguous
+        // buffers.
+        let length = if view.shape.is_null() {
+            (view.len / view.itemsize) as usize
+        } else {
+            unsafe { *view.shape as usize }
+        };
+        let stride = if view.strides.is_null() {
+            view.itemsize
+        } else {
+            unsafe { *view.strides }
+        };
+        (0..length)
+            .map(|index| {
+                // The buffer keeps the array, and therefore its items, alive
+                // until it's released.
+                let item = unsafe {
+                    *((view.buf as *const u8).offset(index as isize * stride)
+                        as *const *mut ffi::PyObject)
+                };
+                let item: &PyAny = if item.is_null() {
+                    py.None().into_ref(py)
+                } else {
+                    unsafe { py.from_borrowed_ptr(item) }
+                };
+                match item.downcast::<PyUnicode>() {
+                    Ok(pattern) => Ok(pattern.into()),
+                    Err(_) => Err(PyTypeError::new_err(format!(
+                        "Pattern at index {} must be a str, got {}.",
+                        index,
+                        item.get_type().name()?
+                    ))),
+                }
+            })
+            .collect::<PyResult<_>>()
+            .map(Some)
+    } else {
+        Ok(None)
+    };
+    unsafe { ffi::PyBuffer_Release(&mut view) };
+    result
+}
+
+/// Write (pattern, start, end) matches into rows offset.. of out, a writable
+/// buffer with shape (N, 3) and unsigned 64-bit items, e.g. a NumPy array
+/// with dtype uint64, and return how many rows were written. Matches that
+/// don't fit are discarded without being pulled from the iterator.
+fn write_matches_to_u64_array(
+    out: &PyAny,
+    offset: usize,
+    matches: impl Iterator<Item = (usize, usize, usize)>,
+) -> PyResult<usize> {
+    use pyo3::{ffi, AsPyPointer};
+
+    let mut view = std::mem::MaybeUninit::<ffi::Py_buffer>::uninit();
+    if unsafe { ffi::PyObject_GetBuffer(out.as_ptr(), view.as_mut_ptr(), ffi::PyBUF_RECORDS) } == -1
+    {
+        return Err(PyErr::fetch(out.py()));
+    }
+    let mut view = unsafe { view.assume_init() };
+    let format = if view.format.is_null() {
+        &b"B"[..]
+    } else {
+        unsafe { std::ffi::CStr::from_ptr(view.format) }.to_bytes()
+    };
+    let is_u64 = view.itemsize == 8
+        && (matches!(format, b"Q" | b"@Q" | b"=Q" | b"L" | b"@L")
+            || (cfg!(target_endian = "little") && format == b"<Q"));
+    let result = if !is_u64 {
+        Err(PyValueError::new_err(format!(
+            "Expected an array of unsigned 64-bit integers (NumPy dtype uint64), got buffer format {:?}.",
+            String::from_utf8_lossy(format)
+        )))
+    } else if view.ndim != 2 || unsafe { *view.shape.add(1) } != 3 {
+        Err(PyValueError::new_err(
+            "Expected an array with shape (N, 3).",
+        ))
+    } else {
+        let rows = unsafe { *view.shape } as usize;
+        let (row_stride, column_stride) = unsafe { (*view.strides, *view.strides.add(1)) };
+        if offset > rows {
+            Err(PyValueError::new_err(format!(
+                "offset {} is past the end of an array with {} rows.",
+                offset, rows
+            )))
+        } else {
+            let mut written = 0;
+            for (row, (pattern, start, end)) in (offset..rows).zip(matches) {
+                for (column, &value) in [pattern, start, end].iter().enumerate() {
+                    // The shape and strides come from the buffer, so the
+                    // item is inside it, but it may not be aligned.
+                    unsafe {
+                        let item = (view.buf as *mut u8)
+                            .offset(row as isize * row_stride + column as isize * column_stride);
+                        (item as *mut u64).write_unaligned(value as u64);
+                    }
+                }
+                written += 1;
+            }
+            Ok(written)
+        }
+    };
+    unsafe { ffi::PyBuffer_Release(&mut view) };
+    result
+}
+
+/// Extract str patterns from a sequence, with a fast path for NumPy object
+/// arrays if the numpy feature is enabled.
+fn extract_str_patterns(patterns: &PyAny) -> PyResult<Vec<Py<PyUnicode>>> {
+    #[cfg(feature = "numpy")]
+    {
+        if let Some(patterns) = get_object_array_patterns(patterns)? {
+            return Ok(patterns);
+        }
+    }
+    patterns.extract().map_err(|error| {
+        // pyo3's error doesn't say which pattern is the problem, so find it:
+        let not_str = patterns.iter().ok().and_then(|patterns| {
+            // Stop at the first failure to get a pattern, rather than
+            // retrying an iterator that may keep failing:
+            patterns
+                .map_while(Result::ok)
+                .enumerate()
+                .find(|(_, pattern)| pattern.downcast::<PyUnicode>().is_err())
+                .map(|(index, pattern)| wrong_pattern_type(index, pattern, "a str"))
+        });
+        not_str.unwrap_or(error)
+    })
+}
+
+/// The TypeError for a pattern at the given index that isn't of the
+/// expected type.
+fn wrong_pattern_type(index: usize, pattern: &PyAny, expected: &str) -> PyErr {
+    PyTypeError::new_err(format!(
+        "Pattern at index {} must be {}, got {}.",
+        index,
+        expected,
+        pattern.get_type().name().unwrap_or("?")
+    ))
+}
+
+/// The ValueError for a pattern that's empty after coerce=True converted it.
+fn empty_coerced_pattern(index: usize) -> PyErr {
+    PyValueError::new_err(format!(
+        "Pattern at index {} is empty after converting it.",
+        index
+    ))
+}
+
+/// Like extract_str_patterns(), but with coerce, patterns that aren't
+/// strings are converted with str(), and with skip_invalid, patterns that
+/// are empty, aren't strings (and weren't converted), or can't be encoded
+/// as UTF-8 are skipped rather than causing an error. Also returns the
+/// indexes of the skipped patterns.
+fn extract_str_patterns_leniently(
+    patterns: &PyAny,
+    coerce: bool,
+    skip_invalid: bool,
+) -> PyResult<(Vec<Py<PyUnicode>>, Vec<usize>)> {
+    if patterns.downcast::<PyUnicode>().is_ok() {
+        return Err(PyTypeError::new_err(
+            "patterns must be an iterable of strings, not a string.",
+        ));
+    }
+    let mut valid = vec![];
+    let mut skipped = vec![];
+    for (index, pattern) in patterns.iter()?.enumerate() {
+        let pattern = pattern?;
+        let (pattern, coerced) = match pattern.downcast::<PyUnicode>() {
+            Ok(pattern) => (pattern, false),
+            Err(_) if coerce => match pattern.str() {
+                Ok(pattern) => (pattern, true),
+                Err(_) if skip_invalid => {
+                    skipped.push(index);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            },
+            Err(_) if skip_invalid => {
+                skipped.push(index);
+                continue;
+            }
+            Err(_) => return Err(wrong_pattern_type(index, pattern, "a str")),
+        };
+        if skip_invalid && !pattern.to_str().is_ok_and(|p| !p.is_empty()) {
+            skipped.push(index);
+            continue;
+        }
+        if coerced && pattern.len()? == 0 {
+            return Err(empty_coerced_pattern(index));
+        }
+        valid.push(pattern.into());
+    }
+    Ok((valid, skipped))
+}
+
+/// Parse the on_invalid argument, returning whether invalid patterns should
+/// be skipped.
+fn parse_on_invalid(on_invalid: &str) -> PyResult<bool> {
+    match on_invalid {
+        "raise" => Ok(false),
+        "skip" => Ok(true),
+        _ => Err(PyValueError::new_err(format!(
+            "on_invalid must be \"raise\" or \"skip\", not {:?}.",
+            on_invalid
+        ))),
+    }
+}
+
+/// Remove the items at the given (sorted) indexes, e.g. the ids of skipped
+/// patterns.
+fn remove_skipped<T>(items: Vec<T>, skipped: &[usize]) -> Vec<T> {
+    let mut skipped = skipped.iter().peekable();
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| {
+            if skipped.peek() == Some(&index) {
+                skipped.next();
+                false
+            } else {
+                true
+            }
+        })
+        .map(|(_, item)| item)
+        .collect()
+}
+
+/// Split data into records on a (possibly multi-byte) delimiter, returning
+/// (record index, record) pairs. Empty records are either skipped or cause an
+/// error, depending on skip_empty.
+fn split_on_delimiter<'a>(
+    data: &'a [u8],
+    delimiter: Option<&[u8]>,
+    skip_empty: bool,
+) -> PyResult<Vec<(usize, &'a [u8])>> {
+    let delimiter = delimiter.unwrap_or(b"\n");
+    if delimiter.is_empty() {
+        return Err(PyValueError::new_err("delimiter must not be empty."));
+    }
+    let mut records = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        if data[i..].starts_with(delimiter) {
+            records.push(&data[start..i]);
+            i += delimiter.len();
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    records.push(&data[start..]);
+    let mut result = Vec::with_capacity(records.len());
+    for (index, record) in records.into_iter().enumerate() {
+        if !record.is_empty() {
+            result.push((index, record));
+        } else if !skip_empty {
+            return Err(PyValueError::new_err(format!(
+                "Record at index {} is empty.",
+                index
+            )));
+        }
+    }
+    Ok(result)
+}
+
+/// Find matches in a sequence of chunks as if they were a single
+/// concatenated haystack, returning (pattern index, start, end) tuples with
+/// offsets into that concatenation. Only the unsearched tail of previous
+/// chunks (at most max_pattern_len() - 1 bytes) is kept around, so the full
+/// concatenation is never materialized.
+fn find_matches_across_chunks(
+    ac_impl: &AhoCorasick,
+    chunks: &[&[u8]],
+    overlapping: bool,
+) -> Vec<(usize, usize, usize)> {
+    let max_len = ac_impl.max_pattern_len();
+    let carry_len = max_len.saturating_sub(1);
+    // An empty list of chunks is an empty haystack:
+    let chunks = if chunks.is_empty() {
+        &[&b""[..]]
+    } else {
+        chunks
+    };
+    let mut result = vec![];
+    // The data we're still interested in, and its offset in the
+    // concatenation:
+    let mut buffer: Vec<u8> = vec![];
+    let mut buffer_offset = 0;
+    if overlapping {
+        // Every match ending in a new chunk starts at most max_len - 1 bytes
+        // before it, so we only need to carry that many bytes, and we skip
+        // matches that ended in data we've already searched.
+        let mut searched_up_to = None;
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk);
+            for m in ac_impl.find_overlapping_iter(&buffer) {
+                let end = buffer_offset + m.end();
+                if searched_up_to.is_none_or(|searched_up_to| end > searched_up_to) {
+                    result.push((m.pattern(), buffer_offset + m.start(), end));
+                }
+            }
+            searched_up_to = Some(buffer_offset + buffer.len());
+            let consumed = buffer.len().saturating_sub(carry_len);
+            buffer.drain(..consumed);
+            buffer_offset += consumed;
+        }
+    } else {
+        // This mirrors the non-overlapping iterator in the aho-corasick
+        // crate, except that a match is only accepted once enough data
+        // follows its start that more data can't change which match is
+        // reported.
+        let mut pos = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            buffer.extend_from_slice(chunk);
+            let is_last = i == chunks.len() - 1;
+            while pos <= buffer.len() {
+                let m = match ac_impl.find(&buffer[pos..]) {
+                    Some(m) => m,
+                    None => {
+                        if !is_last {
+                            pos = pos.max(buffer.len().saturating_sub(carry_len));
+                        }
+                        break;
+                    }
+                };
+                let (start, end) = (pos + m.start(), pos + m.end());
+                if !is_last && start + max_len > buffer.len() {
+                    pos = pos.max(start.min(buffer.len().saturating_sub(carry_len)));
+                    break;
+                }
+                result.push((m.pattern(), buffer_offset + start, buffer_offset + end));
+                pos = if end == pos { pos + 1 } else { end };
+            }
+            let consumed = pos.min(buffer.len());
+            buffer.drain(..consumed);
+            buffer_offset += consumed;
+            pos -= consumed;
+        }
+    }
+    result
+}
+
+/// An io::Read that pulls chunks from a Python iterator of bytes-like objects,
+/// so they can be fed to the streaming search one at a time. Python errors
+/// can't be passed through io::Error, so they're stashed in `error`.
+struct ChunkReader<'a> {
+    chunks: &'a PyIterator,
+    chunk: Vec<u8>,
+    position: usize,
+    error: Option<PyErr>,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn new(chunks: &'a PyIterator) -> Self {
+        Self {
+            chunks,
+            chunk: vec![],
+            position: 0,
+            error: None,
+        }
+    }
+
+    fn next_chunk(&mut self) -> PyResult<bool> {
+        match self.chunks.next() {
+            Some(chunk) => {
+                let buffer: PyBuffer<u8> = chunk?.extract()?;
+                self.chunk = buffer.to_vec(self.chunks.py())?;
+                self.position = 0;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Skip over empty chunks; only the end of the iterator is EOF.
+        while self.position == self.chunk.len() {
+            match self.next_chunk() {
+                Ok(true) => {}
+                Ok(false) => return Ok(0),
+                Err(e) => {
+                    self.error = Some(e);
+                    return Err(io::Error::other("Python error"));
+                }
+            }
+        }
+        let length = buf.len().min(self.chunk.len() - self.position);
+        buf[..length].copy_from_slice(&self.chunk[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// An io::Write that counts the code points in the UTF-8 written to it,
+/// i.e. the bytes that aren't continuation bytes, so a character split
+/// across writes is counted once.
+#[derive(Default)]
+struct CodePointCounter {
+    code_points: usize,
+}
+
+impl Write for CodePointCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.code_points += buf
+            .iter()
+            .filter(|byte| !is_continuation_byte(**byte))
+            .count();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An io::Read over a sequence of byte slices, read as if they were one
+/// contiguous buffer, so they can be streamed without being joined.
+struct SegmentsReader<'a> {
+    segments: &'a [&'a [u8]],
+    position: usize,
+}
+
+impl<'a> SegmentsReader<'a> {
+    fn new(segments: &'a [&'a [u8]]) -> Self {
+        Self {
+            segments,
+            position: 0,
+        }
+    }
+}
+
+impl Read for SegmentsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Skip over empty and finished segments; running out of them is EOF.
+        while let Some((segment, rest)) = self.segments.split_first() {
+            if self.position < segment.len() {
+                let length = buf.len().min(segment.len() - self.position);
+                buf[..length].copy_from_slice(&segment[self.position..self.position + length]);
+                self.position += length;
+                return Ok(length);
+            }
+            self.segments = rest;
+            self.position = 0;
+        }
+        Ok(0)
+    }
+}
+
+/// Below this many bytes in total, find_matches_as_indexes_segmented() joins
+/// the segments and searches them directly, which is faster than streaming.
+const MIN_STREAM_SEGMENTS_BYTES: usize = 1024 * 1024;
+
+/// Convert (pattern, start, end) tuples into a polars.Series of structs with
+/// fields "pattern", "start" and "end".
+fn matches_to_polars(py: Python<'_>, matches: Vec<(usize, usize, usize)>) -> PyResult<PyObject> {
+    let polars = py.import("polars").map_err(|_| {
+        PyImportError::new_err("find_matches_as_polars() requires polars to be installed.")
+    })?;
+    let mut patterns = Vec::with_capacity(matches.len());
+    let mut starts = Vec::with_capacity(matches.len());
+    let mut ends = Vec::with_capacity(matches.len());
+    for (pattern, start, end) in matches {
+        patterns.push(pattern);
+        starts.push(start);
+        ends.push(end);
+    }
+    let columns = PyDict::new(py);
+    columns.set_item("pattern", patterns)?;
+    columns.set_item("start", starts)?;
+    columns.set_item("end", ends)?;
+    let uint64 = polars.getattr("UInt64")?;
+    let schema = PyDict::new(py);
+    for name in ["pattern", "start", "end"] {
+        schema.set_item(name, uint64)?;
+    }
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("schema", schema)?;
+    let df = polars
+        .getattr("DataFrame")?
+        .call((columns,), Some(kwargs))?;
+    Ok(df.call_method1("to_struct", ("matches",))?.into())
+}
+
+/// The memory used by stored Python objects, e.g. patterns: their
+/// __sizeof__() plus the pointers to them, in bytes.
+fn objects_memory_usage<'a>(objects: impl ExactSizeIterator<Item = &'a PyAny>) -> PyResult<usize> {
+    let mut total = objects.len() * std::mem::size_of::<PyObject>();
+    for obj in objects {
+        total += obj.call_method0("__sizeof__")?.extract::<usize>()?;
+    }
+    Ok(total)
+}
+
+/// Validate a find_matches_as_indexes() extra_fields dict, mapping pattern
+/// indexes to arbitrary metadata, and convert it for lookups.
+fn get_extra_fields(
+    extra_fields: Option<&PyDict>,
+    pattern_count: usize,
+) -> PyResult<Option<HashMap<usize, &PyAny>>> {
+    let extra_fields = match extra_fields {
+        Some(extra_fields) => extra_fields,
+        None => return Ok(None),
+    };
+    let mut result = HashMap::with_capacity(extra_fields.len());
+    for (key, value) in extra_fields.iter() {
+        let index: usize = key.extract().map_err(|_| {
+            PyTypeError::new_err(format!(
+                "extra_fields keys must be pattern indexes, got {}.",
+                key
+            ))
+        })?;
+        if index >= pattern_count {
+            return Err(PyIndexError::new_err(format!(
+                "extra_fields key {} is out of range; there are {} patterns.",
+                index, pattern_count
+            )));
+        }
+        result.insert(index, value);
+    }
+    Ok(Some(result))
+}
+
+/// Convert an iterable of pattern indexes into a lookup table with an entry
+/// per pattern, saying whether it was included.
+fn get_pattern_ids_mask(pattern_ids: &PyAny, pattern_count: usize) -> PyResult<Vec<bool>> {
+    let mut mask = vec![false; pattern_count];
+    for pattern_id in pattern_ids.iter()? {
+        let pattern_id: usize = pattern_id?.extract()?;
+        if pattern_id >= pattern_count {
+            return Err(PyIndexError::new_err(format!(
+                "Pattern index {} is out of range; there are {} patterns.",
+                pattern_id, pattern_count
+            )));
+        }
+        mask[pattern_id] = true;
+    }
+    Ok(mask)
+}
+
+/// Call the user-provided filter_fn with the (pattern_index, start, end) the
+/// key function returns for each item, returning whether it was true for
+/// each. Exceptions raised by filter_fn propagate.
+fn filter_mask<T>(
+    filter_fn: &PyAny,
+    items: &[T],
+    key: impl Fn(&T) -> (usize, usize, usize),
+) -> PyResult<Vec<bool>> {
+    items
+        .iter()
+        .map(|item| filter_fn.call1(key(item))?.is_true())
+        .collect()
+}
+
+/// Keep only the items for which the user-provided filter_fn returns true,
+/// see filter_mask().
+fn filter_matches<T>(
+    filter_fn: &PyAny,
+    items: Vec<T>,
+    key: impl Fn(&T) -> (usize, usize, usize),
+) -> PyResult<Vec<T>> {
+    let mask = filter_mask(filter_fn, &items, key)?;
+    Ok(items
+        .into_iter()
+        .zip(mask)
+        .filter_map(|(item, keep)| keep.then_some(item))
+        .collect())
+}
+
+/// Find the matches for which the user-provided predicate returns true when
+/// called with (pattern_index, start, end), with the offsets mapped by
+/// offset(), as sorted (pattern_index, start, end) tuples. Unlike
+/// filter_matches(), rejected matches don't hide accepted ones they overlap;
+/// see find_matches_where(). The GIL stays held, since predicate is Python
+/// code, and exceptions it raises propagate.
+fn find_matches_accepted_by(
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    predicate: &PyAny,
+    offset: impl Fn(usize) -> usize,
+) -> PyResult<Vec<(usize, usize, usize)>> {
+    let error = RefCell::new(None);
+    let keep = |m: &PatternMatch| {
+        // After an exception, finish the search without calling predicate
+        // again; the matches are discarded anyway:
+        if error.borrow().is_some() {
+            return true;
+        }
+        let result = predicate
+            .call1((m.pattern(), offset(m.start()), offset(m.end())))
+            .and_then(PyAny::is_true);
+        result.unwrap_or_else(|err| {
+            *error.borrow_mut() = Some(err);
+            true
+        })
+    };
+    let mut matches = find_matches_where(ac_impl, haystack, overlapping, keep, |start| start + 1);
+    if let Some(err) = error.into_inner() {
+        return Err(err);
+    }
+    matches.sort_unstable_by_key(|m| (m.start(), m.end(), m.pattern()));
+    Ok(matches
+        .into_iter()
+        .map(|m| (m.pattern(), offset(m.start()), offset(m.end())))
+        .collect())
+}
+
+/// How many matches for_each_match() finds with the GIL released before
+/// reacquiring it to pass them to the callback. Releasing the GIL for every
+/// match would cost more than the search itself.
+const FOR_EACH_BATCH_SIZE: usize = 256;
+
+/// Call callback(pattern_index, start, end) for each match, with the offsets
+/// mapped by offset(), in the order the automaton finds them. The search runs
+/// with the GIL released, pausing every FOR_EACH_BATCH_SIZE matches to call
+/// callback with it held, so memory use doesn't grow with the number of
+/// matches. If callback raises StopIteration the search stops early, and
+/// returns normally; other exceptions propagate.
+fn for_each_match(
+    py: Python<'_>,
+    ac_impl: &Automaton,
+    haystack: &[u8],
+    overlapping: bool,
+    callback: &PyAny,
+    offset: impl Fn(usize) -> usize,
+) -> PyResult<()> {
+    let mut matches = if overlapping {
+        ac_impl.find_overlapping_iter(haystack)
+    } else {
+        ac_impl.find_iter(haystack)
+    };
+    let mut batch = Vec::with_capacity(FOR_EACH_BATCH_SIZE);
+    loop {
+        batch.clear();
+        py.allow_threads(|| batch.extend(matches.by_ref().take(FOR_EACH_BATCH_SIZE)));
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for m in batch.iter() {
+            match callback.call1((m.pattern(), offset(m.start()), offset(m.end()))) {
+                Ok(_) => {}
+                Err(err) if err.is_instance_of::<PyStopIteration>(py) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Replace the items of a list of results that filter_fn rejected with None,
+/// if fill_gaps was requested, so results keep their positions.
+fn fill_filtered_gaps(
+    py: Python,
+    results: PyObject,
+    mask: Option<Vec<bool>>,
+) -> PyResult<PyObject> {
+    if let Some(mask) = mask {
+        let list = results.as_ref(py).downcast::<PyList>()?;
+        for (index, keep) in mask.into_iter().enumerate() {
+            if !keep {
+                list.set_item(index, py.None())?;
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Compare user-provided per-pattern objects (IDs or values), if any, with
+/// Python's ==.
+fn objects_eq(
+    py: Python,
+    objects: &Option<Vec<PyObject>>,
+    other: &Option<Vec<PyObject>>,
+) -> PyResult<bool> {
+    match (objects, other) {
+        (None, None) => Ok(true),
+        (Some(objects), Some(other)) => PyList::new(py, objects)
+            .rich_compare(PyList::new(py, other), CompareOp::Eq)?
+            .is_true(),
+        _ => Ok(false),
+    }
+}
+
+/// Make sure user-provided per-pattern objects (IDs or values), if any, match
+/// the number of patterns. `name` is the argument name, for the error message.
+fn check_per_pattern(
+    name: &str,
+    objects: &Option<Vec<PyObject>>,
+    pattern_count: usize,
+) -> PyResult<()> {
+    match objects {
+        Some(objects) if objects.len() != pattern_count => Err(PyValueError::new_err(format!(
+            "Got {} {} for {} patterns; there must be exactly one per pattern.",
+            objects.len(),
+            name,
+            pattern_count
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Reports construction progress to a user-provided on_build_progress
+/// callback, which is called with (patterns_processed, total_patterns).
+struct BuildProgress<'a> {
+    callback: Option<&'a PyAny>,
+    // Call the callback every this many patterns.
+    interval: usize,
+    // The number of patterns, or -1 if it isn't known up front, e.g. for a
+    // generator.
+    total: isize,
+}
+
+impl<'a> BuildProgress<'a> {
+    fn new(callback: Option<&'a PyAny>, interval: usize, patterns: &PyAny) -> PyResult<Self> {
+        if interval == 0 {
+            return Err(PyValueError::new_err("progress_interval must be positive."));
+        }
+        let total = match callback {
+            Some(_) => patterns.len().map_or(-1, |len| len as isize),
+            None => -1,
+        };
+        Ok(Self {
+            callback,
+            interval,
+            total,
+        })
+    }
+
+    /// No progress reporting.
+    fn none() -> Self {
+        Self {
+            callback: None,
+            interval: usize::MAX,
+            total: -1,
+        }
+    }
+
+    /// Call after processing each pattern; processed counts from 1.
+    fn pattern_processed(&self, processed: usize) -> PyResult<()> {
+        if let Some(callback) = self.callback {
+            if processed.is_multiple_of(self.interval) {
+                callback.call1((processed, self.total))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call once the automaton has been built, when the total is known.
+    fn finished(&self, processed: usize) -> PyResult<()> {
+        if let Some(callback) = self.callback {
+            callback.call1((processed, processed))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reorder items so the i-th result is items[order[i]]; order must be a
+/// permutation of the indexes.
+fn reorder<T>(items: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut items: Vec<Option<T>> = items.into_iter().map(Some).collect();
+    order.iter().map(|&i| items[i].take().unwrap()).collect()
+}
+
+/// Get the user-provided ID for a pattern index, or the index itself if no
+/// IDs were provided.
+fn get_id(
+    py: Python<'_>,
+    ids: &Option<Vec<PyObject>>,
+    pattern_count: usize,
+    index: usize,
+) -> PyResult<PyObject> {
+    if index >= pattern_count {
+        return Err(PyIndexError::new_err(format!(
+            "Pattern index {} is out of range; there are {} patterns.",
+            index, pattern_count
+        )));
+    }
+    Ok(match ids {
+        Some(ids) => ids[index].clone_ref(py),
+        None => index.into_py(py),
+    })
+}
+
+/// A haystack string with a precomputed byte to code point map, for searching
+/// the same string with several automatons without recomputing the map each
+/// time. Python strings are immutable, so the map can't go stale.
+#[pyclass(name = "Haystack", module = "ahocorasick_rs")]
+struct PyHaystack {
+    text: String,
+    byte_to_code_point: ByteToCodePoint<'static>,
+}
+
+/// Methods for PyHaystack.
+#[pymethods]
+impl PyHaystack {
+    /// __new__() implementation.
+    #[new]
+    fn new(py: Python, text: String) -> Self {
+        let byte_to_code_point = py.allow_threads(|| get_byte_to_code_point(text.as_bytes()));
+        Self {
+            text,
+            byte_to_code_point,
+        }
+    }
+}
+
+/// A haystack argument to AhoCorasick methods: a str, a Haystack with a
+/// precomputed byte to code point map, or a bytes-like object containing
+/// UTF-8.
+enum HaystackArg<'a> {
+    Haystack(PyRef<'a, PyHaystack>),
+    Str(&'a str),
+    // A str containing surrogate code points (e.g. from decoding with
+    // errors="surrogatepass") can't be encoded as UTF-8, so it's encoded with
+    // surrogatepass instead, where each surrogate takes 3 bytes. Patterns
+    // are valid UTF-8, so they can't match part of an encoded surrogate.
+    Surrogates(&'a [u8]),
+    // UTF-8 in a bytes object, which is immutable, so it's searched in place.
+    Utf8(&'a str),
+    // UTF-8 in any other bytes-like object. Those can be changed by other
+    // threads while the GIL is released, or by callbacks, even through a
+    // read-only view, so the text is copied rather than trusting it to stay
+    // valid UTF-8.
+    Copied(String),
+}
+
+impl<'a> FromPyObject<'a> for HaystackArg<'a> {
+    fn extract(obj: &'a PyAny) -> PyResult<Self> {
+        if let Ok(haystack) = obj.extract::<PyRef<PyHaystack>>() {
+            return Ok(HaystackArg::Haystack(haystack));
+        }
+        if let Ok(text) = obj.downcast::<PyUnicode>() {
+            return Ok(match text.to_str() {
+                Ok(text) => HaystackArg::Str(text),
+                Err(_) => HaystackArg::Surrogates(encode_surrogatepass(obj)?),
+            });
+        }
+        let invalid_utf8 = |e: std::str::Utf8Error| {
+            PyValueError::new_err(format!("Haystack isn't valid UTF-8: {}", e))
+        };
+        if let Ok(bytes) = obj.downcast::<PyBytes>() {
+            let text = std::str::from_utf8(bytes.as_bytes()).map_err(invalid_utf8)?;
+            return Ok(HaystackArg::Utf8(text));
+        }
+        if let Ok(buffer) = PyBuffer::<u8>::get(obj) {
+            let bytes = buffer.to_vec(obj.py())?;
+            let text = String::from_utf8(bytes).map_err(|e| invalid_utf8(e.utf8_error()))?;
+            return Ok(HaystackArg::Copied(text));
+        }
+        Err(PyTypeError::new_err(format!(
+            "Haystack must be a str, Haystack or UTF-8 bytes-like object, got {}.",
+            obj.get_type().name()?
+        )))
+    }
+}
+
+/// Encode a str as UTF-8, passing surrogate code points through.
+fn encode_surrogatepass(obj: &PyAny) -> PyResult<&[u8]> {
+    let encoded = obj.call_method1("encode", ("utf-8", "surrogatepass"))?;
+    Ok(encoded.downcast::<PyBytes>()?.as_bytes())
+}
+
+impl HaystackArg<'_> {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            HaystackArg::Haystack(haystack) => haystack.text.as_bytes(),
+            HaystackArg::Str(text) => text.as_bytes(),
+            HaystackArg::Surrogates(bytes) => bytes,
+            HaystackArg::Utf8(text) => text.as_bytes(),
+            HaystackArg::Copied(text) => text.as_bytes(),
+        }
+    }
+
+    /// The haystack as a str, for the few options that need valid UTF-8.
+    fn as_str(&self) -> PyResult<&str> {
+        match self {
+            HaystackArg::Haystack(haystack) => Ok(&haystack.text),
+            HaystackArg::Str(text) | HaystackArg::Utf8(text) => Ok(text),
+            HaystackArg::Copied(text) => Ok(text),
+            HaystackArg::Surrogates(_) => Err(PyValueError::new_err(
+                "This option doesn't support haystacks containing surrogate code points.",
+            )),
+        }
+    }
+
+    /// Limit searching to the first search_limit code points, if given, and
+    /// return the byte offset to search up to. A str is truncated, so its
+    /// byte to code point map only covers that prefix; a Haystack's
+    /// precomputed map is kept as is.
+    fn limit(&mut self, search_limit: Option<usize>) -> usize {
+        let search_limit = match search_limit {
+            Some(search_limit) => search_limit,
+            None => return self.as_bytes().len(),
+        };
+        let end = code_point_to_byte(self.as_bytes(), search_limit);
+        match self {
+            HaystackArg::Haystack(_) => {}
+            HaystackArg::Str(text) | HaystackArg::Utf8(text) => *text = &text[..end],
+            HaystackArg::Copied(text) => text.truncate(end),
+            HaystackArg::Surrogates(bytes) => *bytes = &bytes[..end],
+        }
+        end
+    }
+
+    fn byte_to_code_point(&self) -> ByteToCodePoint<'_> {
+        match self {
+            HaystackArg::Haystack(haystack) => haystack.byte_to_code_point.borrowed(),
+            HaystackArg::Str(text) | HaystackArg::Utf8(text) => {
+                get_byte_to_code_point(text.as_bytes())
+            }
+            HaystackArg::Copied(text) => get_byte_to_code_point(text.as_bytes()),
+            HaystackArg::Surrogates(bytes) => get_byte_to_code_point(bytes),
+        }
+    }
+}
+
+/// Create a new instance of the same class as `original`, which may be a
+/// Python subclass, from `value`, without calling __new__() or __init__().
+fn new_instance_like<T>(original: &PyCell<T>, value: T) -> PyResult<&PyCell<T>>
+where
+    T: PyClass,
+    PyClassInitializer<T>: From<T>,
+{
+    // The type of an existing T is T or a subclass of it:
+    new_instance_of(original.get_type(), value)
+}
+
+/// Create a new instance of cls from `value`, without calling __new__() or
+/// __init__(). cls must be T or a Python subclass of it, as it is for the
+/// cls a classmethod of T is called with.
+fn new_instance_of<T>(cls: &PyType, value: T) -> PyResult<&PyCell<T>>
+where
+    T: PyClass,
+    PyClassInitializer<T>: From<T>,
+{
+    use pyo3::ffi;
+
+    let py = cls.py();
+    // The cell is returned as a new reference:
+    unsafe {
+        let cell =
+            PyClassInitializer::from(value).create_cell_from_subtype(py, cls.as_type_ptr())?;
+        py.from_owned_ptr_or_err(cell as *mut ffi::PyObject)
+    }
+}
+
+/// Record in a __deepcopy__() memo that `original` was copied to `copied`, as
+/// copy.deepcopy() expects, keyed by id(original).
+fn add_to_memo(memo: &PyAny, original: &PyAny, copied: &PyAny) -> PyResult<()> {
+    use pyo3::AsPyPointer;
+
+    memo.set_item(original.as_ptr() as usize, copied)
+}
+
+/// copy.deepcopy(objects, memo), for IDs and values.
+fn deepcopy_objects(
+    py: Python,
+    objects: &Option<Vec<PyObject>>,
+    memo: &PyAny,
+) -> PyResult<Option<Vec<PyObject>>> {
+    let deepcopy = py.import("copy")?.getattr("deepcopy")?;
+    objects
+        .as_ref()
+        .map(|objects| deepcopy.call1((PyList::new(py, objects), memo))?.extract())
+        .transpose()
+}
+
+/// Copy the instance __dict__ of a Python subclass instance, deep copying it
+/// if there's a memo; instances of the classes themselves have no __dict__.
+fn copy_instance_dict(original: &PyAny, copied: &PyAny, memo: Option<&PyAny>) -> PyResult<()> {
+    let dict = match original.getattr("__dict__") {
+        Ok(dict) => dict,
+        Err(_) => return Ok(()),
+    };
+    let dict = match memo {
+        Some(memo) => original
+            .py()
+            .import("copy")?
+            .getattr("deepcopy")?
+            .call1((dict, memo))?,
+        None => dict,
+    };
+    copied
+        .getattr("__dict__")?
+        .call_method1("update", (dict,))?;
+    Ok(())
+}
+
+/// The arguments AhoCorasick.__new__() takes, in order; must match new().
+const AHOCORASICK_PARAMETERS: &[&str] = &[
+    "patterns",
+    "matchkind",
+    "ids",
+    "values",
+    "force_byte_offsets_on_ascii",
+    "sort_patterns",
+    "store_patterns",
+    "on_build_progress",
+    "progress_interval",
+    "implementation",
+    "on_invalid",
+    "coerce",
+];
+
+/// The arguments BytesAhoCorasick.__new__() takes, in order; must match new().
+const BYTES_AHOCORASICK_PARAMETERS: &[&str] = &[
+    "patterns",
+    "matchkind",
+    "ids",
+    "store_patterns",
+    "values",
+    "on_build_progress",
+    "progress_interval",
+    "implementation",
+    "on_invalid",
+    "coerce",
+];
+
+/// __init_subclass__() for both classes: chain up to object's, then, if cls
+/// doesn't define its own __new__(), give it `new`, which calls base's with
+/// only the arguments it takes, so cls can take others in __init__().
+fn init_subclass(
+    cls: &PyType,
+    base: &PyType,
+    new: &PyAny,
+    kwargs: Option<&PyDict>,
+) -> PyResult<()> {
+    let builtins = cls.py().import("builtins")?;
+    builtins
+        .getattr("super")?
+        .call1((base, cls))?
+        .getattr("__init_subclass__")?
+        .call((), kwargs)?;
+    if cls.getattr("__new__")?.is(base.getattr("__new__")?) {
+        cls.setattr("__new__", builtins.getattr("staticmethod")?.call1((new,))?)?;
+    }
+    Ok(())
+}
+
+/// Call base.__new__(cls, ...) with the positional arguments and keyword
+/// arguments named in parameters, dropping the rest.
+fn new_subclass_instance(
+    cls: &PyType,
+    base: &PyType,
+    parameters: &[&str],
+    args: &PyTuple,
+    kwargs: Option<&PyDict>,
+) -> PyResult<PyObject> {
+    let py = cls.py();
+    let mut base_args: Vec<&PyAny> = vec![cls];
+    base_args.extend(args.iter().take(parameters.len()));
+    let base_kwargs = PyDict::new(py);
+    for (name, value) in kwargs.into_iter().flatten() {
+        if parameters.contains(&name.extract::<&str>()?) {
+            base_kwargs.set_item(name, value)?;
+        }
+    }
+    Ok(base
+        .getattr("__new__")?
+        .call(PyTuple::new(py, base_args), Some(base_kwargs))?
+        .into())
+}
+
+/// The __new__() AhoCorasick subclasses get if they don't define one.
+#[pyfunction(args = "*", kwargs = "**")]
+fn new_ahocorasick_subclass(
+    cls: &PyType,
+    args: &PyTuple,
+    kwargs: Option<&PyDict>,
+) -> PyResult<PyObject> {
+    let base = cls.py().get_type::<PyAhoCorasick>();
+    new_subclass_instance(cls, base, AHOCORASICK_PARAMETERS, args, kwargs)
+}
+
+/// The __new__() BytesAhoCorasick subclasses get if they don't define one.
+#[pyfunction(args = "*", kwargs = "**")]
+fn new_bytes_ahocorasick_subclass(
+    cls: &PyType,
+    args: &PyTuple,
+    kwargs: Option<&PyDict>,
+) -> PyResult<PyObject> {
+    let base = cls.py().get_type::<PyBytesAhoCorasick>();
+    new_subclass_instance(cls, base, BYTES_AHOCORASICK_PARAMETERS, args, kwargs)
+}
+
+/// The patterns stored by an AhoCorasick.
+#[derive(Clone)]
+enum StoredPatterns {
+    /// A Python string per pattern.
+    Objects(Vec<Py<PyUnicode>>),
+    /// All the patterns concatenated, with the end offset of each; this
+    /// avoids the per-object overhead of Python strings, at the cost of
+    /// creating a new string whenever a pattern is returned.
+    Compact { text: String, ends: Vec<usize> },
+}
+
+impl StoredPatterns {
+    fn compact(py: Python, patterns: &[Py<PyUnicode>]) -> PyResult<Self> {
+        let mut text = String::new();
+        let mut ends = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            text.push_str(pattern.as_ref(py).to_str()?);
+            ends.push(text.len());
+        }
+        text.shrink_to_fit();
+        Ok(Self::Compact { text, ends })
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Objects(patterns) => patterns.len(),
+            Self::Compact { ends, .. } => ends.len(),
+        }
+    }
+
+    /// The text of the pattern with the given index.
+    fn text<'a>(&'a self, py: Python<'a>, index: usize) -> PyResult<&'a str> {
+        match self {
+            Self::Objects(patterns) => patterns[index].as_ref(py).to_str(),
+            Self::Compact { text, ends } => {
+                let start = if index == 0 { 0 } else { ends[index - 1] };
+                Ok(&text[start..ends[index]])
+            }
+        }
+    }
+
+    /// The text of each pattern, in pattern index order.
+    fn texts<'a>(&'a self, py: Python<'a>) -> PyResult<Vec<&'a str>> {
+        (0..self.len()).map(|index| self.text(py, index)).collect()
+    }
+
+    /// The pattern with the given index as a Python string; a new one if
+    /// the patterns are compact.
+    fn get(&self, py: Python, index: usize) -> Py<PyUnicode> {
+        match self {
+            Self::Objects(patterns) => patterns[index].clone_ref(py),
+            Self::Compact { text, ends } => {
+                let start = if index == 0 { 0 } else { ends[index - 1] };
+                PyUnicode::new(py, &text[start..ends[index]]).into()
+            }
+        }
+    }
+
+    /// All the patterns as Python strings, in pattern index order.
+    fn to_vec(&self, py: Python) -> Vec<Py<PyUnicode>> {
+        (0..self.len()).map(|index| self.get(py, index)).collect()
+    }
+
+    fn memory_usage(&self, py: Python) -> PyResult<usize> {
+        match self {
+            Self::Objects(patterns) => {
+                objects_memory_usage(patterns.iter().map(|p| p.as_ref(py).as_ref()))
+            }
+            Self::Compact { text, ends } => {
+                Ok(text.capacity() + ends.capacity() * std::mem::size_of::<usize>())
+            }
+        }
+    }
+}
+
+/// A set operation for PyAhoCorasick::combine().
+#[derive(Clone, Copy)]
+enum SetOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// A Python wrapper for AhoCorasick.
+#[pyclass(subclass, name = "AhoCorasick", module = "ahocorasick_rs")]
+struct PyAhoCorasick {
+    // Shared with copies, see __copy__().
+    ac_impl: Arc<Automaton>,
+    // None once drop_patterns() has been called.
+    patterns: Option<StoredPatterns>,
+    // If the patterns were reordered before building (see from_counter()
+    // and new()'s sort_patterns), maps the automaton's pattern indexes to the original ones.
+    sorted_to_original: Option<Vec<usize>>,
+    // User-provided IDs, one per pattern, if any.
+    ids: Option<Vec<PyObject>>,
+    // User-provided values, one per pattern, if any.
+    values: Option<Vec<PyObject>>,
+    // Maps patterns to their (first) index; built on first use by
+    // __contains__() and index().
+    pattern_indexes: GILOnceCell<HashMap<String, usize>>,
+    // Whether to return byte offsets rather than code point offsets, see
+    // new().
+    byte_offsets: bool,
+    // Cached result of __hash__().
+    hash: GILOnceCell<u64>,
+    // Indexes of the constructor's patterns skipped by on_invalid="skip".
+    skipped_patterns: Vec<usize>,
+    // Whether get_or_build() hands this object out to every caller, in which
+    // case drop_patterns() refuses to change it.
+    cached: bool,
+}
+
+impl PyAhoCorasick {
+    /// A copy sharing the built automaton, see __copy__().
+    fn shallow_copy(&self) -> Self {
+        Self {
+            ac_impl: self.ac_impl.clone(),
+            patterns: self.patterns.clone(),
+            sorted_to_original: self.sorted_to_original.clone(),
+            ids: self.ids.clone(),
+            values: self.values.clone(),
+            pattern_indexes: GILOnceCell::new(),
+            byte_offsets: self.byte_offsets,
+            hash: GILOnceCell::new(),
+            skipped_patterns: self.skipped_patterns.clone(),
+            cached: false,
+        }
+    }
+
+    fn from_patterns_with_progress(
+        py: Python,
+        patterns: Vec<Py<PyUnicode>>,
+        matchkind: MatchKind,
+        kind: Implementation,
+        progress: &BuildProgress,
+    ) -> PyResult<Self> {
+        let mut rust_patterns: Vec<String> = vec![];
+        for s in patterns.iter() {
+            rust_patterns.push(s.as_ref(py).extract()?);
+            progress.pattern_processed(rust_patterns.len())?;
+        }
+        let ac_impl = Arc::new(build_automaton(py, rust_patterns, matchkind, kind));
+        progress.finished(patterns.len())?;
+        Ok(Self {
+            ac_impl,
+            patterns: Some(StoredPatterns::Objects(patterns)),
+            sorted_to_original: None,
+            ids: None,
+            values: None,
+            pattern_indexes: GILOnceCell::new(),
+            byte_offsets: false,
+            hash: GILOnceCell::new(),
+            skipped_patterns: vec![],
+            cached: false,
+        })
+    }
+
+    fn byte_to_code_point<'h>(&self, haystack: &'h HaystackArg) -> ByteToCodePoint<'h> {
+        if self.byte_offsets {
+            ByteToCodePoint::Identity
+        } else {
+            haystack.byte_to_code_point()
+        }
+    }
+
+    fn get_values(&self) -> PyResult<&[PyObject]> {
+        self.values.as_deref().ok_or_else(|| {
+            PyValueError::new_err("No values were passed when creating this AhoCorasick.")
+        })
+    }
+
+    fn get_patterns(&self) -> PyResult<&StoredPatterns> {
+        self.patterns.as_ref().ok_or_else(|| {
+            PyRuntimeError::new_err(
+                "Patterns aren't stored, since drop_patterns() was called on this AhoCorasick.",
+            )
+        })
+    }
+
+    /// The text of a match: the stored pattern, or if patterns were dropped,
+    /// that part of the haystack.
+    fn matched_text(&self, py: Python, haystack: &[u8], m: &PatternMatch) -> Py<PyUnicode> {
+        match &self.patterns {
+            Some(patterns) => patterns.get(py, m.pattern()),
+            // A match is exactly a pattern's bytes, so it's valid UTF-8 even
+            // if the haystack contains encoded surrogates:
+            None => PyUnicode::new(
+                py,
+                std::str::from_utf8(&haystack[m.start()..m.end()]).unwrap(),
+            )
+            .into(),
+        }
+    }
+
+    fn get_pattern_indexes(&self, py: Python) -> PyResult<&HashMap<String, usize>> {
+        if let Some(pattern_indexes) = self.pattern_indexes.get(py) {
+            return Ok(pattern_indexes);
+        }
+        let patterns = self.get_patterns()?;
+        let mut pattern_indexes = HashMap::with_capacity(patterns.len());
+        for (index, pattern) in patterns.texts(py)?.into_iter().enumerate() {
+            pattern_indexes.entry(pattern.to_owned()).or_insert(index);
+        }
+        Ok(self.pattern_indexes.get_or_init(py, || pattern_indexes))
+    }
+
+    fn stored_patterns_memory_usage(&self, py: Python) -> PyResult<usize> {
+        match &self.patterns {
+            Some(patterns) => patterns.memory_usage(py),
+            None => Ok(0),
+        }
+    }
+
+    /// Like combine(), for the |, & and - operators, which have no way to
+    /// pass a matchkind, so both operands must have the same one.
+    fn combine_operator(&self, other: PyRef<Self>, operation: SetOperation) -> PyResult<Self> {
+        let (matchkind, other_matchkind) =
+            (*self.ac_impl.match_kind(), *other.ac_impl.match_kind());
+        if matchkind != other_matchkind {
+            return Err(PyValueError::new_err(format!(
+                "Can't combine an AhoCorasick using {} with one using {}; call union(), intersection() or difference() with a matchkind instead.",
+                matchkind_name(matchkind),
+                matchkind_name(other_matchkind)
+            )));
+        }
+        self.combine(other.py(), &other, operation, None)
+    }
+
+    /// Build a new AhoCorasick from a set operation on this one's and
+    /// other's patterns; see union(), intersection() and difference().
+    fn combine(
+        &self,
+        py: Python,
+        other: &Self,
+        operation: SetOperation,
+        matchkind: Option<&str>,
+    ) -> PyResult<Self> {
+        let matchkind = match matchkind {
+            Some(matchkind) => parse_matchkind(matchkind)?,
+            None => *self.ac_impl.match_kind(),
+        };
+        let patterns = self.get_patterns()?;
+        let other_patterns = other.get_patterns()?;
+        let other_indexes = other.get_pattern_indexes(py)?;
+        // (whether it's from other, pattern index) of each kept pattern:
+        let mut kept = vec![];
+        let mut seen = HashSet::new();
+        for (index, pattern) in patterns.texts(py)?.into_iter().enumerate() {
+            let keep = match operation {
+                SetOperation::Union => true,
+                SetOperation::Intersection => other_indexes.contains_key(pattern),
+                SetOperation::Difference => !other_indexes.contains_key(pattern),
+            };
+            if keep && seen.insert(pattern) {
+                kept.push((false, index));
+            }
+        }
+        if let SetOperation::Union = operation {
+            for (index, pattern) in other_patterns.texts(py)?.into_iter().enumerate() {
+                if seen.insert(pattern) {
+                    kept.push((true, index));
+                }
+            }
+        }
+        let select = |name: &str,
+                      mine: &Option<Vec<PyObject>>,
+                      theirs: &Option<Vec<PyObject>>|
+         -> PyResult<Option<Vec<PyObject>>> {
+            if let SetOperation::Union = operation {
+                if mine.is_some() != theirs.is_some() {
+                    return Err(PyValueError::new_err(format!(
+                        "Can't combine an AhoCorasick with {} and one without them.",
+                        name
+                    )));
+                }
+            }
+            Ok(mine.as_ref().map(|mine| {
+                kept.iter()
+                    .map(|&(from_other, index)| match theirs {
+                        Some(theirs) if from_other => theirs[index].clone_ref(py),
+                        _ => mine[index].clone_ref(py),
+                    })
+                    .collect()
+            }))
+        };
+        let ids = select("ids", &self.ids, &other.ids)?;
+        let values = select("values", &self.values, &other.values)?;
+        let mut result = Self::from_patterns_with_progress(
+            py,
+            kept.iter()
+                .map(|&(from_other, index)| {
+                    if from_other {
+                        other_patterns.get(py, index)
+                    } else {
+                        patterns.get(py, index)
+                    }
+                })
+                .collect(),
+            matchkind,
+            self.ac_impl.kind,
+            &BuildProgress::none(),
+        )?;
+        result.ids = ids;
+        result.values = values;
+        result.byte_offsets = self.byte_offsets
+            && result
+                .get_patterns()?
+                .texts(py)?
+                .iter()
+                .all(|p| p.is_ascii());
+        if let Some(StoredPatterns::Compact { .. }) = self.patterns {
+            result.set_pattern_storage(py, PyUnicode::new(py, "compact"))?;
+        }
+        Ok(result)
+    }
+
+    /// Whether every pattern of other is also one of this one's patterns.
+    fn has_all_patterns_of(&self, py: Python, other: &Self) -> PyResult<bool> {
+        let pattern_indexes = self.get_pattern_indexes(py)?;
+        Ok(other
+            .get_patterns()?
+            .texts(py)?
+            .into_iter()
+            .all(|pattern| pattern_indexes.contains_key(pattern)))
+    }
+
+    /// Store the patterns as requested by the store_patterns argument: True
+    /// keeps a Python string per pattern, "compact" keeps them in one Rust
+    /// buffer, and False drops them.
+    fn set_pattern_storage(&mut self, py: Python, store_patterns: &PyAny) -> PyResult<()> {
+        if let Ok(mode) = store_patterns.extract::<&str>() {
+            if mode != "compact" {
+                return Err(PyValueError::new_err(format!(
+                    "store_patterns must be True, False or \"compact\", not {:?}.",
+                    mode
+                )));
+            }
+            if let Some(StoredPatterns::Objects(patterns)) = &self.patterns {
+                self.patterns = Some(StoredPatterns::compact(py, patterns)?);
+            }
+        } else if !store_patterns.extract::<bool>()? {
+            self.drop_patterns()?;
+        }
+        Ok(())
+    }
+
+    fn check_pattern_index(&self, index: usize) -> PyResult<()> {
+        let pattern_count = self.ac_impl.pattern_count();
+        if index >= pattern_count {
+            return Err(PyIndexError::new_err(format!(
+                "Pattern index {} is out of range; there are {} patterns.",
+                index, pattern_count
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Methods for PyAhoCorasick.
+#[pymethods]
+impl PyAhoCorasick {
+    /// Unless a subclass defines its own __new__(), give it one that drops
+    /// arguments AhoCorasick's doesn't take, so that the subclass can take
+    /// its own in __init__().
+    #[classmethod]
+    #[args(kwargs = "**")]
+    fn __init_subclass__(cls: &PyType, kwargs: Option<&PyDict>) -> PyResult<()> {
+        let py = cls.py();
+        let new = wrap_pyfunction!(new_ahocorasick_subclass, py)?;
+        init_subclass(cls, py.get_type::<Self>(), new, kwargs)
+    }
+
+    /// __new__() implementation. If force_byte_offsets_on_ascii is true and
+    /// all the patterns are ASCII, offsets are UTF-8 byte offsets rather than
+    /// code point offsets, which skips mapping between the two; they're only
+    /// the same for ASCII haystacks.
+    ///
+    /// If sort_patterns is true, patterns (and their ids and values) are
+    /// sorted lexicographically before building, so the same set of patterns
+    /// always gets the same indexes regardless of input order.
+    ///
+    /// store_patterns controls how patterns are kept for returning from
+    /// find_matches_as_strings() and the like: by default (None or True) as
+    /// the Python strings passed in, as "compact" in a single Rust buffer
+    /// that avoids per-object overhead, or if False not at all, as if
+    /// drop_patterns() was called.
+    ///
+    /// If on_build_progress is given, it's called with (patterns_processed,
+    /// total_patterns) every progress_interval patterns, and once more when
+    /// the automaton has been built; an exception it raises aborts
+    /// construction.
+    ///
+    /// implementation is "dfa" (the default), which searches faster, or
+    /// "noncontiguous", which uses less memory and builds faster.
+    ///
+    /// If on_invalid is "skip" rather than the default "raise", patterns
+    /// that are empty, aren't strings or contain lone surrogates are left
+    /// out (along with their ids and values) instead of raising an
+    /// exception; skipped_patterns lists their indexes.
+    ///
+    /// If coerce is true, patterns that aren't strings, e.g. pathlib.Path
+    /// objects, are converted with str(); it's still an error if the result
+    /// is empty. With on_invalid="skip", patterns whose str() raises an
+    /// exception are skipped too.
+    ///
+    /// Unknown arguments raise a TypeError, except in subclasses: see
+    /// __init_subclass__().
+    #[new]
+    #[args(
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        ids = "None",
+        values = "None",
+        force_byte_offsets_on_ascii = "false",
+        sort_patterns = "false",
+        store_patterns = "None",
+        on_build_progress = "None",
+        progress_interval = "10_000",
+        implementation = "None",
+        on_invalid = "\"raise\"",
+        coerce = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python,
+        patterns: &PyAny,
+        matchkind: &str,
+        mut ids: Option<Vec<PyObject>>,
+        mut values: Option<Vec<PyObject>>,
+        force_byte_offsets_on_ascii: bool,
+        sort_patterns: bool,
+        store_patterns: Option<&PyAny>,
+        on_build_progress: Option<&PyAny>,
+        progress_interval: usize,
+        implementation: Option<&str>,
+        on_invalid: &str,
+        coerce: bool,
+    ) -> PyResult<Self> {
+        let kind = parse_implementation(implementation)?;
+        let skip_invalid = parse_on_invalid(on_invalid)?;
+        let progress = BuildProgress::new(on_build_progress, progress_interval, patterns)?;
+        let (mut patterns, skipped_patterns) = if skip_invalid || coerce {
+            extract_str_patterns_leniently(patterns, coerce, skip_invalid)?
+        } else {
+            (extract_str_patterns(patterns)?, vec![])
+        };
+        let input_count = patterns.len() + skipped_patterns.len();
+        check_per_pattern("ids", &ids, input_count)?;
+        check_per_pattern("values", &values, input_count)?;
+        ids = ids.map(|ids| remove_skipped(ids, &skipped_patterns));
+        values = values.map(|values| remove_skipped(values, &skipped_patterns));
+        let mut sorted_to_original = None;
+        if sort_patterns {
+            let mut order: Vec<usize> = (0..patterns.len()).collect();
+            {
+                let texts = patterns
+                    .iter()
+                    .map(|p| p.as_ref(py).to_str())
+                    .collect::<PyResult<Vec<_>>>()?;
+                // Stable sort, so duplicate patterns keep their relative order:
+                order.sort_by_key(|&index| texts[index]);
+            }
+            patterns = reorder(patterns, &order);
+            ids = ids.map(|ids| reorder(ids, &order));
+            values = values.map(|values| reorder(values, &order));
+            sorted_to_original = Some(order);
+        }
+        let mut result = Self::from_patterns_with_progress(
+            py,
+            patterns,
+            parse_matchkind(matchkind)?,
+            kind,
+            &progress,
+        )?;
+        result.sorted_to_original = sorted_to_original;
+        result.ids = ids;
+        result.values = values;
+        result.skipped_patterns = skipped_patterns;
+        result.byte_offsets = force_byte_offsets_on_ascii
+            && result
+                .get_patterns()?
+                .texts(py)?
+                .iter()
+                .all(|p| p.is_ascii());
+        if let Some(store_patterns) = store_patterns {
+            result.set_pattern_storage(py, store_patterns)?;
+        }
+        Ok(result)
+    }
+
+    /// Create from a dict (e.g. a collections.Counter) mapping patterns to
+    /// frequencies. Patterns are sorted by descending frequency before
+    /// building, which can improve cache behavior. With MATCHKIND_STANDARD
+    /// and MATCHKIND_LEFTMOST_LONGEST this doesn't change which matches are
+    /// found, but with MATCHKIND_LEFTMOST_FIRST pattern order is match
+    /// priority, so when several patterns match at the same position the
+    /// most frequent one wins. Returned pattern indexes refer to the sorted
+    /// order; use sorted_index_to_original_index() to map them back to the
+    /// dict's iteration order.
+    ///
+    /// Keyword arguments are passed on to the constructor, that of the
+    /// subclass if called on one; ids and values, if given, are in the
+    /// dict's iteration order and are sorted along with the patterns.
+    #[classmethod]
+    #[args(kwargs = "**")]
+    fn from_counter(cls: &PyType, counter: &PyDict, kwargs: Option<&PyDict>) -> PyResult<PyObject> {
+        let py = cls.py();
+        let mut entries: Vec<(usize, Py<PyUnicode>, i64)> = vec![];
+        for (index, (pattern, count)) in counter.iter().enumerate() {
+            entries.push((index, pattern.extract()?, count.extract()?));
+        }
+        // Stable sort, so patterns with the same frequency keep their order:
+        entries.sort_by_key(|(_, _, count)| std::cmp::Reverse(*count));
+        let order: Vec<usize> = entries.iter().map(|(index, _, _)| *index).collect();
+        let patterns: Vec<Py<PyUnicode>> =
+            entries.into_iter().map(|(_, pattern, _)| pattern).collect();
+
+        let kwargs = match kwargs {
+            Some(kwargs) => kwargs.copy()?,
+            None => PyDict::new(py),
+        };
+        if kwargs.contains("sort_patterns")? {
+            return Err(PyValueError::new_err(
+                "from_counter() orders patterns by frequency, so sort_patterns can't be passed.",
+            ));
+        }
+        for name in ["ids", "values"] {
+            if let Some(objects) = kwargs.get_item(name) {
+                let objects: Option<Vec<PyObject>> = objects.extract()?;
+                check_per_pattern(name, &objects, order.len())?;
+                if let Some(objects) = objects {
+                    kwargs.set_item(name, reorder(objects, &order))?;
+                }
+            }
+        }
+        let result = cls.call((patterns,), Some(kwargs))?;
+        let mut cell = result.downcast::<PyCell<PyAhoCorasick>>()?.borrow_mut();
+        // on_invalid="skip" may have left out some of the sorted patterns:
+        let skipped: HashSet<usize> = cell.skipped_patterns.iter().copied().collect();
+        cell.sorted_to_original = Some(
+            (0..order.len())
+                .filter(|index| !skipped.contains(index))
+                .map(|index| order[index])
+                .collect(),
+        );
+        let mut skipped_patterns: Vec<usize> =
+            skipped.into_iter().map(|index| order[index]).collect();
+        skipped_patterns.sort_unstable();
+        cell.skipped_patterns = skipped_patterns;
+        Ok(result.into())
+    }
+
+    /// Create from an object exposing an Arrow string or large_string array
+    /// (or a stream of them) via the Arrow PyCapsule interface, e.g. a
+    /// pyarrow.Array or polars.Series. Null entries cause a ValueError, or
+    /// are skipped if skip_nulls is true. The values are read directly from
+    /// the Arrow buffers without creating Python strings, and are stored as
+    /// with store_patterns="compact".
+    #[staticmethod]
+    #[args(
+        skip_nulls = "false",
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        implementation = "None"
+    )]
+    fn from_arrow(
+        py: Python,
+        array: &PyAny,
+        skip_nulls: bool,
+        matchkind: &str,
+        implementation: Option<&str>,
+    ) -> PyResult<Self> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let strings = arrow::read_arrow_strings(array, skip_nulls)?;
+        let ac_impl = Arc::new(build_automaton(py, strings.iter(), matchkind, kind));
+        let arrow::ArrowStrings { text, ends } = strings;
+        Ok(Self {
+            ac_impl,
+            patterns: Some(StoredPatterns::Compact { text, ends }),
+            sorted_to_original: None,
+            ids: None,
+            values: None,
+            pattern_indexes: GILOnceCell::new(),
+            byte_offsets: false,
+            hash: GILOnceCell::new(),
+            skipped_patterns: vec![],
+            cached: false,
+        })
+    }
+
+    /// Create from regular expressions that are really literal strings, e.g.
+    /// "foo\\.bar" or "^hello$". Leading "^" and trailing "$" anchors are
+    /// dropped, so matches aren't anchored; escaped punctuation is unescaped.
+    /// A pattern using any other regular expression syntax raises ValueError.
+    /// Keyword arguments, e.g. matchkind, ids or values, are passed on to the
+    /// constructor, that of the subclass if called on one.
+    #[classmethod]
+    #[args(kwargs = "**")]
+    fn from_regex_literals(
+        cls: &PyType,
+        patterns: &PyAny,
+        kwargs: Option<&PyDict>,
+    ) -> PyResult<PyObject> {
+        let py = cls.py();
+        let mut literals: Vec<Py<PyUnicode>> = vec![];
+        for (index, pattern) in patterns.iter()?.enumerate() {
+            let pattern: &str = pattern?.extract()?;
+            let literal = regex_to_literal(pattern).map_err(|message| {
+                PyValueError::new_err(format!(
+                    "Pattern {:?} at index {} isn't a literal: {}.",
+                    pattern, index, message
+                ))
+            })?;
+            literals.push(PyUnicode::new(py, &literal).into());
+        }
+        Ok(cls.call((literals,), kwargs)?.into())
+    }
+
+    /// Create from a nested dict in the format returned by to_trie_dict().
+    /// Patterns are numbered in the order of their indexes in the dict; if
+    /// some indexes are missing, e.g. because duplicate patterns were left
+    /// out, later patterns' indexes shift down. Keyword arguments are passed
+    /// on to the constructor, that of the subclass if called on one.
+    #[classmethod]
+    #[args(kwargs = "**")]
+    fn from_trie_dict(cls: &PyType, trie: &PyDict, kwargs: Option<&PyDict>) -> PyResult<PyObject> {
+        let mut patterns: Vec<(usize, String)> = vec![];
+        // (node, the prefix leading to it); a stack rather than recursion, so
+        // long patterns can't overflow it:
+        let mut stack = vec![(trie, String::new())];
+        while let Some((node, prefix)) = stack.pop() {
+            for (key, child) in node.iter() {
+                let key: &str = key.extract()?;
+                if key.is_empty() {
+                    patterns.push((child.extract()?, prefix.clone()));
+                    continue;
+                }
+                if key.chars().count() != 1 {
+                    return Err(PyValueError::new_err(format!(
+                        "Trie keys must be single characters or \"\", got {:?}.",
+                        key
+                    )));
+                }
+                stack.push((child.downcast()?, prefix.clone() + key));
+            }
+        }
+        patterns.sort_unstable();
+        if let Some(pair) = patterns.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(PyValueError::new_err(format!(
+                "Pattern index {} appears more than once in the trie.",
+                pair[0].0
+            )));
+        }
+        let patterns: Vec<String> = patterns.into_iter().map(|(_, pattern)| pattern).collect();
+        Ok(cls.call((patterns,), kwargs)?.into())
+    }
+
+    /// Return a new AhoCorasick built from this one's patterns followed by
+    /// additional_patterns, so existing patterns keep their indexes. The
+    /// automaton is rebuilt from scratch, with the same matchkind and
+    /// implementation. If this AhoCorasick has IDs or values, ids or values
+    /// must be given for the additional patterns too. Additional patterns
+    /// that duplicate an existing (or earlier additional) pattern are
+    /// skipped, or cause a ValueError if deduplicate_patterns is false.
+    #[args(ids = "None", values = "None", deduplicate_patterns = "true")]
+    fn update(
+        &self,
+        py: Python,
+        additional_patterns: &PyAny,
+        ids: Option<Vec<PyObject>>,
+        values: Option<Vec<PyObject>>,
+        deduplicate_patterns: bool,
+    ) -> PyResult<Self> {
+        let additional_patterns = extract_str_patterns(additional_patterns)?;
+        check_per_pattern("ids", &ids, additional_patterns.len())?;
+        check_per_pattern("values", &values, additional_patterns.len())?;
+        for (name, existing, additional) in [
+            ("ids", self.ids.is_some(), ids.is_some()),
+            ("values", self.values.is_some(), values.is_some()),
+        ] {
+            if existing != additional {
+                return Err(PyValueError::new_err(format!(
+                    "{} must be passed if and only if this AhoCorasick was created with {}.",
+                    name, name
+                )));
+            }
+        }
+
+        let pattern_indexes = self.get_pattern_indexes(py)?;
+        let mut added = HashSet::new();
+        let mut kept = vec![];
+        for (index, pattern) in additional_patterns.iter().enumerate() {
+            let text: &str = pattern.as_ref(py).extract()?;
+            if pattern_indexes.contains_key(text) || !added.insert(text) {
+                if deduplicate_patterns {
+                    continue;
+                }
+                return Err(PyValueError::new_err(format!(
+                    "Additional pattern at index {} is a duplicate: {:?}.",
+                    index, text
+                )));
+            }
+            kept.push(index);
+        }
+
+        let mut patterns = self.get_patterns()?.to_vec(py);
+        patterns.extend(kept.iter().map(|&i| additional_patterns[i].clone()));
+        let extend = |existing: &Option<Vec<PyObject>>, additional: Option<Vec<PyObject>>| {
+            existing
+                .as_ref()
+                .zip(additional)
+                .map(|(existing, additional)| {
+                    let mut result = existing.clone();
+                    result.extend(kept.iter().map(|&i| additional[i].clone()));
+                    result
+                })
+        };
+        let mut result = Self::from_patterns_with_progress(
+            py,
+            patterns,
+            *self.ac_impl.match_kind(),
+            self.ac_impl.kind,
+            &BuildProgress::none(),
+        )?;
+        result.ids = extend(&self.ids, ids);
+        result.values = extend(&self.values, values);
+        result.sorted_to_original = self.sorted_to_original.as_ref().map(|sorted_to_original| {
+            let mut sorted_to_original = sorted_to_original.clone();
+            sorted_to_original.extend(self.ac_impl.pattern_count()..result.ac_impl.pattern_count());
+            sorted_to_original
+        });
+        result.byte_offsets = self.byte_offsets
+            && kept.iter().all(|&i| {
+                additional_patterns[i]
+                    .as_ref(py)
+                    .to_str()
+                    .is_ok_and(str::is_ascii)
+            });
+        if let Some(StoredPatterns::Compact { .. }) = self.patterns {
+            result.set_pattern_storage(py, PyUnicode::new(py, "compact"))?;
+        }
+        Ok(result)
+    }
+
+    /// Return a new AhoCorasick with only the patterns that are also in
+    /// other, like intersection().
+    fn intersect(&self, py: Python, other: PyRef<Self>) -> PyResult<Self> {
+        self.combine(py, &other, SetOperation::Intersection, None)
+    }
+
+    /// Return a new AhoCorasick with this one's patterns followed by those of
+    /// other that aren't already included. Both automatons must store their
+    /// patterns; duplicates are removed, keeping the first. IDs and values
+    /// are carried over, so either both or neither must have them. The
+    /// matchkind is this one's unless given.
+    #[args(matchkind = "None")]
+    fn union(&self, py: Python, other: PyRef<Self>, matchkind: Option<&str>) -> PyResult<Self> {
+        self.combine(py, &other, SetOperation::Union, matchkind)
+    }
+
+    /// Return a new AhoCorasick with only the patterns that are also in
+    /// other, in the same order as in this one, along with their IDs and
+    /// values. Each shared pattern appears once, so a.intersection(b) and
+    /// b.intersection(a) have the same patterns, possibly in a different
+    /// order. Both automatons must store their patterns. The matchkind is
+    /// this one's unless given.
+    #[args(matchkind = "None")]
+    fn intersection(
+        &self,
+        py: Python,
+        other: PyRef<Self>,
+        matchkind: Option<&str>,
+    ) -> PyResult<Self> {
+        self.combine(py, &other, SetOperation::Intersection, matchkind)
+    }
+
+    /// Return a new AhoCorasick with only the patterns that aren't in other,
+    /// in the same order as in this one, along with their IDs and values;
+    /// duplicates are removed, keeping the first. Both automatons must store
+    /// their patterns. The matchkind is this one's unless given.
+    #[args(matchkind = "None")]
+    fn difference(
+        &self,
+        py: Python,
+        other: PyRef<Self>,
+        matchkind: Option<&str>,
+    ) -> PyResult<Self> {
+        self.combine(py, &other, SetOperation::Difference, matchkind)
+    }
+
+    /// a | b is a.union(b); both must have the same matchkind. There's no
+    /// in-place version, so a |= b binds a to a new AhoCorasick, leaving any
+    /// other references to the old one unchanged.
+    fn __or__(&self, other: PyRef<Self>) -> PyResult<Self> {
+        self.combine_operator(other, SetOperation::Union)
+    }
+
+    /// a & b is a.intersection(b); both must have the same matchkind.
+    fn __and__(&self, other: PyRef<Self>) -> PyResult<Self> {
+        self.combine_operator(other, SetOperation::Intersection)
+    }
+
+    /// a - b is a.difference(b); both must have the same matchkind.
+    fn __sub__(&self, other: PyRef<Self>) -> PyResult<Self> {
+        self.combine_operator(other, SetOperation::Difference)
+    }
+
+    /// Whether every pattern of this AhoCorasick is also a pattern of other.
+    /// Both automatons must store their patterns.
+    fn is_subset_of(&self, py: Python, other: PyRef<Self>) -> PyResult<bool> {
+        other.has_all_patterns_of(py, self)
+    }
+
+    /// Whether every pattern of other is also a pattern of this AhoCorasick.
+    /// Both automatons must store their patterns.
+    fn is_superset_of(&self, py: Python, other: PyRef<Self>) -> PyResult<bool> {
+        self.has_all_patterns_of(py, &other)
+    }
+
+    /// Save the patterns and construction options to a file, so load() can
+    /// rebuild the automaton without going through Python objects. The file
+    /// format is internal, and may change between releases. IDs and values
+    /// are arbitrary Python objects, so they can't be saved.
+    fn save(&self, py: Python, path: PathBuf) -> PyResult<()> {
+        if self.ids.is_some() || self.values.is_some() {
+            return Err(PyValueError::new_err(
+                "Can't save an AhoCorasick with ids or values.",
+            ));
+        }
+        let patterns = self.get_patterns()?.texts(py)?;
+        let data = saved::serialize(&saved::Saved {
+            class: saved::Class::AhoCorasick,
+            matchkind: *self.ac_impl.match_kind(),
+            implementation: self.ac_impl.kind,
+            byte_offsets: self.byte_offsets,
+            patterns,
+            sorted_to_original: self.sorted_to_original.clone(),
+        });
+        py.allow_threads(|| std::fs::write(&path, data))
+            .map_err(|e| PyIOError::new_err(format!("Couldn't write {}: {}", path.display(), e)))
+    }
+
+    /// Load an AhoCorasick written by save(). Raises a ValueError if the file
+    /// is truncated or was written in an unsupported format version. Called
+    /// on a subclass, returns an instance of it, without calling its
+    /// __new__() or __init__(), as when unpickling.
+    #[classmethod]
+    fn load(cls: &PyType, path: PathBuf) -> PyResult<&PyCell<Self>> {
+        let py = cls.py();
+        let data = py
+            .allow_threads(|| std::fs::read(&path))
+            .map_err(|e| PyIOError::new_err(format!("Couldn't read {}: {}", path.display(), e)))?;
+        let loaded = saved::deserialize(&data, &path, saved::Class::AhoCorasick)?;
+        let rust_patterns = loaded
+            .patterns
+            .iter()
+            .map(|p| std::str::from_utf8(p))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| {
+                PyValueError::new_err(format!(
+                    "Couldn't load {}: it has a pattern that isn't valid UTF-8.",
+                    path.display()
+                ))
+            })?;
+        let patterns = rust_patterns
+            .iter()
+            .map(|pattern| PyUnicode::new(py, pattern).into())
+            .collect();
+        let result = Self {
+            ac_impl: Arc::new(build_automaton(
+                py,
+                &rust_patterns,
+                loaded.matchkind,
+                loaded.implementation,
+            )),
+            patterns: Some(StoredPatterns::Objects(patterns)),
+            sorted_to_original: loaded.sorted_to_original,
+            ids: None,
+            values: None,
+            pattern_indexes: GILOnceCell::new(),
+            byte_offsets: loaded.byte_offsets,
+            hash: GILOnceCell::new(),
+            skipped_patterns: vec![],
+            cached: false,
+        };
+        new_instance_of(cls, result)
+    }
+
+    /// Map a pattern index in the original (pre-sorting) order to the
+    /// automaton's pattern index. The identity unless created with
+    /// from_counter() or sort_patterns=True. Raises IndexError for an index
+    /// with no pattern in the automaton, e.g. one skipped by
+    /// on_invalid="skip".
+    fn original_index_to_sorted_index(&self, index: usize) -> PyResult<usize> {
+        match &self.sorted_to_original {
+            Some(sorted_to_original) => sorted_to_original
+                .iter()
+                .position(|&original| original == index)
+                .ok_or_else(|| {
+                    PyIndexError::new_err(format!(
+                        "Original pattern index {} isn't in the automaton.",
+                        index
+                    ))
+                }),
+            None => {
+                self.check_pattern_index(index)?;
+                Ok(index)
+            }
+        }
+    }
+
+    /// Map an automaton's pattern index to the original (pre-sorting) pattern
+    /// index. The identity unless created with from_counter() or
+    /// sort_patterns=True.
+    fn sorted_index_to_original_index(&self, index: usize) -> PyResult<usize> {
+        self.check_pattern_index(index)?;
+        Ok(match &self.sorted_to_original {
+            Some(sorted_to_original) => sorted_to_original[index],
+            None => index,
+        })
+    }
+
+    /// Create from a buffer containing UTF-8 encoded patterns separated by a
+    /// delimiter (default is a newline). Empty records are skipped, or if
+    /// skip_empty is false cause a ValueError. implementation works like the
+    /// constructor's.
+    #[staticmethod]
+    #[args(
+        delimiter = "None",
+        skip_empty = "true",
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        implementation = "None"
+    )]
+    fn from_delimited(
+        py: Python,
+        blob: PyBuffer<u8>,
+        delimiter: Option<&[u8]>,
+        skip_empty: bool,
+        matchkind: &str,
+        implementation: Option<&str>,
+    ) -> PyResult<Self> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let records = split_on_delimiter(buffer_as_bytes(&blob)?, delimiter, skip_empty)?;
+        let mut patterns = Vec::with_capacity(records.len());
+        for (index, record) in records.iter() {
+            let pattern = std::str::from_utf8(record).map_err(|e| {
+                PyValueError::new_err(format!(
+                    "Record at index {} isn't valid UTF-8: {}",
+                    index, e
+                ))
+            })?;
+            patterns.push(PyUnicode::new(py, pattern).into());
+        }
+        Ok(Self {
+            ac_impl: Arc::new(build_automaton(
+                py,
+                records.iter().map(|(_, r)| r),
+                matchkind,
+                kind,
+            )),
+            patterns: Some(StoredPatterns::Objects(patterns)),
+            sorted_to_original: None,
+            ids: None,
+            values: None,
+            pattern_indexes: GILOnceCell::new(),
+            byte_offsets: false,
+            hash: GILOnceCell::new(),
+            skipped_patterns: vec![],
+            cached: false,
+        })
+    }
+
+    /// The number of patterns the automaton was built with.
+    fn __len__(&self) -> usize {
+        self.ac_impl.pattern_count()
+    }
+
+    /// The memory used by the automaton and stored patterns, in bytes; see
+    /// memory_usage_breakdown().
+    fn memory_usage(&self, py: Python) -> PyResult<usize> {
+        Ok(self.ac_impl.memory_usage() + self.stored_patterns_memory_usage(py)?)
+    }
+
+    /// Return a dict with the memory used by the automaton and by stored
+    /// patterns, in bytes.
+    fn memory_usage_breakdown(&self, py: Python) -> PyResult<PyObject> {
+        let breakdown = PyDict::new(py);
+        breakdown.set_item("automaton", self.ac_impl.memory_usage())?;
+        breakdown.set_item("stored_patterns", self.stored_patterns_memory_usage(py)?)?;
+        Ok(breakdown.into())
+    }
+
+    /// Include memory_usage(), so sys.getsizeof() is meaningful.
+    fn __sizeof__(&self, py: Python) -> PyResult<usize> {
+        Ok(std::mem::size_of::<Self>() + self.memory_usage(py)?)
+    }
+
+    /// A new object of the same class, sharing the built automaton rather
+    /// than rebuilding it, since that is immutable. The patterns, IDs, values
+    /// and a subclass instance's __dict__ are copied shallowly, so
+    /// drop_patterns() on the copy doesn't affect the original.
+    fn __copy__(slf: &PyCell<Self>) -> PyResult<&PyCell<Self>> {
+        let copied = new_instance_like(slf, slf.borrow().shallow_copy())?;
+        copy_instance_dict(slf, copied, None)?;
+        Ok(copied)
+    }
+
+    /// Like __copy__(), but IDs, values and a subclass instance's __dict__
+    /// are deep copied. The built automaton is still shared.
+    fn __deepcopy__<'py>(slf: &'py PyCell<Self>, memo: &'py PyAny) -> PyResult<&'py PyCell<Self>> {
+        let py = slf.py();
+        let copied = new_instance_like(slf, slf.borrow().shallow_copy())?;
+        // Register the copy first, in case the values refer back to us:
+        add_to_memo(memo, slf, copied)?;
+        {
+            let self_ = slf.borrow();
+            let ids = deepcopy_objects(py, &self_.ids, memo)?;
+            let values = deepcopy_objects(py, &self_.values, memo)?;
+            let mut copied = copied.borrow_mut();
+            copied.ids = ids;
+            copied.values = values;
+        }
+        copy_instance_dict(slf, copied, Some(memo))?;
+        Ok(copied)
+    }
+
+    /// Support pickling, by reconstructing from the stored patterns (in
+    /// pattern index order) and the constructor arguments; this requires the
+    /// patterns to be stored, compactly or not. The state restores any
+    /// reordering by from_counter() or sort_patterns=True, plus a subclass
+    /// instance's __dict__.
+    ///
+    /// Subclass instances are reconstructed with AhoCorasick.__new__(cls,
+    /// ...), so the subclass's own __new__() and __init__() aren't called
+    /// and can take different arguments.
+    fn __reduce__(slf: &PyCell<Self>) -> PyResult<(PyObject, PyObject, PyObject)> {
+        let py = slf.py();
+        let self_ = slf.borrow();
+        let patterns = self_.patterns.as_ref().ok_or_else(|| {
+            PyTypeError::new_err("Can't pickle an AhoCorasick whose patterns were dropped.")
+        })?;
+        let args = (
+            slf.get_type(),
+            PyList::new(py, patterns.to_vec(py)),
+            self_.matchkind(),
+            self_.ids.as_ref().map(|ids| PyList::new(py, ids)),
+            self_.values.as_ref().map(|values| PyList::new(py, values)),
+            self_.byte_offsets,
+            false,
+            self_.store_patterns(py),
+            py.None(),
+            10_000,
+            self_.ac_impl.kind.name(),
+        );
+        // Only instances of Python subclasses have a __dict__:
+        let dict: Option<PyObject> = slf.getattr("__dict__").ok().map(|dict| dict.into());
+        let state = (dict, self_.sorted_to_original.clone());
+        Ok((
+            py.get_type::<Self>().getattr("__new__")?.into(),
+            args.into_py(py),
+            state.into_py(py),
+        ))
+    }
+
+    /// Restore the state returned by __reduce__().
+    fn __setstate__(
+        slf: &PyCell<Self>,
+        state: (Option<&PyDict>, Option<Vec<usize>>),
+    ) -> PyResult<()> {
+        let (dict, sorted_to_original) = state;
+        if let Some(sorted_to_original) = &sorted_to_original {
+            let pattern_count = slf.borrow().ac_impl.pattern_count();
+            // It has to be a permutation of the pattern indexes:
+            let mut seen = vec![false; pattern_count];
+            if sorted_to_original.len() != pattern_count
+                || sorted_to_original.iter().any(|&index| {
+                    index >= pattern_count || std::mem::replace(&mut seen[index], true)
+                })
+            {
+                return Err(PyValueError::new_err(
+                    "The pickled pattern order doesn't match the patterns.",
+                ));
+            }
+        }
+        slf.borrow_mut().sorted_to_original = sorted_to_original;
+        if let Some(dict) = dict {
+            slf.getattr("__dict__")?.call_method1("update", (dict,))?;
+        }
+        Ok(())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<AhoCorasick with {} patterns, matchkind={}, implementation={}, store_patterns={}>",
+            self.ac_impl.pattern_count(),
+            self.matchkind(),
+            self.implementation(),
+            match self.patterns {
+                Some(StoredPatterns::Objects(_)) => "True",
+                Some(StoredPatterns::Compact { .. }) => "'compact'",
+                None => "False",
+            },
+        )
+    }
+
+    /// AhoCorasick objects are equal if they were built from the same
+    /// patterns (in the same order) with the same options, IDs and values.
+    /// Once its patterns have been dropped, comparisons return
+    /// NotImplemented, so Python falls back to identity.
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyResult<PyObject> {
+        let py = other.py();
+        let other = match other.extract::<PyRef<Self>>() {
+            Ok(other) => other,
+            Err(_) => return Ok(py.NotImplemented()),
+        };
+        let (patterns, other_patterns) = match (&self.patterns, &other.patterns) {
+            (Some(patterns), Some(other_patterns)) => (patterns, other_patterns),
+            _ => return Ok(py.NotImplemented()),
+        };
+        let equal = || -> PyResult<bool> {
+            if patterns.len() != other_patterns.len()
+                || self.ac_impl.match_kind() != other.ac_impl.match_kind()
+                || self.byte_offsets != other.byte_offsets
+                || self.sorted_to_original != other.sorted_to_original
+            {
+                return Ok(false);
+            }
+            if patterns.texts(py)? != other_patterns.texts(py)? {
+                return Ok(false);
+            }
+            Ok(objects_eq(py, &self.ids, &other.ids)?
+                && objects_eq(py, &self.values, &other.values)?)
+        };
+        match op {
+            CompareOp::Eq => Ok(equal()?.into_py(py)),
+            CompareOp::Ne => Ok((!equal()?).into_py(py)),
+            _ => Ok(py.NotImplemented()),
+        }
+    }
+
+    /// A hash of the patterns and match kind, consistent with __eq__(), so
+    /// AhoCorasick objects can be used as cache keys.
+    fn __hash__(&self, py: Python) -> PyResult<u64> {
+        if let Some(hash) = self.hash.get(py) {
+            return Ok(*hash);
+        }
+        let mut hasher = DefaultHasher::new();
+        match &self.patterns {
+            Some(patterns) => {
+                matchkind_name(*self.ac_impl.match_kind()).hash(&mut hasher);
+                for pattern in patterns.texts(py)? {
+                    pattern.hash(&mut hasher);
+                }
+            }
+            // Only equal to itself, see __richcmp__(). drop_patterns() resets
+            // the cached hash, so this is recomputed afterwards:
+            None => (self as *const Self as usize).hash(&mut hasher),
+        }
+        Ok(*self.hash.get_or_init(py, || hasher.finish()))
+    }
+
+    /// The number of patterns the automaton was built with.
+    #[getter]
+    fn patterns_len(&self) -> usize {
+        self.ac_impl.pattern_count()
+    }
+
+    /// How long building the automaton took, in microseconds. This includes
+    /// releasing and reacquiring the GIL, but not converting the patterns
+    /// from Python objects beforehand.
+    #[getter]
+    fn build_time_us(&self) -> u64 {
+        self.ac_impl.build_time.as_micros() as u64
+    }
+
+    /// A summary of how the automaton was built: its implementation, memory
+    /// use, pattern statistics, pattern storage and build time.
+    #[getter]
+    fn build_info(&self, py: Python) -> PyBuildInfo {
+        PyBuildInfo::new(&self.ac_impl, self.store_patterns(py))
+    }
+
+    /// The length of the longest pattern, in bytes.
+    fn max_pattern_length(&self) -> usize {
+        self.ac_impl.max_pattern_len()
+    }
+
+    /// The length of the shortest pattern, in bytes.
+    fn min_pattern_length(&self) -> usize {
+        self.ac_impl.min_pattern_len
+    }
+
+    /// Return the patterns as a trie of nested dicts, keyed by character,
+    /// for inspecting a pattern set. The entry with key "" in a dict marks
+    /// the end of a pattern, and its value is the pattern's index; for
+    /// duplicate patterns, only the first index is included. Requires the
+    /// patterns to be stored.
+    fn to_trie_dict<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let root = PyDict::new(py);
+        let mut buffer = [0; 4];
+        for (index, pattern) in self.get_patterns()?.texts(py)?.into_iter().enumerate() {
+            let mut node = root;
+            for character in pattern.chars() {
+                let key = &*character.encode_utf8(&mut buffer);
+                node = match node.get_item(key) {
+                    Some(child) => child.downcast()?,
+                    None => {
+                        let child = PyDict::new(py);
+                        node.set_item(key, child)?;
+                        child
+                    }
+                };
+            }
+            if !node.contains("")? {
+                node.set_item("", index)?;
+            }
+        }
+        Ok(root)
+    }
+
+    /// Return a regular expression matching any of the patterns, as an
+    /// alternation of escaped patterns, "(?:p1|p2|...)". Longer patterns come
+    /// first, so a regex engine trying alternatives in order behaves roughly
+    /// like MATCHKIND_LEFTMOST_LONGEST. If word_boundaries is true, the
+    /// alternation is wrapped in \b, and if case_insensitive is true, it's
+    /// prefixed with (?i).
+    #[args(word_boundaries = "false", case_insensitive = "false")]
+    fn to_regex_pattern(
+        &self,
+        py: Python,
+        word_boundaries: bool,
+        case_insensitive: bool,
+    ) -> PyResult<String> {
+        let mut patterns = self.get_patterns()?.texts(py)?;
+        if patterns.is_empty() {
+            // An empty alternation would match everywhere, not nowhere:
+            return Err(PyValueError::new_err(
+                "Can't make a regular expression from no patterns.",
+            ));
+        }
+        // Stable sort, so patterns of the same length keep their order:
+        patterns.sort_by_key(|pattern| std::cmp::Reverse(pattern.chars().count()));
+        let mut seen = HashSet::new();
+        patterns.retain(|pattern| seen.insert(*pattern));
+        let alternation = patterns
+            .into_iter()
+            .map(escape_regex)
+            .collect::<Vec<_>>()
+            .join("|");
+        let mut result = String::new();
+        if case_insensitive {
+            result.push_str("(?i)");
+        }
+        if word_boundaries {
+            result.push_str(&format!("\\b(?:{})\\b", alternation));
+        } else {
+            result.push_str(&format!("(?:{})", alternation));
+        }
+        Ok(result)
+    }
+
+    /// Find patterns that non-overlapping searches can never report, because
+    /// wherever they occur another pattern's match is reported instead.
+    /// Returns a list of (shadowed_index, shadowing_index, reason) tuples,
+    /// where reason is "duplicate" for an identical earlier pattern,
+    /// "prefix" for a prefix of it that takes precedence (an earlier one
+    /// with MATCHKIND_LEFTMOST_FIRST, any with MATCHKIND_STANDARD), or
+    /// "substring" for another pattern it contains that MATCHKIND_STANDARD
+    /// finds first. Overlapping searches report all matches, so nothing is
+    /// shadowed for them.
+    fn find_shadowed_patterns(&self, py: Python) -> PyResult<Vec<(usize, usize, &'static str)>> {
+        let patterns = self.get_patterns()?.texts(py)?;
+        let ac_impl = &self.ac_impl;
+        Ok(py.allow_threads(|| {
+            let mut result = vec![];
+            for (index, pattern) in patterns.iter().enumerate() {
+                // Search the pattern itself: it's reported there unless it's
+                // shadowed, and then whatever is reported is what shadows it.
+                let m = match ac_impl.find_iter(pattern.as_bytes()).next() {
+                    Some(m) => m,
+                    None => continue,
+                };
+                if m.pattern() == index && m.start() == 0 && m.end() == pattern.len() {
+                    continue;
+                }
+                let shadowing = patterns[m.pattern()];
+                let reason = if shadowing == *pattern {
+                    "duplicate"
+                } else if m.start() == 0 && pattern.starts_with(shadowing) {
+                    "prefix"
+                } else {
+                    "substring"
+                };
+                result.push((index, m.pattern(), reason));
+            }
+            result
+        }))
+    }
+
+    /// Return the sorted indexes of patterns that non-overlapping searches
+    /// won't report. Without haystack_sample, these are the patterns
+    /// find_shadowed_patterns() finds, which can never be reported under
+    /// this automaton's matchkind. With it, they're the patterns that
+    /// aren't reported when searching haystack_sample, e.g. to check a
+    /// representative document.
+    #[args(haystack_sample = "None")]
+    fn unmatchable_patterns(
+        &self,
+        py: Python,
+        haystack_sample: Option<HaystackArg>,
+    ) -> PyResult<Vec<usize>> {
+        let haystack_sample = match haystack_sample {
+            Some(haystack_sample) => haystack_sample,
+            None => {
+                return Ok(self
+                    .find_shadowed_patterns(py)?
+                    .into_iter()
+                    .map(|(index, _, _)| index)
+                    .collect())
+            }
+        };
+        let matched = get_matched_pattern_ids(py, &self.ac_impl, haystack_sample.as_bytes(), false);
+        Ok((0..self.ac_impl.pattern_count())
+            .filter(|index| !matched.contains(index))
+            .collect())
+    }
+
+    /// The length of each pattern, in pattern index order. Lengths are
+    /// UTF-8 bytes, not code points, since that's what matters for e.g.
+    /// sizing the overlap between chunks of a stream.
+    fn pattern_lengths(&self, py: Python) -> PyResult<Vec<usize>> {
+        Ok(self
+            .get_patterns()?
+            .texts(py)?
+            .iter()
+            .map(|pattern| pattern.len())
+            .collect())
+    }
+
+    /// The longest prefix shared by all the patterns, which is "" if there
+    /// are no patterns or they don't all start the same way. Requires the
+    /// patterns to be stored.
+    fn common_prefix<'a>(&'a self, py: Python<'a>) -> PyResult<&'a str> {
+        let patterns = self.get_patterns()?.texts(py)?;
+        let (first, rest) = match patterns.split_first() {
+            Some(split) => split,
+            None => return Ok(""),
+        };
+        let mut length = first.len();
+        for pattern in rest {
+            length = first[..length]
+                .char_indices()
+                .zip(pattern.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(length.min(pattern.len()), |((index, _), _)| index);
+        }
+        Ok(&first[..length])
+    }
+
+    /// The match kind the automaton was built with, as one of the
+    /// MATCHKIND_* constants.
+    #[getter]
+    fn matchkind(&self) -> &'static str {
+        matchkind_name(*self.ac_impl.match_kind())
+    }
+
+    /// The implementation actually used for non-overlapping searches: "memchr"
+    /// when searching for one to three distinct single bytes, otherwise the
+    /// one passed to the constructor, "dfa" or "noncontiguous". Overlapping
+    /// searches always use the latter.
+    #[getter]
+    fn implementation(&self) -> &'static str {
+        self.ac_impl.implementation()
+    }
+
+    /// How the patterns are stored: "compact" if created with
+    /// store_patterns="compact", otherwise whether they're stored at all,
+    /// which is true unless created with store_patterns=False or
+    /// drop_patterns() has been called.
+    #[getter]
+    fn store_patterns(&self, py: Python) -> PyObject {
+        match self.patterns {
+            Some(StoredPatterns::Objects(_)) => true.into_py(py),
+            Some(StoredPatterns::Compact { .. }) => "compact".into_py(py),
+            None => false.into_py(py),
+        }
+    }
+
+    /// Stop storing the patterns, to free the memory they use. Afterwards,
+    /// find_matches_as_strings() and similar methods return the matched
+    /// parts of the haystack instead, and methods that need the patterns
+    /// themselves raise a RuntimeError. Calling it again does nothing.
+    ///
+    /// Since equality and hashing are based on the patterns, the object is
+    /// afterwards only equal to itself and its hash changes, so don't call
+    /// this on an object that is already in a set or used as a dict key.
+    ///
+    /// Automatons returned by get_or_build() are shared, so this raises a
+    /// TypeError for them; drop the patterns of a copy.copy() instead.
+    fn drop_patterns(&mut self) -> PyResult<()> {
+        if self.cached {
+            return Err(PyTypeError::new_err(
+                "Can't drop the patterns of an automaton shared by get_or_build(); use copy.copy() to get one of your own.",
+            ));
+        }
+        self.patterns = None;
+        self.pattern_indexes = GILOnceCell::new();
+        self.hash = GILOnceCell::new();
+        Ok(())
+    }
+
+    /// The indexes of the patterns passed to the constructor that were
+    /// skipped because of on_invalid="skip"; empty otherwise.
+    #[getter]
+    fn skipped_patterns(&self) -> Vec<usize> {
+        self.skipped_patterns.clone()
+    }
+
+    /// A new list of the patterns, in pattern index order.
+    #[getter]
+    fn patterns(&self, py: Python) -> PyResult<Vec<Py<PyUnicode>>> {
+        Ok(self.get_patterns()?.to_vec(py))
+    }
+
+    /// Whether the given string is one of the patterns.
+    fn __contains__(&self, py: Python, pattern: &str) -> PyResult<bool> {
+        Ok(self.get_pattern_indexes(py)?.contains_key(pattern))
+    }
+
+    /// Return the index of the (first) pattern equal to the given string, or
+    /// raise a ValueError if there isn't one, like list.index().
+    fn index(&self, py: Python, pattern: &str) -> PyResult<usize> {
+        self.get_pattern_indexes(py)?
+            .get(pattern)
+            .copied()
+            .ok_or_else(|| {
+                PyValueError::new_err(format!(
+                    "{:?} is not a pattern.",
+                    PyUnicode::new(py, pattern)
+                ))
+            })
+    }
+
+    /// Iterate over a snapshot of the patterns, in pattern index order. Like
+    /// BytesAhoCorasick, the object isn't iterable if it doesn't store its
+    /// patterns.
+    fn __iter__(&self, py: Python) -> PyResult<PyObject> {
+        let patterns = self.patterns.as_ref().ok_or_else(|| {
+            PyTypeError::new_err(
+                "AhoCorasick is only iterable if created with store_patterns=True (or \"compact\") and drop_patterns() wasn't called.",
+            )
+        })?;
+        Ok(PyIterator::from_object(py, PyList::new(py, patterns.to_vec(py)))?.into())
+    }
+
+    /// Return the pattern with the given index.
+    fn get_pattern(&self, py: Python, index: usize) -> PyResult<Py<PyUnicode>> {
+        self.check_pattern_index(index)?;
+        Ok(self.get_patterns()?.get(py, index))
+    }
+
+    /// Return a CachingAhoCorasick wrapping this object, which remembers the
+    /// results of the last maxsize calls to find_matches_as_indexes(). A
+    /// maxsize of 0 disables caching.
+    #[args(maxsize = "128")]
+    fn with_cache(self_: PyRef<Self>, maxsize: usize) -> PyCachingAhoCorasick {
+        PyCachingAhoCorasick {
+            ac: self_.into(),
+            maxsize,
+            cache: Mutex::new(MatchCache {
+                results: if maxsize == 0 {
+                    None
+                } else {
+                    Some(LruCache::new(maxsize))
+                },
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// Return matches as tuple of (index_into_patterns,
+    /// start_index_in_haystack, end_index_in_haystack). If sort is true
+    /// (the default), matches are sorted by (start, end, index_into_patterns).
+    /// If both_offsets is true, the tuples also include the start and end
+    /// UTF-8 byte offsets, i.e. (index_into_patterns, start_index, end_index,
+    /// start_byte, end_byte). If unicode_words is true, start_index and
+    /// end_index are indexes into the haystack's Unicode words (UAX #29)
+    /// instead of code points; see get_span_offsets() for details. If
+    /// utf16_offsets is true, they're UTF-16 code unit offsets instead. If
+    /// grapheme_offsets is true, they're indexes into the haystack's
+    /// extended grapheme clusters, with matches that start or end inside a
+    /// cluster widened to include it. If
+    /// you know roughly how many matches to expect, passing it as size_hint
+    /// preallocates space for them. If search_limit is given, only the first
+    /// search_limit code points are searched, without copying them; matches
+    /// straddling the limit aren't returned. If extra_fields, a dict mapping
+    /// pattern indexes to arbitrary metadata, is given, each tuple ends with
+    /// the metadata for its pattern, or None if there's none. If filter_fn
+    /// is given, it's called with (index_into_patterns, start_index,
+    /// end_index) for each match, and only matches for which it returns true
+    /// are returned; if fill_gaps is also true, rejected matches are
+    /// replaced with None instead, so the result has an entry for every
+    /// match. If positions_only is true, a list of just the start_index of
+    /// each match is returned instead of tuples. If include_pattern_text is
+    /// true, each tuple ends with the matched pattern.
+    ///
+    /// Matches shorter than min_match_length code points, or longer than
+    /// max_match_length if it's given, are skipped before anything else,
+    /// including filter_fn, sees them.
+    ///
+    /// order controls how nested and other overlapping matches are sorted
+    /// instead: "start" is the default order, so at the same start inner
+    /// matches come first; "end" sorts by (end, start, index_into_patterns),
+    /// so at the same end outer matches come first; "length" puts the
+    /// longest matches (in code points) first, then sorts by start.
+    ///
+    /// If normalize_haystack is "NFC", "NFD", "NFKC" or "NFKD", the haystack
+    /// is converted to that Unicode normalization form before searching, and
+    /// all offsets are into the normalized haystack. If offset_map is also
+    /// true, a tuple of the results and a list mapping each code point
+    /// offset in the normalized haystack to one in the original is returned.
+    ///
+    /// If deduplicate_spans is true, when several patterns match the same
+    /// span, e.g. with overlapping=True, only the match with the lowest
+    /// index_into_patterns is returned.
+    #[args(
+        overlapping = "false",
+        sort = "true",
+        both_offsets = "false",
+        unicode_words = "false",
+        utf16_offsets = "false",
+        size_hint = "0",
+        search_limit = "None",
+        extra_fields = "None",
+        filter_fn = "None",
+        positions_only = "false",
+        include_pattern_text = "false",
+        fill_gaps = "false",
+        min_match_length = "0",
+        max_match_length = "None",
+        order = "None",
+        deduplicate_spans = "false",
+        normalize_haystack = "None",
+        offset_map = "false",
+        grapheme_offsets = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn find_matches_as_indexes(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+        sort: bool,
+        both_offsets: bool,
+        unicode_words: bool,
+        utf16_offsets: bool,
+        size_hint: usize,
+        search_limit: Option<usize>,
+        extra_fields: Option<&PyDict>,
+        filter_fn: Option<&PyAny>,
+        positions_only: bool,
+        include_pattern_text: bool,
+        fill_gaps: bool,
+        min_match_length: usize,
+        max_match_length: Option<usize>,
+        order: Option<&str>,
+        deduplicate_spans: bool,
+        normalize_haystack: Option<&str>,
+        offset_map: bool,
+        grapheme_offsets: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let order = parse_match_order(order, sort)?;
+        let normalization = normalize_haystack
+            .map(parse_normalization_form)
+            .transpose()?;
+        if offset_map && normalization.is_none() {
+            return Err(PyValueError::new_err(
+                "offset_map can only be used with normalize_haystack.",
+            ));
+        }
+        if offset_map && (unicode_words || utf16_offsets || grapheme_offsets) {
+            return Err(PyValueError::new_err(
+                "offset_map can't be combined with unicode_words, utf16_offsets or grapheme_offsets.",
+            ));
+        }
+        if [unicode_words, utf16_offsets, grapheme_offsets]
+            .iter()
+            .filter(|&&flag| flag)
+            .count()
+            > 1
+        {
+            return Err(PyValueError::new_err(
+                "Only one of unicode_words, utf16_offsets and grapheme_offsets can be true.",
+            ));
+        }
+        if positions_only && (both_offsets || extra_fields.is_some()) {
+            return Err(PyValueError::new_err(
+                "positions_only can't be combined with both_offsets or extra_fields.",
+            ));
+        }
+        if include_pattern_text && (positions_only || both_offsets || extra_fields.is_some()) {
+            return Err(PyValueError::new_err(
+                "include_pattern_text can't be combined with positions_only, both_offsets or extra_fields.",
+            ));
+        }
+        let extra_fields = get_extra_fields(extra_fields, self_.ac_impl.pattern_count())?;
+        let py = self_.py();
+        let normalized = match normalization {
+            Some(form) => {
+                let text = haystack.as_str()?;
+                Some(py.allow_threads(|| normalize(text, form, offset_map)))
+            }
+            None => None,
+        };
+        let mut haystack = match &normalized {
+            Some((text, _)) => HaystackArg::Str(text),
+            None => haystack,
+        };
+        let normalized_offsets = normalized
+            .as_ref()
+            .and_then(|(_, offsets)| offsets.as_ref());
+        let with_offset_map = |results: PyObject| match normalized_offsets {
+            Some(offsets) => (results, offsets.clone()).into_py(py),
+            None => results,
+        };
+        // Segmentation depends on context, so it's done over the whole
+        // haystack, before search_limit truncates it to the part searched:
+        let word_spans = if unicode_words {
+            let text = haystack.as_str()?;
+            Some(py.allow_threads(|| get_word_spans(text)))
+        } else {
+            None
+        };
+        let grapheme_spans = if grapheme_offsets {
+            let text = haystack.as_str()?;
+            Some(py.allow_threads(|| get_grapheme_spans(text)))
+        } else {
+            None
+        };
+        let end = haystack.limit(search_limit);
+        let mut matches = get_matches_with_capacity(
+            py,
+            &self_.ac_impl,
+            &haystack.as_bytes()[..end],
+            overlapping,
+            sort && order.is_none(),
+            size_hint,
+        );
+        let length = |m: &PatternMatch| code_point_len(&haystack.as_bytes()[m.start()..m.end()]);
+        if let Some(order) = order {
+            sort_matches(&mut matches, order, length);
+        }
+        let matches = filter_match_lengths(matches, min_match_length, max_match_length, length);
+        let matches = if deduplicate_spans {
+            deduplicate_matches(matches)
+        } else {
+            matches
+        };
+        // The common case only needs start offsets, so skip mapping the ends:
+        if positions_only
+            && filter_fn.is_none()
+            && !unicode_words
+            && !utf16_offsets
+            && !grapheme_offsets
+        {
+            let byte_to_code_point = self_.byte_to_code_point(&haystack);
+            return Ok(with_offset_map(
+                matches
+                    .iter()
+                    .map(|m| byte_to_code_point.get(m.start()))
+                    .collect::<Vec<_>>()
+                    .into_py(py),
+            ));
+        }
+        let offsets: Vec<(usize, usize)> = if let Some(word_spans) = &word_spans {
+            matches
+                .iter()
+                .map(|m| get_span_offsets(word_spans, m.start(), m.end()))
+                .collect()
+        } else if let Some(grapheme_spans) = &grapheme_spans {
+            matches
+                .iter()
+                .map(|m| get_span_offsets(grapheme_spans, m.start(), m.end()))
+                .collect()
+        } else if utf16_offsets {
+            let text = &haystack.as_str()?[..end];
+            let byte_to_utf16 = py.allow_threads(|| get_byte_to_utf16(text));
+            matches
+                .iter()
+                .map(|m| (byte_to_utf16[m.start()], byte_to_utf16[m.end()]))
+                .collect()
+        } else {
+            let byte_to_code_point = self_.byte_to_code_point(&haystack);
+            matches
+                .iter()
+                .map(|m| {
+                    (
+                        byte_to_code_point.get(m.start()),
+                        byte_to_code_point.get(m.end()),
+                    )
+                })
+                .collect()
+        };
+        let mut mask = None;
+        let (matches, offsets): (Vec<_>, Vec<_>) = match filter_fn {
+            Some(filter_fn) if fill_gaps => {
+                mask = Some(filter_mask(
+                    filter_fn,
+                    &matches.iter().zip(offsets.iter()).collect::<Vec<_>>(),
+                    |(m, (start, end))| (m.pattern(), *start, *end),
+                )?);
+                (matches, offsets)
+            }
+            Some(filter_fn) => filter_matches(
+                filter_fn,
+                matches.into_iter().zip(offsets).collect(),
+                |(m, (start, end))| (m.pattern(), *start, *end),
+            )?
+            .into_iter()
+            .unzip(),
+            None => (matches, offsets),
+        };
+        let results = matches.iter().zip(offsets);
+        let results = if positions_only {
+            results
+                .map(|(_, (start, _))| start)
+                .collect::<Vec<_>>()
+                .into_py(py)
+        } else if include_pattern_text {
+            results
+                .map(|(m, (start, end))| {
+                    let text = self_.matched_text(py, haystack.as_bytes(), m);
+                    (m.pattern(), start, end, text)
+                })
+                .collect::<Vec<_>>()
+                .into_py(py)
+        } else if let Some(extra_fields) = extra_fields {
+            let extra = |m: &PatternMatch| extra_fields.get(&m.pattern()).copied();
+            if both_offsets {
+                results
+                    .map(|(m, (start, end))| {
+                        (m.pattern(), start, end, m.start(), m.end(), extra(m))
+                    })
+                    .collect::<Vec<_>>()
+                    .into_py(py)
+            } else {
+                results
+                    .map(|(m, (start, end))| (m.pattern(), start, end, extra(m)))
+                    .collect::<Vec<_>>()
+                    .into_py(py)
+            }
+        } else if both_offsets {
+            results
+                .map(|(m, (start, end))| (m.pattern(), start, end, m.start(), m.end()))
+                .collect::<Vec<_>>()
+                .into_py(py)
+        } else {
+            results
+                .map(|(m, (start, end))| (m.pattern(), start, end))
+                .collect::<Vec<_>>()
+                .into_py(py)
+        };
+        Ok(with_offset_map(fill_filtered_gaps(py, results, mask)?))
+    }
+
+    /// Return matches of just the patterns whose indexes are in pattern_ids
+    /// (e.g. a set or list), as (index_into_patterns, start_index, end_index)
+    /// tuples like find_matches_as_indexes(). Other matches are skipped
+    /// before any Python objects are created for them.
+    #[args(overlapping = "false")]
+    fn find_matches_filtered(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        pattern_ids: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let mask = get_pattern_ids_mask(pattern_ids, self_.ac_impl.pattern_count())?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let py = self_.py();
+        let matches = get_matches(py, &self_.ac_impl, haystack.as_bytes(), overlapping, true);
+        Ok(matches
+            .into_iter()
+            .filter(|m| mask[m.pattern()])
+            .map(|m| {
+                (
+                    m.pattern(),
+                    byte_to_code_point.get(m.start()),
+                    byte_to_code_point.get(m.end()),
+                )
+            })
+            .collect())
+    }
+
+    /// Return the matches for which predicate(index_into_patterns,
+    /// start_index, end_index) returns true, as tuples like
+    /// find_matches_as_indexes(). Unlike its filter_fn, a rejected match
+    /// doesn't hide an accepted one it overlaps: the search carries on as if
+    /// the rejected match wasn't there. The GIL is held while searching,
+    /// since predicate is called as matches are found.
+    #[args(overlapping = "false")]
+    fn find_matches_filtered_by(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        predicate: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        find_matches_accepted_by(
+            &self_.ac_impl,
+            haystack.as_bytes(),
+            overlapping,
+            predicate,
+            |offset| byte_to_code_point.get(offset),
+        )
+    }
+
+    /// Call callback(index_into_patterns, start_index, end_index) for each
+    /// match as it's found, with offsets like find_matches_as_indexes(), and
+    /// return None. No list of results is built, so memory use doesn't grow
+    /// with the number of matches. Matches are passed in the order they're
+    /// found, which with overlapping=True is by end_index. The search runs
+    /// with the GIL released, pausing to call callback. To stop early, raise
+    /// StopIteration from callback; for_each_match() then returns normally.
+    /// Other exceptions propagate.
+    #[args(overlapping = "false")]
+    fn for_each_match(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        callback: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<()> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        for_each_match(
+            self_.py(),
+            &self_.ac_impl,
+            haystack.as_bytes(),
+            overlapping,
+            callback,
+            |offset| byte_to_code_point.get(offset),
+        )
+    }
+
+    /// Append (index_into_patterns, start_index, end_index) tuples for the
+    /// matches to out, an existing list, like find_matches_as_indexes()
+    /// with sorting, and return how many were appended. Reusing the same
+    /// list across calls saves allocating a new one each time.
+    #[args(overlapping = "false")]
+    fn find_matches_into(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        out: &PyList,
+        overlapping: bool,
+    ) -> PyResult<usize> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let matches = get_matches(py, &self_.ac_impl, haystack.as_bytes(), overlapping, true);
+        for m in matches.iter() {
+            out.append((
+                m.pattern(),
+                byte_to_code_point.get(m.start()),
+                byte_to_code_point.get(m.end()),
+            ))?;
+        }
+        Ok(matches.len())
+    }
+
+    /// Write the (index_into_patterns, start_index, end_index) rows for the
+    /// matches into out, a writable NumPy array (or other buffer) with shape
+    /// (N, 3) and dtype uint64, starting at row offset, and return how many
+    /// rows were written. Offsets are the same as find_matches_as_indexes()'s.
+    /// Matches are written as they're found, without collecting them first;
+    /// once the array is full the search stops, and any further matches are
+    /// discarded.
+    #[args(offset = "0")]
+    fn find_matches_into_numpy(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        out: &PyAny,
+        offset: usize,
+    ) -> PyResult<usize> {
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let matches = self_.ac_impl.find_iter(haystack.as_bytes()).map(|m| {
+            (
+                m.pattern(),
+                byte_to_code_point.get(m.start()),
+                byte_to_code_point.get(m.end()),
+            )
+        });
+        write_matches_to_u64_array(out, offset, matches)
+    }
+
+    /// Return the matches as a JSON array of objects with "pattern" (the
+    /// index into patterns), "start" and "end" keys, plus "text", the
+    /// matched pattern, if patterns are stored. Offsets are the same as
+    /// find_matches_as_indexes()'s, i.e. code points unless
+    /// force_byte_offsets_on_ascii applies. No Python objects are created
+    /// for individual matches.
+    #[args(overlapping = "false")]
+    fn find_matches_as_json(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<String> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let matches = get_matches(py, &self_.ac_impl, haystack.as_bytes(), overlapping, true);
+        let texts = match &self_.patterns {
+            Some(patterns) => Some(patterns.texts(py)?),
+            None => None,
+        };
+        let json: Vec<serde_json::Value> = matches
+            .iter()
+            .map(|m| {
+                let mut object = serde_json::json!({
+                    "pattern": m.pattern(),
+                    "start": byte_to_code_point.get(m.start()),
+                    "end": byte_to_code_point.get(m.end()),
+                });
+                if let Some(texts) = &texts {
+                    object["text"] = texts[m.pattern()].into();
+                }
+                object
+            })
+            .collect();
+        Ok(serde_json::Value::Array(json).to_string())
+    }
+
+    /// Like find_matches_as_indexes(), but with the user-provided ID of each
+    /// pattern (see get_id()) instead of its index.
+    #[args(overlapping = "false")]
+    fn find_matches_as_ids(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<Vec<(PyObject, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let py = self_.py();
+        let matches = get_matches(py, &self_.ac_impl, haystack, overlapping, true);
+        matches
+            .into_iter()
+            .map(|m| {
+                Ok((
+                    get_id(py, &self_.ids, self_.ac_impl.pattern_count(), m.pattern())?,
+                    byte_to_code_point.get(m.start()),
+                    byte_to_code_point.get(m.end()),
+                ))
+            })
+            .collect()
+    }
+
+    /// Return the ID passed to the constructor for the given pattern index,
+    /// or the index itself if no IDs were passed.
+    fn get_id(&self, py: Python, pattern_index: usize) -> PyResult<PyObject> {
+        get_id(py, &self.ids, self.ac_impl.pattern_count(), pattern_index)
+    }
+
+    /// Like find_matches_as_indexes(), but with the value passed to the
+    /// constructor for each pattern (see get_value()) instead of its index.
+    #[args(overlapping = "false")]
+    fn find_matches_as_values(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<Vec<(PyObject, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let values = self_.get_values()?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let py = self_.py();
+        let matches = get_matches(py, &self_.ac_impl, haystack, overlapping, true);
+        Ok(matches
+            .into_iter()
+            .map(|m| {
+                (
+                    values[m.pattern()].clone_ref(py),
+                    byte_to_code_point.get(m.start()),
+                    byte_to_code_point.get(m.end()),
+                )
+            })
+            .collect())
+    }
+
+    /// Return the value passed to the constructor for the given pattern
+    /// index.
+    fn get_value(&self, py: Python, pattern_index: usize) -> PyResult<PyObject> {
+        self.check_pattern_index(pattern_index)?;
+        Ok(self.get_values()?[pattern_index].clone_ref(py))
+    }
+
+    /// Return a list of (pattern, value) tuples, in pattern index order.
+    fn items(&self, py: Python) -> PyResult<Vec<(Py<PyUnicode>, PyObject)>> {
+        Ok(self
+            .get_patterns()?
+            .to_vec(py)
+            .into_iter()
+            .zip(self.get_values()?)
+            .map(|(pattern, value)| (pattern, value.clone_ref(py)))
+            .collect())
+    }
+
+    /// Return matches as a polars.Series of structs with fields "pattern",
+    /// "start" and "end", sorted like find_matches_as_indexes(). Requires
+    /// polars to be installed.
+    #[args(overlapping = "false")]
+    fn find_matches_as_polars(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let py = self_.py();
+        let matches = get_matches(py, &self_.ac_impl, haystack, overlapping, true);
+        matches_to_polars(
+            py,
+            matches
+                .into_iter()
+                .map(|m| {
+                    (
+                        m.pattern(),
+                        byte_to_code_point.get(m.start()),
+                        byte_to_code_point.get(m.end()),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Search an iterable of bytes-like chunks of UTF-8 encoded text as if
+    /// they were a single haystack, pulling them in one at a time, e.g. as
+    /// they arrive over the network. Return matches as tuple of
+    /// (index_into_patterns, start_index, end_index), where the indexes are
+    /// code point offsets from the start of the first chunk, like
+    /// find_matches_as_indexes() returns; a character may be split across
+    /// chunks. Only supported with MATCHKIND_STANDARD, and doesn't support
+    /// overlapping matches.
+    fn find_matches_streaming_bytes(
+        self_: PyRef<Self>,
+        chunks: &PyAny,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        if !self_.ac_impl.supports_stream() {
+            return Err(PyValueError::new_err(
+                "Streaming search is only supported with MATCHKIND_STANDARD.",
+            ));
+        }
+        let mut reader = ChunkReader::new(chunks.iter()?);
+        let mut result = vec![];
+        // Every byte of the stream passes through the writer in order, the
+        // matches' via the closure, so it keeps a running code point count:
+        let searched = self_.ac_impl.stream_replace_all_with(
+            &mut reader,
+            CodePointCounter::default(),
+            |m, bytes, counter| {
+                if self_.byte_offsets {
+                    result.push((m.pattern(), m.start(), m.end()));
+                    return Ok(());
+                }
+                let start = counter.code_points;
+                counter.write_all(bytes)?;
+                result.push((m.pattern(), start, counter.code_points));
+                Ok(())
+            },
+        );
+        if let Err(e) = searched {
+            return Err(reader
+                .error
+                .take()
+                .unwrap_or_else(|| PyIOError::new_err(e.to_string())));
+        }
+        Ok(result)
+    }
+
+    /// Search the contents of an io.StringIO (or any object with a
+    /// getvalue() method returning a str), returning matches like
+    /// find_matches_as_indexes(). CPython doesn't expose StringIO's internal
+    /// buffer, neither via the buffer protocol nor the C API, so this calls
+    /// getvalue() once; it just saves doing that in Python.
+    #[args(overlapping = "false")]
+    fn find_matches_from_stringio(
+        self_: PyRef<Self>,
+        sio: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let haystack = HaystackArg::Str(sio.call_method0("getvalue")?.extract()?);
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let matches = get_matches(py, &self_.ac_impl, haystack.as_bytes(), overlapping, true);
+        Ok(matches
+            .into_iter()
+            .map(|m| {
+                (
+                    m.pattern(),
+                    byte_to_code_point.get(m.start()),
+                    byte_to_code_point.get(m.end()),
+                )
+            })
+            .collect())
+    }
+
+    /// Search each of a sequence of haystacks, returning a list with the
+    /// result of find_matches_as_indexes() (with the default options) for
+    /// each. The haystacks are all converted first, then searched with the
+    /// GIL released, which saves per-call overhead with many small
+    /// haystacks. If first_only is true, each search stops at the first
+    /// match, and the list has that match's tuple, or None if there was no
+    /// match, for each haystack.
+    #[args(overlapping = "false", first_only = "false")]
+    fn find_matches_in_many(
+        self_: PyRef<Self>,
+        haystacks: Vec<HaystackArg>,
+        overlapping: bool,
+        first_only: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        check_first_only(first_only, overlapping)?;
+        let py = self_.py();
+        let texts: Vec<&[u8]> = haystacks.iter().map(HaystackArg::as_bytes).collect();
+        let ac_impl = &self_.ac_impl;
+        let byte_offsets = self_.byte_offsets;
+        if first_only {
+            let matches: Vec<Option<(usize, usize, usize)>> = py.allow_threads(|| {
+                texts
+                    .iter()
+                    .map(|text| {
+                        // Only the one match's offsets are needed, so count
+                        // code points rather than mapping the whole text:
+                        let m = ac_impl.find_iter(text).next()?;
+                        if byte_offsets {
+                            return Some((m.pattern(), m.start(), m.end()));
+                        }
+                        let start = code_point_len(&text[..m.start()]);
+                        let end = start + code_point_len(&text[m.start()..m.end()]);
+                        Some((m.pattern(), start, end))
+                    })
+                    .collect()
+            });
+            return Ok(matches.into_py(py));
+        }
+        let matches: Vec<Vec<(usize, usize, usize)>> = py.allow_threads(|| {
+            texts
+                .iter()
+                .map(|text| {
+                    let byte_to_code_point = if byte_offsets {
+                        ByteToCodePoint::Identity
+                    } else {
+                        get_byte_to_code_point(text)
+                    };
+                    find_matches(ac_impl, text, overlapping, true, 0)
+                        .into_iter()
+                        .map(|m| {
+                            (
+                                m.pattern(),
+                                byte_to_code_point.get(m.start()),
+                                byte_to_code_point.get(m.end()),
+                            )
+                        })
+                        .collect()
+                })
+                .collect()
+        });
+        Ok(matches.into_py(py))
+    }
+
+    /// Return a list with whether any pattern matches each of a sequence of
+    /// haystacks. Like find_matches_in_many(), the haystacks are searched
+    /// with the GIL released, but each search stops at the first match and
+    /// no offsets are computed.
+    fn is_match_many(self_: PyRef<Self>, haystacks: Vec<HaystackArg>) -> Vec<bool> {
+        let texts: Vec<&[u8]> = haystacks.iter().map(HaystackArg::as_bytes).collect();
+        let ac_impl = &self_.ac_impl;
+        self_
+            .py()
+            .allow_threads(|| texts.iter().map(|text| ac_impl.is_match(text)).collect())
+    }
+
+    /// Search a corpus of haystacks in one pass, returning a dict mapping
+    /// the index of each pattern that matched to a list of its
+    /// (doc_index, start_index, end_index) occurrences, where doc_index is
+    /// the haystack's index in the corpus and offsets are as returned by
+    /// find_matches_as_indexes(). If positions is false, each list holds
+    /// just the indexes of the haystacks the pattern matched, once each.
+    /// Lists are in document order, and the corpus is searched with the GIL
+    /// released.
+    #[args(overlapping = "false", positions = "true")]
+    fn build_inverted_index(
+        self_: PyRef<Self>,
+        haystacks: Vec<HaystackArg>,
+        overlapping: bool,
+        positions: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let texts: Vec<&[u8]> = haystacks.iter().map(HaystackArg::as_bytes).collect();
+        let ac_impl = &self_.ac_impl;
+        let byte_offsets = self_.byte_offsets;
+        let pattern_count = ac_impl.pattern_count();
+        let result = PyDict::new(py);
+        if positions {
+            let occurrences: Vec<Vec<(usize, usize, usize)>> = py.allow_threads(|| {
+                let mut occurrences = vec![vec![]; pattern_count];
+                for (doc_index, text) in texts.iter().enumerate() {
+                    let byte_to_code_point = if byte_offsets {
+                        ByteToCodePoint::Identity
+                    } else {
+                        get_byte_to_code_point(text)
+                    };
+                    for m in find_matches(ac_impl, text, overlapping, true, 0) {
+                        occurrences[m.pattern()].push((
+                            doc_index,
+                            byte_to_code_point.get(m.start()),
+                            byte_to_code_point.get(m.end()),
+                        ));
+                    }
+                }
+                occurrences
+            });
+            for (pattern, occurrences) in occurrences.into_iter().enumerate() {
+                if !occurrences.is_empty() {
+                    result.set_item(pattern, occurrences)?;
+                }
+            }
+        } else {
+            let documents: Vec<Vec<usize>> = py.allow_threads(|| {
+                let mut documents: Vec<Vec<usize>> = vec![vec![]; pattern_count];
+                for (doc_index, text) in texts.iter().enumerate() {
+                    for m in find_matches(ac_impl, text, overlapping, false, 0) {
+                        let documents = &mut documents[m.pattern()];
+                        if documents.last() != Some(&doc_index) {
+                            documents.push(doc_index);
+                        }
+                    }
+                }
+                documents
+            });
+            for (pattern, documents) in documents.into_iter().enumerate() {
+                if !documents.is_empty() {
+                    result.set_item(pattern, documents)?;
+                }
+            }
+        }
+        Ok(result.into())
+    }
+
+    /// Return a list of the haystacks from an iterable that any pattern
+    /// matches, or if invert is true, that none do. The original objects
+    /// are returned, in order. Haystacks are converted in chunks, and each
+    /// chunk is searched with the GIL released, stopping at the first match
+    /// in each haystack.
+    #[args(invert = "false")]
+    fn filter(self_: PyRef<Self>, haystacks: &PyAny, invert: bool) -> PyResult<Vec<PyObject>> {
+        let py = self_.py();
+        let ac_impl = &self_.ac_impl;
+        let mut result = vec![];
+        let mut haystacks = haystacks.iter()?.peekable();
+        while haystacks.peek().is_some() {
+            let chunk = haystacks
+                .by_ref()
+                .take(FILTER_CHUNK_SIZE)
+                .map(|haystack| {
+                    let haystack = haystack?;
+                    Ok((haystack, haystack.extract::<HaystackArg>()?))
+                })
+                .collect::<PyResult<Vec<_>>>()?;
+            let texts: Vec<&[u8]> = chunk.iter().map(|(_, arg)| arg.as_bytes()).collect();
+            let matched: Vec<bool> =
+                py.allow_threads(|| texts.iter().map(|text| ac_impl.is_match(text)).collect());
+            result.extend(
+                chunk
+                    .iter()
+                    .zip(matched)
+                    .filter(|(_, matched)| *matched != invert)
+                    .map(|((haystack, _), _)| haystack.into_py(py)),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Return a list with the number of matches in each of a sequence of
+    /// haystacks. The haystacks are searched with the GIL released, counting
+    /// matches without collecting them. If per_pattern is true, each entry
+    /// is instead a dict mapping the index of each matching pattern to its
+    /// number of matches.
+    #[args(overlapping = "false", per_pattern = "false")]
+    fn count_matches_many(
+        self_: PyRef<Self>,
+        haystacks: Vec<HaystackArg>,
+        overlapping: bool,
+        per_pattern: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let texts: Vec<&[u8]> = haystacks.iter().map(HaystackArg::as_bytes).collect();
+        let ac_impl = &self_.ac_impl;
+        let search = |text| {
+            if overlapping {
+                ac_impl.find_overlapping_iter(text)
+            } else {
+                ac_impl.find_iter(text)
+            }
+        };
+        if !per_pattern {
+            let counts: Vec<usize> =
+                py.allow_threads(|| texts.iter().map(|text| search(text).count()).collect());
+            return Ok(counts.into_py(py));
+        }
+        let counts: Vec<HashMap<usize, usize>> = py.allow_threads(|| {
+            texts
+                .iter()
+                .map(|text| {
+                    let mut counts = HashMap::new();
+                    for m in search(text) {
+                        *counts.entry(m.pattern()).or_insert(0) += 1;
+                    }
+                    counts
+                })
+                .collect()
+        });
+        Ok(counts.into_py(py))
+    }
+
+    /// Search each of an iterable of lines (e.g. an open text file),
+    /// pulling them in one at a time. Return matches as tuple of
+    /// (line_index, index_into_patterns, start_index, end_index), where the
+    /// start and end are code point offsets within the line. Matches are
+    /// sorted by line, then as in find_matches_as_indexes().
+    #[args(overlapping = "false")]
+    fn find_matches_in_lines(
+        self_: PyRef<Self>,
+        lines: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let mut result = vec![];
+        for (line_index, line) in lines.iter()?.enumerate() {
+            let line = HaystackArg::Str(line?.extract()?);
+            let byte_to_code_point = self_.byte_to_code_point(&line);
+            let line = line.as_bytes();
+            for m in get_matches(py, &self_.ac_impl, line, overlapping, true) {
+                result.push((
+                    line_index,
+                    m.pattern(),
+                    byte_to_code_point.get(m.start()),
+                    byte_to_code_point.get(m.end()),
+                ));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Return the match closest to the given code point offset, e.g. an
+    /// editor's cursor position, as a tuple of (index_into_patterns,
+    /// start_index, end_index), or None if there are no matches. A match
+    /// contains the offset if start <= offset <= end. Ties are broken by
+    /// preferring matches containing the offset, then the smallest distance
+    /// to the offset, then the earliest match as sorted by
+    /// find_matches_as_indexes().
+    #[args(overlapping = "false")]
+    fn find_nearest_match(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        offset: usize,
+        overlapping: bool,
+    ) -> PyResult<Option<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let py = self_.py();
+        let matches = get_matches(py, &self_.ac_impl, haystack, overlapping, false);
+        Ok(py.allow_threads(|| {
+            matches
+                .iter()
+                .map(|m| {
+                    let start = byte_to_code_point.get(m.start());
+                    let end = byte_to_code_point.get(m.end());
+                    let distance = if offset < start {
+                        start - offset
+                    } else {
+                        offset.saturating_sub(end)
+                    };
+                    (distance, start, end, m.pattern())
+                })
+                .min()
+                .map(|(_, start, end, pattern)| (pattern, start, end))
+        }))
+    }
+
+    /// Return a dict mapping pattern indexes to lists of (start, end) code
+    /// point offsets of their matches, in order. Patterns without matches
+    /// are left out. If pattern_keys is true, the keys are the patterns
+    /// instead, so the spans of duplicate patterns are combined.
+    #[args(overlapping = "false", pattern_keys = "false")]
+    fn find_matches_grouped_by_pattern(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+        pattern_keys: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let matches = get_matches(py, &self_.ac_impl, haystack, overlapping, true);
+        let spans = |m: &PatternMatch| {
+            (
+                byte_to_code_point.get(m.start()),
+                byte_to_code_point.get(m.end()),
+            )
+        };
+        let result = PyDict::new(py);
+        if pattern_keys {
+            let groups = group_spans(matches.iter().map(|m| {
+                let (start, end) = spans(m);
+                (&haystack[m.start()..m.end()], start, end)
+            }));
+            for (pattern, spans) in groups {
+                result.set_item(std::str::from_utf8(pattern)?, spans)?;
+            }
+        } else {
+            let groups = group_spans(matches.iter().map(|m| {
+                let (start, end) = spans(m);
+                (m.pattern(), start, end)
+            }));
+            for (pattern_index, spans) in groups {
+                result.set_item(pattern_index, spans)?;
+            }
+        }
+        Ok(result.into())
+    }
+
+    /// Return a dict mapping pattern indexes to the (start, end) code point
+    /// offsets of their first match, in the order they were found. Patterns
+    /// without matches are left out. If pattern_keys is true, the keys are
+    /// the patterns instead, so duplicate patterns share an entry. The search
+    /// stops as soon as every pattern has been found.
+    #[args(overlapping = "false", pattern_keys = "false")]
+    fn first_match_per_pattern(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+        pattern_keys: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let result = PyDict::new(py);
+        for m in get_first_matches(py, &self_.ac_impl, haystack, overlapping) {
+            let span = (
+                byte_to_code_point.get(m.start()),
+                byte_to_code_point.get(m.end()),
+            );
+            if pattern_keys {
+                let pattern = std::str::from_utf8(&haystack[m.start()..m.end()])?;
+                if !result.contains(pattern)? {
+                    result.set_item(pattern, span)?;
+                }
+            } else {
+                result.set_item(m.pattern(), span)?;
+            }
+        }
+        Ok(result.into())
+    }
+
+    /// Return matches as list of patterns. If search_limit is given, only
+    /// the first search_limit code points are searched, as in
+    /// find_matches_as_indexes(). Matches are in the order they're found,
+    /// i.e. by end for overlapping matches, unless order is given, which
+    /// works like find_matches_as_indexes()'s.
+    #[args(overlapping = "false", search_limit = "None", order = "None")]
+    fn find_matches_as_strings(
+        self_: PyRef<Self>,
+        mut haystack: HaystackArg,
+        overlapping: bool,
+        search_limit: Option<usize>,
+        order: Option<&str>,
+    ) -> PyResult<Vec<Py<PyUnicode>>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let order = parse_match_order(order, true)?;
+        let py = self_.py();
+        let end = haystack.limit(search_limit);
+        let mut matches = get_matches(
+            py,
+            &self_.ac_impl,
+            &haystack.as_bytes()[..end],
+            overlapping,
+            false,
+        );
+        if let Some(order) = order {
+            sort_matches(&mut matches, order, |m| {
+                code_point_len(&haystack.as_bytes()[m.start()..m.end()])
+            });
+        }
+        Ok(matches
+            .iter()
+            .map(|m| self_.matched_text(py, haystack.as_bytes(), m))
+            .collect())
+    }
+
+    /// Return the set of indexes of patterns that match the haystack.
+    #[args(overlapping = "false")]
+    fn matched_pattern_ids(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<HashSet<usize>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        Ok(get_matched_pattern_ids(
+            py,
+            &self_.ac_impl,
+            haystack.as_bytes(),
+            overlapping,
+        ))
+    }
+
+    /// Return a list with a bool for each pattern, saying whether it matches
+    /// the haystack. If packed is true, return bytes with one bit per
+    /// pattern instead, in the same layout as numpy.packbits().
+    #[args(overlapping = "false", packed = "false")]
+    fn matched_pattern_mask(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+        packed: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        Ok(get_matched_pattern_mask(
+            self_.py(),
+            &self_.ac_impl,
+            haystack.as_bytes(),
+            overlapping,
+            packed,
+        ))
+    }
+
+    /// Return the patterns that match the haystack, each listed once, in
+    /// the order they were passed in to the constructor.
+    #[args(overlapping = "false")]
+    fn matched_patterns(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<Vec<Py<PyUnicode>>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let haystack = haystack.as_bytes();
+        if self_.patterns.is_none() {
+            // Use the first match of each pattern for its text:
+            let mut first_matches = HashMap::new();
+            for m in get_matches(py, &self_.ac_impl, haystack, overlapping, false) {
+                first_matches.entry(m.pattern()).or_insert(m);
+            }
+            let mut first_matches: Vec<_> = first_matches.into_values().collect();
+            first_matches.sort_unstable_by_key(|m| m.pattern());
+            return Ok(first_matches
+                .iter()
+                .map(|m| self_.matched_text(py, haystack, m))
+                .collect());
+        }
+        let mut ids: Vec<usize> =
+            get_matched_pattern_ids(py, &self_.ac_impl, haystack, overlapping)
+                .into_iter()
+                .collect();
+        ids.sort_unstable();
+        let patterns = self_.get_patterns()?;
+        Ok(ids.into_iter().map(|id| patterns.get(py, id)).collect())
+    }
+
+    /// Return the (start, end) spans of the haystack that aren't covered by
+    /// any match.
+    #[args(overlapping = "false")]
+    fn find_gaps(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        let py = self_.py();
+        let covered = get_covered_spans(py, &self_.ac_impl, haystack, overlapping);
+        Ok(get_gaps(&covered, haystack.len())
+            .into_iter()
+            .map(|(start, end)| (byte_to_code_point.get(start), byte_to_code_point.get(end)))
+            .collect())
+    }
+
+    /// Return the fraction of the haystack's characters that are covered by
+    /// at least one match. Overlapping matches are merged, so characters are
+    /// only counted once; an empty haystack gives 0.0.
+    #[args(overlapping = "false")]
+    fn find_coverage_fraction(
+        self_: PyRef<Self>,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<f64> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let byte_to_code_point = self_.byte_to_code_point(&haystack);
+        let haystack = haystack.as_bytes();
+        if haystack.is_empty() {
+            return Ok(0.0);
+        }
+        let py = self_.py();
+        let covered: usize = get_covered_spans(py, &self_.ac_impl, haystack, overlapping)
+            .into_iter()
+            .map(|(start, end)| byte_to_code_point.get(end) - byte_to_code_point.get(start))
+            .sum();
+        Ok(covered as f64 / byte_to_code_point.get(haystack.len()) as f64)
+    }
+}
+
+/// Results of find_matches_as_indexes(), keyed by (haystack, overlapping).
+type ResultsCache = LruCache<(Vec<u8>, bool), Vec<(usize, usize, usize)>>;
+
+/// Cached results of CachingAhoCorasick.find_matches_as_indexes(), plus
+/// statistics.
+struct MatchCache {
+    // None if caching is disabled, i.e. maxsize is 0.
+    results: Option<ResultsCache>,
+    hits: usize,
+    misses: usize,
+}
+
+/// A wrapper around an AhoCorasick that caches the results of
+/// find_matches_as_indexes(), for applications that repeatedly search the
+/// same haystacks.
+#[pyclass(name = "CachingAhoCorasick", module = "ahocorasick_rs")]
+struct PyCachingAhoCorasick {
+    ac: Py<PyAhoCorasick>,
+    maxsize: usize,
+    // Only locked while the GIL is held and never across a search, since
+    // searching releases the GIL.
+    cache: Mutex<MatchCache>,
+}
+
+/// Methods for PyCachingAhoCorasick.
+#[pymethods]
+impl PyCachingAhoCorasick {
+    /// Like AhoCorasick.find_matches_as_indexes() (with sorting), except that
+    /// results for recently searched haystacks are returned from the cache.
+    #[args(overlapping = "false")]
+    fn find_matches_as_indexes(
+        &self,
+        py: Python,
+        haystack: HaystackArg,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        let ac = self.ac.borrow(py);
+        check_overlapping(&ac.ac_impl, overlapping)?;
+        let text = haystack.as_bytes();
+        let key = (text.to_owned(), overlapping);
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(result) = cache.results.as_mut().and_then(|r| r.get(&key)) {
+                let result = result.clone();
+                cache.hits += 1;
+                return Ok(result);
+            }
+            cache.misses += 1;
+        }
+        let byte_to_code_point = ac.byte_to_code_point(&haystack);
+        let result: Vec<(usize, usize, usize)> =
+            get_matches(py, &ac.ac_impl, text, overlapping, true)
+                .into_iter()
+                .map(|m| {
+                    (
+                        m.pattern(),
+                        byte_to_code_point.get(m.start()),
+                        byte_to_code_point.get(m.end()),
+                    )
+                })
+                .collect();
+        if let Some(results) = self.cache.lock().unwrap().results.as_mut() {
+            results.put(key, result.clone());
+        }
+        Ok(result)
+    }
+
+    /// Return a dict with the cache's hits, misses, maxsize and currsize,
+    /// like functools.lru_cache's cache_info().
+    fn cache_info(&self, py: Python) -> PyResult<PyObject> {
+        let cache = self.cache.lock().unwrap();
+        let info = PyDict::new(py);
+        info.set_item("hits", cache.hits)?;
+        info.set_item("misses", cache.misses)?;
+        info.set_item("maxsize", self.maxsize)?;
+        info.set_item("currsize", cache.results.as_ref().map_or(0, |r| r.len()))?;
+        Ok(info.into())
+    }
+
+    /// Empty the cache and reset its statistics.
+    fn cache_clear(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(results) = cache.results.as_mut() {
+            results.clear();
+        }
+        cache.hits = 0;
+        cache.misses = 0;
+    }
+}
+
+/// A Python wrapper for AhoCorasick that searches bytes-like objects.
+#[pyclass(subclass, name = "BytesAhoCorasick", module = "ahocorasick_rs")]
+struct PyBytesAhoCorasick {
+    // Shared with copies, see __copy__().
+    ac_impl: Arc<Automaton>,
+    // User-provided IDs, one per pattern, if any.
+    ids: Option<Vec<PyObject>>,
+    // The patterns as bytes objects, if store_patterns was true.
+    patterns: Option<Vec<PyObject>>,
+    // User-provided values, one per pattern, if any.
+    values: Option<Vec<PyObject>>,
+    // Indexes of the constructor's patterns skipped by on_invalid="skip".
+    skipped_patterns: Vec<usize>,
+}
+
+impl PyBytesAhoCorasick {
+    /// A copy sharing the built automaton, see __copy__().
+    fn shallow_copy(&self) -> Self {
+        Self {
+            ac_impl: self.ac_impl.clone(),
+            ids: self.ids.clone(),
+            patterns: self.patterns.clone(),
+            values: self.values.clone(),
+            skipped_patterns: self.skipped_patterns.clone(),
+        }
+    }
+
+    fn get_values(&self) -> PyResult<&[PyObject]> {
+        self.values.as_deref().ok_or_else(|| {
+            PyValueError::new_err("No values were passed when creating this BytesAhoCorasick.")
+        })
+    }
+
+    fn check_pattern_index(&self, index: usize) -> PyResult<()> {
+        let pattern_count = self.ac_impl.pattern_count();
+        if index >= pattern_count {
+            return Err(PyIndexError::new_err(format!(
+                "Pattern index {} is out of range; there are {} patterns.",
+                index, pattern_count
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_patterns(&self) -> PyResult<&[PyObject]> {
+        self.patterns.as_deref().ok_or_else(|| {
+            PyRuntimeError::new_err(
+                "Patterns weren't stored; pass store_patterns=True when creating the BytesAhoCorasick.",
+            )
+        })
+    }
+
+    fn stored_patterns_memory_usage(&self, py: Python) -> PyResult<usize> {
+        match &self.patterns {
+            Some(patterns) => objects_memory_usage(patterns.iter().map(|p| p.as_ref(py))),
+            None => Ok(0),
+        }
+    }
+}
+
+/// Methods for PyBytesAhoCorasick.
+#[pymethods]
+impl PyBytesAhoCorasick {
+    /// Like AhoCorasick.__init_subclass__().
+    #[classmethod]
+    #[args(kwargs = "**")]
+    fn __init_subclass__(cls: &PyType, kwargs: Option<&PyDict>) -> PyResult<()> {
+        let py = cls.py();
+        let new = wrap_pyfunction!(new_bytes_ahocorasick_subclass, py)?;
+        init_subclass(cls, py.get_type::<Self>(), new, kwargs)
+    }
+
+    /// __new__() implementation. patterns can be any iterable; all of it is
+    /// consumed before anything else happens, so an exception raised while
+    /// iterating, or a pattern that isn't bytes-like, fails construction
+    /// rather than dropping the remaining patterns.
+    ///
+    /// on_build_progress and progress_interval work like AhoCorasick's; the
+    /// total passed to the callback is -1 until the end if patterns has no
+    /// len(), e.g. a generator. implementation and on_invalid work like
+    /// AhoCorasick's; with on_invalid="skip", patterns that are empty or
+    /// aren't bytes-like are skipped. If coerce is true, patterns that
+    /// aren't bytes-like but have a __bytes__() method are converted with it;
+    /// others, such as ints, are still invalid, and it's an error if the
+    /// result is empty. With on_invalid="skip", patterns whose conversion
+    /// fails are skipped too.
+    ///
+    /// Unknown arguments raise a TypeError, except in subclasses, as for
+    /// AhoCorasick.
+    #[new]
+    #[args(
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        ids = "None",
+        store_patterns = "false",
+        values = "None",
+        on_build_progress = "None",
+        progress_interval = "10_000",
+        implementation = "None",
+        on_invalid = "\"raise\"",
+        coerce = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python,
+        patterns: &PyAny,
+        matchkind: &str,
+        mut ids: Option<Vec<PyObject>>,
+        store_patterns: bool,
+        mut values: Option<Vec<PyObject>>,
+        on_build_progress: Option<&PyAny>,
+        progress_interval: usize,
+        implementation: Option<&str>,
+        on_invalid: &str,
+        coerce: bool,
+    ) -> PyResult<Self> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let skip_invalid = parse_on_invalid(on_invalid)?;
+        let progress = BuildProgress::new(on_build_progress, progress_interval, patterns)?;
+        let mut rust_patterns: Vec<Vec<u8>> = vec![];
+        let mut skipped_patterns = vec![];
+        for (index, pattern) in patterns.iter()?.enumerate() {
+            let pattern = pattern?;
+            let (buffer, coerced) = match pattern.extract::<PyBuffer<u8>>() {
+                Ok(buffer) => (buffer, false),
+                // Only via __bytes__(), since bytes() would turn an int n
+                // into n NUL bytes:
+                Err(_) if coerce && pattern.get_type().hasattr("__bytes__")? => {
+                    match py
+                        .get_type::<PyBytes>()
+                        .call1((pattern,))
+                        .and_then(|pattern| pattern.extract())
+                    {
+                        Ok(buffer) => (buffer, true),
+                        Err(_) if skip_invalid => {
+                            skipped_patterns.push(index);
+                            continue;
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                Err(_) if skip_invalid => {
+                    skipped_patterns.push(index);
+                    continue;
+                }
+                Err(_) => return Err(wrong_pattern_type(index, pattern, "bytes-like")),
+            };
+            if buffer.item_count() == 0 {
+                if skip_invalid {
+                    skipped_patterns.push(index);
+                    continue;
+                }
+                if coerced {
+                    return Err(empty_coerced_pattern(index));
+                }
+            }
+            rust_patterns.push(buffer.to_vec(py)?);
+            progress.pattern_processed(rust_patterns.len())?;
+        }
+        let input_count = rust_patterns.len() + skipped_patterns.len();
+        check_per_pattern("ids", &ids, input_count)?;
+        check_per_pattern("values", &values, input_count)?;
+        ids = ids.map(|ids| remove_skipped(ids, &skipped_patterns));
+        values = values.map(|values| remove_skipped(values, &skipped_patterns));
+        let ac_impl = Arc::new(build_automaton(py, &rust_patterns, matchkind, kind));
+        progress.finished(rust_patterns.len())?;
+        let patterns = if store_patterns {
+            Some(
+                rust_patterns
+                    .iter()
+                    .map(|pattern| PyBytes::new(py, pattern).into())
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        Ok(Self {
+            ac_impl,
+            ids,
+            patterns,
+            values,
+            skipped_patterns,
+        })
+    }
+
+    /// Create from a buffer containing patterns separated by a delimiter
+    /// (default is a newline). Empty records are skipped, or if skip_empty is
+    /// false cause a ValueError. implementation works like the
+    /// constructor's.
+    #[staticmethod]
+    #[args(
+        delimiter = "None",
+        skip_empty = "true",
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        implementation = "None"
+    )]
+    fn from_delimited(
+        py: Python,
+        blob: PyBuffer<u8>,
+        delimiter: Option<&[u8]>,
+        skip_empty: bool,
+        matchkind: &str,
+        implementation: Option<&str>,
+    ) -> PyResult<Self> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let records = split_on_delimiter(buffer_as_bytes(&blob)?, delimiter, skip_empty)?;
+        Ok(Self {
+            ac_impl: Arc::new(build_automaton(
+                py,
+                records.iter().map(|(_, r)| r),
+                matchkind,
+                kind,
+            )),
+            ids: None,
+            patterns: None,
+            values: None,
+            skipped_patterns: vec![],
+        })
+    }
+
+    /// Create from a 1-dimensional array of fixed-width byte strings, e.g. a
+    /// NumPy array with dtype S8. Each item is a pattern; trailing NUL
+    /// padding is removed unless keep_nul_padding is true. implementation
+    /// works like the constructor's.
+    #[staticmethod]
+    #[args(
+        keep_nul_padding = "false",
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        implementation = "None"
+    )]
+    fn from_numpy(
+        py: Python,
+        array: &PyAny,
+        keep_nul_padding: bool,
+        matchkind: &str,
+        implementation: Option<&str>,
+    ) -> PyResult<Self> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let view = PyModule::import(py, "builtins")?
+            .getattr("memoryview")?
+            .call1((array,))?;
+        let ndim: usize = view.getattr("ndim")?.extract()?;
+        if ndim != 1 {
+            return Err(PyValueError::new_err(format!(
+                "Expected a 1-dimensional array, got a {}-dimensional one.",
+                ndim
+            )));
+        }
+        let format: &str = view.getattr("format")?.extract()?;
+        let itemsize: usize = view.getattr("itemsize")?.extract()?;
+        if !format.ends_with('s') || itemsize == 0 {
+            return Err(PyValueError::new_err(format!(
+                "Expected an array of fixed-width byte strings (e.g. NumPy dtype S8), got buffer format {:?}.",
+                format
+            )));
+        }
+        let buffer: PyBuffer<u8> = view.call_method1("cast", ("B",))?.extract()?;
+        let patterns = buffer_as_bytes(&buffer)?.chunks(itemsize).map(|pattern| {
+            if keep_nul_padding {
+                pattern
+            } else {
+                let end = pattern.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+                &pattern[..end]
+            }
+        });
+        Ok(Self {
+            ac_impl: Arc::new(build_automaton(py, patterns, matchkind, kind)),
+            ids: None,
+            patterns: None,
+            values: None,
+            skipped_patterns: vec![],
+        })
+    }
+
+    /// Create from a file containing patterns separated by a delimiter
+    /// (default is a newline). Empty records are skipped. When splitting on
+    /// the default newline, a trailing "\\r" is stripped from each line
+    /// unless strip_newlines is false, so CRLF files work. If the file has
+    /// more than max_patterns patterns, a ValueError is raised as soon as
+    /// the one too many is read, before building the automaton.
+    ///
+    /// The file is read a record at a time rather than all at once, with the
+    /// GIL released except briefly every 10,000 records. implementation
+    /// works like the constructor's.
+    #[staticmethod]
+    #[args(
+        delimiter = "None",
+        matchkind = "\"MATCHKIND_STANDARD\"",
+        strip_newlines = "true",
+        max_patterns = "None",
+        implementation = "None"
+    )]
+    fn from_file(
+        py: Python,
+        path: PathBuf,
+        delimiter: Option<&[u8]>,
+        matchkind: &str,
+        strip_newlines: bool,
+        max_patterns: Option<usize>,
+        implementation: Option<&str>,
+    ) -> PyResult<Self> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let strip_cr = delimiter.is_none() && strip_newlines;
+        let delimiter = delimiter.unwrap_or(b"\n");
+        let last_byte = *delimiter
+            .last()
+            .ok_or_else(|| PyValueError::new_err("delimiter must not be empty."))?;
+        let read_error =
+            |e: io::Error| PyIOError::new_err(format!("Couldn't read {}: {}", path.display(), e));
+        let mut reader =
+            BufReader::new(py.allow_threads(|| File::open(&path)).map_err(read_error)?);
+        let mut records: Vec<Vec<u8>> = vec![];
+        let mut record = vec![];
+        let mut at_end = false;
+        let mut too_many = false;
+        while !(at_end || too_many) {
+            py.allow_threads(|| -> io::Result<()> {
+                for _ in 0..FROM_FILE_BATCH_SIZE {
+                    // Read up to the delimiter's last byte; if the record
+                    // doesn't end with the whole delimiter, keep going.
+                    if reader.read_until(last_byte, &mut record)? == 0 {
+                        at_end = true;
+                    } else if record.ends_with(delimiter) {
+                        record.truncate(record.len() - delimiter.len());
+                    } else {
+                        continue;
+                    }
+                    if strip_cr && record.ends_with(b"\r") {
+                        record.pop();
+                    }
+                    if !record.is_empty() {
+                        records.push(std::mem::take(&mut record));
+                        if matches!(max_patterns, Some(max_patterns) if records.len() > max_patterns) {
+                            too_many = true;
+                            return Ok(());
+                        }
+                    }
+                    if at_end {
+                        return Ok(());
+                    }
+                }
+                Ok(())
+            })
+            .map_err(read_error)?;
+            py.check_signals()?;
+        }
+        if too_many {
+            return Err(PyValueError::new_err(format!(
+                "{} has more than max_patterns={} patterns.",
+                path.display(),
+                max_patterns.unwrap_or_default()
+            )));
+        }
+        Ok(Self {
+            ac_impl: Arc::new(build_automaton(py, records, matchkind, kind)),
+            ids: None,
+            patterns: None,
+            values: None,
+            skipped_patterns: vec![],
+        })
+    }
 
-/// A Python wrapper for AhoCorasick.
-#[pyclass(name = "AhoCorasick")]
-struct PyAhoCorasick {
-    ac_impl: AhoCorasick,
-    patterns: Vec<Py<PyUnicode>>,
-}
+    /// Save the patterns and construction options to a file, so load() can
+    /// rebuild the automaton without going through Python objects; this
+    /// requires store_patterns=True. The file format is internal, and may
+    /// change between releases. IDs and values are arbitrary Python objects,
+    /// so they can't be saved.
+    fn save(&self, py: Python, path: PathBuf) -> PyResult<()> {
+        let patterns = self.patterns.as_ref().ok_or_else(|| {
+            PyTypeError::new_err(
+                "Can't save a BytesAhoCorasick that doesn't store its patterns; pass store_patterns=True when creating it.",
+            )
+        })?;
+        if self.ids.is_some() || self.values.is_some() {
+            return Err(PyValueError::new_err(
+                "Can't save a BytesAhoCorasick with ids or values.",
+            ));
+        }
+        let patterns = patterns
+            .iter()
+            .map(|p| Ok(p.as_ref(py).downcast::<PyBytes>()?.as_bytes()))
+            .collect::<PyResult<Vec<_>>>()?;
+        let data = saved::serialize(&saved::Saved {
+            class: saved::Class::BytesAhoCorasick,
+            matchkind: *self.ac_impl.match_kind(),
+            implementation: self.ac_impl.kind,
+            byte_offsets: false,
+            patterns,
+            sorted_to_original: None,
+        });
+        py.allow_threads(|| std::fs::write(&path, data))
+            .map_err(|e| PyIOError::new_err(format!("Couldn't write {}: {}", path.display(), e)))
+    }
 
-impl PyAhoCorasick {
-    fn check_overlapping(&self, overlapping: bool) -> PyResult<()> {
-        if overlapping && !self.ac_impl.supports_overlapping() {
-            return Err(PyValueError::new_err("This automaton doesn't support overlapping results; perhaps you didn't use the defalt matchkind (MATCHKIND_STANDARD)?"));
+    /// Load a BytesAhoCorasick written by save(); it stores its patterns.
+    /// Raises a ValueError if the file is truncated or was written in an
+    /// unsupported format version. Like AhoCorasick.load(), returns an
+    /// instance of the subclass if called on one.
+    #[classmethod]
+    fn load(cls: &PyType, path: PathBuf) -> PyResult<&PyCell<Self>> {
+        let py = cls.py();
+        let data = py
+            .allow_threads(|| std::fs::read(&path))
+            .map_err(|e| PyIOError::new_err(format!("Couldn't read {}: {}", path.display(), e)))?;
+        let loaded = saved::deserialize(&data, &path, saved::Class::BytesAhoCorasick)?;
+        let patterns = loaded
+            .patterns
+            .iter()
+            .map(|pattern| PyBytes::new(py, pattern).into())
+            .collect();
+        let result = Self {
+            ac_impl: Arc::new(build_automaton(
+                py,
+                &loaded.patterns,
+                loaded.matchkind,
+                loaded.implementation,
+            )),
+            ids: None,
+            patterns: Some(patterns),
+            values: None,
+            skipped_patterns: vec![],
+        };
+        new_instance_of(cls, result)
+    }
+
+    /// The number of patterns the automaton was built with.
+    fn __len__(&self) -> usize {
+        self.ac_impl.pattern_count()
+    }
+
+    /// The memory used by the automaton and stored patterns, in bytes; see
+    /// memory_usage_breakdown().
+    fn memory_usage(&self, py: Python) -> PyResult<usize> {
+        Ok(self.ac_impl.memory_usage() + self.stored_patterns_memory_usage(py)?)
+    }
+
+    /// Return a dict with the memory used by the automaton and by stored
+    /// patterns, in bytes.
+    fn memory_usage_breakdown(&self, py: Python) -> PyResult<PyObject> {
+        let breakdown = PyDict::new(py);
+        breakdown.set_item("automaton", self.ac_impl.memory_usage())?;
+        breakdown.set_item("stored_patterns", self.stored_patterns_memory_usage(py)?)?;
+        Ok(breakdown.into())
+    }
+
+    /// Include memory_usage(), so sys.getsizeof() is meaningful.
+    fn __sizeof__(&self, py: Python) -> PyResult<usize> {
+        Ok(std::mem::size_of::<Self>() + self.memory_usage(py)?)
+    }
+
+    /// A new object of the same class, sharing the built automaton rather
+    /// than rebuilding it, since that is immutable. The IDs, values and a
+    /// subclass instance's __dict__ are copied shallowly.
+    fn __copy__(slf: &PyCell<Self>) -> PyResult<&PyCell<Self>> {
+        let copied = new_instance_like(slf, slf.borrow().shallow_copy())?;
+        copy_instance_dict(slf, copied, None)?;
+        Ok(copied)
+    }
+
+    /// Like __copy__(), but IDs, values and a subclass instance's __dict__
+    /// are deep copied. The built automaton is still shared.
+    fn __deepcopy__<'py>(slf: &'py PyCell<Self>, memo: &'py PyAny) -> PyResult<&'py PyCell<Self>> {
+        let py = slf.py();
+        let copied = new_instance_like(slf, slf.borrow().shallow_copy())?;
+        // Register the copy first, in case the values refer back to us:
+        add_to_memo(memo, slf, copied)?;
+        {
+            let self_ = slf.borrow();
+            let ids = deepcopy_objects(py, &self_.ids, memo)?;
+            let values = deepcopy_objects(py, &self_.values, memo)?;
+            let mut copied = copied.borrow_mut();
+            copied.ids = ids;
+            copied.values = values;
         }
-        Ok(())
+        copy_instance_dict(slf, copied, Some(memo))?;
+        Ok(copied)
     }
 
-    /// Return matches for a given haystack.
-    fn get_matches(&self, py: Python<'_>, haystack: &str, overlapping: bool) -> Vec<Match> {
-        let ac_impl = &self.ac_impl;
-        py.allow_threads(|| {
-            if overlapping {
-                ac_impl.find_overlapping_iter(haystack).collect()
-            } else {
-                ac_impl.find_iter(haystack).collect()
+    /// Support pickling, by reconstructing from the stored patterns and the
+    /// constructor arguments; this requires store_patterns=True. Subclasses
+    /// are reconstructed as the subclass with BytesAhoCorasick.__new__(cls,
+    /// ...), bypassing their own __new__() and __init__(), and their
+    /// instance __dict__ is restored.
+    fn __reduce__(slf: &PyCell<Self>) -> PyResult<(PyObject, PyObject, Option<PyObject>)> {
+        let py = slf.py();
+        let self_ = slf.borrow();
+        let patterns = self_.patterns.as_ref().ok_or_else(|| {
+            PyTypeError::new_err(
+                "Can't pickle a BytesAhoCorasick that doesn't store its patterns; pass store_patterns=True when creating it.",
+            )
+        })?;
+        let args = (
+            slf.get_type(),
+            PyList::new(py, patterns),
+            self_.matchkind(),
+            self_.ids.as_ref().map(|ids| PyList::new(py, ids)),
+            true,
+            self_.values.as_ref().map(|values| PyList::new(py, values)),
+            py.None(),
+            10_000,
+            self_.ac_impl.kind.name(),
+        );
+        // Only instances of Python subclasses have a __dict__:
+        let state = slf.getattr("__dict__").ok().map(|state| state.into());
+        Ok((
+            py.get_type::<Self>().getattr("__new__")?.into(),
+            args.into_py(py),
+            state,
+        ))
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<BytesAhoCorasick with {} patterns, matchkind={}, implementation={}, store_patterns={}>",
+            self.ac_impl.pattern_count(),
+            self.matchkind(),
+            self.implementation(),
+            if self.store_patterns() { "True" } else { "False" },
+        )
+    }
+
+    /// The number of patterns the automaton was built with.
+    #[getter]
+    fn patterns_len(&self) -> usize {
+        self.ac_impl.pattern_count()
+    }
+
+    /// How long building the automaton took, in microseconds. This includes
+    /// releasing and reacquiring the GIL, but not converting the patterns
+    /// from Python objects beforehand.
+    #[getter]
+    fn build_time_us(&self) -> u64 {
+        self.ac_impl.build_time.as_micros() as u64
+    }
+
+    /// A summary of how the automaton was built: its implementation, memory
+    /// use, pattern statistics, pattern storage and build time.
+    #[getter]
+    fn build_info(&self, py: Python) -> PyBuildInfo {
+        PyBuildInfo::new(&self.ac_impl, self.store_patterns().into_py(py))
+    }
+
+    /// The length of the longest pattern, in bytes.
+    fn max_pattern_length(&self) -> usize {
+        self.ac_impl.max_pattern_len()
+    }
+
+    /// The length of the shortest pattern, in bytes.
+    fn min_pattern_length(&self) -> usize {
+        self.ac_impl.min_pattern_len
+    }
+
+    /// The length of each pattern in bytes, in pattern index order. Requires
+    /// store_patterns=True.
+    fn pattern_lengths(&self, py: Python) -> PyResult<Vec<usize>> {
+        self.get_patterns()?
+            .iter()
+            .map(|pattern| pattern.as_ref(py).len())
+            .collect()
+    }
+
+    /// The match kind the automaton was built with, as one of the
+    /// MATCHKIND_* constants.
+    #[getter]
+    fn matchkind(&self) -> &'static str {
+        matchkind_name(*self.ac_impl.match_kind())
+    }
+
+    /// The implementation actually used for non-overlapping searches: "memchr"
+    /// when searching for one to three distinct single bytes, otherwise the
+    /// one passed to the constructor, "dfa" or "noncontiguous". Overlapping
+    /// searches always use the latter.
+    #[getter]
+    fn implementation(&self) -> &'static str {
+        self.ac_impl.implementation()
+    }
+
+    /// Whether the patterns were stored, i.e. store_patterns=True was passed.
+    #[getter]
+    fn store_patterns(&self) -> bool {
+        self.patterns.is_some()
+    }
+
+    /// The indexes of the patterns passed to the constructor that were
+    /// skipped because of on_invalid="skip"; empty otherwise.
+    #[getter]
+    fn skipped_patterns(&self) -> Vec<usize> {
+        self.skipped_patterns.clone()
+    }
+
+    /// The patterns, as a list of bytes. Requires store_patterns=True.
+    #[getter]
+    fn patterns(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        Ok(self
+            .get_patterns()?
+            .iter()
+            .map(|pattern| pattern.clone_ref(py))
+            .collect())
+    }
+
+    /// Iterate over a snapshot of the patterns. Requires store_patterns=True;
+    /// otherwise the object isn't iterable.
+    fn __iter__(&self, py: Python) -> PyResult<PyObject> {
+        let patterns = self.patterns.as_deref().ok_or_else(|| {
+            PyTypeError::new_err(
+                "BytesAhoCorasick is only iterable if created with store_patterns=True.",
+            )
+        })?;
+        Ok(PyIterator::from_object(py, PyList::new(py, patterns))?.into())
+    }
+
+    /// Get a pattern by index, with negative indexes counting from the end
+    /// like a list. Requires store_patterns=True.
+    fn __getitem__(&self, py: Python, index: isize) -> PyResult<PyObject> {
+        let patterns = self.get_patterns()?;
+        let length = patterns.len() as isize;
+        let index = if index < 0 { index + length } else { index };
+        if index < 0 || index >= length {
+            return Err(PyIndexError::new_err("Pattern index out of range."));
+        }
+        Ok(patterns[index as usize].clone_ref(py))
+    }
+
+    /// Get the pattern with the given index. Unlike indexing, negative
+    /// indexes aren't allowed. Requires store_patterns=True.
+    fn pattern_at(&self, py: Python, index: isize) -> PyResult<PyObject> {
+        let patterns = self.get_patterns()?;
+        if index < 0 || index as usize >= patterns.len() {
+            return Err(PyIndexError::new_err(format!(
+                "Pattern index {} is out of range; there are {} patterns.",
+                index,
+                patterns.len()
+            )));
+        }
+        Ok(patterns[index as usize].clone_ref(py))
+    }
+
+    /// Return the index of the first pattern equal to the given bytes, or
+    /// raise a ValueError if there isn't one. Requires store_patterns=True.
+    fn pattern_index(&self, py: Python, pattern: PyBuffer<u8>) -> PyResult<usize> {
+        let pattern = buffer_as_bytes(&pattern)?;
+        for (index, stored) in self.get_patterns()?.iter().enumerate() {
+            if stored.as_ref(py).downcast::<PyBytes>()?.as_bytes() == pattern {
+                return Ok(index);
             }
-        })
+        }
+        Err(PyValueError::new_err(format!(
+            "{:?} isn't a pattern.",
+            PyBytes::new(py, pattern)
+        )))
     }
-}
 
-/// Methods for PyAhoCorasick.
-#[pymethods]
-impl PyAhoCorasick {
-    /// __new__() implementation.
-    #[new]
-    #[args(matchkind = "\"MATCHKIND_STANDARD\"")]
-    fn new(py: Python, patterns: Vec<Py<PyUnicode>>, matchkind: &str) -> PyResult<Self> {
-        let matchkind = match matchkind {
-            "MATCHKIND_STANDARD" => MatchKind::Standard,
-            "MATCHKIND_LEFTMOST_FIRST" => MatchKind::LeftmostFirst,
-            "MATCHKIND_LEFTMOST_LONGEST" => MatchKind::LeftmostLongest,
-            _ => {
-                return Err(PyValueError::new_err(
-                    "matchkind must be one of the ahocorasick_rs.MATCHKIND_* constants.",
-                ));
+    /// Return matches as tuple of (index_into_patterns,
+    /// start_index_in_haystack, end_index_in_haystack), with indexes being
+    /// byte offsets. If sort is true (the default), matches are sorted by
+    /// (start, end, index_into_patterns). If you know roughly how many
+    /// matches to expect, passing it as size_hint preallocates space for
+    /// them. If search_limit is given, only the first search_limit bytes are
+    /// searched; matches straddling the limit aren't returned. If
+    /// extra_fields, a dict mapping pattern indexes to arbitrary metadata, is
+    /// given, each tuple ends with the metadata for its pattern, or None if
+    /// there's none. If filter_fn is given, it's called with
+    /// (index_into_patterns, start_index, end_index) for each match, and only
+    /// matches for which it returns true are returned; if fill_gaps is also
+    /// true, rejected matches are replaced with None instead. If
+    /// positions_only is true, a list of just the start_index of each match
+    /// is returned instead of tuples. If include_pattern_text is true, each
+    /// tuple ends with the matched bytes, which are the stored pattern if
+    /// store_patterns was true or a copy of that part of the haystack
+    /// otherwise. min_match_length, max_match_length and order work like
+    /// AhoCorasick's, but lengths are in bytes. If whole_word is true, only
+    /// matches that aren't preceded or followed by a word byte are returned;
+    /// word_bytes lists the bytes that count, by default ASCII letters,
+    /// digits and underscore. deduplicate_spans works like AhoCorasick's.
+    #[args(
+        overlapping = "false",
+        sort = "true",
+        size_hint = "0",
+        search_limit = "None",
+        extra_fields = "None",
+        filter_fn = "None",
+        positions_only = "false",
+        include_pattern_text = "false",
+        fill_gaps = "false",
+        min_match_length = "0",
+        max_match_length = "None",
+        order = "None",
+        whole_word = "false",
+        word_bytes = "None",
+        deduplicate_spans = "false"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn find_matches_as_indexes(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+        sort: bool,
+        size_hint: usize,
+        search_limit: Option<usize>,
+        extra_fields: Option<&PyDict>,
+        filter_fn: Option<&PyAny>,
+        positions_only: bool,
+        include_pattern_text: bool,
+        fill_gaps: bool,
+        min_match_length: usize,
+        max_match_length: Option<usize>,
+        order: Option<&str>,
+        whole_word: bool,
+        word_bytes: Option<PyBuffer<u8>>,
+        deduplicate_spans: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let order = parse_match_order(order, sort)?;
+        let word_bytes = parse_word_bytes(whole_word, word_bytes)?;
+        if positions_only && extra_fields.is_some() {
+            return Err(PyValueError::new_err(
+                "positions_only can't be combined with extra_fields.",
+            ));
+        }
+        if include_pattern_text && (positions_only || extra_fields.is_some()) {
+            return Err(PyValueError::new_err(
+                "include_pattern_text can't be combined with positions_only or extra_fields.",
+            ));
+        }
+        let extra_fields = get_extra_fields(extra_fields, self_.ac_impl.pattern_count())?;
+        let py = self_.py();
+        let haystack = buffer_as_bytes(&haystack)?;
+        let end = search_limit.map_or(haystack.len(), |limit| limit.min(haystack.len()));
+        let mut matches = match &word_bytes {
+            Some(word_bytes) => get_whole_word_matches(
+                py,
+                &self_.ac_impl,
+                haystack,
+                end,
+                overlapping,
+                sort && order.is_none(),
+                word_bytes,
+            ),
+            None => get_matches_with_capacity(
+                py,
+                &self_.ac_impl,
+                &haystack[..end],
+                overlapping,
+                sort && order.is_none(),
+                size_hint,
+            ),
+        };
+        let length = |m: &PatternMatch| m.end() - m.start();
+        if let Some(order) = order {
+            sort_matches(&mut matches, order, length);
+        }
+        let matches = filter_match_lengths(matches, min_match_length, max_match_length, length);
+        let matches = if deduplicate_spans {
+            deduplicate_matches(matches)
+        } else {
+            matches
+        };
+        let key = |m: &PatternMatch| (m.pattern(), m.start(), m.end());
+        let mut mask = None;
+        let matches = match filter_fn {
+            Some(filter_fn) if fill_gaps => {
+                mask = Some(filter_mask(filter_fn, &matches, key)?);
+                matches
             }
+            Some(filter_fn) => filter_matches(filter_fn, matches, key)?,
+            None => matches,
         };
-        let mut rust_patterns: Vec<String> = vec![];
-        for s in patterns.iter() {
-            rust_patterns.push(s.as_ref(py).extract()?);
+        let results = if positions_only {
+            matches
+                .into_iter()
+                .map(|m| m.start())
+                .collect::<Vec<_>>()
+                .into_py(py)
+        } else if include_pattern_text {
+            matches
+                .into_iter()
+                .map(|m| {
+                    let text = match &self_.patterns {
+                        Some(patterns) => patterns[m.pattern()].clone_ref(py),
+                        None => PyBytes::new(py, &haystack[m.start()..m.end()]).into(),
+                    };
+                    (m.pattern(), m.start(), m.end(), text)
+                })
+                .collect::<Vec<_>>()
+                .into_py(py)
+        } else if let Some(extra_fields) = extra_fields {
+            matches
+                .into_iter()
+                .map(|m| {
+                    let extra = extra_fields.get(&m.pattern()).copied();
+                    (m.pattern(), m.start(), m.end(), extra)
+                })
+                .collect::<Vec<_>>()
+                .into_py(py)
+        } else {
+            matches
+                .into_iter()
+                .map(|m| (m.pattern(), m.start(), m.end()))
+                .collect::<Vec<_>>()
+                .into_py(py)
+        };
+        fill_filtered_gaps(py, results, mask)
+    }
+
+    /// Return a dict mapping pattern indexes to lists of (start, end) byte
+    /// offsets of their matches, in order. Patterns without matches are left
+    /// out. If pattern_keys is true, the keys are the patterns (as bytes)
+    /// instead, so the spans of duplicate patterns are combined; the keys
+    /// come from the haystack, so this doesn't require store_patterns=True.
+    #[args(overlapping = "false", pattern_keys = "false")]
+    fn find_matches_grouped_by_pattern(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+        pattern_keys: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let haystack = buffer_as_bytes(&haystack)?;
+        let matches = get_matches(py, &self_.ac_impl, haystack, overlapping, true);
+        let result = PyDict::new(py);
+        if pattern_keys {
+            let groups = group_spans(
+                matches
+                    .iter()
+                    .map(|m| (&haystack[m.start()..m.end()], m.start(), m.end())),
+            );
+            for (pattern, spans) in groups {
+                result.set_item(PyBytes::new(py, pattern), spans)?;
+            }
+        } else {
+            let groups = group_spans(matches.iter().map(|m| (m.pattern(), m.start(), m.end())));
+            for (pattern_index, spans) in groups {
+                result.set_item(pattern_index, spans)?;
+            }
         }
-        Ok(Self {
-            ac_impl: py.allow_threads(|| {
-                AhoCorasickBuilder::new()
-                    .dfa(true) // DFA results in faster matches
-                    .match_kind(matchkind)
-                    .build(rust_patterns)
-            }),
-            patterns,
-        })
+        Ok(result.into())
     }
 
-    /// Return matches as tuple of (index_into_patterns,
-    /// start_index_in_haystack, end_index_in_haystack).
+    /// Return a dict mapping pattern indexes to the (start, end) byte offsets
+    /// of their first match, in the order they were found. Patterns without
+    /// matches are left out. If pattern_keys is true, the keys are the
+    /// patterns (as bytes) instead, so duplicate patterns share an entry; the
+    /// keys come from the haystack, so this doesn't require
+    /// store_patterns=True. The search stops as soon as every pattern has
+    /// been found.
+    #[args(overlapping = "false", pattern_keys = "false")]
+    fn first_match_per_pattern(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+        pattern_keys: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let haystack = buffer_as_bytes(&haystack)?;
+        let result = PyDict::new(py);
+        for m in get_first_matches(py, &self_.ac_impl, haystack, overlapping) {
+            let span = (m.start(), m.end());
+            if pattern_keys {
+                let pattern = PyBytes::new(py, &haystack[m.start()..m.end()]);
+                if !result.contains(pattern)? {
+                    result.set_item(pattern, span)?;
+                }
+            } else {
+                result.set_item(m.pattern(), span)?;
+            }
+        }
+        Ok(result.into())
+    }
+
+    /// Search the contents of an io.BytesIO without copying them, using the
+    /// memoryview returned by its getbuffer() method. Returns matches like
+    /// find_matches_as_indexes().
     #[args(overlapping = "false")]
-    fn find_matches_as_indexes(
+    fn find_matches_from_bytesio(
         self_: PyRef<Self>,
-        haystack: &str,
+        bio: &PyAny,
         overlapping: bool,
     ) -> PyResult<Vec<(usize, usize, usize)>> {
-        self_.check_overlapping(overlapping)?;
-        // Map UTF-8 byte index to Unicode code point index; the latter is what
-        // Python users expect.
-        let mut byte_to_code_point = vec![usize::MAX; haystack.len() + 1];
-        let mut max_codepoint = 0;
-        for (codepoint_off, (byte_off, _)) in haystack.char_indices().enumerate() {
-            byte_to_code_point[byte_off] = codepoint_off;
-            max_codepoint = codepoint_off;
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let view = bio.call_method0("getbuffer")?;
+        let result = (|| {
+            let buffer: PyBuffer<u8> = view.extract()?;
+            let matches = get_matches(
+                py,
+                &self_.ac_impl,
+                buffer_as_bytes(&buffer)?,
+                overlapping,
+                true,
+            );
+            Ok(matches
+                .into_iter()
+                .map(|m| (m.pattern(), m.start(), m.end()))
+                .collect())
+        })();
+        // The BytesIO can't be resized while the view exists, so release it
+        // right away rather than waiting for garbage collection:
+        view.call_method0("release")?;
+        result
+    }
+
+    /// Return matches of just the patterns whose indexes are in pattern_ids
+    /// (e.g. a set or list), as (index_into_patterns, start_index, end_index)
+    /// tuples like find_matches_as_indexes(). Other matches are skipped
+    /// before any Python objects are created for them.
+    #[args(overlapping = "false")]
+    fn find_matches_filtered(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        pattern_ids: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let mask = get_pattern_ids_mask(pattern_ids, self_.ac_impl.pattern_count())?;
+        let matches = get_matches(
+            self_.py(),
+            &self_.ac_impl,
+            buffer_as_bytes(&haystack)?,
+            overlapping,
+            true,
+        );
+        Ok(matches
+            .into_iter()
+            .filter(|m| mask[m.pattern()])
+            .map(|m| (m.pattern(), m.start(), m.end()))
+            .collect())
+    }
+
+    /// Return the matches for which predicate(index_into_patterns,
+    /// start_index, end_index) returns true, like
+    /// AhoCorasick.find_matches_filtered_by() but with byte offsets.
+    #[args(overlapping = "false")]
+    fn find_matches_filtered_by(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        predicate: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        find_matches_accepted_by(
+            &self_.ac_impl,
+            buffer_as_bytes(&haystack)?,
+            overlapping,
+            predicate,
+            |offset| offset,
+        )
+    }
+
+    /// Call callback(index_into_patterns, start_index, end_index) for each
+    /// match as it's found, like AhoCorasick.for_each_match() but with byte
+    /// offsets.
+    #[args(overlapping = "false")]
+    fn for_each_match(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        callback: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<()> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        for_each_match(
+            self_.py(),
+            &self_.ac_impl,
+            buffer_as_bytes(&haystack)?,
+            overlapping,
+            callback,
+            |offset| offset,
+        )
+    }
+
+    /// Search a haystack of word_size-byte words, e.g. UTF-16 text, for
+    /// patterns that start on a word boundary, returning
+    /// (index_into_patterns, start_word, end_word) tuples. The patterns'
+    /// words are taken to be little-endian; if byteorder is "big", each word
+    /// of the haystack is byte-swapped before searching. Raises a ValueError
+    /// if the haystack's or any pattern's length isn't a multiple of
+    /// word_size.
+    #[args(word_size = "2", byteorder = "\"little\"", overlapping = "false")]
+    fn find_matches_as_indexes_words(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        word_size: usize,
+        byteorder: &str,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        if word_size == 0 {
+            return Err(PyValueError::new_err("word_size must be positive."));
         }
-        // End index is exclusive (e.g. 0:3 is first 3 characters), so handle
-        // the case where pattern is at end of string.
-        if !haystack.is_empty() {
-            byte_to_code_point[haystack.len()] = max_codepoint + 1;
+        let big_endian = match byteorder {
+            "little" => false,
+            "big" => true,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "byteorder must be \"little\" or \"big\", not {:?}.",
+                    byteorder
+                )))
+            }
+        };
+        if !self_.ac_impl.pattern_len_gcd.is_multiple_of(word_size) {
+            return Err(PyValueError::new_err(format!(
+                "Every pattern's length must be a multiple of word_size={}.",
+                word_size
+            )));
+        }
+        let haystack = buffer_as_bytes(&haystack)?;
+        if !haystack.len().is_multiple_of(word_size) {
+            return Err(PyValueError::new_err(format!(
+                "The haystack's length, {}, isn't a multiple of word_size={}.",
+                haystack.len(),
+                word_size
+            )));
         }
+        let ac_impl = &self_.ac_impl;
+        Ok(self_.py().allow_threads(|| {
+            let swapped: Vec<u8>;
+            let haystack = if big_endian && word_size > 1 {
+                swapped = haystack
+                    .chunks(word_size)
+                    .flat_map(|word| word.iter().rev())
+                    .copied()
+                    .collect();
+                &swapped[..]
+            } else {
+                haystack
+            };
+            find_aligned_matches(ac_impl, haystack, word_size, overlapping)
+                .into_iter()
+                .map(|m| (m.pattern(), m.start() / word_size, m.end() / word_size))
+                .collect()
+        }))
+    }
+
+    /// Like find_matches_as_indexes(), but with the user-provided ID of each
+    /// pattern (see get_id()) instead of its index.
+    #[args(overlapping = "false", whole_word = "false", word_bytes = "None")]
+    fn find_matches_as_ids(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+        whole_word: bool,
+        word_bytes: Option<PyBuffer<u8>>,
+    ) -> PyResult<Vec<(PyObject, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let word_bytes = parse_word_bytes(whole_word, word_bytes)?;
         let py = self_.py();
-        let matches = self_.get_matches(py, haystack, overlapping);
-        Ok(matches
+        let matches = get_bytes_matches(
+            py,
+            &self_.ac_impl,
+            buffer_as_bytes(&haystack)?,
+            overlapping,
+            word_bytes.as_ref(),
+        );
+        let pattern_count = self_.ac_impl.pattern_count();
+        matches
             .into_iter()
             .map(|m| {
-                (
-                    m.pattern(),
-                    byte_to_code_point[m.start()],
-                    byte_to_code_point[m.end()],
-                )
+                Ok((
+                    get_id(py, &self_.ids, pattern_count, m.pattern())?,
+                    m.start(),
+                    m.end(),
+                ))
             })
+            .collect()
+    }
+
+    /// Return the ID passed to the constructor for the given pattern index,
+    /// or the index itself if no IDs were passed.
+    fn get_id(&self, py: Python, pattern_index: usize) -> PyResult<PyObject> {
+        get_id(py, &self.ids, self.ac_impl.pattern_count(), pattern_index)
+    }
+
+    /// Like find_matches_as_indexes(), but with the value passed to the
+    /// constructor for each pattern (see get_value()) instead of its index.
+    #[args(overlapping = "false", whole_word = "false", word_bytes = "None")]
+    fn find_matches_as_values(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+        whole_word: bool,
+        word_bytes: Option<PyBuffer<u8>>,
+    ) -> PyResult<Vec<(PyObject, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let word_bytes = parse_word_bytes(whole_word, word_bytes)?;
+        let values = self_.get_values()?;
+        let py = self_.py();
+        let matches = get_bytes_matches(
+            py,
+            &self_.ac_impl,
+            buffer_as_bytes(&haystack)?,
+            overlapping,
+            word_bytes.as_ref(),
+        );
+        Ok(matches
+            .into_iter()
+            .map(|m| (values[m.pattern()].clone_ref(py), m.start(), m.end()))
+            .collect())
+    }
+
+    /// Return the value passed to the constructor for the given pattern
+    /// index.
+    fn get_value(&self, py: Python, pattern_index: usize) -> PyResult<PyObject> {
+        self.check_pattern_index(pattern_index)?;
+        Ok(self.get_values()?[pattern_index].clone_ref(py))
+    }
+
+    /// Return a list of (pattern, value) tuples, in pattern index order.
+    /// Requires store_patterns=True.
+    fn items(&self, py: Python) -> PyResult<Vec<(PyObject, PyObject)>> {
+        Ok(self
+            .get_patterns()?
+            .iter()
+            .zip(self.get_values()?)
+            .map(|(pattern, value)| (pattern.clone_ref(py), value.clone_ref(py)))
             .collect())
     }
 
-    /// Return matches as list of patterns.
+    /// Return matches as a polars.Series of structs with fields "pattern",
+    /// "start" and "end", with byte offsets, sorted like
+    /// find_matches_as_indexes(). Requires polars to be installed.
     #[args(overlapping = "false")]
-    fn find_matches_as_strings(
+    fn find_matches_as_polars(
         self_: PyRef<Self>,
-        haystack: &str,
+        haystack: PyBuffer<u8>,
         overlapping: bool,
-    ) -> PyResult<Vec<Py<PyUnicode>>> {
-        self_.check_overlapping(overlapping)?;
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
         let py = self_.py();
-        let matches = self_.get_matches(py, haystack, overlapping);
-        Ok(matches
+        let matches = get_matches(
+            py,
+            &self_.ac_impl,
+            buffer_as_bytes(&haystack)?,
+            overlapping,
+            true,
+        );
+        matches_to_polars(
+            py,
+            matches
+                .into_iter()
+                .map(|m| (m.pattern(), m.start(), m.end()))
+                .collect(),
+        )
+    }
+
+    /// Return the set of indexes of patterns that match the haystack.
+    #[args(overlapping = "false")]
+    fn matched_pattern_ids(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+    ) -> PyResult<HashSet<usize>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let haystack = buffer_as_bytes(&haystack)?;
+        Ok(get_matched_pattern_ids(
+            self_.py(),
+            &self_.ac_impl,
+            haystack,
+            overlapping,
+        ))
+    }
+
+    /// Return a list with a bool for each pattern, saying whether it matches
+    /// the haystack. If packed is true, return bytes with one bit per
+    /// pattern instead, in the same layout as numpy.packbits().
+    #[args(overlapping = "false", packed = "false")]
+    fn matched_pattern_mask(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+        packed: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let haystack = buffer_as_bytes(&haystack)?;
+        Ok(get_matched_pattern_mask(
+            self_.py(),
+            &self_.ac_impl,
+            haystack,
+            overlapping,
+            packed,
+        ))
+    }
+
+    /// Return the (start, end) spans of the haystack that aren't covered by
+    /// any match, as byte offsets.
+    #[args(overlapping = "false")]
+    fn find_gaps(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let haystack = buffer_as_bytes(&haystack)?;
+        let covered = get_covered_spans(self_.py(), &self_.ac_impl, haystack, overlapping);
+        Ok(get_gaps(&covered, haystack.len()))
+    }
+
+    /// Return the fraction of the haystack's bytes that are covered by at
+    /// least one match.
+    #[args(overlapping = "false")]
+    fn find_coverage_fraction(
+        self_: PyRef<Self>,
+        haystack: PyBuffer<u8>,
+        overlapping: bool,
+    ) -> PyResult<f64> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let haystack = buffer_as_bytes(&haystack)?;
+        if haystack.is_empty() {
+            return Ok(0.0);
+        }
+        let covered: usize = get_covered_spans(self_.py(), &self_.ac_impl, haystack, overlapping)
+            .into_iter()
+            .map(|(start, end)| end - start)
+            .sum();
+        Ok(covered as f64 / haystack.len() as f64)
+    }
+
+    /// Search each of a sequence of bytes-like haystacks, returning a list
+    /// with the result of find_matches_as_indexes() (with the default
+    /// options) for each. The buffers are all acquired first, then searched
+    /// with the GIL released, spread over threads threads (by default one
+    /// per logical CPU). Small batches are searched on the calling thread.
+    /// first_only works like AhoCorasick's.
+    #[args(overlapping = "false", threads = "None", first_only = "false")]
+    fn find_matches_in_many(
+        self_: PyRef<Self>,
+        haystacks: Vec<PyBuffer<u8>>,
+        overlapping: bool,
+        threads: Option<usize>,
+        first_only: bool,
+    ) -> PyResult<PyObject> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        check_first_only(first_only, overlapping)?;
+        if threads == Some(0) {
+            return Err(PyValueError::new_err("threads must be positive."));
+        }
+        let haystacks = haystacks
+            .iter()
+            .map(buffer_as_bytes)
+            .collect::<PyResult<Vec<_>>>()?;
+        let ac_impl = &self_.ac_impl;
+        let py = self_.py();
+        if first_only {
+            let matches: Vec<Option<(usize, usize, usize)>> = py.allow_threads(|| {
+                map_haystacks_in_parallel(&haystacks, threads, |haystack| {
+                    let m = ac_impl.find_iter(haystack).next()?;
+                    Some((m.pattern(), m.start(), m.end()))
+                })
+            });
+            return Ok(matches.into_py(py));
+        }
+        let matches: Vec<Vec<(usize, usize, usize)>> = py.allow_threads(|| {
+            map_haystacks_in_parallel(&haystacks, threads, |haystack| {
+                find_matches(ac_impl, haystack, overlapping, true, 0)
+                    .into_iter()
+                    .map(|m| (m.pattern(), m.start(), m.end()))
+                    .collect()
+            })
+        });
+        Ok(matches.into_py(py))
+    }
+
+    /// Search a sequence of bytes-like segments as if they were a single
+    /// haystack, e.g. the pieces of a reassembled network stream, returning
+    /// matches like find_matches_as_indexes() with offsets from the start of
+    /// the first segment. Matches can span segments. Up to 1MB in total the
+    /// segments are joined and searched directly; beyond that they're
+    /// streamed through the automaton without being copied into one buffer,
+    /// unless the match kind or overlapping=True rules out streaming.
+    #[args(overlapping = "false")]
+    fn find_matches_as_indexes_segmented(
+        self_: PyRef<Self>,
+        segments: Vec<PyBuffer<u8>>,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let segments = segments
+            .iter()
+            .map(buffer_as_bytes)
+            .collect::<PyResult<Vec<_>>>()?;
+        let total_length: usize = segments.iter().map(|segment| segment.len()).sum();
+        let ac_impl = &self_.ac_impl;
+        let py = self_.py();
+        if total_length >= MIN_STREAM_SEGMENTS_BYTES && !overlapping && ac_impl.supports_stream() {
+            return Ok(py.allow_threads(|| {
+                ac_impl
+                    .stream_find_iter(SegmentsReader::new(&segments))
+                    .map(|m| {
+                        let m = m.expect("reading from memory can't fail");
+                        (m.pattern(), m.start(), m.end())
+                    })
+                    .collect()
+            }));
+        }
+        let haystack = segments.concat();
+        Ok(get_matches(py, ac_impl, &haystack, overlapping, true)
             .into_iter()
-            .map(|m| self_.patterns[m.pattern()].clone_ref(py))
+            .map(|m| (m.pattern(), m.start(), m.end()))
             .collect())
     }
+
+    /// Return a list with whether any pattern matches each of a sequence of
+    /// bytes-like haystacks, searched with the GIL released and stopping at
+    /// the first match in each.
+    fn is_match_many(self_: PyRef<Self>, haystacks: Vec<PyBuffer<u8>>) -> PyResult<Vec<bool>> {
+        let haystacks = haystacks
+            .iter()
+            .map(buffer_as_bytes)
+            .collect::<PyResult<Vec<_>>>()?;
+        let ac_impl = &self_.ac_impl;
+        Ok(self_.py().allow_threads(|| {
+            haystacks
+                .iter()
+                .map(|haystack| ac_impl.is_match(haystack))
+                .collect()
+        }))
+    }
+
+    /// Return matches as tuple of (index_into_patterns,
+    /// start_index_in_haystack, end_index_in_haystack) for a list of chunks
+    /// treated as a single haystack. Indexes are byte offsets into the
+    /// concatenation of the chunks, and matches can span chunk boundaries.
+    #[args(overlapping = "false")]
+    fn find_matches_across_chunks(
+        self_: PyRef<Self>,
+        chunks: &PyAny,
+        overlapping: bool,
+    ) -> PyResult<Vec<(usize, usize, usize)>> {
+        check_overlapping(&self_.ac_impl, overlapping)?;
+        let py = self_.py();
+        let mut buffers: Vec<PyBuffer<u8>> = vec![];
+        for chunk in chunks.iter()? {
+            buffers.push(chunk?.extract()?);
+        }
+        let chunks = buffers
+            .iter()
+            .map(buffer_as_bytes)
+            .collect::<PyResult<Vec<_>>>()?;
+        let ac_impl = &self_.ac_impl;
+        Ok(py.allow_threads(|| find_matches_across_chunks(ac_impl, &chunks, overlapping)))
+    }
+}
+
+/// Check that patterns (any iterable) could be used to build an AhoCorasick,
+/// without building it, and return how many there are. Raises a TypeError
+/// for a pattern that isn't a str, or a ValueError for an empty one, or one
+/// that can't be encoded as UTF-8; the message includes its index.
+#[pyfunction]
+fn validate_patterns(patterns: &PyAny) -> PyResult<usize> {
+    if patterns.is_instance_of::<PyUnicode>()? {
+        return Err(PyTypeError::new_err(
+            "patterns must be an iterable of str, not a single str.",
+        ));
+    }
+    let mut count = 0;
+    for (index, pattern) in patterns.iter()?.enumerate() {
+        let pattern = pattern?;
+        let pattern = pattern.downcast::<PyUnicode>().map_err(|_| {
+            PyTypeError::new_err(format!(
+                "Pattern at index {} must be a str, got {}.",
+                index,
+                pattern.get_type().name().unwrap_or("?")
+            ))
+        })?;
+        let pattern = pattern.to_str().map_err(|_| {
+            PyValueError::new_err(format!(
+                "Pattern at index {} can't be encoded as UTF-8.",
+                index
+            ))
+        })?;
+        if pattern.is_empty() {
+            return Err(PyValueError::new_err(format!(
+                "Pattern at index {} is empty.",
+                index
+            )));
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Convert a UTF-8 byte offset into haystack (a str, Haystack or UTF-8
+/// bytes-like object) into a code point offset, e.g. to compare
+/// BytesAhoCorasick results with AhoCorasick ones. Raises a ValueError if the
+/// offset is past the end or not on a character boundary.
+#[pyfunction]
+fn byte_to_codepoint(haystack: HaystackArg, byte_offset: usize) -> PyResult<usize> {
+    let bytes = haystack.as_bytes();
+    if byte_offset > bytes.len() {
+        return Err(PyValueError::new_err(format!(
+            "Byte offset {} is past the end of the haystack, which is {} bytes long.",
+            byte_offset,
+            bytes.len()
+        )));
+    }
+    if bytes
+        .get(byte_offset)
+        .copied()
+        .is_some_and(is_continuation_byte)
+    {
+        return Err(PyValueError::new_err(format!(
+            "Byte offset {} isn't on a character boundary.",
+            byte_offset
+        )));
+    }
+    Ok(bytes[..byte_offset]
+        .iter()
+        .filter(|byte| !is_continuation_byte(**byte))
+        .count())
+}
+
+/// Convert a code point offset into haystack (a str, Haystack or UTF-8
+/// bytes-like object) into a UTF-8 byte offset; the inverse of
+/// byte_to_codepoint(). Raises a ValueError if the offset is past the end.
+#[pyfunction]
+fn codepoint_to_byte(haystack: HaystackArg, codepoint_offset: usize) -> PyResult<usize> {
+    let bytes = haystack.as_bytes();
+    let length = bytes
+        .iter()
+        .filter(|byte| !is_continuation_byte(**byte))
+        .count();
+    if codepoint_offset > length {
+        return Err(PyValueError::new_err(format!(
+            "Code point offset {} is past the end of the haystack, which is {} code points long.",
+            codepoint_offset, length
+        )));
+    }
+    Ok(code_point_to_byte(bytes, codepoint_offset))
+}
+
+/// How many haystacks filter() converts before releasing the GIL to search
+/// them, so the GIL is released regularly without any one chunk of
+/// converted haystacks getting big.
+const FILTER_CHUNK_SIZE: usize = 1024;
+
+/// How many records BytesAhoCorasick.from_file() reads with the GIL released
+/// before briefly reacquiring it, so other threads and signal handlers get
+/// to run while a big file is read.
+const FROM_FILE_BATCH_SIZE: usize = 10_000;
+
+/// How many automatons get_or_build() keeps.
+const BUILD_CACHE_SIZE: usize = 32;
+
+/// A digest of the patterns get_or_build() was called with, along with their
+/// count and total length in bytes, plus the matchkind and implementation.
+/// Digests can collide, so a hit is checked against the cached automaton's
+/// own patterns; that way the cache doesn't keep a second copy of them.
+type BuildCacheKey = (u64, usize, usize, &'static str, Implementation);
+
+/// The automatons built by get_or_build(). Only locked while the GIL is held
+/// and never across a build, since building releases the GIL.
+static BUILD_CACHE: GILOnceCell<Mutex<LruCache<BuildCacheKey, Py<PyAhoCorasick>>>> =
+    GILOnceCell::new();
+
+fn build_cache(py: Python) -> &'static Mutex<LruCache<BuildCacheKey, Py<PyAhoCorasick>>> {
+    BUILD_CACHE.get_or_init(py, || Mutex::new(LruCache::new(BUILD_CACHE_SIZE)))
+}
+
+/// Return an AhoCorasick built from the given patterns and options, reusing
+/// one from a process-wide cache if the same patterns and options were
+/// passed before. The cache keeps the 32 most recently used automatons,
+/// which keep their patterns as Python strings, so memory use is that of the
+/// automatons themselves. Cached automatons are shared between callers, so
+/// drop_patterns() raises a TypeError on them.
+#[pyfunction(matchkind = "\"MATCHKIND_STANDARD\"", implementation = "None")]
+fn get_or_build(
+    py: Python,
+    patterns: &PyAny,
+    matchkind: &str,
+    implementation: Option<&str>,
+) -> PyResult<Py<PyAhoCorasick>> {
+    let matchkind = parse_matchkind(matchkind)?;
+    let kind = parse_implementation(implementation)?;
+    let patterns = extract_str_patterns(patterns)?;
+    let texts = patterns
+        .iter()
+        .map(|p| p.as_ref(py).to_str())
+        .collect::<PyResult<Vec<&str>>>()?;
+    let mut hasher = DefaultHasher::new();
+    texts.hash(&mut hasher);
+    let total_len = texts.iter().map(|text| text.len()).sum();
+    let key = (
+        hasher.finish(),
+        texts.len(),
+        total_len,
+        matchkind_name(matchkind),
+        kind,
+    );
+    if let Some(ac) = build_cache(py).lock().unwrap().get(&key) {
+        let same_patterns = match &ac.borrow(py).patterns {
+            Some(stored) => stored.texts(py)? == texts,
+            None => false,
+        };
+        if same_patterns {
+            return Ok(ac.clone_ref(py));
+        }
+    }
+    let mut ac = PyAhoCorasick::from_patterns_with_progress(
+        py,
+        patterns,
+        matchkind,
+        kind,
+        &BuildProgress::none(),
+    )?;
+    ac.cached = true;
+    let ac = Py::new(py, ac)?;
+    build_cache(py).lock().unwrap().put(key, ac.clone_ref(py));
+    Ok(ac)
+}
+
+/// Empty get_or_build()'s cache.
+#[pyfunction]
+fn clear_cache(py: Python) {
+    build_cache(py).lock().unwrap().clear();
+}
+
+/// Build an AhoCorasick from the given patterns and options, returning
+/// (automaton, None) on success or (None, error message) if the constructor
+/// would raise a TypeError or ValueError, e.g. for a pattern of the wrong
+/// type or an unknown matchkind. Other exceptions are still raised.
+#[pyfunction(matchkind = "\"MATCHKIND_STANDARD\"", implementation = "None")]
+fn try_build(
+    py: Python,
+    patterns: &PyAny,
+    matchkind: &str,
+    implementation: Option<&str>,
+) -> PyResult<(Option<Py<PyAhoCorasick>>, Option<String>)> {
+    let build = || -> PyResult<Py<PyAhoCorasick>> {
+        let matchkind = parse_matchkind(matchkind)?;
+        let kind = parse_implementation(implementation)?;
+        let patterns = extract_str_patterns(patterns)?;
+        Py::new(
+            py,
+            PyAhoCorasick::from_patterns_with_progress(
+                py,
+                patterns,
+                matchkind,
+                kind,
+                &BuildProgress::none(),
+            )?,
+        )
+    };
+    match build() {
+        Ok(ac) => Ok((Some(ac), None)),
+        Err(err)
+            if err.is_instance_of::<PyTypeError>(py) || err.is_instance_of::<PyValueError>(py) =>
+        {
+            Ok((None, Some(err.value(py).str()?.to_str()?.to_owned())))
+        }
+        Err(err) => Err(err),
+    }
 }
 
 /// The main Python module.
 #[pymodule]
-fn ahocorasick_rs(_py: Python, m: &PyModule) -> PyResult<()> {
+fn ahocorasick_rs(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyAhoCorasick>()?;
+    m.add_class::<PyBytesAhoCorasick>()?;
+    m.add_class::<PyHaystack>()?;
+    m.add_class::<PyCachingAhoCorasick>()?;
+    m.add_class::<PyBuildInfo>()?;
+    m.add_function(wrap_pyfunction!(validate_patterns, m)?)?;
+    m.add_function(wrap_pyfunction!(byte_to_codepoint, m)?)?;
+    m.add_function(wrap_pyfunction!(codepoint_to_byte, m)?)?;
+    m.add_function(wrap_pyfunction!(get_or_build, m)?)?;
+    m.add_function(wrap_pyfunction!(clear_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(try_build, m)?)?;
+    m.add("AhoCorasickError", py.get_type::<AhoCorasickError>())?;
     // PyO3 doesn't support auto-wrapping Enums, so we just do it manually.
     m.add("MATCHKIND_STANDARD", "MATCHKIND_STANDARD")?;
     m.add("MATCHKIND_LEFTMOST_FIRST", "MATCHKIND_LEFTMOST_FIRST")?;
     m.add("MATCHKIND_LEFTMOST_LONGEST", "MATCHKIND_LEFTMOST_LONGEST")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_to_code_point_skips_table_for_ascii() {
+        for haystack in ["", "hello world", "\x00\x7f"] {
+            assert!(matches!(
+                get_byte_to_code_point(haystack.as_bytes()),
+                ByteToCodePoint::Identity
+            ));
+        }
+        for haystack in ["héllo", "\u{80}", "hello ☃"] {
+            assert!(matches!(
+                get_byte_to_code_point(haystack.as_bytes()),
+                ByteToCodePoint::Table(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn byte_to_code_point_table() {
+        let byte_to_code_point = get_byte_to_code_point("a☃b".as_bytes());
+        let offsets: Vec<usize> = [0, 1, 4, 5]
+            .iter()
+            .map(|&byte_offset| byte_to_code_point.get(byte_offset))
+            .collect();
+        assert_eq!(offsets, vec![0, 1, 2, 3]);
+        assert_eq!(get_byte_to_code_point(b"ab").get(2), 2);
+    }
+
+    #[test]
+    fn byte_to_code_point_empty() {
+        // The only valid offset into an empty haystack is 0, which maps to 0:
+        assert_eq!(get_byte_to_code_point(b"").get(0), 0);
+        assert_eq!(code_point_to_byte(b"", 0), 0);
+        assert_eq!(code_point_to_byte(b"", 3), 0);
+    }
+
+    #[test]
+    fn byte_to_code_point_surrogates() {
+        // "a\ud800b" encoded with surrogatepass:
+        let haystack = b"a\xed\xa0\x80b";
+        let byte_to_code_point = get_byte_to_code_point(haystack);
+        assert_eq!(byte_to_code_point.get(4), 2);
+        assert_eq!(byte_to_code_point.get(5), 3);
+        assert_eq!(code_point_to_byte(haystack, 2), 4);
+        assert_eq!(code_point_to_byte(haystack, 10), 5);
+    }
+}
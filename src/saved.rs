@@ -0,0 +1,224 @@
+//! The file format used by save() and load(). It's internal and may change
+//! between releases; the version number lets load() reject files written in
+//! a format it doesn't understand.
+//!
+//! All integers are little-endian:
+//!
+//! * The magic bytes b"ahocorasick_rs\0".
+//! * The format version, a u32.
+//! * The class, a u8: 0 for AhoCorasick, 1 for BytesAhoCorasick.
+//! * The match kind, a u8: 0 for standard, 1 for leftmost-first, 2 for
+//!   leftmost-longest.
+//! * Flags, a u8: bit 0 is set to return byte offsets (see
+//!   force_byte_offsets_on_ascii), bit 1 if a mapping from sorted to original
+//!   pattern indexes follows the patterns (see from_counter()), bit 2 if the
+//!   implementation is "noncontiguous" rather than "dfa".
+//! * The number of patterns, a u64, followed by each pattern as a u64 length
+//!   and that many bytes.
+//! * If flag bit 1 is set, one u64 original index per pattern.
+
+use std::{convert::TryInto, path::Path};
+
+use aho_corasick::MatchKind;
+use pyo3::{exceptions::PyValueError, PyResult};
+
+use crate::Implementation;
+
+const MAGIC: &[u8] = b"ahocorasick_rs\0";
+const VERSION: u32 = 1;
+
+const FLAG_BYTE_OFFSETS: u8 = 1;
+const FLAG_SORTED_TO_ORIGINAL: u8 = 2;
+const FLAG_NONCONTIGUOUS: u8 = 4;
+
+/// Which class a file was saved from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    AhoCorasick = 0,
+    BytesAhoCorasick = 1,
+}
+
+impl Class {
+    fn name(self) -> &'static str {
+        match self {
+            Class::AhoCorasick => "AhoCorasick",
+            Class::BytesAhoCorasick => "BytesAhoCorasick",
+        }
+    }
+}
+
+/// The contents of a saved automaton.
+pub struct Saved<P> {
+    pub class: Class,
+    pub matchkind: MatchKind,
+    pub implementation: Implementation,
+    pub byte_offsets: bool,
+    pub patterns: Vec<P>,
+    pub sorted_to_original: Option<Vec<usize>>,
+}
+
+/// Serialize an automaton's patterns and construction options.
+pub fn serialize<P: AsRef<[u8]>>(saved: &Saved<P>) -> Vec<u8> {
+    let mut result = Vec::with_capacity(
+        MAGIC.len()
+            + 16
+            + saved
+                .patterns
+                .iter()
+                .map(|p| p.as_ref().len() + 8)
+                .sum::<usize>(),
+    );
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&VERSION.to_le_bytes());
+    result.push(saved.class as u8);
+    result.push(match saved.matchkind {
+        MatchKind::Standard => 0,
+        MatchKind::LeftmostFirst => 1,
+        _ => 2,
+    });
+    let mut flags = 0;
+    if saved.byte_offsets {
+        flags |= FLAG_BYTE_OFFSETS;
+    }
+    if saved.sorted_to_original.is_some() {
+        flags |= FLAG_SORTED_TO_ORIGINAL;
+    }
+    if saved.implementation == Implementation::NonContiguous {
+        flags |= FLAG_NONCONTIGUOUS;
+    }
+    result.push(flags);
+    result.extend_from_slice(&(saved.patterns.len() as u64).to_le_bytes());
+    for pattern in saved.patterns.iter() {
+        let pattern = pattern.as_ref();
+        result.extend_from_slice(&(pattern.len() as u64).to_le_bytes());
+        result.extend_from_slice(pattern);
+    }
+    if let Some(sorted_to_original) = &saved.sorted_to_original {
+        for &index in sorted_to_original.iter() {
+            result.extend_from_slice(&(index as u64).to_le_bytes());
+        }
+    }
+    result
+}
+
+/// Reads from the serialized data, failing rather than panicking if it's
+/// too short.
+struct Reader<'a> {
+    data: &'a [u8],
+    path: &'a Path,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> pyo3::PyErr {
+        PyValueError::new_err(format!(
+            "Couldn't load {}: {}",
+            self.path.display(),
+            message
+        ))
+    }
+
+    fn take(&mut self, length: u64) -> PyResult<&'a [u8]> {
+        if length > self.data.len() as u64 {
+            return Err(self.error("the file is truncated."));
+        }
+        let (result, rest) = self.data.split_at(length as usize);
+        self.data = rest;
+        Ok(result)
+    }
+
+    fn u8(&mut self) -> PyResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> PyResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> PyResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Deserialize an automaton saved from the given class, returning the
+/// patterns as slices of data.
+pub fn deserialize<'a>(data: &'a [u8], path: &'a Path, class: Class) -> PyResult<Saved<&'a [u8]>> {
+    let mut reader = Reader { data, path };
+    if !data.starts_with(MAGIC) {
+        return Err(reader.error("it isn't a saved ahocorasick_rs automaton."));
+    }
+    reader.take(MAGIC.len() as u64)?;
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(reader.error(&format!(
+            "it was saved in format version {}, but only version {} is supported.",
+            version, VERSION
+        )));
+    }
+    let saved_class = match reader.u8()? {
+        0 => Class::AhoCorasick,
+        1 => Class::BytesAhoCorasick,
+        _ => return Err(reader.error("it has an unknown class.")),
+    };
+    if saved_class != class {
+        return Err(reader.error(&format!(
+            "it was saved from a {}, not a {}.",
+            saved_class.name(),
+            class.name()
+        )));
+    }
+    let matchkind = match reader.u8()? {
+        0 => MatchKind::Standard,
+        1 => MatchKind::LeftmostFirst,
+        2 => MatchKind::LeftmostLongest,
+        _ => return Err(reader.error("it has an unknown match kind.")),
+    };
+    let flags = reader.u8()?;
+    if flags & !(FLAG_BYTE_OFFSETS | FLAG_SORTED_TO_ORIGINAL | FLAG_NONCONTIGUOUS) != 0 {
+        return Err(reader.error("it has unknown flags set."));
+    }
+    let pattern_count = reader.u64()?;
+    // Each pattern takes at least 8 bytes, so this also stops a corrupt
+    // count from allocating a huge Vec:
+    if pattern_count > reader.data.len() as u64 / 8 {
+        return Err(reader.error("the file is truncated."));
+    }
+    let mut patterns = Vec::with_capacity(pattern_count as usize);
+    for _ in 0..pattern_count {
+        let length = reader.u64()?;
+        patterns.push(reader.take(length)?);
+    }
+    let sorted_to_original = if flags & FLAG_SORTED_TO_ORIGINAL != 0 {
+        let mut sorted_to_original = Vec::with_capacity(patterns.len());
+        // It has to be a permutation, or some patterns would map to the
+        // same original index and others to none:
+        let mut seen = vec![false; patterns.len()];
+        for _ in 0..patterns.len() {
+            let index = reader.u64()?;
+            if index >= patterns.len() as u64 {
+                return Err(reader.error("it has an out of range pattern index."));
+            }
+            if std::mem::replace(&mut seen[index as usize], true) {
+                return Err(reader.error("it has a duplicate pattern index."));
+            }
+            sorted_to_original.push(index as usize);
+        }
+        Some(sorted_to_original)
+    } else {
+        None
+    };
+    if !reader.data.is_empty() {
+        return Err(reader.error("it has unexpected data at the end."));
+    }
+    Ok(Saved {
+        class,
+        matchkind,
+        implementation: if flags & FLAG_NONCONTIGUOUS != 0 {
+            Implementation::NonContiguous
+        } else {
+            Implementation::Dfa
+        },
+        byte_offsets: flags & FLAG_BYTE_OFFSETS != 0,
+        patterns,
+        sorted_to_original,
+    })
+}
@@ -1,5 +1,8 @@
 """Tests for ahocorasick_rs."""
 
+import copy
+import sys
+
 import pytest
 
 from hypothesis import strategies as st
@@ -7,9 +10,18 @@ from hypothesis import given, assume
 
 from ahocorasick_rs import (
     AhoCorasick,
+    AhoCorasickError,
+    BytesAhoCorasick,
+    Haystack,
     MATCHKIND_STANDARD,
     MATCHKIND_LEFTMOST_FIRST,
     MATCHKIND_LEFTMOST_LONGEST,
+    byte_to_codepoint,
+    clear_cache,
+    codepoint_to_byte,
+    get_or_build,
+    try_build,
+    validate_patterns,
 )
 
 
@@ -154,3 +166,3887 @@ def test_overlapping():
     # Other matchkinds don't support overlapping.
     assert_no_overlapping(AhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_FIRST))
     assert_no_overlapping(AhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_LONGEST))
+
+
+@pytest.mark.parametrize(
+    "order,expected,expected_bytes",
+    [
+        ("start", ["é", "ébc", "b", "bc"], None),
+        ("end", ["é", "b", "ébc", "bc"], None),
+        # Lengths are in code points for str, bytes for bytes:
+        ("length", ["ébc", "bc", "é", "b"], ["ébc", "é", "bc", "b"]),
+    ],
+)
+def test_match_order(order, expected, expected_bytes):
+    """
+    order sorts overlapping matches by start, end or length, with nested
+    matches innermost or outermost first.
+    """
+    patterns = ["b", "bc", "ébc", "é"]
+    ac = AhoCorasick(patterns)
+    haystack = "xébc"
+    assert (
+        ac.find_matches_as_strings(haystack, overlapping=True, order=order) == expected
+    )
+    indexes = ac.find_matches_as_indexes(haystack, overlapping=True, order=order)
+    assert [patterns[i] for (i, _, _) in indexes] == expected
+    assert [haystack[s:e] for (_, s, e) in indexes] == expected
+
+    bytes_ac = BytesAhoCorasick([p.encode() for p in patterns])
+    result = bytes_ac.find_matches_as_indexes(
+        haystack.encode(), overlapping=True, order=order
+    )
+    assert [patterns[i] for (i, _, _) in result] == (expected_bytes or expected)
+
+
+def test_match_order_errors():
+    """
+    order must be a known order, and can't be combined with sort=False.
+    """
+    ac = AhoCorasick(["a", "ab"])
+    assert ac.find_matches_as_indexes(
+        "ab", overlapping=True, order="start"
+    ) == ac.find_matches_as_indexes("ab", overlapping=True)
+    with pytest.raises(ValueError, match="order must be"):
+        ac.find_matches_as_indexes("ab", order="middle")
+    with pytest.raises(ValueError, match="sort=False"):
+        ac.find_matches_as_indexes("ab", order="end", sort=False)
+    with pytest.raises(ValueError, match="order must be"):
+        ac.find_matches_as_strings("ab", order="nope")
+    with pytest.raises(ValueError, match="sort=False"):
+        BytesAhoCorasick([b"a"]).find_matches_as_indexes(b"a", order="end", sort=False)
+
+
+def test_bytes_basic_matching():
+    """
+    BytesAhoCorasick.find_matches_as_indexes() returns matching patterns, with
+    byte offsets, in any buffer-protocol haystack.
+    """
+    patterns = [b"hello", bytearray(b"world"), memoryview(b"\x00\xff")]
+    ac = BytesAhoCorasick(patterns)
+    haystack = b"hello, world \x00\xff hello again"
+    expected = [(0, 0, 5), (1, 7, 12), (2, 13, 15), (0, 16, 21)]
+    assert ac.find_matches_as_indexes(haystack) == expected
+    assert ac.find_matches_as_indexes(bytearray(haystack)) == expected
+    assert ac.find_matches_as_indexes(memoryview(haystack)) == expected
+
+
+def test_bytes_from_file(tmp_path):
+    """
+    BytesAhoCorasick.from_file() loads patterns split on a delimiter, skipping
+    empty records.
+    """
+    path = tmp_path / "patterns.txt"
+    path.write_bytes(b"hello\n\nworld\n")
+    ac = BytesAhoCorasick.from_file(path)
+    assert ac.find_matches_as_indexes(b"hello world") == [(0, 0, 5), (1, 6, 11)]
+
+    # Multi-byte delimiter, with NUL bytes inside patterns:
+    path.write_bytes(b"\x00a\x00\r\n\r\nb\x00\r\n")
+    ac = BytesAhoCorasick.from_file(str(path), delimiter=b"\r\n")
+    assert ac.find_matches_as_indexes(b"\x00a\x00b\x00") == [(0, 0, 3), (1, 3, 5)]
+
+    # Matchkind is supported:
+    path.write_bytes(b"disc\x00disco")
+    ac = BytesAhoCorasick.from_file(
+        path, delimiter=b"\x00", matchkind=MATCHKIND_LEFTMOST_LONGEST
+    )
+    assert ac.find_matches_as_indexes(b"discontent") == [(1, 0, 5)]
+
+    with pytest.raises(ValueError):
+        BytesAhoCorasick.from_file(path, delimiter=b"")
+    with pytest.raises(IOError):
+        BytesAhoCorasick.from_file(tmp_path / "nonexistent.txt")
+
+
+def test_bytes_from_file_lines(tmp_path):
+    """
+    BytesAhoCorasick.from_file() strips "\\r" from CRLF lines unless
+    strip_newlines=False, and max_patterns limits how many patterns it loads.
+    """
+    path = tmp_path / "patterns.txt"
+    path.write_bytes(b"a\x00b\r\n\r\n\x00c\r\nd")
+    ac = BytesAhoCorasick.from_file(path)
+    assert ac.patterns_len == 3
+    assert ac.find_matches_as_indexes(b"a\x00b\x00c\r\nd") == [
+        (0, 0, 3),
+        (1, 3, 5),
+        (2, 7, 8),
+    ]
+
+    ac = BytesAhoCorasick.from_file(path, strip_newlines=False)
+    assert ac.patterns_len == 4
+    assert ac.find_matches_as_indexes(b"a\x00b\x00c\r\nd") == [
+        (2, 3, 6),
+        (3, 7, 8),
+    ]
+
+    # Only applies when splitting on the default newline:
+    ac = BytesAhoCorasick.from_file(path, delimiter=b"\x00")
+    assert ac.find_matches_as_indexes(b"b\r\n\r\n") == [(1, 0, 5)]
+
+    assert BytesAhoCorasick.from_file(path, max_patterns=3).patterns_len == 3
+    with pytest.raises(ValueError, match="max_patterns=2"):
+        BytesAhoCorasick.from_file(path, max_patterns=2)
+
+
+def test_bytes_from_file_large(tmp_path):
+    """
+    BytesAhoCorasick.from_file() reads files of more than one batch of
+    records, supports implementation, and stops at max_patterns.
+    """
+    path = tmp_path / "patterns.txt"
+    path.write_bytes(b"".join(b"<%d>\r\n" % i for i in range(25_000)))
+    ac = BytesAhoCorasick.from_file(path, implementation="noncontiguous")
+    assert ac.patterns_len == 25_000
+    assert ac.implementation == "noncontiguous"
+    assert ac.find_matches_as_indexes(b"<0> <24999>") == [(0, 0, 3), (24999, 4, 11)]
+
+    ac = BytesAhoCorasick.from_file(path, delimiter=b"\r\n")
+    assert ac.patterns_len == 25_000
+    with pytest.raises(ValueError, match="more than max_patterns=20000"):
+        BytesAhoCorasick.from_file(path, max_patterns=20_000)
+    with pytest.raises(ValueError):
+        BytesAhoCorasick.from_file(path, implementation="nope")
+
+
+def test_sorted_indexes():
+    """
+    find_matches_as_indexes() returns matches sorted by (start, end, index) by
+    default; sort=False returns them in the order the automaton found them.
+    """
+    haystack = "This is the winter of my discontent"
+    patterns = ["content", "disco", "disc", "discontent", "winter", "is", "s"]
+
+    def sort_key(match):
+        return (match[1], match[2], match[0])
+
+    for matchkind in [
+        MATCHKIND_STANDARD,
+        MATCHKIND_LEFTMOST_FIRST,
+        MATCHKIND_LEFTMOST_LONGEST,
+    ]:
+        ac = AhoCorasick(patterns, matchkind=matchkind)
+        bytes_ac = BytesAhoCorasick(
+            [p.encode("utf-8") for p in patterns], matchkind=matchkind
+        )
+        for overlapping in [False, True]:
+            if overlapping and matchkind != MATCHKIND_STANDARD:
+                continue
+            for a, h in [(ac, haystack), (bytes_ac, haystack.encode("utf-8"))]:
+                result = a.find_matches_as_indexes(h, overlapping=overlapping)
+                assert result == sorted(result, key=sort_key)
+                assert result == a.find_matches_as_indexes(
+                    h, overlapping=overlapping, sort=True
+                )
+                unsorted = a.find_matches_as_indexes(
+                    h, overlapping=overlapping, sort=False
+                )
+                assert sorted(unsorted, key=sort_key) == result
+
+
+def test_sorted_overlapping_indexes():
+    """
+    Overlapping matches are found in order of their end position, so sorting
+    changes the order; with sort=True start positions never decrease.
+    """
+    ac = AhoCorasick(["abcd", "b", "bc"])
+    assert ac.find_matches_as_indexes("abcd", overlapping=True, sort=False) == [
+        (1, 1, 2),
+        (2, 1, 3),
+        (0, 0, 4),
+    ]
+    result = ac.find_matches_as_indexes("abcd", overlapping=True)
+    assert result == [(0, 0, 4), (1, 1, 2), (2, 1, 3)]
+    starts = [start for (_, start, _) in result]
+    assert starts == sorted(starts)
+
+
+def test_deduplicate_spans():
+    """
+    With deduplicate_spans=True, a span matched by several patterns is only
+    reported once, for the pattern with the lowest index.
+    """
+    ac = AhoCorasick(["bc", "abc", "abc", "bc"])
+    haystack = "abcé abc"
+    assert ac.find_matches_as_indexes(haystack, overlapping=True) == [
+        (1, 0, 3),
+        (2, 0, 3),
+        (0, 1, 3),
+        (3, 1, 3),
+        (1, 5, 8),
+        (2, 5, 8),
+        (0, 6, 8),
+        (3, 6, 8),
+    ]
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, deduplicate_spans=True
+    ) == [(1, 0, 3), (0, 1, 3), (1, 5, 8), (0, 6, 8)]
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, deduplicate_spans=True, sort=False
+    ) == [(1, 0, 3), (0, 1, 3), (1, 5, 8), (0, 6, 8)]
+    assert ac.find_matches_as_indexes(haystack, deduplicate_spans=True) == (
+        ac.find_matches_as_indexes(haystack)
+    )
+
+    bytes_ac = BytesAhoCorasick([b"abc", b"abc"])
+    assert bytes_ac.find_matches_as_indexes(
+        b"abcabc", overlapping=True, deduplicate_spans=True
+    ) == [(0, 0, 3), (0, 3, 6)]
+
+
+def test_from_delimited():
+    """
+    AhoCorasick.from_delimited() and BytesAhoCorasick.from_delimited() split a
+    buffer into patterns on a delimiter.
+    """
+    blob = "hello\nworld\n\nsnow☃\n".encode("utf-8")
+    ac = AhoCorasick.from_delimited(blob)
+    haystack = "hello snow☃ world"
+    assert ac.find_matches_as_strings(haystack) == ["hello", "snow☃", "world"]
+    assert ac.find_matches_as_indexes(haystack) == [
+        (0, 0, 5),
+        (2, 6, 11),
+        (1, 12, 17),
+    ]
+    bytes_ac = BytesAhoCorasick.from_delimited(bytearray(blob))
+    assert bytes_ac.find_matches_as_indexes(b"hello world") == [
+        (0, 0, 5),
+        (1, 6, 11),
+    ]
+
+    # Multi-byte delimiter and matchkind:
+    for cls, haystack in [
+        (AhoCorasick, "discontent"),
+        (BytesAhoCorasick, b"discontent"),
+    ]:
+        ac = cls.from_delimited(
+            memoryview(b"disc||disco"),
+            delimiter=b"||",
+            matchkind=MATCHKIND_LEFTMOST_LONGEST,
+        )
+        assert ac.find_matches_as_indexes(haystack) == [(1, 0, 5)]
+        ac = cls.from_delimited(blob, implementation="noncontiguous")
+        assert ac.implementation == "noncontiguous"
+
+    # Empty records can be rejected:
+    for cls in [AhoCorasick, BytesAhoCorasick]:
+        with pytest.raises(ValueError, match="index 2"):
+            cls.from_delimited(blob, skip_empty=False)
+        with pytest.raises(ValueError):
+            cls.from_delimited(blob, delimiter=b"")
+
+    # Invalid UTF-8 is rejected by the string class:
+    with pytest.raises(ValueError, match="index 1"):
+        AhoCorasick.from_delimited(b"abc\n\xff")
+    bytes_ac = BytesAhoCorasick.from_delimited(b"abc\n\xff")
+    assert bytes_ac.find_matches_as_indexes(b"\xff") == [(1, 0, 1)]
+
+
+def test_find_matches_across_chunks():
+    """
+    BytesAhoCorasick.find_matches_across_chunks() gives the same results as
+    searching the concatenation of the chunks.
+    """
+    patterns = [b"hello", b"world", b"low", b"o"]
+    haystack = b"hello, world, hello again, oh hello world"
+    for matchkind in [
+        MATCHKIND_STANDARD,
+        MATCHKIND_LEFTMOST_FIRST,
+        MATCHKIND_LEFTMOST_LONGEST,
+    ]:
+        ac = BytesAhoCorasick(patterns, matchkind=matchkind)
+        for overlapping in [False, True]:
+            if overlapping and matchkind != MATCHKIND_STANDARD:
+                continue
+            expected = ac.find_matches_as_indexes(
+                haystack, overlapping=overlapping, sort=False
+            )
+            for chunk_size in [1, 2, 3, 7, 100]:
+                chunks = [
+                    haystack[i : i + chunk_size]
+                    for i in range(0, len(haystack), chunk_size)
+                ]
+                # Empty chunks and different buffer types are fine:
+                chunks.insert(1, b"")
+                chunks[0] = bytearray(chunks[0])
+                assert (
+                    ac.find_matches_across_chunks(chunks, overlapping=overlapping)
+                    == expected
+                )
+
+    ac = BytesAhoCorasick([b"hello", b"world"])
+    # Matches spanning chunk boundaries are found:
+    assert ac.find_matches_across_chunks([b"he", b"l", b"lo w", b"orld"]) == [
+        (0, 0, 5),
+        (1, 6, 11),
+    ]
+    assert ac.find_matches_across_chunks([]) == []
+    ac = BytesAhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_FIRST)
+    with pytest.raises(ValueError):
+        ac.find_matches_across_chunks([b"hello"], overlapping=True)
+
+
+def test_bytes_from_numpy():
+    """
+    BytesAhoCorasick.from_numpy() treats each item of a fixed-width byte
+    string array as a pattern.
+    """
+    np = pytest.importorskip("numpy")
+    array = np.array([b"hello", b"ab\x00c", b"w"], dtype="S8")
+    ac = BytesAhoCorasick.from_numpy(array)
+    assert ac.find_matches_as_indexes(b"hello w ab\x00c") == [
+        (0, 0, 5),
+        (2, 6, 7),
+        (1, 8, 12),
+    ]
+
+    # NUL padding can be kept:
+    ac = BytesAhoCorasick.from_numpy(array, keep_nul_padding=True)
+    assert ac.find_matches_as_indexes(b"w\x00\x00\x00\x00\x00\x00\x00 w") == [
+        (2, 0, 8)
+    ]
+
+    # Matchkind is supported:
+    array = np.array([b"disc", b"disco"], dtype="S5")
+    ac = BytesAhoCorasick.from_numpy(array, matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    assert ac.find_matches_as_indexes(b"discontent") == [(1, 0, 5)]
+    ac = BytesAhoCorasick.from_numpy(array, implementation="noncontiguous")
+    assert ac.implementation == "noncontiguous"
+
+    # Multi-dimensional arrays and non-byte-string arrays are rejected:
+    with pytest.raises(ValueError, match="1-dimensional"):
+        BytesAhoCorasick.from_numpy(np.array([[b"a", b"b"]], dtype="S1"))
+    with pytest.raises(ValueError):
+        BytesAhoCorasick.from_numpy(np.array([1, 2, 3], dtype=np.int64))
+
+
+def test_find_gaps():
+    """
+    find_gaps() returns the spans not covered by any match, and
+    find_coverage_fraction() the fraction of the haystack that is covered.
+    """
+    ac = AhoCorasick(["hello", "world", "low", "☃"])
+    bytes_ac = BytesAhoCorasick([b"hello", b"world", b"low"])
+
+    # No matches gives one gap covering the whole haystack:
+    assert ac.find_gaps("nothing") == [(0, 7)]
+    assert ac.find_coverage_fraction("nothing") == 0.0
+    assert bytes_ac.find_gaps(b"nothing") == [(0, 7)]
+    assert bytes_ac.find_coverage_fraction(b"nothing") == 0.0
+
+    # Full coverage gives no gaps:
+    assert ac.find_gaps("helloworld") == []
+    assert ac.find_coverage_fraction("helloworld") == 1.0
+    assert bytes_ac.find_gaps(b"helloworld") == []
+    assert bytes_ac.find_coverage_fraction(b"helloworld") == 1.0
+
+    # Empty haystack:
+    assert ac.find_gaps("") == []
+    assert ac.find_coverage_fraction("") == 0.0
+    assert bytes_ac.find_gaps(b"") == []
+    assert bytes_ac.find_coverage_fraction(b"") == 0.0
+
+    # Offsets are code points for AhoCorasick, bytes for BytesAhoCorasick:
+    haystack = "☃ hello ☃☃ world!"
+    assert ac.find_gaps(haystack) == [(1, 2), (7, 8), (10, 11), (16, 17)]
+    assert ac.find_coverage_fraction(haystack) == 13 / 17
+    assert bytes_ac.find_gaps(haystack.encode("utf-8")) == [(0, 4), (9, 17), (22, 23)]
+
+    # Overlapping matches are merged:
+    assert ac.find_gaps("a hellow b", overlapping=True) == [(0, 2), (8, 10)]
+    assert ac.find_coverage_fraction("a hellow b", overlapping=True) == 0.6
+    assert bytes_ac.find_gaps(b"a hellow b", overlapping=True) == [(0, 2), (8, 10)]
+
+
+def test_matched_pattern_ids():
+    """
+    matched_pattern_ids() returns the set of indexes of matching patterns, and
+    matched_patterns() the matching patterns in construction order.
+    """
+    patterns = ["winter", "disc", "content", "disco", "summer"]
+    haystack = "winter of my discontent, this winter"
+    ac = AhoCorasick(patterns)
+    bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in patterns])
+    assert ac.matched_pattern_ids(haystack) == {0, 1}
+    assert ac.matched_pattern_ids(haystack, overlapping=True) == {0, 1, 2, 3}
+    assert ac.matched_patterns(haystack) == ["winter", "disc"]
+    assert ac.matched_patterns(haystack, overlapping=True) == [
+        "winter",
+        "disc",
+        "content",
+        "disco",
+    ]
+    bytes_haystack = haystack.encode("utf-8")
+    assert bytes_ac.matched_pattern_ids(bytes_haystack) == {0, 1}
+    assert bytes_ac.matched_pattern_ids(bytes_haystack, overlapping=True) == {
+        0,
+        1,
+        2,
+        3,
+    }
+    assert ac.matched_pattern_ids("nothing") == set()
+    assert ac.matched_patterns("nothing") == []
+
+    ac = AhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    assert ac.matched_pattern_ids(haystack) == {0, 3}
+    with pytest.raises(ValueError):
+        ac.matched_pattern_ids(haystack, overlapping=True)
+
+
+def test_from_counter():
+    """
+    AhoCorasick.from_counter() sorts patterns by descending frequency, finds
+    the same matches as a manually constructed AhoCorasick, and can map
+    pattern indexes back to the original order.
+    """
+    from collections import Counter
+
+    counter = Counter(["disc", "winter", "winter", "disco", "disco", "disco"])
+    haystack = "This is the winter of my discontent"
+    for matchkind in [
+        MATCHKIND_STANDARD,
+        MATCHKIND_LEFTMOST_FIRST,
+        MATCHKIND_LEFTMOST_LONGEST,
+    ]:
+        ac = AhoCorasick.from_counter(counter, matchkind=matchkind)
+        manual = AhoCorasick(["disco", "winter", "disc"], matchkind=matchkind)
+        for method in ["find_matches_as_indexes", "find_matches_as_strings"]:
+            result = getattr(ac, method)(haystack)
+            assert result == getattr(manual, method)(haystack)
+
+    ac = AhoCorasick.from_counter(counter)
+    original = list(counter)
+    for i in range(3):
+        sorted_index = ac.original_index_to_sorted_index(i)
+        assert ac.sorted_index_to_original_index(sorted_index) == i
+        original_index = ac.sorted_index_to_original_index(i)
+        assert ac.original_index_to_sorted_index(original_index) == i
+    assert [original[ac.sorted_index_to_original_index(i)] for i in range(3)] == [
+        "disco",
+        "winter",
+        "disc",
+    ]
+    with pytest.raises(IndexError):
+        ac.sorted_index_to_original_index(3)
+
+    # Plain dicts work too, and ties keep their order:
+    ac = AhoCorasick.from_counter({"a": 1, "b": 2, "c": 1})
+    assert ac.find_matches_as_strings("abc") == ["a", "b", "c"]
+    assert [ac.find_matches_as_indexes(c)[0][0] for c in "abc"] == [1, 0, 2]
+
+    # Regular construction maps indexes to themselves:
+    ac = AhoCorasick(["a", "b"])
+    assert ac.original_index_to_sorted_index(1) == 1
+    assert ac.sorted_index_to_original_index(0) == 0
+
+
+def test_from_counter_leftmost_first_priority():
+    """
+    With MATCHKIND_LEFTMOST_FIRST, from_counter()'s frequency order is the
+    match priority, so the most frequent of several patterns starting at the
+    same position wins.
+    """
+    haystack = "disco"
+    for counter, expected in [
+        ({"disc": 1, "disco": 2}, ["disco"]),
+        ({"disc": 2, "disco": 1}, ["disc"]),
+    ]:
+        ac = AhoCorasick.from_counter(counter, matchkind=MATCHKIND_LEFTMOST_FIRST)
+        assert ac.find_matches_as_strings(haystack) == expected
+        # The other match kinds don't depend on the order:
+        for matchkind in [MATCHKIND_STANDARD, MATCHKIND_LEFTMOST_LONGEST]:
+            ac = AhoCorasick.from_counter(counter, matchkind=matchkind)
+            manual = AhoCorasick(list(counter), matchkind=matchkind)
+            assert ac.find_matches_as_strings(
+                haystack
+            ) == manual.find_matches_as_strings(haystack)
+
+
+def test_from_counter_kwargs():
+    """
+    from_counter() passes keyword arguments on to the constructor, sorting ids
+    and values along with the patterns.
+    """
+    counter = {"hello": 1, "": 5, "world": 2}
+    ac = AhoCorasick.from_counter(
+        counter,
+        ids=["h", "e", "w"],
+        values=[1, 0, 2],
+        on_invalid="skip",
+        implementation="noncontiguous",
+        store_patterns="compact",
+    )
+    assert ac.patterns == ["world", "hello"]
+    assert ac.skipped_patterns == [1]
+    assert ac.find_matches_as_ids("hello world") == [("h", 0, 5), ("w", 6, 11)]
+    assert ac.find_matches_as_values("hello world") == [(1, 0, 5), (2, 6, 11)]
+    assert [ac.sorted_index_to_original_index(i) for i in range(2)] == [2, 0]
+    assert ac.original_index_to_sorted_index(2) == 0
+    with pytest.raises(IndexError):
+        ac.original_index_to_sorted_index(1)
+    assert ac.build_info.implementation == "noncontiguous"
+
+    with pytest.raises(ValueError):
+        AhoCorasick.from_counter(counter, sort_patterns=True)
+    with pytest.raises(TypeError):
+        AhoCorasick.from_counter(counter, not_an_option=True)
+    with pytest.raises(ValueError):
+        AhoCorasick.from_counter(counter, ids=["h"])
+
+
+def test_both_offsets():
+    """
+    find_matches_as_indexes(both_offsets=True) returns code point and byte
+    offsets together.
+    """
+    haystack = "hello, world ☃fishá l🤦l"
+    patterns = ["d ☃f", "há", "l🤦l", "hello"]
+    ac = AhoCorasick(patterns)
+    encoded = haystack.encode("utf-8")
+    for overlapping in [False, True]:
+        result = ac.find_matches_as_indexes(
+            haystack, overlapping=overlapping, both_offsets=True
+        )
+        assert len(result) == 4
+        assert [t[:3] for t in result] == ac.find_matches_as_indexes(
+            haystack, overlapping=overlapping
+        )
+        for i, start, end, start_byte, end_byte in result:
+            assert haystack[start:end] == patterns[i]
+            assert encoded[start_byte:end_byte] == patterns[i].encode("utf-8")
+
+
+def test_from_arrow():
+    """
+    AhoCorasick.from_arrow() loads patterns from Arrow string arrays.
+    """
+    pa = pytest.importorskip("pyarrow")
+    haystack = "hello, world ☃fishá"
+    for string_type in [pa.string(), pa.large_string()]:
+        array = pa.array(["hello", "☃f", "há"], type=string_type)
+        ac = AhoCorasick.from_arrow(array)
+        assert ac.find_matches_as_strings(haystack) == ["hello", "☃f", "há"]
+        assert ac.find_matches_as_indexes(haystack) == [
+            (0, 0, 5),
+            (1, 13, 15),
+            (2, 17, 19),
+        ]
+
+        # Slices and chunked arrays work too:
+        ac = AhoCorasick.from_arrow(array.slice(1))
+        assert ac.find_matches_as_strings(haystack) == ["☃f", "há"]
+        chunked = pa.chunked_array([["hello"], [], ["há"]], type=string_type)
+        ac = AhoCorasick.from_arrow(chunked)
+        assert ac.find_matches_as_indexes(haystack) == [(0, 0, 5), (1, 17, 19)]
+
+        # Nulls are an error by default, or can be skipped:
+        chunked = pa.chunked_array([["hello"], ["há", None]], type=string_type)
+        with pytest.raises(ValueError, match="index 2"):
+            AhoCorasick.from_arrow(chunked)
+        ac = AhoCorasick.from_arrow(chunked, skip_nulls=True)
+        assert ac.find_matches_as_strings(haystack) == ["hello", "há"]
+
+    with pytest.raises(ValueError):
+        AhoCorasick.from_arrow(pa.array([1, 2]))
+    with pytest.raises(ValueError):
+        AhoCorasick.from_arrow(["hello"])
+
+
+class CArrowArray:
+    """
+    A minimal implementation of the Arrow PyCapsule interface for a string
+    array, built with ctypes, so from_arrow() can be tested with layouts that
+    libraries don't necessarily produce, like a null data buffer.
+    """
+
+    def __init__(self, offsets, data, format=b"u"):
+        import ctypes
+
+        class ArrowSchema(ctypes.Structure):
+            _fields_ = [
+                ("format", ctypes.c_char_p),
+                ("name", ctypes.c_char_p),
+                ("metadata", ctypes.c_char_p),
+                ("flags", ctypes.c_int64),
+                ("n_children", ctypes.c_int64),
+                ("children", ctypes.c_void_p),
+                ("dictionary", ctypes.c_void_p),
+                ("release", ctypes.c_void_p),
+                ("private_data", ctypes.c_void_p),
+            ]
+
+        class ArrowArray(ctypes.Structure):
+            _fields_ = [
+                ("length", ctypes.c_int64),
+                ("null_count", ctypes.c_int64),
+                ("offset", ctypes.c_int64),
+                ("n_buffers", ctypes.c_int64),
+                ("n_children", ctypes.c_int64),
+                ("buffers", ctypes.POINTER(ctypes.c_void_p)),
+                ("children", ctypes.c_void_p),
+                ("dictionary", ctypes.c_void_p),
+                ("release", ctypes.c_void_p),
+                ("private_data", ctypes.c_void_p),
+            ]
+
+        # Everything the capsules point to must outlive them:
+        self.offsets = (ctypes.c_int32 * len(offsets))(*offsets)
+        self.data = None if data is None else ctypes.create_string_buffer(data)
+        self.buffers = (ctypes.c_void_p * 3)(
+            None,
+            ctypes.addressof(self.offsets),
+            None if data is None else ctypes.addressof(self.data),
+        )
+        self.schema = ArrowSchema(format=format)
+        self.array = ArrowArray(
+            length=len(offsets) - 1, n_buffers=3, buffers=self.buffers
+        )
+        self.new_capsule = ctypes.pythonapi.PyCapsule_New
+        self.new_capsule.restype = ctypes.py_object
+        self.new_capsule.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_void_p]
+        self.addressof = ctypes.addressof
+
+    def __arrow_c_array__(self, requested_schema=None):
+        return (
+            self.new_capsule(self.addressof(self.schema), b"arrow_schema", None),
+            self.new_capsule(self.addressof(self.array), b"arrow_array", None),
+        )
+
+
+def test_from_arrow_buffers():
+    """
+    from_arrow() reads the Arrow buffers directly, stores the patterns
+    compactly, accepts an implementation, and handles a null data buffer
+    for an array of empty strings.
+    """
+    array = CArrowArray([0, 5, 8], "hello☃".encode("utf-8"))
+    for implementation in [None, "noncontiguous"]:
+        ac = AhoCorasick.from_arrow(array, implementation=implementation)
+        assert ac.patterns == ["hello", "☃"]
+        assert ac.store_patterns == "compact"
+        assert ac.build_info.implementation == (implementation or "dfa")
+        assert ac.find_matches_as_strings("☃ hello") == ["☃", "hello"]
+
+    ac = AhoCorasick.from_arrow(CArrowArray([0, 0, 0], None))
+    assert ac.patterns == ["", ""]
+    with pytest.raises(ValueError, match="no data buffer"):
+        AhoCorasick.from_arrow(CArrowArray([0, 1], None))
+    with pytest.raises(ValueError, match="UTF-8"):
+        AhoCorasick.from_arrow(CArrowArray([0, 1], b"\xff"))
+    with pytest.raises(ValueError, match="format"):
+        AhoCorasick.from_arrow(CArrowArray([0, 1], b"a", format=b"i"))
+
+
+def test_find_matches_as_polars():
+    """
+    find_matches_as_polars() returns a polars.Series of structs matching
+    find_matches_as_indexes().
+    """
+    pl = pytest.importorskip("polars")
+    haystack = "hello, world ☃fishá l🤦l"
+    ac = AhoCorasick(["d ☃f", "há", "l🤦l", "hello"])
+    bytes_ac = BytesAhoCorasick([b"d", b"h\xc3\xa1", b"l", b"hello"])
+    for overlapping in [False, True]:
+        for automaton, h in [(ac, haystack), (bytes_ac, haystack.encode("utf-8"))]:
+            series = automaton.find_matches_as_polars(h, overlapping=overlapping)
+            assert isinstance(series, pl.Series)
+            assert series.dtype == pl.Struct(
+                {"pattern": pl.UInt64, "start": pl.UInt64, "end": pl.UInt64}
+            )
+            assert [
+                (d["pattern"], d["start"], d["end"]) for d in series.to_list()
+            ] == automaton.find_matches_as_indexes(h, overlapping=overlapping)
+
+    series = ac.find_matches_as_polars("nothing")
+    assert len(series) == 0
+
+
+def test_custom_ids():
+    """
+    IDs passed to the constructor are returned by get_id() and
+    find_matches_as_ids().
+    """
+    haystack = "hello, world ☃fishá"
+    ac = AhoCorasick(["hello", "☃f", "há"], ids=[1001, "snowman", (3, 4)])
+    assert [ac.get_id(i) for i in range(3)] == [1001, "snowman", (3, 4)]
+    assert ac.find_matches_as_ids(haystack) == [
+        (1001, 0, 5),
+        ("snowman", 13, 15),
+        ((3, 4), 17, 19),
+    ]
+    bytes_ac = BytesAhoCorasick([b"hello", b"world"], ids=["a", "b"])
+    assert bytes_ac.find_matches_as_ids(b"hello world") == [
+        ("a", 0, 5),
+        ("b", 6, 11),
+    ]
+    assert bytes_ac.get_id(1) == "b"
+
+    # Without IDs, the pattern index is the ID:
+    ac = AhoCorasick(["hello", "world"])
+    assert ac.get_id(1) == 1
+    assert ac.find_matches_as_ids("hello world") == ac.find_matches_as_indexes(
+        "hello world"
+    )
+
+    for index in [2, 3]:
+        with pytest.raises(IndexError):
+            AhoCorasick(["a", "b"], ids=[1, 2]).get_id(index)
+        with pytest.raises(IndexError):
+            BytesAhoCorasick([b"a", b"b"], ids=[1, 2]).get_id(index)
+    with pytest.raises(ValueError):
+        AhoCorasick(["a", "b"], ids=[1])
+    with pytest.raises(ValueError):
+        BytesAhoCorasick([b"a", b"b"], ids=[1, 2, 3])
+
+
+def test_haystack():
+    """
+    A Haystack can be passed wherever a str haystack is accepted, with the
+    same results.
+    """
+    text = "hello, world ☃fishá l🤦l"
+    haystack = Haystack(text)
+    automatons = [
+        AhoCorasick(["d ☃f", "há", "l🤦l", "hello"]),
+        AhoCorasick(["fish", "🤦", "wor"], ids=["a", "b", "c"]),
+    ]
+    for ac in automatons:
+        for method in [
+            "find_matches_as_indexes",
+            "find_matches_as_ids",
+            "find_matches_as_strings",
+            "matched_pattern_ids",
+            "matched_patterns",
+            "find_gaps",
+            "find_coverage_fraction",
+        ]:
+            for overlapping in [False, True]:
+                assert getattr(ac, method)(
+                    haystack, overlapping=overlapping
+                ) == getattr(ac, method)(text, overlapping=overlapping)
+        assert ac.find_matches_as_indexes(
+            haystack, both_offsets=True
+        ) == ac.find_matches_as_indexes(text, both_offsets=True)
+
+    assert AhoCorasick(["a"]).find_coverage_fraction(Haystack("")) == 0.0
+    with pytest.raises(TypeError):
+        AhoCorasick(["a"]).find_matches_as_indexes(1)
+
+
+@given(
+    st.lists(st.sampled_from("ab☃\x00"), min_size=1, max_size=3, unique=True),
+    st.text(alphabet="ab☃\x00c", max_size=30),
+)
+def test_single_byte_patterns(patterns, haystack):
+    """
+    A few single byte patterns, which are searched for with memchr rather
+    than the automaton, give the same results as a naive search.
+    """
+    assume("☃" not in patterns)
+    expected = [
+        (patterns.index(c), i, i + 1) for i, c in enumerate(haystack) if c in patterns
+    ]
+    expected_bytes = []
+    for pattern, start, _ in expected:
+        start = len(haystack[:start].encode("utf-8"))
+        expected_bytes.append((pattern, start, start + 1))
+    for matchkind in [
+        MATCHKIND_STANDARD,
+        MATCHKIND_LEFTMOST_FIRST,
+        MATCHKIND_LEFTMOST_LONGEST,
+    ]:
+        ac = AhoCorasick(patterns, matchkind=matchkind)
+        assert ac.find_matches_as_indexes(haystack) == expected
+        assert ac.find_matches_as_indexes(haystack, sort=False) == expected
+        bytes_ac = BytesAhoCorasick(
+            [p.encode("utf-8") for p in patterns], matchkind=matchkind
+        )
+        haystack_bytes = haystack.encode("utf-8")
+        assert bytes_ac.find_matches_as_indexes(haystack_bytes) == expected_bytes
+    ac = AhoCorasick(patterns)
+    assert ac.find_matches_as_indexes(haystack, overlapping=True) == expected
+
+
+def test_values():
+    """
+    Values passed to the constructor are returned by get_value(), items()
+    and find_matches_as_values().
+    """
+    haystack = "hello, world ☃fishá"
+    first, second, third = object(), {"x": 1}, [1, 2]
+    refcount = sys.getrefcount(first)
+    ac = AhoCorasick(["hello", "☃f", "há"], values=[first, second, third])
+    assert ac.get_value(0) is first
+    assert ac.get_value(2) is third
+    assert ac.items() == [("hello", first), ("☃f", second), ("há", third)]
+    matches = ac.find_matches_as_values(haystack)
+    assert [(v, s, e) for (v, s, e) in matches] == [
+        (first, 0, 5),
+        (second, 13, 15),
+        (third, 17, 19),
+    ]
+    assert matches[0][0] is first
+    del matches
+    assert sys.getrefcount(first) == refcount + 1
+    del ac
+    assert sys.getrefcount(first) == refcount
+
+    ac = AhoCorasick(["he", "hello"], values=["short", "long"])
+    assert ac.find_matches_as_values("hello", overlapping=True) == [
+        ("short", 0, 2),
+        ("long", 0, 5),
+    ]
+    with pytest.raises(IndexError):
+        ac.get_value(2)
+    with pytest.raises(ValueError):
+        AhoCorasick(["a", "b"], values=[1])
+    with pytest.raises(ValueError):
+        AhoCorasick(["a"]).find_matches_as_values("a")
+    with pytest.raises(ValueError):
+        AhoCorasick(["a"]).items()
+
+
+def test_find_matches_streaming_bytes():
+    """
+    find_matches_streaming_bytes() searches an iterable of chunks, with
+    code point offsets from the start of the first chunk.
+    """
+    haystack = "hello, world ☃fishá l🤦l hello"
+    patterns = ["d ☃f", "há", "l🤦l", "hello"]
+    ac = AhoCorasick(patterns)
+    encoded = haystack.encode("utf-8")
+    expected = ac.find_matches_as_indexes(haystack)
+    assert len(expected) == 5
+    for size in [1, 2, 3, 7, len(encoded)]:
+        chunks = [encoded[i : i + size] for i in range(0, len(encoded), size)]
+        assert ac.find_matches_streaming_bytes(chunks) == expected
+        # Any iterable of bytes-like objects works, including generators:
+        assert (
+            ac.find_matches_streaming_bytes(bytearray(c) for c in chunks) == expected
+        )
+
+    # A character split across chunks is counted once:
+    ac_ab = AhoCorasick(["ab"])
+    assert ac_ab.find_matches_streaming_bytes([b"\xc3", b"\xa9ab"]) == [(0, 1, 3)]
+    assert ac_ab.find_matches_streaming_bytes([b"\xc3\xa9", b"ab"]) == [(0, 1, 3)]
+    assert ac_ab.find_matches_as_indexes("éab") == [(0, 1, 3)]
+
+    # Empty chunks are fine:
+    assert ac.find_matches_streaming_bytes([b"hel", b"", b"lo"]) == [(3, 0, 5)]
+    assert ac.find_matches_streaming_bytes([]) == []
+
+    # Errors from the iterable are passed through:
+    def chunks():
+        yield b"hello"
+        raise RuntimeError("oops")
+
+    with pytest.raises(RuntimeError):
+        ac.find_matches_streaming_bytes(chunks())
+    with pytest.raises(TypeError):
+        ac.find_matches_streaming_bytes(["hello"])
+
+    ac = AhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_FIRST)
+    with pytest.raises(ValueError):
+        ac.find_matches_streaming_bytes([encoded])
+
+
+def test_unicode_words():
+    """
+    find_matches_as_indexes(unicode_words=True) returns offsets in terms of
+    Unicode words, such that words[start:end] are the words overlapping the
+    match.
+    """
+    haystack = "The quick brown fox, jumped over the lazy dog."
+    # The words according to UAX #29, which skips whitespace and punctuation:
+    words = "The quick brown fox jumped over the lazy dog".split()
+    ac = AhoCorasick(
+        ["quick brown", "fox, jumped", "azy do", "fox"],
+        matchkind=MATCHKIND_LEFTMOST_LONGEST,
+    )
+    result = ac.find_matches_as_indexes(haystack, unicode_words=True)
+    assert result == [(0, 1, 3), (1, 3, 5), (2, 7, 9)]
+    assert words[1:3] == ["quick", "brown"]
+    assert words[3:5] == ["fox", "jumped"]
+    # Matches that start or end mid-word are widened to the whole word:
+    assert words[7:9] == ["lazy", "dog"]
+
+    # Patterns that don't overlap any words give empty spans:
+    ac = AhoCorasick([", "])
+    assert ac.find_matches_as_indexes(haystack, unicode_words=True) == [(0, 4, 4)]
+
+    # Words are segmented over the whole haystack, so search_limit doesn't
+    # split "3.5" into "3" and ".":
+    ac = AhoCorasick(["."])
+    assert ac.find_matches_as_indexes("a 3.5", unicode_words=True) == [(0, 1, 2)]
+    assert ac.find_matches_as_indexes(
+        "a 3.5", unicode_words=True, search_limit=4
+    ) == [(0, 1, 2)]
+
+    # Japanese has no spaces; ideographs and kana are mostly separate words,
+    # but runs of katakana are a single word:
+    haystack = "私は東京に住んでいます。カタカナテストです"
+    ac = AhoCorasick(["東京", "住んで", "カタカナ"])
+    assert ac.find_matches_as_indexes(
+        haystack, unicode_words=True, both_offsets=True
+    ) == [(0, 2, 4, 6, 12), (1, 5, 8, 15, 24), (2, 11, 12, 36, 48)]
+
+
+def test_utf16_offsets():
+    """
+    find_matches_as_indexes(utf16_offsets=True) returns UTF-16 code unit
+    offsets, where characters outside the BMP count as 2.
+    """
+    haystack = "🤦 hello, world ☃fishá l🤦l 😀"
+    patterns = ["d ☃f", "há", "l🤦l", "hello", "😀"]
+    ac = AhoCorasick(patterns)
+    encoded = haystack.encode("utf-16-le")
+    for overlapping in [False, True]:
+        result = ac.find_matches_as_indexes(
+            haystack, overlapping=overlapping, utf16_offsets=True
+        )
+        assert len(result) == 5
+        for i, start, end in result:
+            assert encoded[start * 2 : end * 2].decode("utf-16-le") == patterns[i]
+    assert result[0] == (3, 3, 8)
+    assert result[-1] == (4, 28, 30)
+
+    result = ac.find_matches_as_indexes(haystack, utf16_offsets=True, both_offsets=True)
+    assert result[0] == (3, 3, 8, 5, 10)
+
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(haystack, utf16_offsets=True, unicode_words=True)
+
+
+def test_grapheme_offsets():
+    """
+    find_matches_as_indexes(grapheme_offsets=True) returns offsets in terms of
+    extended grapheme clusters, widening matches that start or end inside one.
+    """
+    # The clusters are c, a, f, e + combining acute, space, a two code point
+    # flag, space, a five code point family emoji, space, o, k:
+    haystack = (
+        "cafe\u0301 \U0001F1EF\U0001F1F5 "
+        "\U0001F468\u200d\U0001F469\u200d\U0001F467 ok"
+    )
+    ac = AhoCorasick(["cafe\u0301", "e", "\U0001F1EF", "\U0001F469", "ok"])
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, grapheme_offsets=True
+    ) == [(0, 0, 4), (1, 3, 4), (2, 5, 6), (3, 7, 8), (4, 9, 11)]
+    assert ac.find_matches_as_indexes(haystack, overlapping=True)[-1] == (4, 15, 17)
+    assert ac.find_matches_as_indexes(
+        haystack, grapheme_offsets=True, both_offsets=True
+    )[0] == (1, 3, 4, 3, 4)
+
+    # With search_limit, clusters still extend past the searched part, so an
+    # empty match at the limit, inside "e" + combining acute, is widened:
+    with_empty = AhoCorasick(["", "e"])
+    for h in [haystack, bytearray(haystack.encode("utf-8"))]:
+        assert with_empty.find_matches_as_indexes(
+            h, overlapping=True, search_limit=4, grapheme_offsets=True
+        ) == [(0, 0, 0), (1, 3, 4), (0, 3, 4)]
+
+    for option in ["unicode_words", "utf16_offsets"]:
+        with pytest.raises(ValueError):
+            ac.find_matches_as_indexes(
+                haystack, grapheme_offsets=True, **{option: True}
+            )
+
+
+def test_len():
+    """
+    len() and patterns_len give the number of patterns.
+    """
+    for patterns in [[], ["a"], ["hello", "world", "hello", ""]]:
+        ac = AhoCorasick(patterns)
+        assert len(ac) == ac.patterns_len == len(patterns)
+        bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in patterns])
+        assert len(bytes_ac) == bytes_ac.patterns_len == len(patterns)
+    ac = AhoCorasick.from_counter({"a": 1, "b": 2})
+    assert len(ac) == 2
+    bytes_ac = BytesAhoCorasick.from_delimited(b"a\nb\n\nc")
+    assert len(bytes_ac) == 3
+
+
+def test_bytes_store_patterns():
+    """
+    BytesAhoCorasick gives access to the patterns when created with
+    store_patterns=True.
+    """
+    patterns = [b"hello", bytearray(b"world"), memoryview(b"\x00\xff"), b"hello"]
+    bac = BytesAhoCorasick(patterns, store_patterns=True)
+    assert list(bac) == patterns
+    assert bac.patterns == patterns
+    assert all(type(p) is bytes for p in bac.patterns)
+    assert len(bac) == 4
+    assert bac[1] == bac.pattern_at(1) == b"world"
+    assert bac[-1] == b"hello"
+    assert bac.pattern_index(b"\x00\xff") == 2
+    assert bac.pattern_index(b"hello") == 0
+    with pytest.raises(ValueError):
+        bac.pattern_index(b"nope")
+    for index in [-1, 4]:
+        with pytest.raises(IndexError):
+            bac.pattern_at(index)
+    with pytest.raises(IndexError):
+        bac[4]
+    with pytest.raises(IndexError):
+        bac[-5]
+
+    bac = BytesAhoCorasick(patterns)
+    assert len(bac) == 4
+    with pytest.raises(TypeError, match="store_patterns=True"):
+        list(bac)
+    with pytest.raises(RuntimeError):
+        bac.patterns
+    with pytest.raises(RuntimeError):
+        bac[0]
+    with pytest.raises(RuntimeError):
+        bac.pattern_at(0)
+    with pytest.raises(RuntimeError):
+        bac.pattern_index(b"hello")
+
+
+def test_bytes_values():
+    """
+    BytesAhoCorasick also supports values, aka payloads, attached to
+    patterns.
+    """
+    first, second = object(), {"x": 1}
+    bac = BytesAhoCorasick([b"he", b"hello"], values=[first, second])
+    assert bac.get_value(1) is second
+    matches = bac.find_matches_as_values(b"hello", overlapping=True)
+    assert matches == [(first, 0, 2), (second, 0, 5)]
+    assert matches[0][0] is first
+    with pytest.raises(IndexError):
+        bac.get_value(2)
+    # items() needs the patterns:
+    with pytest.raises(RuntimeError):
+        bac.items()
+    bac = BytesAhoCorasick([b"a", b"b"], values=[1, 2], store_patterns=True)
+    assert bac.items() == [(b"a", 1), (b"b", 2)]
+
+    with pytest.raises(ValueError):
+        BytesAhoCorasick([b"a", b"b"], values=[1])
+    with pytest.raises(ValueError):
+        BytesAhoCorasick([b"a"]).find_matches_as_values(b"a")
+
+
+def test_patterns():
+    """
+    AhoCorasick.patterns and get_pattern() return the patterns.
+    """
+    patterns = ["hello", "☃", "world", "hello"]
+    ac = AhoCorasick(patterns)
+    assert ac.patterns == patterns
+    assert [ac.get_pattern(i) for i in range(4)] == patterns
+    with pytest.raises(IndexError):
+        ac.get_pattern(4)
+
+    # The list is a copy:
+    ac.patterns.append("world")
+    ac.patterns[0] = "bye"
+    assert ac.patterns == patterns
+    assert ac.find_matches_as_strings("hello") == ["hello"]
+
+    # Patterns are in the automaton's (sorted) order:
+    ac = AhoCorasick.from_counter({"a": 1, "b": 2})
+    assert ac.patterns == ["b", "a"]
+
+
+def test_with_cache():
+    """
+    AhoCorasick.with_cache() returns an object that caches the results of
+    find_matches_as_indexes().
+    """
+    ac = AhoCorasick(["hello", "world"])
+    cached = ac.with_cache(maxsize=2)
+    assert cached.cache_info() == {"hits": 0, "misses": 0, "maxsize": 2, "currsize": 0}
+    first, second, third = "hello world", "☃ hello", "world"
+    for _ in range(3):
+        assert cached.find_matches_as_indexes(first) == ac.find_matches_as_indexes(
+            first
+        )
+    assert cached.cache_info() == {"hits": 2, "misses": 1, "maxsize": 2, "currsize": 1}
+
+    # Overlapping is part of the key:
+    assert cached.find_matches_as_indexes(
+        first, overlapping=True
+    ) == ac.find_matches_as_indexes(first, overlapping=True)
+    assert cached.cache_info()["misses"] == 2
+
+    # Least recently used results get evicted: the first haystack was used
+    # less recently than the overlapping search, so the second haystack
+    # evicts it.
+    assert cached.find_matches_as_indexes(second) == [(0, 2, 7)]
+    assert cached.cache_info()["currsize"] == 2
+    cached.find_matches_as_indexes(first)
+    assert cached.cache_info()["misses"] == 4
+    cached.find_matches_as_indexes(third)
+    cached.find_matches_as_indexes(first)
+    assert cached.cache_info()["hits"] == 3
+
+    cached.cache_clear()
+    assert cached.cache_info() == {"hits": 0, "misses": 0, "maxsize": 2, "currsize": 0}
+
+    # maxsize=0 disables caching:
+    cached = ac.with_cache(maxsize=0)
+    for _ in range(3):
+        result = cached.find_matches_as_indexes(Haystack(first))
+        assert result == [(0, 0, 5), (1, 6, 11)]
+    assert cached.cache_info() == {"hits": 0, "misses": 3, "maxsize": 0, "currsize": 0}
+
+
+def test_get_or_build():
+    """
+    get_or_build() returns the same automaton for the same patterns and
+    options, until it's evicted or clear_cache() is called.
+    """
+    clear_cache()
+    ac = get_or_build(["hello", "world"])
+    assert isinstance(ac, AhoCorasick)
+    assert ac.find_matches_as_strings("hello world") == ["hello", "world"]
+    assert get_or_build(("hello", "world")) is ac
+    assert get_or_build(["world", "hello"]) is not ac
+    leftmost = get_or_build(["hello", "world"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert leftmost is not ac
+    assert leftmost.matchkind == MATCHKIND_LEFTMOST_FIRST
+    nfa = get_or_build(["hello", "world"], implementation="noncontiguous")
+    assert nfa.implementation == "noncontiguous"
+    assert get_or_build(["hello", "world"]) is ac
+
+    # Only the 32 most recently used are kept:
+    for i in range(32):
+        get_or_build([str(i)])
+    assert get_or_build(["hello", "world"]) is not ac
+
+    ac = get_or_build(["hello", "world"])
+    clear_cache()
+    assert get_or_build(["hello", "world"]) is not ac
+    with pytest.raises(ValueError):
+        get_or_build(["hello"], matchkind="nope")
+
+    # Patterns with the same count and total length are still told apart:
+    assert get_or_build(["ab", "c"]) is not get_or_build(["a", "bc"])
+    assert get_or_build(["ab", "c"]).patterns == ["ab", "c"]
+
+
+def test_get_or_build_shared():
+    """
+    Automatons from get_or_build() are shared, so drop_patterns() refuses to
+    change them, but works on a copy.
+    """
+    clear_cache()
+    ac = get_or_build(["hello", "world"])
+    with pytest.raises(TypeError, match="copy"):
+        ac.drop_patterns()
+    assert get_or_build(["hello", "world"]).patterns == ["hello", "world"]
+
+    own = copy.copy(ac)
+    own.drop_patterns()
+    assert not own.store_patterns
+    assert ac.store_patterns
+    assert own.find_matches_as_strings("hello world") == ["hello", "world"]
+
+
+def test_with_cache_threads():
+    """
+    CachingAhoCorasick can be used from multiple threads.
+    """
+    from concurrent.futures import ThreadPoolExecutor
+
+    ac = AhoCorasick(["hello", "world", "he"])
+    cached = ac.with_cache(maxsize=5)
+    haystacks = ["hello world {}".format(i % 10) * 1000 for i in range(200)]
+    with ThreadPoolExecutor(8) as executor:
+        results = list(executor.map(cached.find_matches_as_indexes, haystacks))
+    assert results == [ac.find_matches_as_indexes(h) for h in haystacks]
+    info = cached.cache_info()
+    assert info["hits"] + info["misses"] == 200
+    assert info["currsize"] == 5
+
+
+def test_is_match_many():
+    """
+    is_match_many() returns whether find_matches_as_indexes() would find
+    anything in each haystack, in order.
+    """
+    for patterns in [["he", "wörld"], ["d"], ["d", "e", "x"]]:
+        ac = AhoCorasick(patterns)
+        haystacks = ["hello", "", "wörld", Haystack("nothing"), "xyz".encode("utf-8")]
+        assert ac.is_match_many(haystacks) == [
+            bool(ac.find_matches_as_indexes(haystack)) for haystack in haystacks
+        ]
+        bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in patterns])
+        haystacks = [b"hello", b"", bytearray(b"dd"), memoryview(b"nothing")]
+        assert bytes_ac.is_match_many(haystacks) == [
+            bool(bytes_ac.find_matches_as_indexes(haystack)) for haystack in haystacks
+        ]
+    assert AhoCorasick(["a"]).is_match_many([]) == []
+    with pytest.raises(TypeError):
+        AhoCorasick(["a"]).is_match_many(["a", 1])
+    with pytest.raises(TypeError):
+        BytesAhoCorasick([b"a"]).is_match_many([b"a", "a"])
+
+
+def test_normalize_haystack():
+    """
+    normalize_haystack converts the haystack to a Unicode normalization form
+    before searching, and offset_map maps offsets in the normalized haystack
+    back to the original.
+    """
+    import unicodedata
+
+    composed = "caf\u00e9"
+    decomposed = "cafe\u0301"
+    haystack = f"{composed} {decomposed}, \ufb01 {decomposed}"
+    for form, pattern in [("NFC", composed), ("NFD", decomposed)]:
+        ac = AhoCorasick([pattern])
+        normalized = unicodedata.normalize(form, haystack)
+        expected = ac.find_matches_as_indexes(normalized)
+        assert len(expected) == 3
+        assert len(ac.find_matches_as_indexes(haystack)) < 3
+        assert ac.find_matches_as_indexes(haystack, normalize_haystack=form) == expected
+        matches, offset_map = ac.find_matches_as_indexes(
+            haystack, normalize_haystack=form, offset_map=True
+        )
+        assert matches == expected
+        assert len(offset_map) == len(normalized) + 1
+        assert [
+            haystack[offset_map[start] : offset_map[end]] for (_, start, end) in matches
+        ] == [composed, decomposed, decomposed]
+        assert ac.find_matches_as_indexes(
+            haystack, normalize_haystack=form, positions_only=True, offset_map=True
+        ) == ([start for (_, start, _) in expected], offset_map)
+
+    # Compatibility forms also replace e.g. ligatures:
+    ac = AhoCorasick(["fi"])
+    assert ac.find_matches_as_indexes(haystack) == []
+    for form in ["NFKC", "NFKD"]:
+        matches, offset_map = ac.find_matches_as_indexes(
+            haystack, normalize_haystack=form, offset_map=True
+        )
+        (_, start, end) = matches[0]
+        assert (offset_map[start], offset_map[end]) == (12, 13)
+
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(haystack, normalize_haystack="nfc")
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(haystack, offset_map=True)
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(
+            haystack, normalize_haystack="NFC", offset_map=True, utf16_offsets=True
+        )
+
+
+def test_filter():
+    """
+    filter() returns the original haystack objects that match, or with
+    invert=True those that don't, in order.
+    """
+    ac = AhoCorasick(["he", "wörld"])
+    haystacks = ["hello", "", "wörld", Haystack("nothing"), b"he", "x" * 5000]
+    haystacks *= 500
+    expected = [h for h in haystacks if ac.find_matches_as_indexes(h)]
+    assert len(expected) == 1500
+    matching = ac.filter(iter(haystacks))
+    assert len(matching) == len(expected)
+    assert all(a is b for (a, b) in zip(matching, expected))
+    not_matching = ac.filter(haystacks, invert=True)
+    assert not_matching == [h for h in haystacks if not ac.find_matches_as_indexes(h)]
+    assert not_matching[0] is haystacks[1]
+    assert ac.filter([]) == []
+    with pytest.raises(TypeError):
+        ac.filter(["hello", 3])
+    with pytest.raises(TypeError):
+        ac.filter(3)
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_count_matches_many(overlapping):
+    """
+    count_matches_many() returns the number of matches find_matches_as_indexes()
+    finds in each haystack, optionally per pattern.
+    """
+    from collections import Counter
+
+    ac = AhoCorasick(["he", "hello", "wörld", "d"])
+    haystacks = ["hello world", "", "hello wörld", Haystack("d d d"), b"hello"]
+    results = [
+        ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+        for haystack in haystacks
+    ]
+    assert ac.count_matches_many(haystacks, overlapping=overlapping) == [
+        len(matches) for matches in results
+    ]
+    assert ac.count_matches_many(
+        haystacks, overlapping=overlapping, per_pattern=True
+    ) == [dict(Counter(index for (index, _, _) in matches)) for matches in results]
+    assert ac.count_matches_many([]) == []
+    with pytest.raises(TypeError):
+        ac.count_matches_many(["hello", 3])
+    with pytest.raises(ValueError):
+        AhoCorasick(["a"], matchkind=MATCHKIND_LEFTMOST_FIRST).count_matches_many(
+            ["a"], overlapping=True
+        )
+
+
+def test_haystacks_shorter_than_patterns():
+    """
+    Haystacks shorter than the shortest pattern aren't searched, without
+    changing the results for those that are long enough.
+    """
+    patterns = ["abc", "bcd", "abcd", "cdé"]
+    ac = AhoCorasick(patterns)
+    bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in patterns])
+    assert ac.min_pattern_length() == 3
+    for haystack in ["", "a", "ab", "é", "abc", "bcd", "cdé", "abcdé", "xabcd"]:
+        expected = sorted(
+            (
+                (index, start, start + len(pattern))
+                for (index, pattern) in enumerate(patterns)
+                for start in range(len(haystack))
+                if haystack.startswith(pattern, start)
+            ),
+            key=lambda m: (m[1], m[2], m[0]),
+        )
+        assert ac.find_matches_as_indexes(haystack, overlapping=True) == expected
+        assert ac.is_match_many([haystack]) == [bool(expected)]
+        assert bool(ac.find_matches_as_indexes(haystack)) == bool(expected)
+        encoded = haystack.encode("utf-8")
+        assert bytes_ac.is_match_many([encoded]) == [bool(expected)]
+        assert bool(bytes_ac.find_matches_as_indexes(encoded)) == bool(expected)
+
+
+def test_try_build():
+    """
+    try_build() returns (automaton, None) on success, or (None, message)
+    instead of raising for bad patterns or options.
+    """
+    ac, error = try_build(["hello", "world"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert error is None
+    assert isinstance(ac, AhoCorasick)
+    assert ac.matchkind == MATCHKIND_LEFTMOST_FIRST
+    assert ac.find_matches_as_strings("hello world") == ["hello", "world"]
+    assert try_build(["hello", 3]) == (
+        None,
+        "Pattern at index 1 must be a str, got int.",
+    )
+    ac, error = try_build(["hello"], matchkind="MATCHKIND_NOPE")
+    assert ac is None and "matchkind" in error
+    ac, error = try_build(["hello"], implementation="nope")
+    assert ac is None and "nope" in error
+    assert try_build(3)[0] is None
+
+    class Patterns:
+        def __len__(self):
+            return 2
+
+        def __getitem__(self, index):
+            raise KeyError("unexpected")
+
+    # Errors that aren't about the patterns or options are still raised:
+    with pytest.raises(KeyError):
+        try_build(Patterns())
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_find_matches_in_many(overlapping):
+    """
+    find_matches_in_many() returns what find_matches_as_indexes() returns for
+    each haystack, in order.
+    """
+    ac = AhoCorasick(["he", "hello", "wörld", "d"])
+    haystacks = [
+        "hello world",
+        "",
+        "hello wörld",
+        "\ud800 wörld",
+        Haystack("wörld hello"),
+        "hello".encode("utf-8"),
+        "nothing",
+    ]
+    assert ac.find_matches_in_many(haystacks, overlapping=overlapping) == [
+        ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+        for haystack in haystacks
+    ]
+    assert ac.find_matches_in_many([]) == []
+    with pytest.raises(TypeError):
+        ac.find_matches_in_many("hello")
+    with pytest.raises(TypeError):
+        ac.find_matches_in_many(["hello", 3])
+
+    ascii_ac = AhoCorasick(["ab"], force_byte_offsets_on_ascii=True)
+    assert ascii_ac.find_matches_in_many(["éab", "ab"]) == [[(0, 2, 4)], [(0, 0, 2)]]
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_build_inverted_index(overlapping):
+    """
+    build_inverted_index() maps each matching pattern to its occurrences in
+    document order, or just the documents if positions=False.
+    """
+    ac = AhoCorasick(["he", "hello", "wörld", "d", "missing"])
+    haystacks = ["hello world", "", "wörld hello hello", "nothing", "d"]
+    expected = {}
+    for doc_index, haystack in enumerate(haystacks):
+        for pattern, start, end in ac.find_matches_as_indexes(
+            haystack, overlapping=overlapping
+        ):
+            expected.setdefault(pattern, []).append((doc_index, start, end))
+    index = ac.build_inverted_index(haystacks, overlapping=overlapping)
+    assert index == expected
+    assert 4 not in index
+    assert ac.build_inverted_index(
+        haystacks, overlapping=overlapping, positions=False
+    ) == {
+        pattern: sorted({doc_index for (doc_index, _, _) in occurrences})
+        for (pattern, occurrences) in expected.items()
+    }
+    assert ac.build_inverted_index([]) == {}
+    with pytest.raises(TypeError):
+        ac.build_inverted_index(["hello", 3])
+
+
+def test_find_matches_in_many_first_only():
+    """
+    find_matches_in_many() with first_only=True returns the first match in each
+    haystack, or None if there is none, with the same offsets as the full search.
+    """
+    ac = AhoCorasick(["he", "hello", "wörld", "d"])
+    haystacks = ["hello world", "", "wörld hello", "\ud800 wörld", "nothing"]
+    assert ac.find_matches_in_many(haystacks, first_only=True) == [
+        (0, 0, 2),
+        None,
+        (2, 0, 5),
+        (2, 2, 7),
+        None,
+    ]
+    for haystack in haystacks:
+        matches = ac.find_matches_as_indexes(haystack)
+        [first] = ac.find_matches_in_many([haystack], first_only=True)
+        assert first == (matches[0] if matches else None)
+
+    ascii_ac = AhoCorasick(["ab"], force_byte_offsets_on_ascii=True)
+    assert ascii_ac.find_matches_in_many(["éab", "c"], first_only=True) == [
+        (0, 2, 4),
+        None,
+    ]
+    with pytest.raises(ValueError):
+        ac.find_matches_in_many(haystacks, overlapping=True, first_only=True)
+
+
+def test_find_matches_in_lines():
+    """
+    find_matches_in_lines() searches each line separately, with offsets
+    within the line.
+    """
+    import io
+
+    lines = ["hello world\n", "\n", "☃ nothing here\n", "world, hello hello"]
+    ac = AhoCorasick(["hello", "world", "here"])
+    expected = [
+        (i, pattern, start, end)
+        for (i, line) in enumerate(lines)
+        for (pattern, start, end) in ac.find_matches_as_indexes(line)
+    ]
+    assert ac.find_matches_in_lines(lines) == expected
+    assert expected[-1] == (3, 0, 13, 18)
+    assert (2, 2, 10, 14) in expected
+    # Any iterable of lines works, e.g. a file object or generator:
+    assert ac.find_matches_in_lines(io.StringIO("".join(lines))) == expected
+    assert ac.find_matches_in_lines(line for line in lines) == expected
+    assert ac.find_matches_in_lines([]) == []
+
+    # Matches don't span lines:
+    assert ac.find_matches_in_lines(["hel", "lo"]) == []
+
+    ac = AhoCorasick(["he", "hello"])
+    assert ac.find_matches_in_lines(["x", "hello"], overlapping=True) == [
+        (1, 0, 0, 2),
+        (1, 1, 0, 5),
+    ]
+    with pytest.raises(TypeError):
+        ac.find_matches_in_lines([b"hello"])
+
+
+def test_contains_and_index():
+    """
+    ``in`` and index() look up patterns.
+    """
+    ac = AhoCorasick(["hello", "☃", "world", "hello"])
+    assert "hello" in ac
+    assert "☃" in ac
+    assert "hell" not in ac
+    assert "" not in ac
+    assert ac.index("world") == 2
+    # Like list.index(), the first matching index is returned:
+    assert ac.index("hello") == 0
+    with pytest.raises(ValueError, match="'hell' is not a pattern"):
+        ac.index("hell")
+
+    # Indexes are the automaton's indexes:
+    ac = AhoCorasick.from_counter({"a": 1, "b": 2})
+    assert ac.index("a") == 1
+
+
+def test_find_matches_from_stringio_and_bytesio():
+    """
+    find_matches_from_stringio() and find_matches_from_bytesio() search the
+    contents of in-memory buffers.
+    """
+    import io
+
+    haystack = "hello, world ☃fishá l🤦l"
+    patterns = ["d ☃f", "há", "l🤦l", "hello"]
+    ac = AhoCorasick(patterns)
+    sio = io.StringIO(haystack)
+    sio.seek(5)
+    assert ac.find_matches_from_stringio(sio) == ac.find_matches_as_indexes(haystack)
+    assert ac.find_matches_from_stringio(
+        sio, overlapping=True
+    ) == ac.find_matches_as_indexes(haystack, overlapping=True)
+
+    encoded = haystack.encode("utf-8")
+    bac = BytesAhoCorasick([p.encode("utf-8") for p in patterns])
+    bio = io.BytesIO(encoded)
+    expected = bac.find_matches_as_indexes(encoded)
+    assert len(expected) == 4
+    assert bac.find_matches_from_bytesio(bio) == expected
+    # The buffer is released, so the BytesIO can still be resized:
+    bio.seek(0, io.SEEK_END)
+    bio.write(encoded)
+    bio.truncate(len(encoded) + 5)
+    assert bac.find_matches_from_bytesio(bio) == expected + [(3, 29, 34)]
+
+    with pytest.raises(AttributeError):
+        bac.find_matches_from_bytesio(encoded)
+
+
+@pytest.mark.parametrize(
+    "matchkind", [MATCHKIND_LEFTMOST_FIRST, MATCHKIND_LEFTMOST_LONGEST]
+)
+def test_overlapping_leftmost_error(matchkind):
+    """
+    Asking for overlapping matches from an automaton with a leftmost match
+    kind raises an AhoCorasickError, which is also a ValueError.
+    """
+    assert issubclass(AhoCorasickError, ValueError)
+    ac = AhoCorasick(["hello", "he"], matchkind=matchkind)
+    for method in [
+        ac.find_matches_as_indexes,
+        ac.find_matches_as_strings,
+        ac.matched_pattern_ids,
+        ac.find_gaps,
+    ]:
+        with pytest.raises(AhoCorasickError, match=matchkind):
+            method("hello", overlapping=True)
+    bytes_ac = BytesAhoCorasick([b"hello", b"he"], matchkind=matchkind)
+    with pytest.raises(AhoCorasickError, match="MATCHKIND_STANDARD"):
+        bytes_ac.find_matches_as_indexes(b"hello", overlapping=True)
+
+
+def test_iter():
+    """
+    Iterating over an AhoCorasick yields the patterns.
+    """
+    patterns = ["hello", "☃", "world", "hello"]
+    ac = AhoCorasick(patterns)
+    assert list(ac) == patterns
+    assert [p for p in ac] == patterns
+
+    # The iterator keeps working if the automaton goes away:
+    iterator = iter(AhoCorasick(patterns))
+    assert next(iterator) == "hello"
+    assert list(iterator) == patterns[1:]
+
+
+def test_find_nearest_match():
+    """
+    find_nearest_match() returns the match closest to the given offset.
+    """
+    haystack = "☃ hello world, hello there"
+    ac = AhoCorasick(["hello", "world", "there"])
+    # Containing matches, including at the edges:
+    assert ac.find_nearest_match(haystack, 3) == (0, 2, 7)
+    assert ac.find_nearest_match(haystack, 2) == (0, 2, 7)
+    assert ac.find_nearest_match(haystack, 10) == (1, 8, 13)
+    # Touching two matches; the earlier one wins:
+    assert ac.find_nearest_match(Haystack(haystack), 7) == (0, 2, 7)
+    # Otherwise, the closest match:
+    assert ac.find_nearest_match(haystack, 0) == (0, 2, 7)
+    assert ac.find_nearest_match(haystack, 14) == (1, 8, 13)
+    assert ac.find_nearest_match(haystack, 1000) == (2, 21, 26)
+    # Equally distant matches; the earlier one wins:
+    ac = AhoCorasick(["ab", "cd"])
+    assert ac.find_nearest_match("ab  cd", 3) == (0, 0, 2)
+    assert ac.find_nearest_match("ab   cd", 4) == (1, 5, 7)
+
+    ac = AhoCorasick(["he", "hello"])
+    assert ac.find_nearest_match("xx hello", 6, overlapping=True) == (1, 3, 8)
+    assert ac.find_nearest_match("nothing", 3) is None
+
+
+@pytest.mark.parametrize(
+    "haystack",
+    ["hello world, hello there", "", "hellohello", Haystack("world hello")],
+)
+def test_force_byte_offsets_on_ascii_same_results(haystack):
+    """
+    For ASCII haystacks, force_byte_offsets_on_ascii=True gives the same
+    results as the default code point offsets.
+    """
+    patterns = ["hello", "world", "o"]
+    ac = AhoCorasick(patterns)
+    ac_bytes = AhoCorasick(patterns, force_byte_offsets_on_ascii=True)
+    for overlapping in [False, True]:
+        assert ac.find_matches_as_indexes(
+            haystack, overlapping=overlapping
+        ) == ac_bytes.find_matches_as_indexes(haystack, overlapping=overlapping)
+    assert ac.find_gaps(haystack) == ac_bytes.find_gaps(haystack)
+
+
+def test_force_byte_offsets_on_ascii():
+    """
+    force_byte_offsets_on_ascii=True returns byte offsets for non-ASCII
+    haystacks, unless some patterns aren't ASCII.
+    """
+    haystack = "☃ hello"
+    ac = AhoCorasick(["hello"], force_byte_offsets_on_ascii=True)
+    assert ac.find_matches_as_indexes(haystack) == [(0, 4, 9)]
+    assert ac.find_matches_as_indexes(Haystack(haystack)) == [(0, 4, 9)]
+    assert AhoCorasick(["hello"]).find_matches_as_indexes(haystack) == [(0, 2, 7)]
+    ac = AhoCorasick(["hello", "☃"], force_byte_offsets_on_ascii=True)
+    assert ac.find_matches_as_indexes(haystack) == [(1, 0, 1), (0, 2, 7)]
+
+
+@pytest.mark.parametrize("size_hint", [0, 1, 9999, 10000, 2**40])
+def test_size_hint(size_hint):
+    """
+    size_hint doesn't change the results of find_matches_as_indexes(), whether
+    it's too small, right, or far too large.
+    """
+    haystack = "ab" * 9999
+    ac = AhoCorasick(["ab"])
+    result = ac.find_matches_as_indexes(haystack, size_hint=size_hint)
+    assert len(result) == 9999
+    assert result == ac.find_matches_as_indexes(haystack)
+    bytes_ac = BytesAhoCorasick([b"ab"])
+    result = bytes_ac.find_matches_as_indexes(haystack.encode(), size_hint=size_hint)
+    assert result == bytes_ac.find_matches_as_indexes(haystack.encode())
+    assert len(result) == 9999
+
+
+class PatternsError(Exception):
+    """Raised while iterating over patterns."""
+
+
+@pytest.mark.parametrize("error_index", [0, 2, 4])
+def test_patterns_iteration_error(error_index):
+    """
+    An exception raised while iterating over AhoCorasick patterns, whether at
+    the start, middle or end, propagates rather than patterns being dropped.
+    """
+
+    class FailingList(list):
+        def __iter__(self):
+            for i, pattern in enumerate(list.__iter__(self)):
+                if i == error_index:
+                    raise PatternsError()
+                yield pattern
+
+    patterns = FailingList(["a", "b", "c", "d", "e"])
+    with pytest.raises(PatternsError):
+        AhoCorasick(patterns)
+    with pytest.raises(TypeError):
+        AhoCorasick([1 if i == error_index else "a" for i in range(5)])
+
+
+@pytest.mark.parametrize("store_patterns", [True, False])
+@pytest.mark.parametrize("error_index", [0, 2, 4])
+def test_bytes_patterns_iteration_error(error_index, store_patterns):
+    """
+    An exception raised while iterating over BytesAhoCorasick patterns,
+    whether at the start, middle or end, propagates rather than patterns
+    being dropped, regardless of store_patterns.
+    """
+
+    def patterns():
+        for i in range(5):
+            if i == error_index:
+                raise PatternsError()
+            yield b"x%d" % i
+
+    with pytest.raises(PatternsError):
+        BytesAhoCorasick(patterns(), store_patterns=store_patterns)
+    with pytest.raises(TypeError):
+        BytesAhoCorasick(
+            [1 if i == error_index else b"a" for i in range(5)],
+            store_patterns=store_patterns,
+        )
+    # Without errors, all patterns are kept:
+    ac = BytesAhoCorasick((b"x%d" % i for i in range(5)), store_patterns=store_patterns)
+    assert len(ac) == 5
+    assert ac.matched_pattern_ids(b"x0 x4") == {0, 4}
+
+
+def test_introspection():
+    """
+    matchkind, implementation and store_patterns reflect how the automaton was
+    actually built.
+    """
+    ac = AhoCorasick(["hello", "world"])
+    assert ac.matchkind == MATCHKIND_STANDARD
+    assert ac.implementation == "dfa"
+    assert ac.store_patterns
+    ac = AhoCorasick(["?", "!"], matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    assert ac.matchkind == MATCHKIND_LEFTMOST_LONGEST
+    assert ac.implementation == "memchr"
+    assert AhoCorasick(["?", "?"]).implementation == "dfa"
+
+    ac = BytesAhoCorasick([b"hello"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert ac.matchkind == MATCHKIND_LEFTMOST_FIRST
+    assert ac.implementation == "dfa"
+    assert not ac.store_patterns
+    ac = BytesAhoCorasick([b"\x00"], store_patterns=True)
+    assert ac.matchkind == MATCHKIND_STANDARD
+    assert ac.implementation == "memchr"
+    assert ac.store_patterns
+    with pytest.raises(AttributeError):
+        ac.matchkind = MATCHKIND_STANDARD
+
+
+@pytest.mark.parametrize(
+    "cls,encode", [(AhoCorasick, str), (BytesAhoCorasick, str.encode)]
+)
+def test_implementation_argument(cls, encode, tmp_path):
+    """
+    implementation can be "dfa" or "noncontiguous"; both find the same
+    matches, and the choice survives pickling and update().
+    """
+    import pickle
+
+    patterns = [encode(p) for p in ["he", "hello", "lo", "world"]]
+    haystack = encode("hello world")
+    dfa = cls(patterns, store_patterns=True, implementation="dfa")
+    nfa = cls(patterns, store_patterns=True, implementation="noncontiguous")
+    assert cls(patterns).implementation == "dfa"
+    assert dfa.implementation == "dfa"
+    assert nfa.implementation == "noncontiguous"
+    for overlapping in [False, True]:
+        assert dfa.find_matches_as_indexes(
+            haystack, overlapping=overlapping
+        ) == nfa.find_matches_as_indexes(haystack, overlapping=overlapping)
+    assert pickle.loads(pickle.dumps(nfa)).implementation == "noncontiguous"
+    if cls is AhoCorasick:
+        assert nfa.update(["xyz"]).implementation == "noncontiguous"
+        assert (
+            nfa.union(AhoCorasick(["xyz"])).implementation == "noncontiguous"
+        )
+    # memchr still takes priority:
+    assert cls([encode("?")], implementation="noncontiguous").implementation == (
+        "memchr"
+    )
+    path = str(tmp_path / "saved")
+    nfa.save(path)
+    assert cls.load(path).implementation == "noncontiguous"
+    for bad in ["contiguous", "DFA"]:
+        with pytest.raises(ValueError, match="noncontiguous"):
+            cls(patterns, implementation=bad)
+    # aho-corasick 0.7 has no contiguous NFA, which the error explains:
+    with pytest.raises(ValueError, match="has no contiguous NFA"):
+        cls(patterns, implementation="contiguous")
+
+
+@pytest.mark.parametrize("wrap", [str, Haystack])
+def test_search_limit(wrap):
+    """
+    search_limit only searches the first N code points; matches straddling the
+    limit aren't returned.
+    """
+    haystack = wrap("☃ hello world, hello")
+    ac = AhoCorasick(["hello", "world"])
+    assert ac.find_matches_as_indexes(haystack, search_limit=0) == []
+    assert ac.find_matches_as_indexes(haystack, search_limit=7) == [(0, 2, 7)]
+    assert ac.find_matches_as_indexes(haystack, search_limit=12) == [(0, 2, 7)]
+    assert ac.find_matches_as_indexes(haystack, search_limit=13) == [
+        (0, 2, 7),
+        (1, 8, 13),
+    ]
+    assert ac.find_matches_as_indexes(
+        haystack, search_limit=1000
+    ) == ac.find_matches_as_indexes(haystack)
+    assert ac.find_matches_as_indexes(
+        haystack, search_limit=13, utf16_offsets=True
+    ) == [(0, 2, 7), (1, 8, 13)]
+    assert ac.find_matches_as_strings(haystack, search_limit=13) == ["hello", "world"]
+
+
+def test_bytes_search_limit():
+    """
+    BytesAhoCorasick's search_limit is in bytes.
+    """
+    ac = BytesAhoCorasick([b"hello", b"world"])
+    haystack = "☃ hello world".encode("utf-8")
+    assert ac.find_matches_as_indexes(haystack, search_limit=9) == [(0, 4, 9)]
+    assert ac.find_matches_as_indexes(haystack, search_limit=14) == [(0, 4, 9)]
+    assert ac.find_matches_as_indexes(haystack, search_limit=100) == [
+        (0, 4, 9),
+        (1, 10, 15),
+    ]
+
+
+@pytest.mark.parametrize("byteorder", ["little", "big"])
+def test_bytes_find_matches_as_indexes_words(byteorder):
+    """
+    find_matches_as_indexes_words() only returns matches that start on a word
+    boundary, with offsets in words, and byte-swaps big-endian haystacks.
+    """
+    text = "hello world, the world"
+    haystack = text.encode("utf-16-le" if byteorder == "little" else "utf-16-be")
+    ac = BytesAhoCorasick(
+        [p.encode("utf-16-le") for p in ["world", "the"]] + [b"\x00w"]
+    )
+    expected = [(0, 6, 11), (1, 13, 16), (0, 17, 22)]
+    assert ac.find_matches_as_indexes_words(haystack, byteorder=byteorder) == expected
+    assert (
+        ac.find_matches_as_indexes_words(
+            haystack, byteorder=byteorder, overlapping=True
+        )
+        == expected
+    )
+
+    # A pattern spanning two words doesn't hide an aligned match it overlaps,
+    # whatever the match kind:
+    haystack = " w".encode("utf-16-le")
+    for matchkind in [MATCHKIND_STANDARD, MATCHKIND_LEFTMOST_FIRST]:
+        ac = BytesAhoCorasick([b"\x00w", b"w\x00"], matchkind=matchkind)
+        assert ac.find_matches_as_indexes(haystack) == [(0, 1, 3)]
+        assert ac.find_matches_as_indexes_words(haystack) == [(1, 1, 2)]
+
+    ac = BytesAhoCorasick([b"ab\x00\x00"])
+    assert ac.find_matches_as_indexes_words(
+        b"ab\x00\x00" * 2, word_size=4
+    ) == [(0, 0, 1), (0, 1, 2)]
+    with pytest.raises(ValueError, match="multiple of word_size=2"):
+        ac.find_matches_as_indexes_words(b"abc")
+    with pytest.raises(ValueError, match="multiple of word_size=3"):
+        ac.find_matches_as_indexes_words(b"abcdef", word_size=3)
+    with pytest.raises(ValueError, match="byteorder"):
+        ac.find_matches_as_indexes_words(b"ab", byteorder="middle")
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes_words(b"ab", word_size=0)
+
+
+def test_bytes_whole_word():
+    """
+    whole_word=True only returns matches not bordered by word bytes, treating
+    the ends of the haystack as boundaries.
+    """
+    ac = BytesAhoCorasick([b"cat", b"cat_"], ids=["a", "b"], values=[1, 2])
+    haystack = b"cat scat cat_ cats cat"
+    assert ac.find_matches_as_indexes(haystack, whole_word=True) == [
+        (0, 0, 3),
+        (1, 9, 13),
+        (0, 19, 22),
+    ]
+    assert ac.find_matches_as_ids(haystack, whole_word=True) == [
+        ("a", 0, 3),
+        ("b", 9, 13),
+        ("a", 19, 22),
+    ]
+    assert ac.find_matches_as_values(haystack, whole_word=True) == [
+        (1, 0, 3),
+        (2, 9, 13),
+        (1, 19, 22),
+    ]
+    assert ac.find_matches_as_indexes(
+        haystack, whole_word=True, word_bytes=b"abcdefghijklmnopqrstuvwxyz"
+    ) == [(0, 0, 3), (0, 9, 12), (0, 19, 22)]
+    # A word cut short by search_limit doesn't count as whole:
+    assert ac.find_matches_as_indexes(
+        haystack, whole_word=True, search_limit=17
+    ) == [(0, 0, 3), (1, 9, 13)]
+    # UTF-8 bytes aren't word bytes by default:
+    assert ac.find_matches_as_indexes(
+        "écat".encode("utf-8"), whole_word=True
+    ) == [(0, 2, 5)]
+
+    # A match that isn't a whole word doesn't hide one it overlaps, whatever
+    # the match kind:
+    for matchkind in [
+        MATCHKIND_STANDARD,
+        MATCHKIND_LEFTMOST_FIRST,
+        MATCHKIND_LEFTMOST_LONGEST,
+    ]:
+        ac = BytesAhoCorasick([b"xa b", b"a", b"b"], matchkind=matchkind)
+        assert ac.find_matches_as_indexes(b"xa b", whole_word=True) == [
+            (0, 0, 4),
+        ]
+        assert ac.find_matches_as_indexes(b"yxa b", whole_word=True) == [
+            (2, 4, 5),
+        ]
+
+    with pytest.raises(ValueError, match="whole_word"):
+        ac.find_matches_as_indexes(b"abc", word_bytes=b"a")
+
+
+@pytest.mark.parametrize("threads", [None, 1, 3])
+def test_bytes_find_matches_in_many(threads):
+    """
+    BytesAhoCorasick.find_matches_in_many() returns what
+    find_matches_as_indexes() returns for each haystack, in order, whether or
+    not the batch is big enough to be split between threads.
+    """
+    ac = BytesAhoCorasick([b"he", b"hello", b"d"])
+    small = [b"hello world", b"", bytearray(b"dd"), memoryview(b"nothing")]
+    large = [b"hello world %d" % i * 100 for i in range(200)] + small
+    for haystacks in [small, large]:
+        assert ac.find_matches_in_many(haystacks, threads=threads) == [
+            ac.find_matches_as_indexes(haystack) for haystack in haystacks
+        ]
+        assert ac.find_matches_in_many(
+            haystacks, overlapping=True, threads=threads
+        ) == [
+            ac.find_matches_as_indexes(haystack, overlapping=True)
+            for haystack in haystacks
+        ]
+    assert ac.find_matches_in_many([], threads=threads) == []
+    with pytest.raises(TypeError):
+        ac.find_matches_in_many([b"hello", "hello"])
+    with pytest.raises(ValueError):
+        ac.find_matches_in_many([b"hello"], threads=0)
+    assert ac.find_matches_in_many(
+        [b"hello world", b"nothing"], threads=threads, first_only=True
+    ) == [ac.find_matches_as_indexes(b"hello world")[0], None]
+
+
+def test_bytes_find_matches_as_indexes_segmented():
+    """
+    find_matches_as_indexes_segmented() searches the segments as if they were
+    joined, including matches that span segments, whether the segments are
+    joined or streamed.
+    """
+    ac = BytesAhoCorasick([b"hello", b"world", b"lo w"])
+    assert ac.find_matches_as_indexes_segmented([b"hel", b"lo wor", b"ld"]) == [
+        (0, 0, 5),
+        (1, 6, 11),
+    ]
+    assert ac.find_matches_as_indexes_segmented(
+        [b"hel", b"", bytearray(b"lo w"), b"", memoryview(b"orld"), b""],
+        overlapping=True,
+    ) == [(0, 0, 5), (2, 3, 7), (1, 6, 11)]
+    assert ac.find_matches_as_indexes_segmented([]) == []
+    assert ac.find_matches_as_indexes_segmented([b"", b""]) == []
+    with pytest.raises(TypeError):
+        ac.find_matches_as_indexes_segmented([b"hello", "world"])
+
+    # Over 1MB the segments are streamed, unless that isn't supported:
+    segments = [b"xhel", b"lo w", b"orld" * 3000] * 100
+    joined = b"".join(segments)
+    assert len(joined) > 1024 * 1024
+    for matchkind in [MATCHKIND_STANDARD, MATCHKIND_LEFTMOST_LONGEST]:
+        ac = BytesAhoCorasick([b"hello", b"world", b"lo w"], matchkind=matchkind)
+        assert ac.find_matches_as_indexes_segmented(
+            segments
+        ) == ac.find_matches_as_indexes(joined)
+
+
+def test_surrogates():
+    """
+    Haystacks containing surrogate code points can be searched, with offsets
+    counting each surrogate as one code point.
+    """
+    haystack = "abc\ud800xyz".encode("utf-16", errors="surrogatepass").decode(
+        "utf-16", errors="surrogatepass"
+    )
+    assert haystack == "abc\ud800xyz"
+    ac = AhoCorasick(["abc", "xyz"])
+    assert ac.find_matches_as_strings(haystack) == ["abc", "xyz"]
+    assert ac.find_matches_as_indexes(haystack) == [(0, 0, 3), (1, 4, 7)]
+    assert ac.find_matches_as_indexes(haystack, search_limit=6) == [(0, 0, 3)]
+    assert ac.find_gaps(haystack) == [(3, 4)]
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(haystack, utf16_offsets=True)
+
+
+def test_memory_usage():
+    """
+    memory_usage() adds up memory_usage_breakdown(), grows with the number of
+    patterns, and is included in sys.getsizeof().
+    """
+    small = AhoCorasick(["hello", "world"])
+    large = AhoCorasick(["pattern%d" % i for i in range(1000)])
+    for ac in [small, large]:
+        breakdown = ac.memory_usage_breakdown()
+        assert set(breakdown) == {"automaton", "stored_patterns"}
+        assert ac.memory_usage() == sum(breakdown.values())
+        assert sys.getsizeof(ac) > ac.memory_usage()
+    assert large.memory_usage() > small.memory_usage()
+    assert (
+        large.memory_usage_breakdown()["stored_patterns"]
+        > small.memory_usage_breakdown()["stored_patterns"]
+    )
+
+    patterns = [b"pattern%d" % i for i in range(1000)]
+    unstored = BytesAhoCorasick(patterns)
+    stored = BytesAhoCorasick(patterns, store_patterns=True)
+    assert unstored.memory_usage_breakdown()["stored_patterns"] == 0
+    assert stored.memory_usage_breakdown()["stored_patterns"] > 0
+    assert (
+        stored.memory_usage_breakdown()["automaton"]
+        == unstored.memory_usage_breakdown()["automaton"]
+    )
+    assert sys.getsizeof(stored) > stored.memory_usage() > unstored.memory_usage()
+
+
+def test_extra_fields():
+    """
+    extra_fields adds per-pattern metadata to the end of each match tuple, with
+    None for patterns missing from the dict.
+    """
+    ac = AhoCorasick(["a", "b", "c", "d", "e"])
+    extra_fields = {0: "label", 1: 7, 2: {"action": "block"}, 3: None}
+    result = ac.find_matches_as_indexes("abcde", extra_fields=extra_fields)
+    assert result == [
+        (0, 0, 1, "label"),
+        (1, 1, 2, 7),
+        (2, 2, 3, {"action": "block"}),
+        (3, 3, 4, None),
+        (4, 4, 5, None),
+    ]
+    assert all(len(match) == 4 for match in result)
+    assert result[2][3] is extra_fields[2]
+    assert ac.find_matches_as_indexes("☃e", extra_fields={}) == [(4, 1, 2, None)]
+    assert ac.find_matches_as_indexes(
+        "☃a", both_offsets=True, extra_fields=extra_fields
+    ) == [(0, 1, 2, 3, 4, "label")]
+
+    bytes_ac = BytesAhoCorasick([b"a", b"b"])
+    assert bytes_ac.find_matches_as_indexes(b"ab", extra_fields={1: "x"}) == [
+        (0, 0, 1, None),
+        (1, 1, 2, "x"),
+    ]
+
+
+def test_extra_fields_validation():
+    """
+    extra_fields keys must be pattern indexes.
+    """
+    ac = AhoCorasick(["a", "b"])
+    with pytest.raises(TypeError):
+        ac.find_matches_as_indexes("ab", extra_fields={"a": 1})
+    with pytest.raises(IndexError):
+        ac.find_matches_as_indexes("ab", extra_fields={2: 1})
+    with pytest.raises(TypeError):
+        ac.find_matches_as_indexes("ab", extra_fields=[1, 2])
+    with pytest.raises(IndexError):
+        BytesAhoCorasick([b"a"]).find_matches_as_indexes(b"a", extra_fields={1: 1})
+
+
+def test_numpy_object_array():
+    """
+    AhoCorasick can be built from a NumPy array with dtype object.
+    """
+    np = pytest.importorskip("numpy")
+    patterns = np.array(["hello", "world", "☃"], dtype=object)
+    ac = AhoCorasick(patterns)
+    assert ac.patterns == ["hello", "world", "☃"]
+    assert ac.find_matches_as_strings("hello ☃ world") == ["hello", "☃", "world"]
+    # Non-contiguous arrays work too:
+    ac = AhoCorasick(patterns[::2])
+    assert ac.patterns == ["hello", "☃"]
+    with pytest.raises(TypeError, match="index 1"):
+        AhoCorasick(np.array(["hello", 3, "world"], dtype=object))
+
+
+def test_object_buffer():
+    """
+    Any buffer of Python objects is read directly, e.g. a ctypes array.
+    """
+    import ctypes
+
+    patterns = (ctypes.py_object * 3)("hello", "world", "☃")
+    ac = AhoCorasick(patterns)
+    assert ac.patterns == ["hello", "world", "☃"]
+    with pytest.raises(TypeError, match="index 2"):
+        AhoCorasick((ctypes.py_object * 3)("hello", "world", b"x"))
+
+
+def test_repr():
+    """
+    repr() shows the class, number of patterns and options.
+    """
+    ac = AhoCorasick(["hello", "world"])
+    assert repr(ac) == (
+        "<AhoCorasick with 2 patterns, matchkind=MATCHKIND_STANDARD, "
+        "implementation=dfa, store_patterns=True>"
+    )
+    ac = BytesAhoCorasick([b"?"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert repr(ac) == (
+        "<BytesAhoCorasick with 1 patterns, matchkind=MATCHKIND_LEFTMOST_FIRST, "
+        "implementation=memchr, store_patterns=False>"
+    )
+
+
+def test_eq_hash():
+    """
+    AhoCorasick objects built the same way are equal and have the same hash.
+    """
+    ac = AhoCorasick(["hello", "world"])
+    same = AhoCorasick(["hello", "world"])
+    assert ac == same
+    assert not (ac != same)
+    assert hash(ac) == hash(same)
+    assert len({ac, same}) == 1
+    assert ac != AhoCorasick(["world", "hello"])
+    assert ac != AhoCorasick(["hello"])
+    assert ac != AhoCorasick(["hello", "world"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert ac != AhoCorasick(["hello", "world"], ids=["a", "b"])
+    assert AhoCorasick(["hello"], values=[{"x": 1}]) == AhoCorasick(
+        ["hello"], values=[{"x": 1}]
+    )
+    assert AhoCorasick(["hello"], values=[1]) != AhoCorasick(["hello"], values=[2])
+    assert ac != ["hello", "world"]
+    with pytest.raises(TypeError):
+        ac < same
+
+
+def test_lru_cache_key():
+    """
+    AhoCorasick objects can be used as functools.lru_cache keys.
+    """
+    from functools import lru_cache
+
+    @lru_cache()
+    def count(ac, haystack):
+        return len(ac.find_matches_as_indexes(haystack))
+
+    assert count(AhoCorasick(["a"]), "aaa") == 3
+    assert count(AhoCorasick(["a"]), "aaa") == 3
+    assert count.cache_info().hits == 1
+
+
+def test_pattern_lengths():
+    """
+    Pattern lengths are in UTF-8 bytes.
+    """
+    patterns = ["hello", "☃", "hi"]
+    ac = AhoCorasick(patterns)
+    assert ac.pattern_lengths() == [len(p.encode("utf-8")) for p in patterns]
+    assert ac.pattern_lengths() == [5, 3, 2]
+    assert ac.max_pattern_length() == 5
+    assert ac.min_pattern_length() == 2
+
+    patterns = [b"hello", b"\x00", b"abc"]
+    ac = BytesAhoCorasick(patterns, store_patterns=True)
+    assert ac.pattern_lengths() == [5, 1, 3]
+    assert ac.max_pattern_length() == 5
+    assert ac.min_pattern_length() == 1
+    ac = BytesAhoCorasick(patterns)
+    assert ac.max_pattern_length() == 5
+    assert ac.min_pattern_length() == 1
+    with pytest.raises(RuntimeError):
+        ac.pattern_lengths()
+
+
+def test_common_prefix():
+    """
+    common_prefix() returns the longest prefix all the patterns share, which
+    never splits a character.
+    """
+    assert AhoCorasick(["user_id", "user_name", "user"]).common_prefix() == "user"
+    assert AhoCorasick(["hello"]).common_prefix() == "hello"
+    assert AhoCorasick(["hello", "world"]).common_prefix() == ""
+    assert AhoCorasick(["hello", ""]).common_prefix() == ""
+    assert AhoCorasick([]).common_prefix() == ""
+    # "é" and "ê" share their first UTF-8 byte:
+    assert AhoCorasick(["☃é", "☃ê"]).common_prefix() == "☃"
+    assert AhoCorasick(["☃é", "☃é!", "☃é"]).common_prefix() == "☃é"
+    ac = AhoCorasick(["hello", "help"])
+    ac.drop_patterns()
+    with pytest.raises(RuntimeError):
+        ac.common_prefix()
+
+
+def test_find_matches_grouped_by_pattern():
+    """
+    find_matches_grouped_by_pattern() groups match spans by pattern index, or
+    by pattern with pattern_keys=True.
+    """
+    ac = AhoCorasick(["hello", "world", "fish", "hello"])
+    haystack = "☃ hello world, hello"
+    assert ac.find_matches_grouped_by_pattern(haystack) == {
+        0: [(2, 7), (15, 20)],
+        1: [(8, 13)],
+    }
+    grouped = ac.find_matches_grouped_by_pattern(Haystack(haystack), pattern_keys=True)
+    assert grouped == {"hello": [(2, 7), (15, 20)], "world": [(8, 13)]}
+    ac = AhoCorasick(["he", "hello"])
+    assert ac.find_matches_grouped_by_pattern("hello", overlapping=True) == {
+        0: [(0, 2)],
+        1: [(0, 5)],
+    }
+
+    ac = BytesAhoCorasick([b"hello", b"world"])
+    haystack = "☃ hello world, hello".encode("utf-8")
+    assert ac.find_matches_grouped_by_pattern(haystack) == {
+        0: [(4, 9), (17, 22)],
+        1: [(10, 15)],
+    }
+    assert ac.find_matches_grouped_by_pattern(haystack, pattern_keys=True) == {
+        b"hello": [(4, 9), (17, 22)],
+        b"world": [(10, 15)],
+    }
+
+
+@pytest.mark.parametrize(
+    "ac,haystack",
+    [
+        (AhoCorasick(["a", "b", "c", "d"]), "☃abcd"),
+        (BytesAhoCorasick([b"a", b"b", b"c", b"d"]), b"xabcd"),
+    ],
+)
+def test_filter_fn(ac, haystack):
+    """
+    filter_fn decides which matches find_matches_as_indexes() returns.
+    """
+    calls = []
+
+    def even_patterns(pattern_index, start, end):
+        calls.append((pattern_index, start, end))
+        return pattern_index % 2 == 0
+
+    expected = [(0, 1, 2), (1, 2, 3), (2, 3, 4), (3, 4, 5)]
+    assert ac.find_matches_as_indexes(haystack, filter_fn=even_patterns) == [
+        (0, 1, 2),
+        (2, 3, 4),
+    ]
+    assert calls == expected
+    assert ac.find_matches_as_indexes(haystack, filter_fn=lambda *args: False) == []
+    assert ac.find_matches_as_indexes(haystack, filter_fn=None) == expected
+    assert ac.find_matches_as_indexes(haystack) == expected
+
+    class FilterError(Exception):
+        pass
+
+    def failing(pattern_index, start, end):
+        if pattern_index == 2:
+            raise FilterError()
+        return True
+
+    with pytest.raises(FilterError):
+        ac.find_matches_as_indexes(haystack, filter_fn=failing)
+
+
+def test_matched_pattern_mask():
+    """
+    matched_pattern_mask() returns a bool per pattern, optionally bit-packed.
+    """
+    patterns = ["p%d" % i for i in range(10)]
+    ac = AhoCorasick(patterns)
+    haystack = "p0 p3 p3 p8 p9"
+    mask = ac.matched_pattern_mask(haystack)
+    assert mask == [i in (0, 3, 8, 9) for i in range(10)]
+    assert ac.matched_pattern_mask(haystack, packed=True) == bytes(
+        [0b10010000, 0b11000000]
+    )
+    assert ac.matched_pattern_mask("nothing") == [False] * 10
+    assert ac.matched_pattern_mask("nothing", packed=True) == b"\x00\x00"
+
+    ac = AhoCorasick(["he", "hello"])
+    assert ac.matched_pattern_mask("hello") == [True, False]
+    assert ac.matched_pattern_mask("hello", overlapping=True) == [True, True]
+
+    ac = BytesAhoCorasick([p.encode() for p in patterns])
+    assert ac.matched_pattern_mask(haystack.encode()) == mask
+    assert ac.matched_pattern_mask(haystack.encode(), packed=True) == bytes(
+        [0b10010000, 0b11000000]
+    )
+
+
+@pytest.mark.parametrize("matchkind", [MATCHKIND_STANDARD, MATCHKIND_LEFTMOST_LONGEST])
+def test_bytes_pickle(matchkind):
+    """
+    BytesAhoCorasick can be pickled if it stores its patterns.
+    """
+    import pickle
+
+    ac = BytesAhoCorasick(
+        [b"he", b"hello", b"\x00"],
+        matchkind=matchkind,
+        store_patterns=True,
+        ids=["a", "b", "c"],
+        values=[1, 2, 3],
+    )
+    ac2 = pickle.loads(pickle.dumps(ac))
+    assert isinstance(ac2, BytesAhoCorasick)
+    assert ac2.patterns == ac.patterns
+    assert ac2.matchkind == matchkind
+    assert ac2.implementation == ac.implementation
+    assert ac2.store_patterns
+    haystack = b"hello\x00"
+    assert ac2.find_matches_as_indexes(haystack) == ac.find_matches_as_indexes(
+        haystack
+    )
+    assert ac2.find_matches_as_ids(haystack) == ac.find_matches_as_ids(haystack)
+    assert ac2.find_matches_as_values(haystack) == ac.find_matches_as_values(
+        haystack
+    )
+
+    with pytest.raises(TypeError, match="store_patterns=True"):
+        pickle.dumps(BytesAhoCorasick([b"hello"]))
+
+
+def test_build_time_us():
+    """
+    build_time_us is how long building took, and is read-only.
+    """
+    patterns = ["pattern%d" % i for i in range(10_000)]
+    for ac in [
+        AhoCorasick(patterns),
+        BytesAhoCorasick([p.encode() for p in patterns]),
+    ]:
+        assert isinstance(ac.build_time_us, int)
+        assert ac.build_time_us > 0
+        with pytest.raises(AttributeError):
+            ac.build_time_us = 0
+
+
+@pytest.mark.parametrize("wrap", [bytes, bytearray, memoryview])
+def test_utf8_bytes_haystack(wrap):
+    """
+    AhoCorasick methods accept UTF-8 bytes-like haystacks, returning code point
+    offsets as if the haystack had been decoded.
+    """
+    text = "hello, world ☃fishá l🤦l"
+    haystack = wrap(text.encode("utf-8"))
+    ac = AhoCorasick(["d ☃f", "há", "l🤦l", "hello"])
+    for method in [
+        "find_matches_as_indexes",
+        "find_matches_as_strings",
+        "matched_pattern_ids",
+        "find_gaps",
+        "find_coverage_fraction",
+    ]:
+        for overlapping in [False, True]:
+            assert getattr(ac, method)(haystack, overlapping=overlapping) == getattr(
+                ac, method
+            )(text, overlapping=overlapping)
+    assert ac.find_matches_as_indexes(
+        haystack, search_limit=15
+    ) == ac.find_matches_as_indexes(text, search_limit=15)
+
+    with pytest.raises(ValueError, match="UTF-8"):
+        ac.find_matches_as_indexes(wrap(b"hello \xff"))
+
+
+def test_utf8_mutable_haystack():
+    """
+    A mutable bytes-like haystack is searched as it was when the call started,
+    even if it's changed to invalid UTF-8 during the search.
+    """
+    text = "hello wörld " * 1000
+    haystack = bytearray(text.encode("utf-8"))
+    ac = AhoCorasick(["hello", "wörld"])
+    calls = []
+
+    def corrupt(*m):
+        calls.append(m)
+        haystack[:] = b"\xff" * len(haystack)
+
+    ac.for_each_match(haystack, corrupt)
+    assert calls == ac.find_matches_as_indexes(text)
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(haystack)
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(memoryview(haystack).toreadonly())
+
+
+def test_copy():
+    """
+    Copying an automaton returns a new object of the same class that shares
+    the built automaton; deep copies also copy values and subclass attributes.
+    """
+    tagged = TaggedBytesAhoCorasick([b"hello"], values=[[1]], store_patterns=True)
+    tagged.tag = ["mine"]
+    for ac, haystack in [
+        (AhoCorasick(["hello"], values=[[1]]), "oh hello"),
+        (BytesAhoCorasick([b"hello"], values=[[1]]), b"oh hello"),
+        (tagged, b"oh hello"),
+    ]:
+        shallow = copy.copy(ac)
+        assert shallow is not ac
+        assert type(shallow) is type(ac)
+        assert shallow.find_matches_as_indexes(haystack) == [(0, 3, 8)]
+        assert shallow.get_value(0) is ac.get_value(0)
+
+        deep = copy.deepcopy(ac)
+        assert deep is not ac
+        assert type(deep) is type(ac)
+        assert deep.find_matches_as_indexes(haystack) == [(0, 3, 8)]
+        assert deep.get_value(0) == [1]
+        assert deep.get_value(0) is not ac.get_value(0)
+
+        # The same object is only copied once:
+        config = {"first": ac, "second": ac, "other": [1, 2]}
+        copied = copy.deepcopy(config)
+        assert copied["first"] is copied["second"]
+        assert copied["first"] is not ac
+        assert copied["other"] == [1, 2]
+        assert copied["other"] is not config["other"]
+
+    assert copy.copy(tagged).tag is tagged.tag
+    assert copy.deepcopy(tagged).tag == ["mine"]
+    assert copy.deepcopy(tagged).tag is not tagged.tag
+
+    # Dropping the patterns of a copy doesn't affect the original:
+    ac = AhoCorasick(["hello"])
+    copied = copy.copy(ac)
+    copied.drop_patterns()
+    assert ac.patterns == ["hello"]
+    assert not copied.store_patterns
+
+    # Values that refer back to the automaton are copied consistently:
+    values = [[]]
+    ac = AhoCorasick(["hello"], values=values)
+    values[0].append(ac)
+    deep = copy.deepcopy(ac)
+    assert deep.get_value(0)[0] is deep
+
+
+def test_update():
+    """
+    update() returns a new AhoCorasick with the additional patterns appended.
+    """
+    ac = AhoCorasick(
+        ["hello", "world"], matchkind=MATCHKIND_LEFTMOST_LONGEST, values=[1, 2]
+    )
+    updated = ac.update(["new", "hell"], values=[3, 4])
+    assert len(updated) == len(ac) + 2
+    assert updated.patterns == ["hello", "world", "new", "hell"]
+    assert updated.matchkind == ac.matchkind
+    assert updated.find_matches_as_indexes("hello new world") == [
+        (0, 0, 5),
+        (2, 6, 9),
+        (1, 10, 15),
+    ]
+    assert updated.find_matches_as_values("hell new") == [(4, 0, 4), (3, 5, 8)]
+    # The original is unchanged:
+    assert ac.find_matches_as_indexes("hello new world") == [(0, 0, 5), (1, 10, 15)]
+
+    # Duplicates are skipped by default, or raise a ValueError:
+    assert AhoCorasick(["a", "b"]).update(["b", "c", "c"]).patterns == ["a", "b", "c"]
+    with pytest.raises(ValueError):
+        AhoCorasick(["a", "b"]).update(["b", "c"], deduplicate_patterns=False)
+    with pytest.raises(ValueError):
+        AhoCorasick(["a", "b"]).update(["c", "c"], deduplicate_patterns=False)
+
+    # Values must be passed if and only if the original has them:
+    with pytest.raises(ValueError):
+        ac.update(["new"])
+    with pytest.raises(ValueError):
+        AhoCorasick(["a"]).update(["b"], ids=["x"])
+
+
+def test_save_load(tmp_path):
+    """
+    save() and load() round-trip the patterns and construction options.
+    """
+    path = tmp_path / "automaton"
+    ac = AhoCorasick(["hello", "wörld", ""], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    ac.save(path)
+    loaded = AhoCorasick.load(path)
+    assert loaded == ac
+    assert loaded.find_matches_as_indexes("hello wörld") == ac.find_matches_as_indexes(
+        "hello wörld"
+    )
+
+    counted = AhoCorasick.from_counter({"a": 1, "b": 2})
+    counted.save(str(path))
+    loaded = AhoCorasick.load(str(path))
+    assert loaded.patterns == ["b", "a"]
+    assert loaded.sorted_index_to_original_index(0) == 1
+
+    forced = AhoCorasick(["hello"], force_byte_offsets_on_ascii=True)
+    forced.save(path)
+    assert AhoCorasick.load(path).find_matches_as_indexes("☃ hello") == [(0, 4, 9)]
+
+    bytes_ac = BytesAhoCorasick(
+        [b"hello", b"\xff"], matchkind=MATCHKIND_LEFTMOST_LONGEST, store_patterns=True
+    )
+    bytes_ac.save(path)
+    loaded = BytesAhoCorasick.load(path)
+    assert loaded.patterns == bytes_ac.patterns
+    assert loaded.matchkind == MATCHKIND_LEFTMOST_LONGEST
+    assert loaded.find_matches_as_indexes(b"hello \xff") == [(0, 0, 5), (1, 6, 7)]
+
+    # The wrong class:
+    with pytest.raises(ValueError, match="BytesAhoCorasick"):
+        AhoCorasick.load(path)
+
+    # Things that can't be saved:
+    with pytest.raises(TypeError, match="store_patterns=True"):
+        BytesAhoCorasick([b"hello"]).save(path)
+    with pytest.raises(ValueError):
+        AhoCorasick(["hello"], values=[1]).save(path)
+
+
+def test_load_errors(tmp_path):
+    """
+    Loading an invalid file raises a ValueError rather than crashing.
+    """
+    path = tmp_path / "automaton"
+    AhoCorasick(["hello", "world"]).save(path)
+    data = path.read_bytes()
+
+    # Every truncation of the file fails:
+    for length in range(len(data)):
+        path.write_bytes(data[:length])
+        with pytest.raises(ValueError):
+            AhoCorasick.load(path)
+
+    # An unknown version:
+    version_offset = len(b"ahocorasick_rs\0")
+    path.write_bytes(
+        data[:version_offset] + b"\xff\x00\x00\x00" + data[version_offset + 4 :]
+    )
+    with pytest.raises(ValueError, match="version 255"):
+        AhoCorasick.load(path)
+
+    # The pattern order isn't a permutation:
+    AhoCorasick(["b", "a"], sort_patterns=True).save(path)
+    data = path.read_bytes()
+    path.write_bytes(data[:-16] + bytes(16))
+    with pytest.raises(ValueError, match="duplicate pattern index"):
+        AhoCorasick.load(path)
+
+    # Not a saved automaton at all:
+    path.write_bytes(b"hello\nworld\n")
+    with pytest.raises(ValueError, match="isn't a saved"):
+        AhoCorasick.load(path)
+
+    with pytest.raises(IOError):
+        AhoCorasick.load(tmp_path / "nonexistent")
+
+
+def test_positions_only():
+    """
+    positions_only=True returns just the start of each match.
+    """
+    ac = AhoCorasick(["hello", "wörld", "ld"])
+    bytes_ac = BytesAhoCorasick([b"hello", b"w\xc3\xb6rld", b"ld"])
+    haystack = "hello wörld, ld hello"
+    for kwargs in [
+        {},
+        {"overlapping": True},
+        {"utf16_offsets": True},
+        {"filter_fn": lambda p, s, e: p != 2},
+    ]:
+        assert [
+            s for _, s, _ in ac.find_matches_as_indexes(haystack, **kwargs)
+        ] == ac.find_matches_as_indexes(haystack, positions_only=True, **kwargs)
+        if "utf16_offsets" not in kwargs:
+            encoded = haystack.encode("utf-8")
+            assert [
+                s for _, s, _ in bytes_ac.find_matches_as_indexes(encoded, **kwargs)
+            ] == bytes_ac.find_matches_as_indexes(
+                encoded, positions_only=True, **kwargs
+            )
+    assert ac.find_matches_as_indexes(haystack, positions_only=True) == [0, 6, 13, 16]
+
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(haystack, positions_only=True, both_offsets=True)
+    with pytest.raises(ValueError):
+        bytes_ac.find_matches_as_indexes(
+            b"hello", positions_only=True, extra_fields={0: 1}
+        )
+
+
+@pytest.mark.parametrize("haystack", ["", Haystack(""), b""])
+def test_empty_haystack(haystack):
+    """
+    Searching an empty haystack finds nothing, whatever the method.
+    """
+    ac = AhoCorasick(["a", "bc", "é"], values=[1, 2, 3])
+    for kwargs in [
+        {},
+        {"overlapping": True},
+        {"both_offsets": True},
+        {"unicode_words": True},
+        {"utf16_offsets": True},
+        {"positions_only": True},
+    ]:
+        assert ac.find_matches_as_indexes(haystack, **kwargs) == []
+    for method in [
+        "find_matches_as_ids",
+        "find_matches_as_strings",
+        "find_matches_as_values",
+        "matched_patterns",
+        "find_gaps",
+    ]:
+        assert getattr(ac, method)(haystack) == []
+    assert ac.matched_pattern_ids(haystack) == set()
+    assert ac.find_coverage_fraction(haystack) == 0.0
+    assert ac.find_nearest_match(haystack, 0) is None
+    assert ac.find_matches_grouped_by_pattern(haystack) == {}
+    assert ac.matched_pattern_mask(haystack) == [False, False, False]
+    assert ac.with_cache(2).find_matches_as_indexes(haystack) == []
+
+
+def test_empty_haystack_other_inputs():
+    """
+    Empty inputs to the other search methods find nothing.
+    """
+    import io
+
+    ac = AhoCorasick(["a", "bc"])
+    assert ac.find_matches_in_lines([""]) == []
+    assert ac.find_matches_from_stringio(io.StringIO("")) == []
+    assert ac.find_matches_streaming_bytes([]) == []
+    assert ac.find_matches_streaming_bytes([b""]) == []
+
+    bytes_ac = BytesAhoCorasick([b"a", b"bc"], values=[1, 2])
+    assert bytes_ac.find_matches_as_indexes(b"") == []
+    assert bytes_ac.find_matches_as_indexes(b"", positions_only=True) == []
+    assert bytes_ac.find_matches_as_ids(b"") == []
+    assert bytes_ac.find_matches_as_values(b"") == []
+    assert bytes_ac.matched_pattern_ids(b"") == set()
+    assert bytes_ac.find_gaps(b"") == []
+    assert bytes_ac.find_coverage_fraction(b"") == 0.0
+    assert bytes_ac.find_matches_grouped_by_pattern(b"") == {}
+    assert bytes_ac.matched_pattern_mask(b"") == [False, False]
+    assert bytes_ac.find_matches_across_chunks([]) == []
+    assert bytes_ac.find_matches_from_bytesio(io.BytesIO()) == []
+
+
+class TaggedBytesAhoCorasick(BytesAhoCorasick):
+    """
+    A subclass defined at module level, so it can be pickled.
+    """
+
+
+class UpperAhoCorasick(AhoCorasick):
+    """
+    A subclass with its own __init__() arguments, defined at module level so
+    it can be pickled.
+    """
+
+    def __init__(self, patterns, *, source, **kwargs):
+        self.source = source
+
+    def find_matches_as_strings(self, haystack, **kwargs):
+        return [s.upper() for s in super().find_matches_as_strings(haystack)]
+
+
+class PrefixedBytesAhoCorasick(BytesAhoCorasick):
+    """
+    A subclass whose extra positional argument is removed in __new__().
+    """
+
+    def __new__(cls, prefix, patterns, **kwargs):
+        return super().__new__(cls, [prefix + p for p in patterns], **kwargs)
+
+    def __init__(self, prefix, patterns, **kwargs):
+        self.prefix = prefix
+
+
+def test_subclassing():
+    """
+    AhoCorasick and BytesAhoCorasick can be subclassed in Python, and extra
+    keyword arguments are left to the subclass's __init__().
+    """
+    import pickle
+
+    ac = UpperAhoCorasick(
+        ["hello", "world"], source="greetings", matchkind=MATCHKIND_LEFTMOST_FIRST
+    )
+    assert isinstance(ac, AhoCorasick)
+    assert ac.source == "greetings"
+    assert ac.matchkind == MATCHKIND_LEFTMOST_FIRST
+    assert ac.find_matches_as_strings("hello world") == ["HELLO", "WORLD"]
+    assert ac.find_matches_as_indexes("hello world") == [(0, 0, 5), (1, 6, 11)]
+
+    bytes_ac = TaggedBytesAhoCorasick([b"hello"], store_patterns=True)
+    bytes_ac.tag = "mine"
+    assert bytes_ac.find_matches_as_indexes(b"oh hello") == [(0, 3, 8)]
+    unpickled = pickle.loads(pickle.dumps(bytes_ac))
+    assert type(unpickled) is TaggedBytesAhoCorasick
+    assert unpickled.tag == "mine"
+    assert unpickled.find_matches_as_indexes(b"oh hello") == [(0, 3, 8)]
+
+
+def test_unknown_arguments():
+    """
+    AhoCorasick and BytesAhoCorasick reject arguments they don't take; only
+    subclasses without their own __new__() ignore them.
+    """
+    with pytest.raises(TypeError):
+        AhoCorasick(["a"], matchkin=MATCHKIND_LEFTMOST_FIRST)
+    with pytest.raises(TypeError):
+        BytesAhoCorasick([b"a"], store_pattern=True)
+
+    # Known arguments still reach the base class, and are still checked:
+    ac = UpperAhoCorasick(["a"], source="s", store_patterns=True, matchkin="x")
+    assert ac.patterns == ["a"]
+    with pytest.raises(ValueError):
+        UpperAhoCorasick(["a"], source="s", matchkind="oops")
+    with pytest.raises(TypeError):
+        PrefixedBytesAhoCorasick(b"x", [b"a"], store_pattern=True)
+
+
+def test_subclass_factories(tmp_path):
+    """
+    The from_*() factories and load() return an instance of the subclass
+    they're called on; the factories pass keyword arguments to its
+    constructor.
+    """
+    ac = UpperAhoCorasick.from_counter({"hello": 1, "world": 2}, source="counts")
+    assert type(ac) is UpperAhoCorasick
+    assert ac.source == "counts"
+    assert ac.find_matches_as_strings("hello world") == ["HELLO", "WORLD"]
+    assert ac.sorted_index_to_original_index(0) == 1
+
+    ac = UpperAhoCorasick.from_regex_literals(["^hello$"], source="regexes")
+    assert type(ac) is UpperAhoCorasick
+    assert ac.source == "regexes"
+
+    ac = UpperAhoCorasick.from_trie_dict({"h": {"i": {"": 0}}}, source="trie")
+    assert type(ac) is UpperAhoCorasick
+    assert ac.find_matches_as_strings("hi") == ["HI"]
+
+    path = tmp_path / "automaton.bin"
+    ac.save(path)
+    loaded = UpperAhoCorasick.load(path)
+    assert type(loaded) is UpperAhoCorasick
+    assert loaded.find_matches_as_strings("hi") == ["HI"]
+    assert type(AhoCorasick.load(path)) is AhoCorasick
+
+    BytesAhoCorasick([b"hello"], store_patterns=True).save(path)
+    loaded = TaggedBytesAhoCorasick.load(path)
+    assert type(loaded) is TaggedBytesAhoCorasick
+    assert loaded.find_matches_as_indexes(b"oh hello") == [(0, 3, 8)]
+
+
+def test_pickle_subclass_with_init():
+    """
+    Pickling a subclass with its own __new__() or __init__() arguments
+    restores its type and attributes without calling them again.
+    """
+    import pickle
+
+    ac = UpperAhoCorasick(
+        ["hello", "world"], source="greetings", matchkind=MATCHKIND_LEFTMOST_FIRST
+    )
+    unpickled = pickle.loads(pickle.dumps(ac))
+    assert type(unpickled) is UpperAhoCorasick
+    assert unpickled.source == "greetings"
+    assert unpickled == ac
+    assert unpickled.matchkind == MATCHKIND_LEFTMOST_FIRST
+    assert unpickled.find_matches_as_strings("hello world") == ["HELLO", "WORLD"]
+
+    bytes_ac = PrefixedBytesAhoCorasick(b"x", [b"a", b"b"], store_patterns=True)
+    assert bytes_ac.patterns == [b"xa", b"xb"]
+    unpickled = pickle.loads(pickle.dumps(bytes_ac))
+    assert type(unpickled) is PrefixedBytesAhoCorasick
+    assert unpickled.prefix == b"x"
+    assert unpickled.patterns == [b"xa", b"xb"]
+    assert unpickled.find_matches_as_indexes(b"xb xa") == [(1, 0, 2), (0, 3, 5)]
+
+
+def test_include_pattern_text():
+    """
+    include_pattern_text=True adds the matched text to each tuple.
+    """
+    haystack = "hello wörld, hello"
+    ac = AhoCorasick(["hello", "wörld"])
+    result = ac.find_matches_as_indexes(haystack, include_pattern_text=True)
+    assert result == [
+        (0, 0, 5, "hello"),
+        (1, 6, 11, "wörld"),
+        (0, 13, 18, "hello"),
+    ]
+    for t in result:
+        assert t[3] == ac.patterns[t[0]]
+        assert t[3] == haystack[t[1] : t[2]]
+
+    encoded = haystack.encode("utf-8")
+    patterns = [b"hello", "wörld".encode("utf-8")]
+    for store_patterns in [False, True]:
+        bytes_ac = BytesAhoCorasick(patterns, store_patterns=store_patterns)
+        result = bytes_ac.find_matches_as_indexes(encoded, include_pattern_text=True)
+        assert len(result) == 3
+        for t in result:
+            assert t[3] == encoded[t[1] : t[2]]
+            assert t[3] == patterns[t[0]]
+
+    with pytest.raises(ValueError):
+        ac.find_matches_as_indexes(
+            haystack, include_pattern_text=True, positions_only=True
+        )
+    with pytest.raises(ValueError):
+        BytesAhoCorasick(patterns).find_matches_as_indexes(
+            encoded, include_pattern_text=True, extra_fields={}
+        )
+
+
+def test_validate_patterns():
+    """
+    validate_patterns() checks patterns without building an automaton, and
+    returns how many there are.
+    """
+    assert validate_patterns(["hello", "world"]) == 2
+    assert validate_patterns([]) == 0
+    assert validate_patterns(line for line in ["a", "b", "c"]) == 3
+
+    with pytest.raises(ValueError, match="index 1 is empty"):
+        validate_patterns(["hello", "", "world"])
+    with pytest.raises(TypeError, match="index 2"):
+        validate_patterns(["hello", "world", b"bytes"])
+    with pytest.raises(ValueError, match="index 0"):
+        validate_patterns(["\ud800"])
+    with pytest.raises(TypeError):
+        validate_patterns("hello")
+    with pytest.raises(TypeError):
+        validate_patterns(1)
+
+
+def test_on_invalid_skip():
+    """
+    on_invalid="skip" leaves out empty and wrongly typed patterns, along
+    with their ids and values, and records their indexes.
+    """
+    patterns = ["hello", "", None, "world", 3, "\ud800", "there"]
+    with pytest.raises(TypeError):
+        AhoCorasick(patterns)
+    ac = AhoCorasick(
+        patterns,
+        ids=list(range(10, 17)),
+        values=list(range(20, 27)),
+        on_invalid="skip",
+    )
+    assert ac.patterns == ["hello", "world", "there"]
+    assert ac.skipped_patterns == [1, 2, 4, 5]
+    assert ac.find_matches_as_ids("hello world") == [(10, 0, 5), (13, 6, 11)]
+    assert ac.get_value(2) == 26
+    assert AhoCorasick(["", "a"]).skipped_patterns == []
+    assert AhoCorasick(["", "a"], on_invalid="skip").patterns == ["a"]
+    with pytest.raises(ValueError, match="one per pattern"):
+        AhoCorasick(patterns, ids=[1, 2, 3], on_invalid="skip")
+    with pytest.raises(ValueError, match="on_invalid"):
+        AhoCorasick(["a"], on_invalid="ignore")
+    with pytest.raises(TypeError):
+        AhoCorasick("hello", on_invalid="skip")
+
+    bytes_ac = BytesAhoCorasick(
+        [b"hello", b"", "str", bytearray(b"world"), None],
+        store_patterns=True,
+        on_invalid="skip",
+    )
+    assert bytes_ac.patterns == [b"hello", b"world"]
+    assert bytes_ac.skipped_patterns == [1, 2, 4]
+    assert BytesAhoCorasick([b"", b"a"]).skipped_patterns == []
+    with pytest.raises(TypeError):
+        BytesAhoCorasick([b"hello", None])
+
+
+def test_coerce():
+    """
+    coerce=True converts patterns of other types with str() or bytes(), and
+    rejects ones that end up empty; otherwise the TypeError says which
+    pattern has the wrong type.
+    """
+    from pathlib import PurePosixPath
+
+    class Empty:
+        def __str__(self):
+            return ""
+
+        def __bytes__(self):
+            return b""
+
+    class Token:
+        def __bytes__(self):
+            return b"token"
+
+    class Broken:
+        def __str__(self):
+            raise RuntimeError("no str")
+
+        def __bytes__(self):
+            raise RuntimeError("no bytes")
+
+    with pytest.raises(TypeError, match="index 1 must be a str, got PurePosixPath"):
+        AhoCorasick(["hello", PurePosixPath("/tmp")])
+    ac = AhoCorasick(["hello", PurePosixPath("/tmp"), 3], coerce=True)
+    assert ac.patterns == ["hello", "/tmp", "3"]
+    assert ac.find_matches_as_strings("/tmp/3") == ["/tmp", "3"]
+    # Empty strings are still fine if they didn't need converting:
+    assert AhoCorasick(["", "a"], coerce=True).patterns == ["", "a"]
+    with pytest.raises(ValueError, match="index 1 is empty"):
+        AhoCorasick(["a", Empty()], coerce=True)
+    ac = AhoCorasick(["a", Empty(), None], coerce=True, on_invalid="skip")
+    assert ac.patterns == ["a", "None"]
+    assert ac.skipped_patterns == [1]
+
+    with pytest.raises(TypeError, match="index 1 must be bytes-like, got .*Token"):
+        BytesAhoCorasick([b"hello", Token()])
+    ac = BytesAhoCorasick([b"hello", Token()], coerce=True, store_patterns=True)
+    assert ac.patterns == [b"hello", b"token"]
+    with pytest.raises(ValueError, match="index 0 is empty"):
+        BytesAhoCorasick([Empty()], coerce=True)
+    with pytest.raises(TypeError):
+        BytesAhoCorasick(["str"], coerce=True)
+    # Only __bytes__() is used, so ints and lists of ints aren't converted:
+    with pytest.raises(TypeError, match="index 1 must be bytes-like, got int"):
+        BytesAhoCorasick([b"a", 5], coerce=True)
+    with pytest.raises(TypeError, match="index 0 must be bytes-like, got list"):
+        BytesAhoCorasick([[104, 105]], coerce=True)
+
+    # With on_invalid="skip", failed conversions are skipped:
+    ac = AhoCorasick(["a", Broken()], coerce=True, on_invalid="skip")
+    assert ac.skipped_patterns == [1]
+    ac = BytesAhoCorasick(
+        [b"a", 5, Broken(), Token()],
+        coerce=True,
+        on_invalid="skip",
+        store_patterns=True,
+    )
+    assert ac.patterns == [b"a", b"token"]
+    assert ac.skipped_patterns == [1, 2]
+    with pytest.raises(RuntimeError, match="no bytes"):
+        BytesAhoCorasick([Broken()], coerce=True)
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_find_matches_into(overlapping):
+    """
+    find_matches_into() appends the same tuples find_matches_as_indexes()
+    returns to an existing list, and returns how many it appended.
+    """
+    ac = AhoCorasick(["he", "hello", "wörld"])
+    out = ["existing"]
+    for haystack in ["hello wörld", "nothing", "hehe"]:
+        initial_len = len(out)
+        count = ac.find_matches_into(haystack, out, overlapping=overlapping)
+        expected = ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+        assert count == len(out) - initial_len == len(expected)
+        assert out[initial_len:] == expected
+    assert out[0] == "existing"
+    with pytest.raises(TypeError):
+        ac.find_matches_into("hello", ())
+    with pytest.raises(TypeError):
+        ac.find_matches_into("hello", None)
+
+
+def test_find_matches_into_numpy():
+    """
+    find_matches_into_numpy() writes the same matches find_matches_as_indexes()
+    returns into rows of a uint64 array, stopping when the array is full.
+    """
+    np = pytest.importorskip("numpy")
+
+    ac = AhoCorasick(["he", "wörld", "d"])
+    haystack = "hello wörld d he"
+    expected = np.array(ac.find_matches_as_indexes(haystack), dtype=np.uint64)
+    out = np.zeros((10, 3), dtype=np.uint64)
+    n = ac.find_matches_into_numpy(haystack, out)
+    assert n == 4
+    assert (out[:n] == expected[:n]).all()
+    assert (out[n:] == 0).all()
+
+    # Starting at an offset, extra matches are discarded rather than written
+    # past the end:
+    out = np.full((4, 3), 7, dtype=np.uint64)
+    assert ac.find_matches_into_numpy(haystack, out, offset=2) == 2
+    assert (out[:2] == 7).all()
+    assert (out[2:] == expected[:2]).all()
+    assert ac.find_matches_into_numpy(haystack, out, offset=4) == 0
+
+    # Non-contiguous arrays are written through their strides:
+    out = np.zeros((4, 6), dtype=np.uint64)
+    assert ac.find_matches_into_numpy(haystack, out[:, ::2]) == 4
+    assert (out[:, ::2] == expected).all()
+    assert (out[:, 1::2] == 0).all()
+
+    with pytest.raises(ValueError):
+        ac.find_matches_into_numpy(haystack, np.zeros((4, 3), dtype=np.int64))
+    with pytest.raises(ValueError):
+        ac.find_matches_into_numpy(haystack, np.zeros((4, 2), dtype=np.uint64))
+    with pytest.raises(ValueError):
+        ac.find_matches_into_numpy(haystack, np.zeros(12, dtype=np.uint64))
+    with pytest.raises(ValueError):
+        ac.find_matches_into_numpy(haystack, out[:, ::2], offset=5)
+
+
+def test_find_matches_into_buffer():
+    """
+    find_matches_into_numpy() works with any writable buffer of unsigned
+    64-bit integers with shape (N, 3), not just NumPy arrays.
+    """
+    ac = AhoCorasick(["he", "wörld", "d"])
+    haystack = "hello wörld d he"
+    out = memoryview(bytearray(8 * 3 * 3)).cast("Q", shape=[3, 3])
+    assert ac.find_matches_into_numpy(haystack, out, offset=1) == 2
+    assert out.tolist() == [[0, 0, 0], [0, 0, 2], [1, 6, 11]]
+    with pytest.raises(ValueError):
+        ac.find_matches_into_numpy(haystack, memoryview(bytearray(8 * 3)).cast("Q"))
+    with pytest.raises(BufferError):
+        ac.find_matches_into_numpy(
+            haystack, memoryview(bytes(8 * 3)).cast("Q", shape=[1, 3])
+        )
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_find_matches_as_json(overlapping):
+    """
+    find_matches_as_json() returns the same matches as
+    find_matches_as_indexes(), as JSON objects, with the pattern text only
+    if patterns are stored.
+    """
+    import json
+
+    ac = AhoCorasick(["he", "hello", "wörld", 'a"b'])
+    haystack = 'hello wörld a"b'
+    expected = ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+    result = json.loads(ac.find_matches_as_json(haystack, overlapping=overlapping))
+    assert result == [
+        {"pattern": i, "start": start, "end": end, "text": ac.patterns[i]}
+        for (i, start, end) in expected
+    ]
+    ac.drop_patterns()
+    result = json.loads(ac.find_matches_as_json(haystack, overlapping=overlapping))
+    assert result == [
+        {"pattern": i, "start": start, "end": end} for (i, start, end) in expected
+    ]
+    assert ac.find_matches_as_json("nothing") == "[]"
+
+
+def test_drop_patterns():
+    """
+    drop_patterns() stops storing the patterns; matched text then comes from
+    the haystack.
+    """
+    haystack = "hello wörld, hello"
+    ac = AhoCorasick(["wörld", "hello", "hello"])
+    hash_before = hash(ac)
+    expected_strings = ac.find_matches_as_strings(haystack)
+    expected_matched = ac.matched_patterns(haystack)
+    expected_text = ac.find_matches_as_indexes(haystack, include_pattern_text=True)
+    assert ac.store_patterns
+    memory_usage = ac.memory_usage()
+
+    ac.drop_patterns()
+    ac.drop_patterns()
+    assert not ac.store_patterns
+    assert "store_patterns=False" in repr(ac)
+    assert ac.memory_usage() < memory_usage
+    assert len(ac) == 3
+    assert ac.find_matches_as_strings(haystack) == expected_strings
+    assert ac.matched_patterns(haystack) == expected_matched
+    assert (
+        ac.find_matches_as_indexes(haystack, include_pattern_text=True)
+        == expected_text
+    )
+
+    for introspect in [
+        lambda: ac.patterns,
+        lambda: ac.get_pattern(0),
+        lambda: "hello" in ac,
+        lambda: ac.pattern_lengths(),
+    ]:
+        with pytest.raises(RuntimeError):
+            introspect()
+    # Like BytesAhoCorasick without stored patterns, it's not iterable:
+    with pytest.raises(TypeError, match="store_patterns=True"):
+        list(ac)
+    with pytest.raises(TypeError, match="store_patterns=True"):
+        list(AhoCorasick(["a"], store_patterns=False))
+
+    # Without patterns, it's only equal to itself, and the hash that was
+    # cached beforehand is no longer used:
+    same_patterns = AhoCorasick(["wörld", "hello", "hello"])
+    assert ac == ac
+    assert ac != same_patterns
+    assert same_patterns != ac
+    assert ac.__eq__(same_patterns) is NotImplemented
+    assert same_patterns.__eq__(ac) is NotImplemented
+    assert hash(ac) == hash(ac)
+    assert hash(ac) != hash_before
+    assert hash(same_patterns) == hash_before
+    assert len({ac, same_patterns}) == 2
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_fill_gaps(overlapping):
+    """
+    fill_gaps=True replaces matches rejected by filter_fn with None, so the
+    result has an entry for every match.
+    """
+    haystack = "hello world, hello there"
+    ac = AhoCorasick(["hello", "world", "there", "llo"])
+    everything = ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+
+    # Without a filter, fill_gaps does nothing:
+    assert (
+        ac.find_matches_as_indexes(haystack, overlapping=overlapping, fill_gaps=True)
+        == everything
+    )
+
+    def not_hello(pattern, start, end):
+        return pattern != 0
+
+    filled = ac.find_matches_as_indexes(
+        haystack, overlapping=overlapping, filter_fn=not_hello, fill_gaps=True
+    )
+    assert len(filled) == len(everything)
+    assert filled == [None if m[0] == 0 else m for m in everything]
+    assert [m for m in filled if m is not None] == ac.find_matches_as_indexes(
+        haystack, overlapping=overlapping, filter_fn=not_hello
+    )
+    assert ac.find_matches_as_indexes(
+        haystack,
+        overlapping=overlapping,
+        filter_fn=not_hello,
+        fill_gaps=True,
+        positions_only=True,
+    ) == [None if m[0] == 0 else m[1] for m in everything]
+
+    bytes_ac = BytesAhoCorasick([b"hello", b"world", b"there", b"llo"])
+    encoded = haystack.encode("utf-8")
+    assert bytes_ac.find_matches_as_indexes(
+        encoded, overlapping=overlapping, filter_fn=not_hello, fill_gaps=True
+    ) == [None if m[0] == 0 else m for m in everything]
+
+
+def test_match_length_filters():
+    """
+    min_match_length and max_match_length skip matches outside the length
+    range, counting code points for str and bytes for bytes.
+    """
+    ac = AhoCorasick(["é", "éé", "ééé", "éééé"])
+    haystack = "xéééé"
+    everything = ac.find_matches_as_indexes(haystack, overlapping=True)
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, min_match_length=2
+    ) == [m for m in everything if m[2] - m[1] >= 2]
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, max_match_length=3
+    ) == [m for m in everything if m[2] - m[1] <= 3]
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, min_match_length=2, max_match_length=3
+    ) == [m for m in everything if 2 <= m[2] - m[1] <= 3]
+    assert ac.find_matches_as_indexes(
+        haystack, overlapping=True, min_match_length=2, positions_only=True
+    ) == [m[1] for m in everything if m[2] - m[1] >= 2]
+
+    bytes_ac = BytesAhoCorasick([p.encode() for p in ac.patterns])
+    assert bytes_ac.find_matches_as_indexes(
+        haystack.encode(), overlapping=True, min_match_length=3, max_match_length=4
+    ) == [(1, 1, 5), (1, 3, 7), (1, 5, 9)]
+
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_find_matches_filtered(overlapping):
+    """
+    find_matches_filtered() only returns matches of the given patterns.
+    """
+    haystack = "hello wörld, hello there"
+    ac = AhoCorasick(["hello", "wörld", "there", "llo"])
+    bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in ac.patterns])
+    encoded = haystack.encode("utf-8")
+    for pattern_ids in [set(), {0}, [1, 3], (0, 1, 2, 3), range(2)]:
+        assert ac.find_matches_filtered(
+            haystack, pattern_ids, overlapping=overlapping
+        ) == [
+            m
+            for m in ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+            if m[0] in pattern_ids
+        ]
+        assert bytes_ac.find_matches_filtered(
+            encoded, pattern_ids, overlapping=overlapping
+        ) == [
+            m
+            for m in bytes_ac.find_matches_as_indexes(encoded, overlapping=overlapping)
+            if m[0] in pattern_ids
+        ]
+
+    with pytest.raises(IndexError):
+        ac.find_matches_filtered(haystack, [4])
+    with pytest.raises(IndexError):
+        bytes_ac.find_matches_filtered(encoded, {0, 4})
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_find_matches_filtered_by(overlapping):
+    """
+    find_matches_filtered_by() only returns matches the predicate accepts, and
+    rejected matches don't hide accepted ones they overlap.
+    """
+    haystack = "héllo wörld, 1wörld"
+    ac = AhoCorasick(["héllo wörld", "wörld", "héllo"])
+    bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in ac.patterns])
+    encoded = haystack.encode("utf-8")
+    calls = []
+
+    def not_after_digit(text):
+        def predicate(index, start, end):
+            calls.append((index, start, end))
+            return index != 0 and not (start > 0 and text[start - 1 : start].isdigit())
+
+        return predicate
+
+    assert ac.find_matches_filtered_by(
+        haystack, not_after_digit(haystack), overlapping=overlapping
+    ) == [(2, 0, 5), (1, 6, 11)]
+    # The predicate sees code point offsets:
+    assert (1, 14, 19) in calls
+    assert set(calls) <= set(ac.find_matches_as_indexes(haystack, overlapping=True))
+    assert bytes_ac.find_matches_filtered_by(
+        encoded, not_after_digit(encoded), overlapping=overlapping
+    ) == [(2, 0, 6), (1, 7, 13)]
+    assert ac.find_matches_filtered_by(
+        haystack, lambda *m: True, overlapping=overlapping
+    ) == ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+
+    def fail(index, start, end):
+        raise KeyError(index)
+
+    with pytest.raises(KeyError):
+        ac.find_matches_filtered_by(haystack, fail, overlapping=overlapping)
+    with pytest.raises(KeyError):
+        bytes_ac.find_matches_filtered_by(encoded, fail, overlapping=overlapping)
+
+
+@pytest.mark.parametrize("overlapping", [False, True])
+def test_for_each_match(overlapping):
+    """
+    for_each_match() calls the callback with each match, in the order they're
+    found, and stops early if the callback raises StopIteration.
+    """
+    # Enough matches to need several batches:
+    haystack = "héllo wörld, " * 300
+    ac = AhoCorasick(["héllo wörld", "wörld", "héllo"])
+    bytes_ac = BytesAhoCorasick([p.encode("utf-8") for p in ac.patterns])
+    encoded = haystack.encode("utf-8")
+
+    for automaton, text in [(ac, haystack), (bytes_ac, encoded)]:
+        calls = []
+        result = automaton.for_each_match(
+            text, lambda *m: calls.append(m), overlapping=overlapping
+        )
+        assert result is None
+        expected = automaton.find_matches_as_indexes(text, overlapping=overlapping)
+        # Overlapping matches are found in order of their end:
+        assert sorted(calls) == sorted(expected)
+        assert calls == sorted(calls, key=lambda m: m[2])
+
+        for limit in [1, 300]:
+            calls = []
+
+            def stop_at_limit(*m):
+                calls.append(m)
+                if len(calls) == limit:
+                    raise StopIteration
+
+            automaton.for_each_match(text, stop_at_limit, overlapping=overlapping)
+            assert len(calls) == limit
+
+        def fail(index, start, end):
+            raise KeyError(index)
+
+        with pytest.raises(KeyError):
+            automaton.for_each_match(text, fail, overlapping=overlapping)
+
+    calls = []
+    ac.for_each_match("nothing", lambda *m: calls.append(m))
+    assert calls == []
+
+
+def test_trie_dict():
+    """
+    to_trie_dict() returns the patterns as nested dicts keyed by character,
+    and from_trie_dict() builds an equivalent automaton from them.
+    """
+    patterns = [
+        "a",
+        "ab",
+        "abc",
+        "abd",
+        "b",
+        "bcd",
+        "hello",
+        "help",
+        "hell",
+        "world",
+        "wörld",
+        "☃",
+        "☃☃",
+        "🐍",
+        "🐍 snake",
+        "x.y",
+        "{}",
+        "tab\t",
+        "a b c",
+        "zzzzzzzzzz",
+    ]
+    ac = AhoCorasick(patterns)
+    trie = ac.to_trie_dict()
+    assert trie["a"]["b"] == {"": 1, "c": {"": 2}, "d": {"": 3}}
+
+    def depth(node):
+        return max((1 + depth(child) for key, child in node.items() if key), default=0)
+
+    assert depth(trie) == max(len(pattern) for pattern in patterns)
+
+    rebuilt = AhoCorasick.from_trie_dict(trie, matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    assert rebuilt.patterns == patterns
+    assert rebuilt.matchkind == MATCHKIND_LEFTMOST_LONGEST
+    haystack = " ".join(patterns) + " abcd helloworld ☃☃☃"
+    rebuilt = AhoCorasick.from_trie_dict(trie)
+    for overlapping in [False, True]:
+        assert rebuilt.find_matches_as_indexes(
+            haystack, overlapping=overlapping
+        ) == ac.find_matches_as_indexes(haystack, overlapping=overlapping)
+
+    # Duplicates only appear once, so later patterns shift down:
+    trie = AhoCorasick(["ab", "", "ab", "b"]).to_trie_dict()
+    assert trie == {"": 1, "a": {"b": {"": 0}}, "b": {"": 3}}
+    assert AhoCorasick.from_trie_dict(trie).patterns == ["ab", "", "b"]
+
+    with pytest.raises(ValueError):
+        AhoCorasick.from_trie_dict({"ab": {"": 0}})
+    with pytest.raises(ValueError):
+        AhoCorasick.from_trie_dict({"": 0, "a": {"": 0}})
+    with pytest.raises(TypeError):
+        AhoCorasick.from_trie_dict({"a": 0})
+    ac.drop_patterns()
+    with pytest.raises(RuntimeError):
+        ac.to_trie_dict()
+
+
+def test_to_regex_pattern():
+    """
+    to_regex_pattern() returns an equivalent regular expression, with the
+    patterns escaped and longest first.
+    """
+    import re
+
+    ac = AhoCorasick(["he", "hello", "world", "he"])
+    assert ac.to_regex_pattern() == "(?:hello|world|he)"
+    assert ac.to_regex_pattern(word_boundaries=True) == r"\b(?:hello|world|he)\b"
+    assert ac.to_regex_pattern(case_insensitive=True) == "(?i)(?:hello|world|he)"
+
+    haystack = "hello world, help HELLO"
+    leftmost_longest = AhoCorasick(
+        ["he", "hello", "world"], matchkind=MATCHKIND_LEFTMOST_LONGEST
+    )
+    assert [
+        m.group() for m in re.finditer(ac.to_regex_pattern(), haystack)
+    ] == leftmost_longest.find_matches_as_strings(haystack)
+    assert re.findall(ac.to_regex_pattern(word_boundaries=True), haystack) == [
+        "hello",
+        "world",
+    ]
+    assert re.findall(ac.to_regex_pattern(case_insensitive=True), haystack) == [
+        "hello",
+        "world",
+        "he",
+        "HELLO",
+    ]
+
+    # Metacharacters are escaped, including ones that only matter inside
+    # character classes:
+    patterns = ["a.b", "[a-z]", "(a|b)*", "^$", "\\d", "{1,2}", "a+?", "#&~ é"]
+    regex = AhoCorasick(patterns).to_regex_pattern()
+    for pattern in patterns:
+        assert re.fullmatch(regex, pattern)
+        escaped = AhoCorasick([pattern]).to_regex_pattern()[len("(?:") : -1]
+        assert re.fullmatch("[" + escaped + "]+", pattern)
+    assert re.search(regex, "axb") is None
+    # An unescaped "-" would make this a range including "m":
+    escaped = AhoCorasick(["a-z"]).to_regex_pattern()[len("(?:") : -1]
+    assert re.fullmatch("[" + escaped + "]", "m") is None
+
+    with pytest.raises(ValueError):
+        AhoCorasick([]).to_regex_pattern()
+    ac.drop_patterns()
+    with pytest.raises(RuntimeError):
+        ac.to_regex_pattern()
+
+
+def test_from_regex_literals():
+    """
+    from_regex_literals() unescapes regular expressions that are literal
+    strings, dropping anchors, and rejects anything else.
+    """
+    ac = AhoCorasick.from_regex_literals(["foo\\.bar", "^start", "end$", "^a\\*b$"])
+    assert ac.patterns == ["foo.bar", "start", "end", "a*b"]
+    assert ac.find_matches_as_strings("foo.bar fooxbar a*b") == ["foo.bar", "a*b"]
+
+    # Escaped anchors are literals, and common escape sequences work:
+    ac = AhoCorasick.from_regex_literals(
+        ["\\^x\\$", "a\\\\$", "tab\\there"], matchkind=MATCHKIND_LEFTMOST_LONGEST
+    )
+    assert ac.patterns == ["^x$", "a\\", "tab\there"]
+    assert ac.matchkind == MATCHKIND_LEFTMOST_LONGEST
+
+    # Other keyword arguments are passed on to the constructor too:
+    ac = AhoCorasick.from_regex_literals(
+        ["a\\.b", "^c$"],
+        ids=["x", "y"],
+        store_patterns="compact",
+        implementation="noncontiguous",
+    )
+    assert ac.find_matches_as_ids("a.b c") == [("x", 0, 3), ("y", 4, 5)]
+    assert ac.store_patterns == "compact"
+    assert ac.implementation == "noncontiguous"
+
+    # Round trips with to_regex_pattern()'s escaping:
+    patterns = ["a.b", "[x]", "(y)*", "#&~ é"]
+    ac = AhoCorasick(patterns)
+    escaped = ac.to_regex_pattern()[len("(?:") : -1].split("|")
+    assert sorted(AhoCorasick.from_regex_literals(escaped).patterns) == sorted(
+        patterns
+    )
+
+    invalid = ["foo.*", "a|b", "[ab]", "a+", "(a)", "a{2}", "a?", "\\d", "a\\", "a^b"]
+    for regex in invalid:
+        with pytest.raises(ValueError):
+            AhoCorasick.from_regex_literals(["ok", regex])
+
+
+def test_sort_patterns():
+    """
+    sort_patterns=True sorts patterns before building, so indexes don't depend
+    on input order; ids and values are reordered with their patterns.
+    """
+    haystack = "hello world, howdy"
+    results = []
+    for patterns in [["world", "hello", "howdy"], ["howdy", "world", "hello"]]:
+        ac = AhoCorasick(patterns, sort_patterns=True)
+        assert ac.patterns == ["hello", "howdy", "world"]
+        results.append(ac.find_matches_as_indexes(haystack))
+        for index, pattern in enumerate(ac.patterns):
+            assert patterns[ac.sorted_index_to_original_index(index)] == pattern
+            assert ac.original_index_to_sorted_index(patterns.index(pattern)) == index
+    assert results[0] == results[1] == [(0, 0, 5), (2, 6, 11), (1, 13, 18)]
+
+    ac = AhoCorasick(
+        ["b", "a", "c"], ids=["B", "A", "C"], values=[2, 1, 3], sort_patterns=True
+    )
+    assert ac.patterns == ["a", "b", "c"]
+    assert ac.find_matches_as_values("cab") == [(3, 0, 1), (1, 1, 2), (2, 2, 3)]
+    assert [ac.get_id(i) for i in range(3)] == ["A", "B", "C"]
+
+    # The default keeps the input order:
+    assert AhoCorasick(["b", "a"]).patterns == ["b", "a"]
+
+
+def test_store_patterns_compact():
+    """
+    store_patterns="compact" keeps patterns in a single buffer, with the same
+    results as storing Python strings.
+    """
+    patterns = ["hello", "wörld", "", "he", "hello"]
+    haystack = "hello wörld, he said"
+    normal = AhoCorasick(patterns)
+    compact = AhoCorasick(patterns, store_patterns="compact")
+    assert compact.store_patterns == "compact"
+    assert normal.store_patterns is True
+    assert "store_patterns='compact'" in repr(compact)
+    assert compact.patterns == patterns
+    assert list(compact) == patterns
+    assert [compact.get_pattern(i) for i in range(5)] == patterns
+    assert compact.pattern_lengths() == [5, 6, 0, 2, 5]
+    assert "wörld" in compact and "world" not in compact
+    for overlapping in [False, True]:
+        assert compact.find_matches_as_strings(
+            haystack, overlapping=overlapping
+        ) == normal.find_matches_as_strings(haystack, overlapping=overlapping)
+    assert compact == normal
+    assert hash(compact) == hash(normal)
+    assert compact.to_regex_pattern() == normal.to_regex_pattern()
+    breakdown = compact.memory_usage_breakdown()
+    assert breakdown["stored_patterns"] < normal.memory_usage_breakdown()[
+        "stored_patterns"
+    ]
+
+    updated = compact.update(["said"])
+    assert updated.store_patterns == "compact"
+    assert updated.patterns == patterns + ["said"]
+
+    dropped = AhoCorasick(["hello"], store_patterns=False)
+    assert dropped.store_patterns is False
+    assert dropped.find_matches_as_strings("hello") == ["hello"]
+
+    with pytest.raises(ValueError):
+        AhoCorasick(patterns, store_patterns="compressed")
+    with pytest.raises(TypeError):
+        AhoCorasick(patterns, store_patterns=1.5)
+
+
+@pytest.mark.parametrize("store_patterns", [True, "compact"])
+def test_pickle(store_patterns):
+    """
+    AhoCorasick can be pickled unless its patterns were dropped, keeping
+    pattern storage, IDs, values and any pattern reordering.
+    """
+    import pickle
+
+    haystack = "hello world"
+    ac = AhoCorasick(
+        ["world", "hello", "he"],
+        matchkind=MATCHKIND_LEFTMOST_LONGEST,
+        ids=["w", "h", "e"],
+        values=[1, 2, 3],
+        sort_patterns=True,
+        store_patterns=store_patterns,
+    )
+    ac2 = pickle.loads(pickle.dumps(ac))
+    assert type(ac2) is AhoCorasick
+    assert ac2 == ac
+    assert ac2.store_patterns == store_patterns
+    assert ac2.matchkind == MATCHKIND_LEFTMOST_LONGEST
+    assert ac2.find_matches_as_indexes(haystack) == ac.find_matches_as_indexes(
+        haystack
+    )
+    assert ac2.find_matches_as_ids(haystack) == [("h", 0, 5), ("w", 6, 11)]
+    assert ac2.find_matches_as_values(haystack) == [(2, 0, 5), (1, 6, 11)]
+    assert [ac2.sorted_index_to_original_index(i) for i in range(3)] == [2, 1, 0]
+
+    from_counter = AhoCorasick.from_counter({"a": 1, "b": 2})
+    assert pickle.loads(pickle.dumps(from_counter)) == from_counter
+
+    # The pickled pattern order has to be a permutation:
+    for order in [[0], [0, 2], [0, 0]]:
+        with pytest.raises(ValueError):
+            AhoCorasick(["a", "b"]).__setstate__((None, order))
+
+    ac.drop_patterns()
+    with pytest.raises(TypeError):
+        pickle.dumps(ac)
+
+
+def test_offset_conversion():
+    """
+    byte_to_codepoint() and codepoint_to_byte() convert between UTF-8 byte
+    offsets and code point offsets, e.g. to compare results of AhoCorasick
+    and BytesAhoCorasick.
+    """
+    haystack = "héllo wörld 🎉!"
+    encoded = haystack.encode("utf-8")
+    for codepoint_offset in range(len(haystack) + 1):
+        byte_offset = len(haystack[:codepoint_offset].encode("utf-8"))
+        for h in [haystack, encoded, Haystack(haystack)]:
+            assert codepoint_to_byte(h, codepoint_offset) == byte_offset
+            assert byte_to_codepoint(h, byte_offset) == codepoint_offset
+
+    patterns = ["wörld", "!"]
+    str_matches = AhoCorasick(patterns).find_matches_as_indexes(haystack)
+    bytes_matches = BytesAhoCorasick(
+        [p.encode("utf-8") for p in patterns]
+    ).find_matches_as_indexes(encoded)
+    assert [
+        (i, byte_to_codepoint(haystack, s), byte_to_codepoint(haystack, e))
+        for (i, s, e) in bytes_matches
+    ] == str_matches
+
+    # Inside "é", and inside the emoji:
+    for byte_offset in [2, 16]:
+        with pytest.raises(ValueError, match="boundary"):
+            byte_to_codepoint(haystack, byte_offset)
+    with pytest.raises(ValueError):
+        byte_to_codepoint(haystack, len(encoded) + 1)
+    with pytest.raises(ValueError):
+        codepoint_to_byte(haystack, len(haystack) + 1)
+    with pytest.raises(ValueError):
+        byte_to_codepoint(b"\xff", 0)
+
+
+def test_build_info():
+    """
+    build_info summarizes how an automaton was built.
+    """
+    ac = AhoCorasick(["hello", "wörld", "hi"], store_patterns="compact")
+    info = ac.build_info
+    assert info.implementation == "dfa"
+    assert info.matchkind == MATCHKIND_STANDARD
+    assert info.automaton_bytes == ac.memory_usage_breakdown()["automaton"]
+    assert info.patterns_len == 3
+    assert info.total_pattern_bytes == 13
+    assert info.min_pattern_length == 2
+    assert info.max_pattern_length == 6
+    assert info.store_patterns == "compact"
+    assert info.build_time_us == ac.build_time_us
+    assert repr(info).startswith("BuildInfo(implementation='dfa', ")
+    assert "store_patterns='compact'" in repr(info)
+
+    info = BytesAhoCorasick([b"a", b"b"], matchkind=MATCHKIND_LEFTMOST_FIRST).build_info
+    assert info.implementation == "memchr"
+    assert info.matchkind == MATCHKIND_LEFTMOST_FIRST
+    assert (info.patterns_len, info.total_pattern_bytes) == (2, 2)
+    assert info.store_patterns is False
+    assert "store_patterns=False" in repr(info)
+    with pytest.raises(AttributeError):
+        info.patterns_len = 3
+
+
+def test_intersect():
+    """
+    intersect() keeps the patterns shared with another AhoCorasick, in this
+    one's order; is_subset_of() and is_superset_of() compare pattern sets.
+    """
+    a = AhoCorasick(
+        ["hello", "world", "hi", "hello", "there"],
+        ids=["h", "w", "i", "h2", "t"],
+        values=[1, 2, 3, 4, 5],
+    )
+    b = AhoCorasick(["there", "hello", "bye"], matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    ab = a.intersect(b)
+    ba = b.intersect(a)
+    assert ab.patterns == ["hello", "there"]
+    assert ba.patterns == ["there", "hello"]
+    assert len(ab) == len(ba) == 2
+    assert ab.matchkind == MATCHKIND_STANDARD
+    assert ba.matchkind == MATCHKIND_LEFTMOST_LONGEST
+    assert ab.find_matches_as_ids("hello there") == [("h", 0, 5), ("t", 6, 11)]
+    assert ab.find_matches_as_values("there") == [(5, 0, 5)]
+
+    disjoint = AhoCorasick(["x", "y"])
+    assert len(a.intersect(disjoint)) == len(disjoint.intersect(a)) == 0
+    assert a.intersect(a).patterns == ["hello", "world", "hi", "there"]
+
+    assert ab.is_subset_of(a) and ab.is_subset_of(b)
+    assert a.is_superset_of(ab) and not ab.is_superset_of(a)
+    assert not a.is_subset_of(b) and not b.is_subset_of(a)
+    assert a.is_subset_of(a) and a.is_superset_of(a)
+    assert AhoCorasick([]).is_subset_of(disjoint)
+
+    dropped = AhoCorasick(["hello"])
+    dropped.drop_patterns()
+    with pytest.raises(RuntimeError):
+        a.intersect(dropped)
+    with pytest.raises(RuntimeError):
+        dropped.is_subset_of(a)
+
+
+def test_first_match_per_pattern():
+    """
+    first_match_per_pattern() maps each matching pattern to the span of its
+    first match, in the order they were found.
+    """
+    haystack = "héllo world, héllo wörld, hi"
+    ac = AhoCorasick(["wörld", "héllo", "missing", "héllo", "hi"])
+    first = ac.first_match_per_pattern(haystack)
+    assert first == {1: (0, 5), 0: (19, 24), 4: (26, 28)}
+    assert list(first) == [1, 0, 4]
+    assert ac.first_match_per_pattern(haystack, pattern_keys=True) == {
+        "héllo": (0, 5),
+        "wörld": (19, 24),
+        "hi": (26, 28),
+    }
+    # Overlapping matches also find the duplicate pattern:
+    assert ac.first_match_per_pattern(haystack, overlapping=True) == {
+        1: (0, 5),
+        3: (0, 5),
+        0: (19, 24),
+        4: (26, 28),
+    }
+    assert ac.first_match_per_pattern("") == {}
+
+    bytes_ac = BytesAhoCorasick([p.encode() for p in ["wörld", "héllo", "hi"]])
+    encoded = haystack.encode()
+    assert bytes_ac.first_match_per_pattern(encoded) == {
+        1: (0, 6),
+        0: (21, 27),
+        2: (29, 31),
+    }
+    assert bytes_ac.first_match_per_pattern(encoded, pattern_keys=True) == {
+        "héllo".encode(): (0, 6),
+        "wörld".encode(): (21, 27),
+        b"hi": (29, 31),
+    }
+
+    leftmost = AhoCorasick(["a"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    with pytest.raises(ValueError):
+        leftmost.first_match_per_pattern("a", overlapping=True)
+
+
+def test_find_shadowed_patterns():
+    """
+    find_shadowed_patterns() reports patterns that non-overlapping searches
+    can never return, and which pattern is returned instead.
+    """
+    patterns = ["hello", "he", "hello", "help", "world", "wor"]
+    ac = AhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_FIRST)
+    shadowed = ac.find_shadowed_patterns()
+    assert shadowed == [(2, 0, "duplicate"), (3, 1, "prefix")]
+    for index, shadowing, _ in shadowed:
+        assert ac.find_matches_as_indexes(patterns[index]) == [
+            (shadowing, 0, len(patterns[shadowing]))
+        ]
+
+    ac = AhoCorasick(patterns, matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    assert ac.find_shadowed_patterns() == [(2, 0, "duplicate")]
+
+    ac = AhoCorasick(["abcd", "bc", "ab"])
+    assert ac.find_shadowed_patterns() == [(0, 2, "prefix")]
+    ac = AhoCorasick(["abcd", "bc"])
+    assert ac.find_shadowed_patterns() == [(0, 1, "substring")]
+
+    assert AhoCorasick(["a", "b"]).find_shadowed_patterns() == []
+    ac.drop_patterns()
+    with pytest.raises(RuntimeError):
+        ac.find_shadowed_patterns()
+
+
+def test_unmatchable_patterns():
+    """
+    unmatchable_patterns() returns the indexes of shadowed patterns, or with
+    a sample haystack, of patterns a search of it doesn't report.
+    """
+    # With MATCHKIND_LEFTMOST_FIRST, a longer pattern starting with an
+    # earlier, shorter one never wins; the shorter one still can:
+    ac = AhoCorasick(["ab", "abc", "b", "xabcd"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert ac.unmatchable_patterns() == [1]
+    assert ac.find_matches_as_indexes("abc") == [(0, 0, 2)]
+    assert ac.unmatchable_patterns() == [
+        index for (index, _, _) in ac.find_shadowed_patterns()
+    ]
+    assert ac.unmatchable_patterns("xabcd") == [0, 1, 2]
+    assert ac.unmatchable_patterns("ab b") == [1, 3]
+    assert ac.unmatchable_patterns(haystack_sample="") == [0, 1, 2, 3]
+
+    # With MATCHKIND_LEFTMOST_LONGEST, a shorter prefix can still match:
+    ac = AhoCorasick(["ab", "abc"], matchkind=MATCHKIND_LEFTMOST_LONGEST)
+    assert ac.unmatchable_patterns() == []
+    assert ac.unmatchable_patterns("abc") == [0]
+    assert AhoCorasick(["a", "a"]).unmatchable_patterns() == [1]
+
+
+def test_on_build_progress():
+    """
+    on_build_progress is called every progress_interval patterns and once the
+    automaton is built; an exception it raises aborts construction.
+    """
+    patterns = ["pattern%d" % i for i in range(25)]
+    calls = []
+    ac = AhoCorasick(
+        patterns,
+        on_build_progress=lambda done, total: calls.append((done, total)),
+        progress_interval=10,
+    )
+    assert calls == [(10, 25), (20, 25), (25, 25)]
+    assert ac.patterns_len == 25
+
+    # The total isn't known up front for a generator:
+    calls = []
+    BytesAhoCorasick(
+        (p.encode() for p in patterns),
+        on_build_progress=lambda done, total: calls.append((done, total)),
+        progress_interval=10,
+    )
+    assert calls == [(10, -1), (20, -1), (25, 25)]
+    calls = []
+    BytesAhoCorasick(
+        [p.encode() for p in patterns],
+        on_build_progress=lambda done, total: calls.append((done, total)),
+        progress_interval=5,
+    )
+    assert len(calls) == 6 and calls[-1] == (25, 25)
+
+    def fail(done, total):
+        raise RuntimeError("stop")
+
+    for cls, pats in [(AhoCorasick, patterns), (BytesAhoCorasick, [b"a", b"b"])]:
+        with pytest.raises(RuntimeError, match="stop"):
+            cls(pats, on_build_progress=fail, progress_interval=1)
+        with pytest.raises(ValueError):
+            cls(pats, on_build_progress=fail, progress_interval=0)
+
+
+def test_set_operations():
+    """
+    union(), intersection() and difference() build new automatons from
+    combined pattern sets, deduplicated by exact string.
+    """
+    allow = AhoCorasick(["good", "fine", "ok", "good"], values=[1, 2, 3, 4])
+    block = AhoCorasick(
+        ["bad", "ok", "awful"], values=[5, 6, 7], matchkind=MATCHKIND_LEFTMOST_LONGEST
+    )
+    union = allow.union(block)
+    assert union.patterns == ["good", "fine", "ok", "bad", "awful"]
+    assert union.find_matches_as_values("ok bad") == [(3, 0, 2), (5, 3, 6)]
+    assert union.matchkind == MATCHKIND_STANDARD
+    assert block.union(allow).patterns == ["bad", "ok", "awful", "good", "fine"]
+
+    intersection = allow.intersection(block)
+    assert intersection.patterns == allow.intersect(block).patterns == ["ok"]
+    assert intersection.find_matches_as_values("ok") == [(3, 0, 2)]
+
+    difference = allow.difference(block)
+    assert difference.patterns == ["good", "fine"]
+    assert difference.find_matches_as_values("fine") == [(2, 0, 4)]
+    assert block.difference(allow).matchkind == MATCHKIND_LEFTMOST_LONGEST
+    assert allow.difference(allow).patterns == []
+
+    overridden = allow.union(block, matchkind=MATCHKIND_LEFTMOST_FIRST)
+    assert overridden.matchkind == MATCHKIND_LEFTMOST_FIRST
+    assert allow.difference(block, matchkind=MATCHKIND_LEFTMOST_FIRST).matchkind == (
+        MATCHKIND_LEFTMOST_FIRST
+    )
+
+    # Values can't be combined with an automaton that has none:
+    with pytest.raises(ValueError):
+        allow.union(AhoCorasick(["x"]))
+    assert allow.difference(AhoCorasick(["ok"])).patterns == ["good", "fine"]
+
+    with pytest.raises(TypeError):
+        allow.union(BytesAhoCorasick([b"good"], store_patterns=True))
+    with pytest.raises(TypeError):
+        allow.difference(["good"])
+
+
+def test_set_operators():
+    """
+    The |, & and - operators are union(), intersection() and difference(),
+    but need both operands to have the same matchkind.
+    """
+    ac1 = AhoCorasick(["hello", "world", "hi"])
+    ac2 = AhoCorasick(["hi", "hello", "bye"])
+    haystack = "hello world, hi, bye"
+
+    def matches(ac):
+        return [
+            (ac.patterns[index], start, end)
+            for (index, start, end) in ac.find_matches_as_indexes(haystack)
+        ]
+
+    union = ac1 | ac2
+    assert union.patterns == ac1.union(ac2).patterns
+    expected = sorted(set(matches(ac1) + matches(ac2)), key=lambda m: m[1])
+    assert matches(union) == expected
+    assert (ac1 & ac2).patterns == ["hello", "hi"]
+    assert (ac2 & ac1).patterns == ["hi", "hello"]
+    assert (ac1 - ac2).patterns == ["world"]
+
+    # |= binds a new object rather than changing the existing one:
+    ac = ac1
+    ac |= ac2
+    assert ac.patterns == union.patterns
+    assert ac1.patterns == ["hello", "world", "hi"]
+
+    leftmost = AhoCorasick(["hi"], matchkind=MATCHKIND_LEFTMOST_FIRST)
+    for operator in [lambda a, b: a | b, lambda a, b: a & b, lambda a, b: a - b]:
+        with pytest.raises(ValueError, match="MATCHKIND_LEFTMOST_FIRST"):
+            operator(ac1, leftmost)
+    with pytest.raises(TypeError):
+        ac1 | ["bye"]
+    with pytest.raises(TypeError):
+        ac1 & BytesAhoCorasick([b"hi"], store_patterns=True)
+    dropped = AhoCorasick(["bye"])
+    dropped.drop_patterns()
+    with pytest.raises(RuntimeError):
+        ac1 | dropped